@@ -22,15 +22,34 @@ enum TaskAttr {
     ParamsType(syn::Ident),
     TimeLimit(syn::LitInt),
     HardTimeLimit(syn::LitInt),
+    SoftTimeLimit(syn::LitInt),
     MaxRetries(syn::LitInt),
     MinRetryDelay(syn::LitInt),
     MaxRetryDelay(syn::LitInt),
+    RetryBackoff(syn::LitBool),
+    RetryBackoffMax(syn::LitInt),
+    RetryJitter(syn::LitBool),
     ContentType(syn::Ident),
+    Compression(syn::Ident),
     RetryForUnexpected(syn::LitBool),
     AcksLate(syn::LitBool),
+    RejectOnWorkerLost(syn::LitBool),
+    Priority(syn::LitInt),
+    DeliveryMode(syn::Ident),
+    RateLimit(syn::LitStr),
+    Dedup(syn::LitBool),
+    DedupTtl(syn::LitInt),
+    IgnoreResult(syn::LitBool),
+    Queue(syn::LitStr),
+    Exchange(syn::LitStr),
+    RoutingKey(syn::LitStr),
     Bind(syn::LitBool),
+    Blocking(syn::LitBool),
     OnFailure(syn::Ident),
     OnSuccess(syn::Ident),
+    OnRetry(syn::Ident),
+    OnSoftTimeout(syn::Ident),
+    ShouldRetry(syn::Ident),
 }
 
 #[derive(Clone)]
@@ -42,20 +61,38 @@ struct Task {
     params_type: Option<syn::Ident>,
     time_limit: Option<syn::LitInt>,
     hard_time_limit: Option<syn::LitInt>,
+    soft_time_limit: Option<syn::LitInt>,
     max_retries: Option<syn::LitInt>,
     min_retry_delay: Option<syn::LitInt>,
     max_retry_delay: Option<syn::LitInt>,
+    retry_backoff: Option<syn::LitBool>,
+    retry_backoff_max: Option<syn::LitInt>,
+    retry_jitter: Option<syn::LitBool>,
     retry_for_unexpected: Option<syn::LitBool>,
     acks_late: Option<syn::LitBool>,
+    reject_on_worker_lost: Option<syn::LitBool>,
     content_type: Option<syn::Ident>,
+    compression: Option<syn::Ident>,
+    priority: Option<syn::LitInt>,
+    delivery_mode: Option<syn::Ident>,
+    rate_limit: Option<(u32, syn::Ident)>,
+    dedup: Option<syn::LitBool>,
+    dedup_ttl: Option<syn::LitInt>,
+    ignore_result: Option<syn::LitBool>,
+    queue: Option<syn::LitStr>,
+    exchange: Option<(syn::LitStr, syn::LitStr)>,
     original_args: Vec<syn::FnArg>,
     inputs: Option<Punctuated<FnArg, Comma>>,
     inner_block: Option<syn::Block>,
     return_type: Option<syn::Type>,
     is_async: bool,
     bind: bool,
+    blocking: bool,
     on_failure: Option<syn::Ident>,
     on_success: Option<syn::Ident>,
+    on_retry: Option<syn::Ident>,
+    on_soft_timeout: Option<syn::Ident>,
+    should_retry: Option<syn::Ident>,
 }
 
 impl TaskAttrs {
@@ -109,6 +146,16 @@ impl TaskAttrs {
             .next()
     }
 
+    fn soft_time_limit(&self) -> Option<syn::LitInt> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::SoftTimeLimit(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
     fn max_retries(&self) -> Option<syn::LitInt> {
         self.attrs
             .iter()
@@ -139,6 +186,36 @@ impl TaskAttrs {
             .next()
     }
 
+    fn retry_backoff(&self) -> Option<syn::LitBool> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::RetryBackoff(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn retry_backoff_max(&self) -> Option<syn::LitInt> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::RetryBackoffMax(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn retry_jitter(&self) -> Option<syn::LitBool> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::RetryJitter(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
     fn retry_for_unexpected(&self) -> Option<syn::LitBool> {
         self.attrs
             .iter()
@@ -159,6 +236,16 @@ impl TaskAttrs {
             .next()
     }
 
+    fn reject_on_worker_lost(&self) -> Option<syn::LitBool> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::RejectOnWorkerLost(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
     fn content_type(&self) -> Option<syn::Ident> {
         self.attrs
             .iter()
@@ -169,6 +256,106 @@ impl TaskAttrs {
             .next()
     }
 
+    fn compression(&self) -> Option<syn::Ident> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::Compression(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn priority(&self) -> Option<syn::LitInt> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::Priority(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn delivery_mode(&self) -> Option<syn::Ident> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::DeliveryMode(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn rate_limit(&self) -> Option<syn::LitStr> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::RateLimit(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn dedup(&self) -> Option<syn::LitBool> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::Dedup(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn dedup_ttl(&self) -> Option<syn::LitInt> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::DedupTtl(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn ignore_result(&self) -> Option<syn::LitBool> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::IgnoreResult(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn queue(&self) -> Option<syn::LitStr> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::Queue(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn exchange(&self) -> Option<syn::LitStr> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::Exchange(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn routing_key(&self) -> Option<syn::LitStr> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::RoutingKey(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
     fn bind(&self) -> Option<syn::LitBool> {
         self.attrs
             .iter()
@@ -179,6 +366,16 @@ impl TaskAttrs {
             .next()
     }
 
+    fn blocking(&self) -> Option<syn::LitBool> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::Blocking(r) => Some(r.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
     fn on_failure(&self) -> Option<syn::Ident> {
         self.attrs
             .iter()
@@ -198,6 +395,36 @@ impl TaskAttrs {
             })
             .next()
     }
+
+    fn on_retry(&self) -> Option<syn::Ident> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::OnRetry(i) => Some(i.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn on_soft_timeout(&self) -> Option<syn::Ident> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::OnSoftTimeout(i) => Some(i.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn should_retry(&self) -> Option<syn::Ident> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                TaskAttr::ShouldRetry(i) => Some(i.clone()),
+                _ => None,
+            })
+            .next()
+    }
 }
 
 impl parse::Parse for TaskAttrs {
@@ -215,15 +442,34 @@ mod kw {
     syn::custom_keyword!(params_type);
     syn::custom_keyword!(time_limit);
     syn::custom_keyword!(hard_time_limit);
+    syn::custom_keyword!(soft_time_limit);
     syn::custom_keyword!(max_retries);
     syn::custom_keyword!(min_retry_delay);
     syn::custom_keyword!(max_retry_delay);
+    syn::custom_keyword!(retry_backoff);
+    syn::custom_keyword!(retry_backoff_max);
+    syn::custom_keyword!(retry_jitter);
     syn::custom_keyword!(retry_for_unexpected);
     syn::custom_keyword!(acks_late);
+    syn::custom_keyword!(reject_on_worker_lost);
     syn::custom_keyword!(content_type);
+    syn::custom_keyword!(compression);
+    syn::custom_keyword!(priority);
+    syn::custom_keyword!(delivery_mode);
+    syn::custom_keyword!(rate_limit);
+    syn::custom_keyword!(dedup);
+    syn::custom_keyword!(dedup_ttl);
+    syn::custom_keyword!(ignore_result);
+    syn::custom_keyword!(queue);
+    syn::custom_keyword!(exchange);
+    syn::custom_keyword!(routing_key);
     syn::custom_keyword!(bind);
+    syn::custom_keyword!(blocking);
     syn::custom_keyword!(on_failure);
     syn::custom_keyword!(on_success);
+    syn::custom_keyword!(on_retry);
+    syn::custom_keyword!(on_soft_timeout);
+    syn::custom_keyword!(should_retry);
 }
 
 impl parse::Parse for TaskAttr {
@@ -249,6 +495,10 @@ impl parse::Parse for TaskAttr {
             input.parse::<kw::hard_time_limit>()?;
             input.parse::<Token![=]>()?;
             Ok(TaskAttr::HardTimeLimit(input.parse()?))
+        } else if lookahead.peek(kw::soft_time_limit) {
+            input.parse::<kw::soft_time_limit>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::SoftTimeLimit(input.parse()?))
         } else if lookahead.peek(kw::max_retries) {
             input.parse::<kw::max_retries>()?;
             input.parse::<Token![=]>()?;
@@ -261,6 +511,18 @@ impl parse::Parse for TaskAttr {
             input.parse::<kw::max_retry_delay>()?;
             input.parse::<Token![=]>()?;
             Ok(TaskAttr::MaxRetryDelay(input.parse()?))
+        } else if lookahead.peek(kw::retry_backoff_max) {
+            input.parse::<kw::retry_backoff_max>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::RetryBackoffMax(input.parse()?))
+        } else if lookahead.peek(kw::retry_backoff) {
+            input.parse::<kw::retry_backoff>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::RetryBackoff(input.parse()?))
+        } else if lookahead.peek(kw::retry_jitter) {
+            input.parse::<kw::retry_jitter>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::RetryJitter(input.parse()?))
         } else if lookahead.peek(kw::retry_for_unexpected) {
             input.parse::<kw::retry_for_unexpected>()?;
             input.parse::<Token![=]>()?;
@@ -269,14 +531,62 @@ impl parse::Parse for TaskAttr {
             input.parse::<kw::acks_late>()?;
             input.parse::<Token![=]>()?;
             Ok(TaskAttr::AcksLate(input.parse()?))
+        } else if lookahead.peek(kw::reject_on_worker_lost) {
+            input.parse::<kw::reject_on_worker_lost>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::RejectOnWorkerLost(input.parse()?))
         } else if lookahead.peek(kw::content_type) {
             input.parse::<kw::content_type>()?;
             input.parse::<Token![=]>()?;
             Ok(TaskAttr::ContentType(input.parse()?))
+        } else if lookahead.peek(kw::compression) {
+            input.parse::<kw::compression>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::Compression(input.parse()?))
+        } else if lookahead.peek(kw::priority) {
+            input.parse::<kw::priority>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::Priority(input.parse()?))
+        } else if lookahead.peek(kw::delivery_mode) {
+            input.parse::<kw::delivery_mode>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::DeliveryMode(input.parse()?))
+        } else if lookahead.peek(kw::rate_limit) {
+            input.parse::<kw::rate_limit>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::RateLimit(input.parse()?))
+        } else if lookahead.peek(kw::dedup_ttl) {
+            input.parse::<kw::dedup_ttl>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::DedupTtl(input.parse()?))
+        } else if lookahead.peek(kw::dedup) {
+            input.parse::<kw::dedup>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::Dedup(input.parse()?))
+        } else if lookahead.peek(kw::ignore_result) {
+            input.parse::<kw::ignore_result>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::IgnoreResult(input.parse()?))
+        } else if lookahead.peek(kw::queue) {
+            input.parse::<kw::queue>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::Queue(input.parse()?))
+        } else if lookahead.peek(kw::exchange) {
+            input.parse::<kw::exchange>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::Exchange(input.parse()?))
+        } else if lookahead.peek(kw::routing_key) {
+            input.parse::<kw::routing_key>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::RoutingKey(input.parse()?))
         } else if lookahead.peek(kw::bind) {
             input.parse::<kw::bind>()?;
             input.parse::<Token![=]>()?;
             Ok(TaskAttr::Bind(input.parse()?))
+        } else if lookahead.peek(kw::blocking) {
+            input.parse::<kw::blocking>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::Blocking(input.parse()?))
         } else if lookahead.peek(kw::on_failure) {
             input.parse::<kw::on_failure>()?;
             input.parse::<Token![=]>()?;
@@ -285,28 +595,112 @@ impl parse::Parse for TaskAttr {
             input.parse::<kw::on_success>()?;
             input.parse::<Token![=]>()?;
             Ok(TaskAttr::OnSuccess(input.parse()?))
+        } else if lookahead.peek(kw::on_retry) {
+            input.parse::<kw::on_retry>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::OnRetry(input.parse()?))
+        } else if lookahead.peek(kw::on_soft_timeout) {
+            input.parse::<kw::on_soft_timeout>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::OnSoftTimeout(input.parse()?))
+        } else if lookahead.peek(kw::should_retry) {
+            input.parse::<kw::should_retry>()?;
+            input.parse::<Token![=]>()?;
+            Ok(TaskAttr::ShouldRetry(input.parse()?))
+        } else if input.peek(syn::Ident) {
+            let key: syn::Ident = input.parse()?;
+            Err(syn::Error::new(
+                key.span(),
+                format!("unknown task attribute `{}`", key),
+            ))
         } else {
             Err(lookahead.error())
         }
     }
 }
 
+/// Parse a rate limit like `"10/s"` into the number of tasks and the constructor of the
+/// `RateLimit` for its unit.
+fn parse_rate_limit(rate_limit: &str) -> Option<(u32, syn::Ident)> {
+    let (tasks, unit) = match rate_limit.trim().split_once('/') {
+        Some((tasks, unit)) => (tasks, unit),
+        None => (rate_limit.trim(), "s"),
+    };
+    let tasks: u32 = tasks.parse().ok().filter(|tasks| *tasks > 0)?;
+    let constructor = match unit {
+        "s" => "per_second",
+        "m" => "per_minute",
+        "h" => "per_hour",
+        _ => return None,
+    };
+    Some((tasks, syn::Ident::new(constructor, Span::call_site())))
+}
+
 impl Task {
     fn new(attrs: TaskAttrs) -> Self {
+        const ERR_RATE_LIMIT: &str =
+            "invalid rate limit, expected e.g. \"10/s\", \"100/m\" or \"1000/h\"";
+        const ERR_RETRY_DELAYS: &str = "min_retry_delay should not exceed max_retry_delay";
+        const ERR_EXCHANGE: &str = "exchange and routing_key should be set together";
+        const ERR_QUEUE_AND_EXCHANGE: &str =
+            "a task should be routed to either a queue or an exchange";
+
+        let mut errors = Vec::new();
+        let rate_limit = attrs.rate_limit().and_then(|lit| {
+            let rate_limit = parse_rate_limit(&lit.value());
+            if rate_limit.is_none() {
+                errors.push(Error::spanned(ERR_RATE_LIMIT, lit.span()));
+            }
+            rate_limit
+        });
+        if let (Some(min), Some(max)) = (attrs.min_retry_delay(), attrs.max_retry_delay()) {
+            if let (Ok(min_delay), Ok(max_delay)) =
+                (min.base10_parse::<u32>(), max.base10_parse::<u32>())
+            {
+                if min_delay > max_delay {
+                    errors.push(Error::spanned(ERR_RETRY_DELAYS, min.span()));
+                }
+            }
+        }
+        let exchange = match (attrs.exchange(), attrs.routing_key()) {
+            (Some(exchange), Some(routing_key)) => Some((exchange, routing_key)),
+            (Some(lit), None) | (None, Some(lit)) => {
+                errors.push(Error::spanned(ERR_EXCHANGE, lit.span()));
+                None
+            }
+            (None, None) => None,
+        };
+        if let (Some(queue), Some(_)) = (attrs.queue(), &exchange) {
+            errors.push(Error::spanned(ERR_QUEUE_AND_EXCHANGE, queue.span()));
+        }
         Task {
-            errors: Vec::new(),
+            errors,
             visibility: syn::Visibility::Inherited,
             name: attrs.name(),
             wrapper: attrs.wrapper(),
             params_type: attrs.params_type(),
             time_limit: attrs.time_limit(),
             hard_time_limit: attrs.hard_time_limit(),
+            soft_time_limit: attrs.soft_time_limit(),
             max_retries: attrs.max_retries(),
             min_retry_delay: attrs.min_retry_delay(),
             max_retry_delay: attrs.max_retry_delay(),
+            retry_backoff: attrs.retry_backoff(),
+            retry_backoff_max: attrs.retry_backoff_max(),
+            retry_jitter: attrs.retry_jitter(),
             retry_for_unexpected: attrs.retry_for_unexpected(),
             acks_late: attrs.acks_late(),
+            reject_on_worker_lost: attrs.reject_on_worker_lost(),
             content_type: attrs.content_type(),
+            compression: attrs.compression(),
+            priority: attrs.priority(),
+            delivery_mode: attrs.delivery_mode(),
+            rate_limit,
+            dedup: attrs.dedup(),
+            dedup_ttl: attrs.dedup_ttl(),
+            ignore_result: attrs.ignore_result(),
+            queue: attrs.queue(),
+            exchange,
             original_args: Vec::new(),
             inputs: None,
             inner_block: None,
@@ -316,8 +710,15 @@ impl Task {
                 .bind()
                 .map(|lit_bool| lit_bool.value)
                 .unwrap_or_default(),
+            blocking: attrs
+                .blocking()
+                .map(|lit_bool| lit_bool.value)
+                .unwrap_or_default(),
             on_failure: attrs.on_failure(),
             on_success: attrs.on_success(),
+            on_retry: attrs.on_retry(),
+            on_soft_timeout: attrs.on_soft_timeout(),
+            should_retry: attrs.should_retry(),
         }
     }
 }
@@ -328,6 +729,7 @@ impl VisitMut for Task {
         const ERR_VARIADIC: &str = "functions with variadic arguments are not supported";
         const ERR_MISSING_SELF: &str = "bound task should have &self as an argument";
         const ERR_ABI: &str = "functions with non-Rust ABI are not supported";
+        const ERR_BLOCKING_ASYNC: &str = "blocking tasks should not be async";
 
         if let Some(ref mut it) = node.sig.abi {
             self.errors.push(Error::spanned(ERR_ABI, it.span()));
@@ -349,6 +751,13 @@ impl VisitMut for Task {
         self.is_async = node.sig.asyncness.is_some();
         self.inputs = Some(node.sig.inputs.clone());
 
+        if self.blocking && self.is_async {
+            if let Some(ref it) = node.sig.asyncness {
+                self.errors
+                    .push(Error::spanned(ERR_BLOCKING_ASYNC, it.span()));
+            }
+        }
+
         if self.wrapper.is_none() {
             self.wrapper = Some(ident.clone());
         }
@@ -419,7 +828,11 @@ fn args_to_arg_names<'a>(
         })
 }
 
-fn args_to_bindings<'a>(args: impl IntoIterator<Item = &'a syn::FnArg>, bind: bool) -> TokenStream {
+fn args_to_bindings<'a>(
+    args: impl IntoIterator<Item = &'a syn::FnArg>,
+    bind: bool,
+    blocking: bool,
+) -> TokenStream {
     args.into_iter()
         .enumerate()
         .fold(TokenStream::new(), |acc, (i, arg)| match arg {
@@ -427,8 +840,15 @@ fn args_to_bindings<'a>(args: impl IntoIterator<Item = &'a syn::FnArg>, bind: bo
                 syn::Pat::Ident(ref pat) => {
                     let ident = &pat.ident;
                     if bind && i == 0 {
-                        quote! {
-                            let #ident = self;
+                        // Blocking tasks run on another thread, which gets its own copy.
+                        if blocking {
+                            quote! {
+                                let #ident = self.clone();
+                            }
+                        } else {
+                            quote! {
+                                let #ident = self;
+                            }
                         }
                     } else {
                         quote! {
@@ -503,6 +923,11 @@ impl ToTokens for Task {
             .as_ref()
             .map(|r| quote! { Some(#r) })
             .unwrap_or_else(|| quote! { None });
+        let soft_time_limit = self
+            .soft_time_limit
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
         let max_retries = self
             .max_retries
             .as_ref()
@@ -518,6 +943,21 @@ impl ToTokens for Task {
             .as_ref()
             .map(|r| quote! { Some(#r) })
             .unwrap_or_else(|| quote! { None });
+        let retry_backoff = self
+            .retry_backoff
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
+        let retry_backoff_max = self
+            .retry_backoff_max
+            .as_ref()
+            .map(|r| quote! { Some(::std::time::Duration::from_secs(#r)) })
+            .unwrap_or_else(|| quote! { None });
+        let retry_jitter = self
+            .retry_jitter
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
         let retry_for_unexpected = self
             .retry_for_unexpected
             .as_ref()
@@ -528,15 +968,66 @@ impl ToTokens for Task {
             .as_ref()
             .map(|r| quote! { Some(#r) })
             .unwrap_or_else(|| quote! { None });
+        let reject_on_worker_lost = self
+            .reject_on_worker_lost
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
         let content_type = self
             .content_type
             .as_ref()
             .map(|r| quote! { Some(#r) })
-            .unwrap_or_else(|| quote! { Some(#krate::protocol::MessageContentType::Json) });
+            .unwrap_or_else(|| quote! { None });
+        let compression = self
+            .compression
+            .as_ref()
+            .map(|r| quote! { Some(#krate::protocol::Compression::#r) })
+            .unwrap_or_else(|| quote! { None });
+        let priority = self
+            .priority
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
+        let delivery_mode = self
+            .delivery_mode
+            .as_ref()
+            .map(|r| quote! { Some(#krate::protocol::DeliveryMode::#r) })
+            .unwrap_or_else(|| quote! { None });
+        let rate_limit = self
+            .rate_limit
+            .as_ref()
+            .map(|(tasks, constructor)| quote! { Some(#krate::task::RateLimit::#constructor(#tasks)) })
+            .unwrap_or_else(|| quote! { None });
+        let dedup = self
+            .dedup
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
+        let dedup_ttl = self
+            .dedup_ttl
+            .as_ref()
+            .map(|r| quote! { Some(::std::time::Duration::from_secs(#r)) })
+            .unwrap_or_else(|| quote! { None });
+        let ignore_result = self
+            .ignore_result
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
+        let queue = self
+            .queue
+            .as_ref()
+            .map(|r| quote! { Some(#r) })
+            .unwrap_or_else(|| quote! { None });
+        let exchange = self
+            .exchange
+            .as_ref()
+            .map(|(exchange, routing_key)| quote! { Some((#exchange, #routing_key)) })
+            .unwrap_or_else(|| quote! { None });
+        let blocking = self.blocking;
         let task_name = self.name.as_ref().unwrap();
         let arg_names = args_to_arg_names(&self.original_args, self.bind);
         let serialized_fields = args_to_fields(&self.original_args, self.bind);
-        let deserialized_bindings = args_to_bindings(&self.original_args, self.bind);
+        let deserialized_bindings = args_to_bindings(&self.original_args, self.bind, self.blocking);
         let inner_block = {
             let block = &self.inner_block;
             quote!(#block)
@@ -614,6 +1105,27 @@ impl ToTokens for Task {
             quote! {
                 Ok(#wrapper::_run(#calling_args).await?)
             }
+        } else if self.blocking {
+            // The bound task is moved to the blocking thread and borrowed from there.
+            let bind_task = self
+                .original_args
+                .first()
+                .filter(|_| self.bind)
+                .and_then(|arg| match arg {
+                    syn::FnArg::Typed(cap) => match *cap.pat {
+                        syn::Pat::Ident(ref pat) => Some(&pat.ident),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .map(|ident| quote! { let #ident = &#ident; });
+            quote! {
+                Ok(#krate::task::run_blocking(move || {
+                    #bind_task
+                    #wrapper::_run(#calling_args)
+                })
+                .await?)
+            }
         } else {
             quote! {
                 Ok(#wrapper::_run(#calling_args)?)
@@ -622,18 +1134,40 @@ impl ToTokens for Task {
 
         let call_on_failure = match self.on_failure.as_ref() {
             Some(ident) => quote! {
-                #ident(self, err).await
+                #ident(self, ctx, err).await
+            },
+            None => quote! {},
+        };
+
+        let call_on_retry = match self.on_retry.as_ref() {
+            Some(ident) => quote! {
+                #ident(self, ctx, err).await
             },
             None => quote! {},
         };
 
         let call_on_success = match self.on_success.as_ref() {
             Some(ident) => quote! {
-                #ident(self, returned).await
+                #ident(self, ctx, returned).await
             },
             None => quote! {},
         };
 
+        let call_on_soft_timeout = match self.on_soft_timeout.as_ref() {
+            Some(ident) => quote! {
+                #ident(self).await
+            },
+            None => quote! {},
+        };
+
+        let should_retry = self.should_retry.as_ref().map(|ident| {
+            quote! {
+                fn should_retry(&self, err: &#krate::error::TaskError) -> bool {
+                    #ident(err)
+                }
+            }
+        });
+
         let dummy_const = syn::Ident::new(
             &format!("__IMPL_CELERY_TASK_FOR_{}", wrapper.to_string()),
             Span::call_site(),
@@ -660,13 +1194,29 @@ impl ToTokens for Task {
                     const DEFAULTS: #krate::task::TaskOptions = #krate::task::TaskOptions {
                         time_limit: #time_limit,
                         hard_time_limit: #hard_time_limit,
+                        soft_time_limit: #soft_time_limit,
                         max_retries: #max_retries,
                         min_retry_delay: #min_retry_delay,
                         max_retry_delay: #max_retry_delay,
+                        retry_backoff: #retry_backoff,
+                        retry_backoff_max: #retry_backoff_max,
+                        retry_jitter: #retry_jitter,
                         retry_for_unexpected: #retry_for_unexpected,
                         acks_late: #acks_late,
+                        reject_on_worker_lost: #reject_on_worker_lost,
                         content_type: #content_type,
+                        compression: #compression,
+                        compression_threshold: None,
+                        priority: #priority,
+                        delivery_mode: #delivery_mode,
+                        rate_limit: #rate_limit,
+                        dedup: #dedup,
+                        dedup_ttl: #dedup_ttl,
+                        ignore_result: #ignore_result,
                     };
+                    const QUEUE: Option<&'static str> = #queue;
+                    const EXCHANGE: Option<(&'static str, &'static str)> = #exchange;
+                    const BLOCKING: bool = #blocking;
 
                     type Params = #params_type;
                     type Returns = <#return_type as #krate::task::AsTaskResult>::Returns;
@@ -693,14 +1243,37 @@ impl ToTokens for Task {
                     }
 
                     #[allow(unused_variables)]
-                    async fn on_failure(&self, err: &#krate::error::TaskError) {
+                    async fn on_failure(
+                        &self,
+                        ctx: &#krate::task::TaskContext<'_, Self>,
+                        err: &#krate::error::TaskError,
+                    ) {
                         #call_on_failure
                     }
 
                     #[allow(unused_variables)]
-                    async fn on_success(&self, returned: &Self::Returns) {
+                    async fn on_retry(
+                        &self,
+                        ctx: &#krate::task::TaskContext<'_, Self>,
+                        err: &#krate::error::TaskError,
+                    ) {
+                        #call_on_retry
+                    }
+
+                    #[allow(unused_variables)]
+                    async fn on_success(
+                        &self,
+                        ctx: &#krate::task::TaskContext<'_, Self>,
+                        returned: &Self::Returns,
+                    ) {
                         #call_on_success
                     }
+
+                    async fn on_soft_timeout(&self) {
+                        #call_on_soft_timeout
+                    }
+
+                    #should_retry
                 }
             };
         };
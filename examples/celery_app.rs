@@ -42,6 +42,23 @@ fn bound_task(task: &Self) {
     println!("{:?}", task.request.hostname);
 }
 
+// Demonstrates a long running task which stops early when it is aborted with
+// `control_terminate`. Tasks which don't watch their abort token are terminated after the
+// `worker_abort_grace_period` of the worker instead.
+#[celery::task(bind = true)]
+async fn export_task(task: &Self, pages: u32) -> TaskResult<u32> {
+    for page in 0..pages {
+        tokio::select! {
+            _ = task.request.abort_token().cancelled() => {
+                println!("Export aborted after {} pages", page);
+                return Ok(page);
+            }
+            _ = time::sleep(Duration::from_secs(1)) => (),
+        }
+    }
+    Ok(pages)
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "celery_app",
@@ -51,7 +68,7 @@ fn bound_task(task: &Self) {
 enum CeleryOpt {
     Consume,
     Produce {
-        #[structopt(possible_values = &["add", "buggy_task", "bound_task", "long_running_task"])]
+        #[structopt(possible_values = &["add", "buggy_task", "bound_task", "long_running_task", "export_task"])]
         tasks: Vec<String>,
     },
 }
@@ -70,6 +87,7 @@ async fn main() -> Result<()> {
             buggy_task,
             long_running_task,
             bound_task,
+            export_task,
         ],
         // This just shows how we can route certain tasks to certain queues based
         // on glob matching.
@@ -79,6 +97,8 @@ async fn main() -> Result<()> {
         ],
         prefetch_count = 2,
         heartbeat = Some(10),
+        // Required to abort tasks with `control_terminate`.
+        worker_enable_remote_control = true,
     ).await?;
 
     match opt {
@@ -93,7 +113,9 @@ async fn main() -> Result<()> {
                 my_app.send_task(bound_task::new()).await?;
 
                 // Sending a task with additional options like `countdown`.
-                my_app.send_task(add::new(1, 3).with_countdown(3)).await?;
+                my_app
+                    .send_task(add::new(1, 3).with_countdown(Duration::from_secs(3)))
+                    .await?;
 
                 // Send the buggy task that will fail and be retried a few times.
                 my_app.send_task(buggy_task::new()).await?;
@@ -108,6 +130,11 @@ async fn main() -> Result<()> {
                         .send_task(long_running_task::new(Some(10)).with_time_limit(20))
                         .await?;
                 }
+
+                // Send a long export and abort it after a few pages.
+                let export = my_app.send_task(export_task::new(60)).await?;
+                time::sleep(Duration::from_secs(5)).await;
+                my_app.control_terminate(&export.task_id()).await?;
             } else {
                 for task in tasks {
                     match task.as_str() {
@@ -117,6 +144,7 @@ async fn main() -> Result<()> {
                         "long_running_task" => {
                             my_app.send_task(long_running_task::new(Some(3))).await?
                         }
+                        "export_task" => my_app.send_task(export_task::new(60)).await?,
                         _ => panic!("unknown task"),
                     };
                 }
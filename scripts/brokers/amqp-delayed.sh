@@ -0,0 +1,6 @@
+#!/bin/sh
+
+set -e
+
+# RabbitMQ with the delayed message exchange plugin enabled.
+docker run -p 127.0.0.1:5672:5672 --rm heidiks/rabbitmq-delayed-message-exchange
@@ -0,0 +1,5 @@
+#!/bin/sh
+
+set -e
+
+docker run -p 127.0.0.1:4222:4222 --rm nats -js
@@ -0,0 +1,189 @@
+//! Autoscaling of the number of tasks a worker executes concurrently.
+//!
+//! Like Python Celery's autoscaler, the concurrency grows right away when more tasks are
+//! waiting than can be executed, and shrinks once the worker has been less busy for a
+//! while. Shrinking never interrupts tasks: the extra permits are only taken back as the
+//! tasks holding them finish.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{Duration, Instant};
+
+/// How often the queues are sampled.
+pub(crate) const AUTOSCALE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the worker has to be less busy before its concurrency shrinks.
+const AUTOSCALE_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// The semaphore limiting the number of tasks executed concurrently, which can be resized
+/// while tasks hold permits.
+pub(crate) struct Concurrency {
+    semaphore: Semaphore,
+    /// The number of tasks which can be executed concurrently.
+    limit: AtomicUsize,
+    /// The number of permits to forget when they are released, after shrinking below the
+    /// number of tasks being executed.
+    excess: Mutex<usize>,
+    /// The number of tasks holding or waiting for a permit.
+    in_flight: AtomicUsize,
+}
+
+impl Concurrency {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(limit),
+            limit: AtomicUsize::new(limit),
+            excess: Mutex::new(0),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait until a task can be executed. The task can run as long as the returned permit
+    /// is held.
+    pub(crate) async fn acquire(&self) -> ConcurrencyPermit<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        ConcurrencyPermit {
+            concurrency: self,
+            permit: Some(permit),
+        }
+    }
+
+    /// The number of tasks which can be executed concurrently.
+    pub(crate) fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// The number of tasks being executed or waiting to be.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Change the number of tasks which can be executed concurrently.
+    pub(crate) fn resize(&self, limit: usize) {
+        let mut excess = self.excess.lock().unwrap();
+        let current = self.limit.swap(limit, Ordering::Relaxed);
+        if limit > current {
+            // Permits which were still to be forgotten are kept instead.
+            let grow = limit - current;
+            let kept = grow.min(*excess);
+            *excess -= kept;
+            self.semaphore.add_permits(grow - kept);
+        } else {
+            let mut shrink = current - limit;
+            while shrink > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                shrink -= 1;
+            }
+            *excess += shrink;
+        }
+    }
+}
+
+/// A permit to execute a task, released when dropped.
+pub(crate) struct ConcurrencyPermit<'a> {
+    concurrency: &'a Concurrency,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        self.concurrency.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let mut excess = self.concurrency.excess.lock().unwrap();
+        if let Some(permit) = self.permit.take() {
+            if *excess > 0 {
+                *excess -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Decides the concurrency of the worker from how busy it is.
+pub(crate) struct Autoscaler {
+    min: usize,
+    max: usize,
+    /// How long the worker has to be less busy before its concurrency shrinks.
+    keepalive: Duration,
+    /// When the worker last needed its current concurrency.
+    last_busy: Instant,
+}
+
+impl Autoscaler {
+    pub(crate) fn new(min: usize, max: usize) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            keepalive: AUTOSCALE_KEEPALIVE,
+            last_busy: Instant::now(),
+        }
+    }
+
+    /// Get the concurrency the worker should have, given the number of tasks it is
+    /// executing or about to, and the number of messages waiting in its queues.
+    pub(crate) fn target(&mut self, current: usize, in_flight: usize, backlog: usize) -> usize {
+        let wanted = (in_flight + backlog).clamp(self.min, self.max);
+        let now = Instant::now();
+        if wanted >= current || now.duration_since(self.last_busy) >= self.keepalive {
+            self.last_busy = now;
+            wanted
+        } else {
+            current
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_concurrency_resize() {
+        let concurrency = Concurrency::new(2);
+        let first = concurrency.acquire().await;
+        let second = concurrency.acquire().await;
+        assert_eq!(2, concurrency.in_flight());
+
+        // Shrinking waits for the tasks to finish.
+        concurrency.resize(1);
+        drop(first);
+        assert!(concurrency.semaphore.try_acquire().is_err());
+        drop(second);
+        assert_eq!(1, concurrency.semaphore.available_permits());
+
+        concurrency.resize(3);
+        assert_eq!(3, concurrency.semaphore.available_permits());
+        assert_eq!(3, concurrency.limit());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_grow_cancels_shrink() {
+        let concurrency = Concurrency::new(1);
+        let permit = concurrency.acquire().await;
+        concurrency.resize(0);
+        concurrency.resize(1);
+        drop(permit);
+        assert_eq!(1, concurrency.semaphore.available_permits());
+    }
+
+    #[test]
+    fn test_autoscaler_target() {
+        let mut autoscaler = Autoscaler::new(1, 4);
+        assert_eq!(1, autoscaler.target(1, 0, 0));
+        // Growing is immediate, up to the maximum.
+        assert_eq!(3, autoscaler.target(1, 1, 2));
+        assert_eq!(4, autoscaler.target(3, 2, 10));
+        // Shrinking waits for the worker to be less busy for a while.
+        assert_eq!(4, autoscaler.target(4, 1, 0));
+        autoscaler.keepalive = Duration::from_secs(0);
+        assert_eq!(1, autoscaler.target(4, 1, 0));
+    }
+}
@@ -0,0 +1,191 @@
+//! The batches in which the workers run the [`BatchTask`]s.
+
+use crate::logging::error;
+use async_trait::async_trait;
+use futures::FutureExt;
+use std::convert::TryFrom;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Duration;
+
+use crate::broker::Delivery;
+use crate::error::{ProtocolError, TaskError};
+use crate::protocol::Message;
+use crate::task::{BatchFailurePolicy, BatchTask, Request, TaskEvent, TaskState};
+
+/// A task waiting in a batch, along with the delivery of its message.
+pub(super) struct BatchMember {
+    pub(super) delivery: Box<dyn Delivery>,
+    pub(super) task_id: String,
+    pub(super) queue: String,
+    pub(super) ignore_result: bool,
+}
+
+/// The outcome of a task of a batch which ran: the serialized value it returned, or its
+/// error.
+pub(super) struct BatchOutcome {
+    pub(super) member: BatchMember,
+    pub(super) result: Result<String, TaskError>,
+}
+
+/// Where a task was added to the pending batch of its name.
+pub(super) struct BatchPush {
+    /// The generation of the batch, which only the first flush of the batch runs.
+    pub(super) generation: u64,
+    /// Whether the task started the batch, in which case it flushes the batch once the
+    /// flush interval elapsed.
+    pub(super) started: bool,
+    /// Whether the task filled up the batch, in which case it flushes the batch right away.
+    pub(super) full: bool,
+}
+
+/// The pending batch of a [`BatchTask`], with the type of the task erased so that the
+/// worker can keep the batchers of all the batch tasks together.
+#[async_trait]
+pub(super) trait BatcherTrait: Send + Sync {
+    /// The longest time the first task of a batch waits for the batch to fill up.
+    fn flush_interval(&self) -> Duration;
+
+    /// Add the task of a message to the pending batch. The delivery is given back along
+    /// with the error when the message can't be deserialized.
+    fn push(
+        &self,
+        message: Message,
+        delivery: Box<dyn Delivery>,
+        hostname: &str,
+        queue: &str,
+    ) -> Result<BatchPush, (ProtocolError, Box<dyn Delivery>)>;
+
+    /// Run the pending batch if it is still the batch of `generation`, and return the
+    /// outcomes of its tasks. A started event is sent for each task before the batch runs.
+    async fn flush(
+        &self,
+        generation: u64,
+        event_tx: &UnboundedSender<TaskEvent>,
+    ) -> Vec<BatchOutcome>;
+}
+
+struct PendingBatch<T: BatchTask> {
+    generation: u64,
+    tasks: Vec<(Request<T>, BatchMember)>,
+}
+
+pub(super) struct Batcher<T: BatchTask> {
+    /// Whether the tasks ignore their results unless their requests say otherwise.
+    ignore_result: Option<bool>,
+    pending: Mutex<PendingBatch<T>>,
+}
+
+impl<T: BatchTask> Batcher<T> {
+    pub(super) fn new(ignore_result: Option<bool>) -> Self {
+        Self {
+            ignore_result,
+            pending: Mutex::new(PendingBatch {
+                generation: 0,
+                tasks: Vec::new(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: BatchTask + 'static> BatcherTrait for Batcher<T> {
+    fn flush_interval(&self) -> Duration {
+        T::FLUSH_INTERVAL
+    }
+
+    fn push(
+        &self,
+        message: Message,
+        delivery: Box<dyn Delivery>,
+        hostname: &str,
+        queue: &str,
+    ) -> Result<BatchPush, (ProtocolError, Box<dyn Delivery>)> {
+        let task_id = message.task_id().to_string();
+        let mut request = match Request::<T>::try_from(message) {
+            Ok(request) => request,
+            Err(e) => return Err((e, delivery)),
+        };
+        request.hostname = Some(hostname.into());
+        request.queue = Some(queue.into());
+        let ignore_result = request
+            .ignore_result
+            .or(self.ignore_result)
+            .unwrap_or(false);
+        let member = BatchMember {
+            delivery,
+            task_id,
+            queue: queue.into(),
+            ignore_result,
+        };
+
+        let mut pending = self.pending.lock().unwrap();
+        let started = pending.tasks.is_empty();
+        pending.tasks.push((request, member));
+        Ok(BatchPush {
+            generation: pending.generation,
+            started,
+            full: pending.tasks.len() >= T::MAX_SIZE,
+        })
+    }
+
+    async fn flush(
+        &self,
+        generation: u64,
+        event_tx: &UnboundedSender<TaskEvent>,
+    ) -> Vec<BatchOutcome> {
+        let tasks = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.generation != generation || pending.tasks.is_empty() {
+                return vec![];
+            }
+            pending.generation += 1;
+            std::mem::take(&mut pending.tasks)
+        };
+        let (requests, members): (Vec<_>, Vec<_>) = tasks.into_iter().unzip();
+        for _ in &members {
+            event_tx
+                .send(TaskEvent::StatusChange(TaskState::Started))
+                .unwrap_or_else(|_| {
+                    error!("Failed sending task event");
+                });
+        }
+
+        // A batch which panics fails like a batch which returns an error.
+        let size = requests.len();
+        let mut results: Vec<Result<String, TaskError>> =
+            match AssertUnwindSafe(T::run_batch(requests))
+                .catch_unwind()
+                .await
+            {
+                Ok(Ok(results)) => results
+                    .into_iter()
+                    .map(|result| {
+                        result.and_then(|returned| {
+                            serde_json::to_string(&returned)
+                                .map_err(|e| TaskError::UnexpectedError(e.to_string()))
+                        })
+                    })
+                    .collect(),
+                Ok(Err(err)) => vec![Err(err); size],
+                Err(_) => vec![Err(TaskError::UnexpectedError("batch panicked".into())); size],
+            };
+        results.resize_with(size, || {
+            Err(TaskError::UnexpectedError(
+                "batch returned no result for the task".into(),
+            ))
+        });
+        if T::FAILURE_POLICY == BatchFailurePolicy::FailAll {
+            if let Some(err) = results.iter().find_map(|result| result.clone().err()) {
+                results = vec![Err(err); size];
+            }
+        }
+
+        members
+            .into_iter()
+            .zip(results)
+            .map(|(member, result)| BatchOutcome { member, result })
+            .collect()
+    }
+}
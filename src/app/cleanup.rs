@@ -0,0 +1,129 @@
+//! The built-in task deleting the expired results from the backend.
+
+use crate::logging::{info, warn};
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::time::Duration;
+
+use super::trace::{build_task, TraceBuilder, Tracer};
+use crate::backend::Backend;
+use crate::error::{BackendError, TaskError};
+use crate::task::{Request, Task, TaskOptions, TaskResult};
+
+/// The name of the task deleting the results which expired from the backend, registered
+/// on the workers of the apps with a backend. It is named like Python Celery's, so that it
+/// can be scheduled by name with [`Beat::schedule_task_by_name`](crate::beat::Beat::schedule_task_by_name)
+/// or from Python-managed schedules.
+///
+/// The task deletes the results of the tasks which finished longer than the
+/// [`result_expires`](crate::CeleryBuilder::result_expires) ago, with
+/// [`Backend::cleanup`]. It does nothing but log a warning if the backend doesn't support
+/// it.
+pub const BACKEND_CLEANUP_TASK: &str = "celery.backend_cleanup";
+
+/// The parameters of the [`BackendCleanupTask`], which has none. Unlike `()`, they
+/// deserialize from the empty keyword arguments sent by Python.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(super) struct BackendCleanupParams {}
+
+pub(super) struct BackendCleanupTask {
+    request: Request<Self>,
+    options: TaskOptions,
+    backend: Option<Arc<dyn Backend>>,
+    result_expires: Duration,
+}
+
+#[async_trait]
+impl Task for BackendCleanupTask {
+    const NAME: &'static str = BACKEND_CLEANUP_TASK;
+    const ARGS: &'static [&'static str] = &[];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        time_limit: None,
+        hard_time_limit: None,
+        soft_time_limit: None,
+        // The next scheduled cleanup catches up on a failed one.
+        max_retries: Some(0),
+        min_retry_delay: None,
+        max_retry_delay: None,
+        retry_backoff: None,
+        retry_backoff_max: None,
+        retry_jitter: None,
+        retry_for_unexpected: None,
+        acks_late: None,
+        reject_on_worker_lost: None,
+        content_type: None,
+        compression: None,
+        compression_threshold: None,
+        priority: None,
+        delivery_mode: None,
+        rate_limit: None,
+        dedup: None,
+        dedup_ttl: None,
+        ignore_result: Some(true),
+    };
+
+    type Params = BackendCleanupParams;
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self {
+            request,
+            options,
+            backend: None,
+            result_expires: Duration::from_secs(0),
+        }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => {
+                warn!("Skipping backend cleanup since the app has no backend");
+                return Ok(());
+            }
+        };
+        // Nothing can have expired if the expiry goes back further than dates do.
+        let before = match chrono::Duration::from_std(self.result_expires)
+            .ok()
+            .and_then(|expires| Utc::now().checked_sub_signed(expires))
+        {
+            Some(before) => before,
+            None => return Ok(()),
+        };
+        match backend.cleanup(before).await {
+            Ok(()) => {
+                info!("Deleted the results which finished before {}", before);
+                Ok(())
+            }
+            Err(BackendError::CleanupNotSupported) => {
+                warn!("Skipping backend cleanup since the backend doesn't support it");
+                Ok(())
+            }
+            Err(err) => Err(TaskError::ExpectedError(err.to_string())),
+        }
+    }
+}
+
+/// Get the trace builder of the [`BackendCleanupTask`], which gets the backend of the app
+/// along with the message.
+pub(super) fn cleanup_trace_builder(result_expires: Duration) -> TraceBuilder<dyn Backend> {
+    Box::new(
+        move |message, options, event_tx, hostname, queue, is_eager, backend| {
+            let mut task =
+                build_task::<BackendCleanupTask>(message, options, hostname, queue, is_eager)?;
+            task.backend = backend.clone();
+            task.result_expires = result_expires;
+            Ok(Box::new(Tracer::new(task, event_tx, backend)))
+        },
+    )
+}
@@ -0,0 +1,552 @@
+//! Remote control of the workers.
+//!
+//! This is a simplified equivalent of Python Celery's `celery.pidbox` mailbox: the commands
+//! are sent to a broadcast queue consumed by every worker, with the body of Kombu's mailbox
+//! messages, i.e. the `method` to call with its `arguments` and the `destination` workers.
+//!
+//! The commands expecting replies, like the ones sent with [`Inspect`], also carry the queue
+//! to reply to and a ticket identifying the replies. The workers reply with their node name
+//! mapped to the result of the command, and the replies are collected until a timeout.
+
+use crate::logging::{debug, warn};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+use super::Celery;
+use crate::error::{CeleryError, ProtocolError};
+use crate::protocol::{DeliveryMode, Message, MessageHeaders, MessageProperties};
+
+/// The broadcast queue the commands are sent to.
+pub(crate) const CONTROL_QUEUE: &str = "celery.pidbox";
+
+/// The broadcast queue the workers reply to.
+pub(crate) const REPLY_QUEUE: &str = "celery.pidbox.reply";
+
+/// The name of the task of the control messages, which tells them apart from tasks.
+const CONTROL_TASK: &str = "celery.control";
+
+/// The name of the task of the replies to control messages.
+const REPLY_TASK: &str = "celery.control.reply";
+
+/// The maximum number of revoked task IDs remembered by a worker, the same as Python
+/// Celery's.
+const MAX_REVOKED: usize = 50_000;
+
+/// A change to the consumers of a worker requested by a control command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ConsumerControl {
+    /// Stop consuming and shut down once the tasks being executed finished, like on
+    /// `SIGTERM`.
+    Shutdown,
+    /// Start consuming from a queue, declaring it first.
+    AddConsumer(String),
+    /// Stop consuming from a queue.
+    CancelConsumer(String),
+}
+
+/// A command sent to the workers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ControlCommand {
+    /// The name of the command, e.g. `revoke`.
+    pub(crate) method: String,
+    /// The arguments of the command by name.
+    #[serde(default)]
+    pub(crate) arguments: Map<String, Value>,
+    /// The node names of the workers the command is for, or `None` for all of them.
+    #[serde(default)]
+    pub(crate) destination: Option<Vec<String>>,
+    /// The queue to reply to, if the command expects replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reply_to: Option<String>,
+    /// The ticket identifying the replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ticket: Option<String>,
+}
+
+impl ControlCommand {
+    /// A command for all the workers.
+    pub(crate) fn new(method: &str, arguments: Map<String, Value>) -> Self {
+        Self {
+            method: method.into(),
+            arguments,
+            destination: None,
+            reply_to: None,
+            ticket: None,
+        }
+    }
+
+    /// Whether the command is for the worker with the given node name.
+    pub(crate) fn is_for(&self, hostname: &str) -> bool {
+        match &self.destination {
+            Some(destination) => destination.iter().any(|name| name == hostname),
+            None => true,
+        }
+    }
+
+    /// Build the message carrying the command.
+    pub(crate) fn to_message(&self) -> Result<Message, ProtocolError> {
+        let id = Uuid::new_v4().to_string();
+        Ok(Message {
+            properties: MessageProperties {
+                correlation_id: id.clone(),
+                content_type: "application/json".into(),
+                content_encoding: "utf-8".into(),
+                reply_to: None,
+                priority: None,
+                delivery_mode: Some(DeliveryMode::Transient),
+            },
+            headers: MessageHeaders {
+                id,
+                task: CONTROL_TASK.into(),
+                ..Default::default()
+            },
+            raw_body: serde_json::to_vec(self)?,
+        })
+    }
+
+    /// Get the command carried by a message, or `None` if it isn't a control message.
+    pub(crate) fn from_message(message: &Message) -> Result<Option<Self>, ProtocolError> {
+        if message.headers.task != CONTROL_TASK {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&message.raw_body)?))
+    }
+
+    /// Build the message carrying the reply of a worker to the command, if it expects
+    /// one.
+    pub(crate) fn reply_message(
+        &self,
+        hostname: &str,
+        reply: Value,
+    ) -> Result<Option<(Message, &str)>, ProtocolError> {
+        let (reply_to, ticket) = match (&self.reply_to, &self.ticket) {
+            (Some(reply_to), Some(ticket)) => (reply_to, ticket),
+            _ => return Ok(None),
+        };
+        let mut body = Map::new();
+        body.insert(hostname.into(), reply);
+        let message = Message {
+            properties: MessageProperties {
+                correlation_id: ticket.clone(),
+                content_type: "application/json".into(),
+                content_encoding: "utf-8".into(),
+                reply_to: None,
+                priority: None,
+                delivery_mode: Some(DeliveryMode::Transient),
+            },
+            headers: MessageHeaders {
+                id: Uuid::new_v4().to_string(),
+                task: REPLY_TASK.into(),
+                origin: Some(hostname.into()),
+                ..Default::default()
+            },
+            raw_body: serde_json::to_vec(&body)?,
+        };
+        Ok(Some((message, reply_to)))
+    }
+}
+
+/// Get the replies carried by a message, or `None` if it isn't a reply with the given
+/// ticket.
+fn parse_reply(
+    message: &Message,
+    ticket: &str,
+) -> Result<Option<Map<String, Value>>, ProtocolError> {
+    if message.headers.task != REPLY_TASK || message.properties.correlation_id != ticket {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&message.raw_body)?))
+}
+
+/// The reply of a worker to a `ping`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Pong {
+    /// Always `"pong"`.
+    pub ok: String,
+}
+
+/// A task being executed by a worker.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ActiveTask {
+    /// The ID of the task.
+    pub id: String,
+    /// The name of the task.
+    pub name: String,
+    /// The node name of the worker executing the task.
+    #[serde(default)]
+    pub hostname: String,
+    /// When the task started, as a UNIX timestamp in seconds.
+    #[serde(default)]
+    pub time_start: Option<f64>,
+    /// How long the task waited in its queue before it started, in seconds, if it was sent
+    /// with the time it was sent at.
+    #[serde(default)]
+    pub queue_latency: Option<f64>,
+}
+
+/// The statistics of a worker.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkerStats {
+    /// The node name of the worker (see [`CeleryBuilder::worker_hostname`](crate::CeleryBuilder::worker_hostname)).
+    pub hostname: String,
+    /// The ID of the process of the worker.
+    pub pid: u32,
+    /// The number of seconds since the worker app was built.
+    pub uptime: u64,
+    /// The prefetch count of the worker.
+    pub prefetch_count: u16,
+    /// The number of tasks being executed.
+    pub active: usize,
+    /// The number of tasks received so far, including the ones which are still waiting for
+    /// their ETA or their turn.
+    pub received: usize,
+    /// The number of tasks executed so far.
+    pub processed: usize,
+    /// The number of tasks which succeeded so far.
+    pub succeeded: usize,
+    /// The number of tasks which failed so far, not counting the ones which were retried.
+    pub failed: usize,
+    /// The number of tasks which were retried so far.
+    pub retried: usize,
+    /// The statistics of the tasks by name.
+    pub tasks: HashMap<String, TaskStats>,
+    /// The error of the last task which failed, as `name[id]: error`.
+    pub last_error: Option<String>,
+    /// The number of tasks the worker executes concurrently, when
+    /// [autoscaling](crate::CeleryBuilder::autoscale).
+    pub concurrency: Option<usize>,
+    /// The [prefetch multiplier](crate::CeleryBuilder::prefetch_multiplier) of the worker,
+    /// from which its prefetch count is derived, if it has one.
+    pub prefetch_multiplier: Option<u16>,
+    /// The number of tasks the worker executes concurrently from the queues with a
+    /// [concurrency limit](crate::CeleryBuilder::queue_concurrency).
+    pub queue_concurrency: HashMap<String, usize>,
+    /// The number of [blocking tasks](crate::task::Task::BLOCKING) the worker executes
+    /// concurrently, if [limited](crate::CeleryBuilder::blocking_threads).
+    pub blocking_threads: Option<usize>,
+    /// The names of the queues the worker consumes from.
+    pub queues: Vec<String>,
+    /// The number of tasks which seemed to start before they were sent, because the clocks
+    /// of their producer and of the worker are skewed. Their queue latency is counted as
+    /// zero.
+    pub negative_queue_latencies: usize,
+    /// The number of tasks received which were requeued or, once they were requeued too
+    /// many times, rejected, because they are excluded from the worker (see
+    /// [`CeleryBuilder::exclude_tasks`](crate::CeleryBuilder::exclude_tasks)).
+    pub excluded: usize,
+    /// The number of tasks sent to the
+    /// [fallback broker](crate::CeleryBuilder::fallback_broker_url) because the broker
+    /// couldn't be reached.
+    pub fallback_sent: usize,
+    /// The resident memory in bytes above which the worker stops (see
+    /// [`CeleryBuilder::max_memory_per_worker_mb`](crate::CeleryBuilder::max_memory_per_worker_mb)).
+    pub max_memory: Option<u64>,
+    /// The resident memory of the worker in bytes, where it is available.
+    pub resident_memory: Option<u64>,
+}
+
+/// The statistics of the tasks of a given name executed by a worker.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskStats {
+    /// The number of tasks received so far.
+    pub received: usize,
+    /// The number of tasks which succeeded so far.
+    pub succeeded: usize,
+    /// The number of tasks which failed so far, not counting the ones which were retried.
+    pub failed: usize,
+    /// The number of tasks which were retried so far.
+    pub retried: usize,
+    /// How long the tasks waited in their queue before they started.
+    pub queue_latency: QueueLatency,
+}
+
+/// The upper bounds, in seconds, of the buckets of the [`QueueLatency`] histograms.
+pub const QUEUE_LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 60.0,
+];
+
+/// A histogram of how long tasks waited in their queue before they started, from when they
+/// were sent or, for the tasks with an ETA, from when they were due.
+///
+/// Only the tasks sent with the time they were sent at, like the ones sent by Rust
+/// producers, are counted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueLatency {
+    /// The number of tasks in each bucket, i.e. which waited at most the matching bound of
+    /// [`QUEUE_LATENCY_BUCKETS`] and more than the previous one, followed by the number of
+    /// tasks which waited longer than the last bound.
+    pub buckets: Vec<usize>,
+    /// The number of tasks counted.
+    pub count: usize,
+    /// The total time the tasks waited, in seconds.
+    pub sum: f64,
+}
+
+impl Default for QueueLatency {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; QUEUE_LATENCY_BUCKETS.len() + 1],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+}
+
+impl QueueLatency {
+    /// Count a task which waited `latency` in its queue.
+    pub(crate) fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = QUEUE_LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(QUEUE_LATENCY_BUCKETS.len());
+        // Statistics deserialized from another worker may have fewer buckets.
+        self.buckets.resize(QUEUE_LATENCY_BUCKETS.len() + 1, 0);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// A queue consumed by a worker.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ActiveQueue {
+    /// The name of the queue.
+    pub name: String,
+}
+
+/// Sends inspection commands to the workers and collects their replies, created with
+/// [`Celery::inspect`].
+///
+/// The replies are mapped by node name of the workers, and only the ones received before
+/// the [`timeout`](Inspect::timeout) are returned.
+pub struct Inspect<'a> {
+    app: &'a Celery,
+    timeout: Duration,
+    destination: Option<Vec<String>>,
+}
+
+impl<'a> Inspect<'a> {
+    pub(crate) fn new(app: &'a Celery) -> Self {
+        Self {
+            app,
+            timeout: Duration::from_secs(1),
+            destination: None,
+        }
+    }
+
+    /// Set how long to wait for replies. Defaults to 1 second.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Only inspect the workers with the given node names. The replies are then returned
+    /// as soon as all of them replied.
+    pub fn destination(mut self, destination: Vec<String>) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Check which workers are alive.
+    pub async fn ping(&self) -> Result<HashMap<String, Pong>, CeleryError> {
+        self.call("ping").await
+    }
+
+    /// Get the tasks being executed by the workers.
+    pub async fn active(&self) -> Result<HashMap<String, Vec<ActiveTask>>, CeleryError> {
+        self.call("active").await
+    }
+
+    /// Get the names of the tasks registered in the workers.
+    pub async fn registered(&self) -> Result<HashMap<String, Vec<String>>, CeleryError> {
+        self.call("registered").await
+    }
+
+    /// Get the queues the workers consume from.
+    pub async fn active_queues(&self) -> Result<HashMap<String, Vec<ActiveQueue>>, CeleryError> {
+        self.call("active_queues").await
+    }
+
+    /// Get the statistics of the workers.
+    pub async fn stats(&self) -> Result<HashMap<String, WorkerStats>, CeleryError> {
+        self.call("stats").await
+    }
+
+    async fn call<R: DeserializeOwned>(
+        &self,
+        method: &str,
+    ) -> Result<HashMap<String, R>, CeleryError> {
+        if !self.app.worker_enable_remote_control {
+            return Err(CeleryError::RemoteControlDisabled);
+        }
+        let ticket = Uuid::new_v4().to_string();
+        let mut command = ControlCommand::new(method, Map::new());
+        command.destination = self.destination.clone();
+        command.reply_to = Some(REPLY_QUEUE.into());
+        command.ticket = Some(ticket.clone());
+
+        // Start consuming the replies before sending the command so that none is missed.
+        let (consumer_tag, mut replies) = self
+            .app
+            .broker
+            .consume(REPLY_QUEUE, Box::new(|_| {}))
+            .await?;
+        let sent = self.app.send_control_command(command).await;
+        let mut results = HashMap::new();
+        if sent.is_ok() {
+            let deadline = time::Instant::now() + self.timeout;
+            while let Ok(Some(delivery)) = time::timeout_at(deadline, replies.next()).await {
+                let delivery = match delivery {
+                    Ok(delivery) => delivery,
+                    Err(e) => {
+                        warn!("Failed receiving reply: {}", e);
+                        continue;
+                    }
+                };
+                self.app.broker.ack(&*delivery).await?;
+                let reply = delivery
+                    .try_deserialize_message()
+                    .and_then(|message| parse_reply(&message, &ticket));
+                match reply {
+                    Ok(Some(reply)) => {
+                        for (hostname, result) in reply {
+                            match serde_json::from_value(result) {
+                                Ok(result) => {
+                                    results.insert(hostname, result);
+                                }
+                                Err(e) => warn!("Invalid reply from {}: {}", hostname, e),
+                            }
+                        }
+                    }
+                    Ok(None) => debug!("Ignoring reply to another command"),
+                    Err(e) => warn!("Invalid reply: {}", e),
+                }
+                if let Some(destination) = &self.destination {
+                    if destination.iter().all(|name| results.contains_key(name)) {
+                        break;
+                    }
+                }
+            }
+        }
+        self.app.broker.cancel(&consumer_tag).await?;
+        sent?;
+        Ok(results)
+    }
+}
+
+/// The IDs of the revoked tasks. Only the most recent ones are remembered, so that a
+/// long-running worker doesn't run out of memory.
+#[derive(Default)]
+pub(crate) struct RevokedTasks {
+    ids: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl RevokedTasks {
+    /// Remember that a task was revoked.
+    pub(crate) fn insert(&self, task_id: &str) {
+        let mut ids = self.ids.lock().unwrap();
+        let (set, order) = &mut *ids;
+        if !set.insert(task_id.into()) {
+            return;
+        }
+        order.push_back(task_id.into());
+        if order.len() > MAX_REVOKED {
+            if let Some(oldest) = order.pop_front() {
+                set.remove(&oldest);
+            }
+        }
+    }
+
+    /// Whether a task was revoked.
+    pub(crate) fn contains(&self, task_id: &str) -> bool {
+        self.ids.lock().unwrap().0.contains(task_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_command_message() {
+        let mut arguments = Map::new();
+        arguments.insert("task_id".into(), json!("abc"));
+        let command = ControlCommand::new("revoke", arguments);
+        let message = command.to_message().unwrap();
+        assert_eq!(
+            Some(command),
+            ControlCommand::from_message(&message).unwrap()
+        );
+
+        // Kombu's mailbox messages have the same body.
+        let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+        assert_eq!(
+            body,
+            json!({"method": "revoke", "arguments": {"task_id": "abc"}, "destination": null})
+        );
+    }
+
+    #[test]
+    fn test_reply_message() {
+        let mut command = ControlCommand::new("ping", Map::new());
+        assert!(command
+            .reply_message("worker@host", json!({"ok": "pong"}))
+            .unwrap()
+            .is_none());
+
+        command.reply_to = Some(REPLY_QUEUE.into());
+        command.ticket = Some("ticket".into());
+        let (message, queue) = command
+            .reply_message("worker@host", json!({"ok": "pong"}))
+            .unwrap()
+            .unwrap();
+        assert_eq!(REPLY_QUEUE, queue);
+        assert!(parse_reply(&message, "other").unwrap().is_none());
+        let reply = parse_reply(&message, "ticket").unwrap().unwrap();
+        assert_eq!(json!({"worker@host": {"ok": "pong"}}), Value::Object(reply));
+    }
+
+    #[test]
+    fn test_command_destination() {
+        let mut command = ControlCommand::new("revoke", Map::new());
+        assert!(command.is_for("worker@host"));
+        command.destination = Some(vec!["other@host".into()]);
+        assert!(!command.is_for("worker@host"));
+    }
+
+    #[test]
+    fn test_revoked_tasks_bounded() {
+        let revoked = RevokedTasks::default();
+        for i in 0..=MAX_REVOKED {
+            revoked.insert(&i.to_string());
+        }
+        assert!(!revoked.contains("0"));
+        assert!(revoked.contains("1"));
+        assert!(revoked.contains(&MAX_REVOKED.to_string()));
+    }
+
+    #[test]
+    fn test_queue_latency_buckets() {
+        let mut latency = QueueLatency::default();
+        latency.observe(Duration::from_millis(1));
+        latency.observe(Duration::from_millis(100));
+        latency.observe(Duration::from_secs(120));
+        assert_eq!(3, latency.count);
+        assert!((latency.sum - 120.101).abs() < 1e-9);
+        assert_eq!(1, latency.buckets[0]);
+        assert_eq!(1, latency.buckets[4]);
+        assert_eq!(1, latency.buckets[QUEUE_LATENCY_BUCKETS.len()]);
+        assert_eq!(3, latency.buckets.iter().sum::<usize>());
+    }
+}
@@ -0,0 +1,258 @@
+//! Monitoring events sent by the workers.
+//!
+//! The events follow the schema of Python Celery's, and are published to the same topic
+//! exchange with the type of the event as routing key (e.g. `worker.heartbeat`), so that
+//! monitoring tools like Flower can tell which workers are alive and what they execute.
+
+use chrono::{Local, Offset, Utc};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use super::control::WorkerStats;
+use crate::error::ProtocolError;
+use crate::protocol::{DeliveryMode, Message, MessageHeaders, MessageProperties};
+
+/// The exchange the events are published to.
+pub(crate) const EVENT_EXCHANGE: &str = "celeryev";
+
+/// The events sent by a worker about itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum WorkerEvent {
+    /// The worker started consuming tasks.
+    Online,
+    /// The worker is still alive, sent periodically.
+    Heartbeat,
+    /// The worker stopped consuming tasks.
+    Offline,
+}
+
+impl WorkerEvent {
+    /// The type of the event, e.g. `worker-heartbeat`.
+    pub(crate) fn event_type(&self) -> &'static str {
+        match self {
+            WorkerEvent::Online => "worker-online",
+            WorkerEvent::Heartbeat => "worker-heartbeat",
+            WorkerEvent::Offline => "worker-offline",
+        }
+    }
+
+    /// The routing key of the event, e.g. `worker.heartbeat`.
+    pub(crate) fn routing_key(&self) -> String {
+        self.event_type().replace('-', ".")
+    }
+}
+
+/// The events sent by a worker about the tasks it executes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TaskStateEvent {
+    /// The worker received the task.
+    Received,
+    /// The task started executing.
+    Started,
+    /// The task succeeded.
+    Succeeded,
+    /// The task failed, or exhausted its retries.
+    Failed,
+    /// The task will be retried.
+    Retried,
+}
+
+impl TaskStateEvent {
+    /// The type of the event, e.g. `task-started`.
+    pub(crate) fn event_type(&self) -> &'static str {
+        match self {
+            TaskStateEvent::Received => "task-received",
+            TaskStateEvent::Started => "task-started",
+            TaskStateEvent::Succeeded => "task-succeeded",
+            TaskStateEvent::Failed => "task-failed",
+            TaskStateEvent::Retried => "task-retried",
+        }
+    }
+
+    /// The routing key of the event, e.g. `task.started`.
+    pub(crate) fn routing_key(&self) -> String {
+        self.event_type().replace('-', ".")
+    }
+}
+
+/// The task reported in its events.
+#[derive(Clone, Debug)]
+pub(crate) struct TaskEventState<'a> {
+    /// The logical clock of the worker, incremented for each event.
+    pub(crate) clock: u64,
+    /// The hostname of the worker executing the task.
+    pub(crate) hostname: &'a str,
+    /// The ID of the task.
+    pub(crate) uuid: &'a str,
+    /// The name the task is reported under, which is its shadow name if it has one.
+    pub(crate) name: &'a str,
+}
+
+/// The state of the worker reported in its events.
+#[derive(Clone, Debug)]
+pub(crate) struct WorkerState<'a> {
+    /// The logical clock of the worker, incremented for each event.
+    pub(crate) clock: u64,
+    /// The number of seconds between heartbeats.
+    pub(crate) freq: f64,
+    /// The statistics of the worker, which are also the reply to the `stats` control
+    /// command.
+    pub(crate) stats: &'a WorkerStats,
+}
+
+/// Build the message of a worker event.
+pub(crate) fn worker_event_message(
+    event: WorkerEvent,
+    state: &WorkerState<'_>,
+) -> Result<Message, ProtocolError> {
+    let now = Utc::now();
+    // Like Python's `time.timezone`, the offset is in hours west of UTC.
+    let utcoffset = -Local::now().offset().fix().local_minus_utc() / 3600;
+    let body = json!({
+        "type": event.event_type(),
+        "hostname": state.stats.hostname,
+        "utcoffset": utcoffset,
+        "pid": state.stats.pid,
+        "clock": state.clock,
+        "freq": state.freq,
+        "active": state.stats.active,
+        "processed": state.stats.processed,
+        "received": state.stats.received,
+        "succeeded": state.stats.succeeded,
+        "failed": state.stats.failed,
+        "retried": state.stats.retried,
+        "concurrency": state.stats.concurrency,
+        "loadavg": load_average(),
+        "max_memory": state.stats.max_memory,
+        "resident_memory": state.stats.resident_memory,
+        "sw_ident": "rusty-celery",
+        "sw_ver": env!("CARGO_PKG_VERSION"),
+        "sw_sys": std::env::consts::OS,
+        "timestamp": now.timestamp_millis() as f64 / 1000.0,
+    });
+    event_message(&state.stats.hostname, &body)
+}
+
+/// Build the message of a task event, with the `fields` specific to its type, e.g. the
+/// `runtime` of a `task-succeeded` event.
+pub(crate) fn task_event_message(
+    event: TaskStateEvent,
+    state: &TaskEventState<'_>,
+    fields: Value,
+) -> Result<Message, ProtocolError> {
+    let now = Utc::now();
+    let utcoffset = -Local::now().offset().fix().local_minus_utc() / 3600;
+    let mut body = json!({
+        "type": event.event_type(),
+        "uuid": state.uuid,
+        "name": state.name,
+        "hostname": state.hostname,
+        "utcoffset": utcoffset,
+        "pid": std::process::id(),
+        "clock": state.clock,
+        "timestamp": now.timestamp_millis() as f64 / 1000.0,
+    });
+    if let (Value::Object(body), Value::Object(fields)) = (&mut body, fields) {
+        body.extend(fields);
+    }
+    event_message(state.hostname, &body)
+}
+
+/// Build the message of an event with the given body, sent by the worker `hostname`.
+fn event_message(hostname: &str, body: &Value) -> Result<Message, ProtocolError> {
+    let id = Uuid::new_v4().to_string();
+    Ok(Message {
+        properties: MessageProperties {
+            correlation_id: id.clone(),
+            content_type: "application/json".into(),
+            content_encoding: "utf-8".into(),
+            reply_to: None,
+            priority: None,
+            // Events are only useful while they are fresh.
+            delivery_mode: Some(DeliveryMode::Transient),
+        },
+        headers: MessageHeaders {
+            id,
+            origin: Some(hostname.into()),
+            ..Default::default()
+        },
+        raw_body: serde_json::to_vec(body)?,
+    })
+}
+
+/// The load averages of the system over 1, 5 and 15 minutes, or zeros where they aren't
+/// available.
+fn load_average() -> [f64; 3] {
+    let mut loadavg = [0.0; 3];
+    if let Ok(contents) = std::fs::read_to_string("/proc/loadavg") {
+        for (load, value) in loadavg.iter_mut().zip(contents.split_whitespace()) {
+            *load = value.parse().unwrap_or_default();
+        }
+    }
+    loadavg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_event_message() {
+        let stats = WorkerStats {
+            hostname: "worker@host".into(),
+            pid: 42,
+            active: 1,
+            processed: 10,
+            failed: 2,
+            concurrency: Some(4),
+            max_memory: Some(512 * 1024 * 1024),
+            resident_memory: Some(64 * 1024 * 1024),
+            ..Default::default()
+        };
+        let state = WorkerState {
+            clock: 3,
+            freq: 2.0,
+            stats: &stats,
+        };
+        let message = worker_event_message(WorkerEvent::Heartbeat, &state).unwrap();
+        assert_eq!("worker.heartbeat", WorkerEvent::Heartbeat.routing_key());
+        assert_eq!(message.properties.content_type, "application/json");
+        let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+        assert_eq!(body["type"], "worker-heartbeat");
+        assert_eq!(body["hostname"], "worker@host");
+        assert_eq!(body["pid"], 42);
+        assert_eq!(body["clock"], 3);
+        assert_eq!(body["freq"], 2.0);
+        assert_eq!(body["active"], 1);
+        assert_eq!(body["processed"], 10);
+        assert_eq!(body["failed"], 2);
+        assert_eq!(body["concurrency"], 4);
+        assert_eq!(body["loadavg"].as_array().unwrap().len(), 3);
+        assert_eq!(body["max_memory"], 512 * 1024 * 1024);
+        assert_eq!(body["resident_memory"], 64 * 1024 * 1024);
+        assert!(body["timestamp"].as_f64().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_task_event_message() {
+        let state = TaskEventState {
+            clock: 5,
+            hostname: "worker@host",
+            uuid: "task-id",
+            name: "run_report:sales_daily",
+        };
+        let message =
+            task_event_message(TaskStateEvent::Succeeded, &state, json!({ "runtime": 1.5 }))
+                .unwrap();
+        assert_eq!("task.succeeded", TaskStateEvent::Succeeded.routing_key());
+        assert_eq!(message.headers.origin.as_deref(), Some("worker@host"));
+        let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+        assert_eq!(body["type"], "task-succeeded");
+        assert_eq!(body["uuid"], "task-id");
+        assert_eq!(body["name"], "run_report:sales_daily");
+        assert_eq!(body["hostname"], "worker@host");
+        assert_eq!(body["clock"], 5);
+        assert_eq!(body["runtime"], 1.5);
+        assert!(body["timestamp"].as_f64().unwrap() > 0.0);
+    }
+}
@@ -0,0 +1,87 @@
+//! The memory watchdog, which stops the worker once its resident memory exceeds
+//! [`max_memory_per_worker_mb`](crate::CeleryBuilder::max_memory_per_worker_mb), like
+//! Python Celery's `worker_max_memory_per_child`.
+//!
+//! The resident memory is sampled with a single cheap call to the API of the platform:
+//! `/proc/self/status` on Linux, `proc_pidinfo` on macOS and `GetProcessMemoryInfo` (the
+//! working set) on Windows. It isn't available on the other platforms.
+
+use tokio::time::Duration;
+
+/// How often the resident memory is sampled.
+pub(crate) const MEMORY_WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The resident memory of the process in bytes, or `None` where it isn't available.
+#[cfg(target_os = "linux")]
+pub(crate) fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// The resident memory of the process in bytes, or `None` where it isn't available.
+#[cfg(target_os = "macos")]
+pub(crate) fn resident_memory() -> Option<u64> {
+    let mut info = std::mem::MaybeUninit::<libc::proc_taskinfo>::zeroed();
+    let size = std::mem::size_of::<libc::proc_taskinfo>() as libc::c_int;
+    // SAFETY: the buffer is a `proc_taskinfo` of `size` bytes, which `proc_pidinfo`
+    // fills entirely when it returns `size`.
+    let written = unsafe {
+        libc::proc_pidinfo(
+            libc::getpid(),
+            libc::PROC_PIDTASKINFO,
+            0,
+            info.as_mut_ptr().cast(),
+            size,
+        )
+    };
+    if written != size {
+        return None;
+    }
+    // SAFETY: the buffer was filled above.
+    Some(unsafe { info.assume_init() }.pti_resident_size)
+}
+
+/// The resident memory of the process in bytes, or `None` where it isn't available.
+#[cfg(windows)]
+pub(crate) fn resident_memory() -> Option<u64> {
+    use windows_sys::Win32::System::ProcessStatus::{
+        GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS,
+    };
+    use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+    let mut counters = std::mem::MaybeUninit::<PROCESS_MEMORY_COUNTERS>::zeroed();
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    // SAFETY: the buffer is a `PROCESS_MEMORY_COUNTERS` of `size` bytes, and the pseudo
+    // handle of the current process doesn't have to be closed.
+    let succeeded =
+        unsafe { GetProcessMemoryInfo(GetCurrentProcess(), counters.as_mut_ptr(), size) };
+    if succeeded == 0 {
+        return None;
+    }
+    // SAFETY: the buffer was filled above.
+    Some(unsafe { counters.assume_init() }.WorkingSetSize as u64)
+}
+
+/// The resident memory of the process in bytes, or `None` where it isn't available.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub(crate) fn resident_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    #[test]
+    fn test_resident_memory() {
+        assert!(resident_memory().unwrap() > 0);
+        // The other tests may allocate or free memory meanwhile, but the resident memory
+        // can't be less than this allocation, which is written to so that it is resident.
+        let allocated = vec![1u8; 64 * 1024 * 1024];
+        assert!(resident_memory().unwrap() >= 64 * 1024 * 1024);
+        drop(allocated);
+    }
+}
@@ -0,0 +1,142 @@
+//! The shutdown of the workers, on signals or when requested.
+//!
+//! A first `SIGINT` (Ctrl+C) or a `SIGTERM` starts a warm shutdown: the worker stops
+//! consuming and waits for its pending tasks. A second `SIGINT` during the warm shutdown
+//! starts a cold shutdown: the pending tasks are cancelled right away, their messages are
+//! settled according to their acks policy, and the connection is closed.
+
+use tokio::select;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum SigType {
+    /// Equivalent to SIGINT on unix systems.
+    Interrupt,
+    /// Equivalent to SIGTERM on unix systems.
+    Terminate,
+}
+
+/// The phases of the shutdown of a worker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum ShutdownPhase {
+    /// The worker is consuming.
+    #[default]
+    Running,
+    /// The worker stopped consuming and waits for its pending tasks.
+    Warm,
+    /// The worker cancels its pending tasks and stops.
+    Cold,
+}
+
+/// The state machine of the shutdown of a worker, which moves through the phases with the
+/// signals it receives.
+#[derive(Debug, Default)]
+pub(super) struct Shutdown {
+    phase: ShutdownPhase,
+}
+
+impl Shutdown {
+    pub(super) fn phase(&self) -> ShutdownPhase {
+        self.phase
+    }
+
+    /// Move to the phase the signal leads to, and return it. Any signal starts the warm
+    /// shutdown, and only an interrupt turns it into a cold shutdown.
+    pub(super) fn signal(&mut self, sigtype: SigType) -> ShutdownPhase {
+        self.phase = match (self.phase, sigtype) {
+            (ShutdownPhase::Running, _) => ShutdownPhase::Warm,
+            (ShutdownPhase::Warm, SigType::Interrupt) => ShutdownPhase::Cold,
+            (phase, _) => phase,
+        };
+        self.phase
+    }
+}
+
+/// The ender listens for signals, and for the shutdowns requested by the app, which are
+/// sent like signals.
+#[cfg(unix)]
+pub(super) struct Ender {
+    sigint: Signal,
+    sigterm: Signal,
+}
+
+#[cfg(unix)]
+impl Ender {
+    pub(super) fn new() -> Result<Self, std::io::Error> {
+        let sigint = signal(SignalKind::interrupt())?;
+        let sigterm = signal(SignalKind::terminate())?;
+
+        Ok(Ender { sigint, sigterm })
+    }
+
+    /// Waits for either an interrupt or terminate.
+    pub(super) async fn wait(
+        &mut self,
+        requests: &mut UnboundedReceiver<SigType>,
+    ) -> Result<SigType, std::io::Error> {
+        let sigtype;
+
+        select! {
+            _ = self.sigint.recv() => {
+                sigtype = SigType::Interrupt
+            },
+            _ = self.sigterm.recv() => {
+                sigtype = SigType::Terminate
+            },
+            Some(requested) = requests.recv() => {
+                sigtype = requested
+            }
+        }
+
+        Ok(sigtype)
+    }
+}
+
+#[cfg(windows)]
+pub(super) struct Ender;
+
+#[cfg(windows)]
+impl Ender {
+    pub(super) fn new() -> Result<Self, std::io::Error> {
+        Ok(Ender)
+    }
+
+    pub(super) async fn wait(
+        &mut self,
+        requests: &mut UnboundedReceiver<SigType>,
+    ) -> Result<SigType, std::io::Error> {
+        select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                Ok(SigType::Interrupt)
+            },
+            Some(requested) = requests.recv() => Ok(requested),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_interrupt_starts_cold_shutdown() {
+        let mut shutdown = Shutdown::default();
+        assert_eq!(ShutdownPhase::Running, shutdown.phase());
+        assert_eq!(ShutdownPhase::Warm, shutdown.signal(SigType::Interrupt));
+        assert_eq!(ShutdownPhase::Cold, shutdown.signal(SigType::Interrupt));
+        assert_eq!(ShutdownPhase::Cold, shutdown.signal(SigType::Terminate));
+        assert_eq!(ShutdownPhase::Cold, shutdown.signal(SigType::Interrupt));
+    }
+
+    #[test]
+    fn test_terminate_keeps_warm_shutdown() {
+        let mut shutdown = Shutdown::default();
+        assert_eq!(ShutdownPhase::Warm, shutdown.signal(SigType::Terminate));
+        assert_eq!(ShutdownPhase::Warm, shutdown.signal(SigType::Terminate));
+        assert_eq!(ShutdownPhase::Cold, shutdown.signal(SigType::Interrupt));
+    }
+}
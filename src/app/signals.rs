@@ -0,0 +1,310 @@
+//! Signals sent around the publication and the execution of tasks, and along the lifecycle
+//! of the workers.
+//!
+//! Like Python Celery's signals, they let cross-cutting concerns like tracing or metrics be
+//! handled once for every task: e.g. a context can be added to the messages with
+//! [`on_before_publish`](crate::CeleryBuilder::on_before_publish) and restored by the
+//! workers with [`on_task_prerun`](crate::CeleryBuilder::on_task_prerun).
+//!
+//! The handlers are async and run one after the other, in the order they were connected. A
+//! handler which panics is logged and skipped, without affecting the task or the other
+//! handlers.
+//!
+//! The lifecycle hooks of the workers, e.g. to register a worker with a service discovery
+//! when it connects with [`on_broker_connect`](crate::CeleryBuilder::on_broker_connect) and
+//! deregister it with [`on_worker_shutdown`](crate::CeleryBuilder::on_worker_shutdown),
+//! get the [`WorkerInfo`] of the worker and can fail. Their errors are logged, except for a
+//! [`HookError::Fatal`] which skips the next hooks and stops the worker when it is starting
+//! or reconnecting.
+//!
+//! The tasks which fail for good are also reported to the handlers connected with
+//! [`on_task_failure_report`](crate::CeleryBuilder::on_task_failure_report), e.g. to forward
+//! them to an error tracker like Sentry.
+//!
+//! ```rust,no_run
+//! # async fn example() -> Result<(), celery::error::CeleryError> {
+//! let app = celery::CeleryBuilder::new("app", "amqp://127.0.0.1:5672//", None)
+//!     .on_before_publish(|message| {
+//!         message
+//!             .headers
+//!             .extra
+//!             .insert("tenant".into(), "tenant-a".into());
+//!         Box::pin(async {})
+//!     })
+//!     .on_task_postrun(|task, state| {
+//!         Box::pin(async move {
+//!             println!("{}[{}] ended in state {:?}", task.name, task.id, state);
+//!         })
+//!     })
+//!     .build()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::logging::error;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use super::control::WorkerStats;
+use crate::error::{HookError, TaskError};
+use crate::protocol::Message;
+use crate::task::TaskState;
+
+/// A handler of the `before_task_publish` signal, which can modify the message of a task
+/// before it is sent.
+pub type BeforePublishHandler =
+    Box<dyn for<'a> Fn(&'a mut Message) -> BoxFuture<'a, ()> + Send + Sync + 'static>;
+
+/// A handler of the `task_prerun` signal, run by the worker before executing a task.
+pub type TaskPrerunHandler =
+    Box<dyn for<'a> Fn(&'a TaskInfo) -> BoxFuture<'a, ()> + Send + Sync + 'static>;
+
+/// A handler of the `task_postrun` signal, run by the worker after executing a task with the
+/// state the task ended in.
+pub type TaskPostrunHandler =
+    Box<dyn for<'a> Fn(&'a TaskInfo, TaskState) -> BoxFuture<'a, ()> + Send + Sync + 'static>;
+
+/// A handler of the reports of the tasks which failed for good.
+pub type FailureReportHandler = Box<dyn Fn(&FailureReport) + Send + Sync + 'static>;
+
+/// A lifecycle hook of the workers, run with the worker it runs for.
+pub type WorkerHookHandler = Box<
+    dyn for<'a> Fn(&'a WorkerInfo) -> BoxFuture<'a, Result<(), HookError>> + Send + Sync + 'static,
+>;
+
+/// The worker a lifecycle hook runs for.
+#[derive(Clone, Debug)]
+pub struct WorkerInfo {
+    /// The node name of the worker.
+    pub hostname: String,
+
+    /// The queues the worker consumes from, besides the queue of the control commands.
+    pub queues: Vec<String>,
+
+    /// The statistics of the worker when the hook runs.
+    pub stats: WorkerStats,
+}
+
+/// The task executed by the worker, sent with the `task_prerun` and `task_postrun` signals.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    /// The name of the task.
+    pub name: String,
+
+    /// The unique ID of the task.
+    pub id: String,
+
+    /// The custom headers the task was sent with.
+    pub headers: HashMap<String, Value>,
+
+    /// How long the task waited in its queue before it started, e.g. to alert on the
+    /// backlog of a task. It is only known for the tasks sent with the time they were sent
+    /// at, and executed by a worker.
+    pub queue_latency: Option<Duration>,
+}
+
+/// A task which failed for good, i.e. which won't be retried, sent to the handlers
+/// connected with [`on_task_failure_report`](crate::CeleryBuilder::on_task_failure_report).
+#[derive(Clone, Debug, Serialize)]
+pub struct FailureReport {
+    /// The name of the task.
+    pub task_name: String,
+
+    /// The unique ID of the task.
+    pub task_id: String,
+
+    /// The node name of the worker which executed the task.
+    pub hostname: String,
+
+    /// The queue the task was consumed from.
+    pub queue: String,
+
+    /// The number of times the task was retried before this last execution.
+    pub retries: u32,
+
+    /// The positional arguments of the task or, if the task was sent with an `argsrepr`
+    /// header, this representation of them, which producers use to hide sensitive
+    /// arguments like with Python Celery.
+    pub args: Value,
+
+    /// The keyword arguments of the task or their `kwargsrepr` representation.
+    pub kwargs: Value,
+
+    /// The error returned by the task, or `None` if it panicked.
+    pub error: Option<TaskError>,
+
+    /// The messages of the errors which made the task fail, from the outermost one, e.g.
+    /// `task retries exceeded` followed by the error returned by the task.
+    pub error_chain: Vec<String>,
+
+    /// Whether the task exceeded its time limit.
+    pub timed_out: bool,
+
+    /// Whether the task panicked.
+    pub panicked: bool,
+
+    /// The custom headers the task was sent with.
+    pub headers: HashMap<String, Value>,
+
+    /// When the task failed.
+    pub date_done: DateTime<Utc>,
+}
+
+/// The handlers connected to the signals of an app.
+#[derive(Default)]
+pub(crate) struct Signals {
+    pub(crate) before_publish: Vec<BeforePublishHandler>,
+    pub(crate) task_prerun: Vec<TaskPrerunHandler>,
+    pub(crate) task_postrun: Vec<TaskPostrunHandler>,
+    pub(crate) task_failure_report: Vec<FailureReportHandler>,
+    pub(crate) worker_init: Vec<WorkerHookHandler>,
+    pub(crate) broker_connect: Vec<WorkerHookHandler>,
+    pub(crate) broker_reconnect: Vec<WorkerHookHandler>,
+    pub(crate) worker_shutdown: Vec<WorkerHookHandler>,
+}
+
+impl Signals {
+    pub(crate) async fn before_publish(&self, message: &mut Message) {
+        for handler in &self.before_publish {
+            run_handler("before_task_publish", async { handler(message).await }).await;
+        }
+    }
+
+    pub(crate) async fn task_prerun(&self, task: &TaskInfo) {
+        for handler in &self.task_prerun {
+            run_handler("task_prerun", async { handler(task).await }).await;
+        }
+    }
+
+    pub(crate) async fn task_postrun(&self, task: &TaskInfo, state: TaskState) {
+        for handler in &self.task_postrun {
+            run_handler("task_postrun", async { handler(task, state.clone()).await }).await;
+        }
+    }
+
+    pub(crate) fn task_failure_report(&self, report: &FailureReport) {
+        for handler in &self.task_failure_report {
+            if std::panic::catch_unwind(AssertUnwindSafe(|| handler(report))).is_err() {
+                error!("A task failure report handler panicked");
+            }
+        }
+    }
+
+    pub(crate) async fn worker_init(&self, worker: &WorkerInfo) -> Result<(), HookError> {
+        run_hooks("worker_init", &self.worker_init, worker).await
+    }
+
+    pub(crate) async fn broker_connect(&self, worker: &WorkerInfo) -> Result<(), HookError> {
+        run_hooks("broker_connect", &self.broker_connect, worker).await
+    }
+
+    pub(crate) async fn broker_reconnect(&self, worker: &WorkerInfo) -> Result<(), HookError> {
+        run_hooks("broker_reconnect", &self.broker_reconnect, worker).await
+    }
+
+    pub(crate) async fn worker_shutdown(&self, worker: &WorkerInfo) -> Result<(), HookError> {
+        run_hooks("worker_shutdown", &self.worker_shutdown, worker).await
+    }
+}
+
+/// Run the lifecycle hooks of a worker in order, until one of them fails fatally.
+async fn run_hooks(
+    signal: &str,
+    hooks: &[WorkerHookHandler],
+    worker: &WorkerInfo,
+) -> Result<(), HookError> {
+    for hook in hooks {
+        match AssertUnwindSafe(hook(worker)).catch_unwind().await {
+            Ok(Ok(())) => (),
+            Ok(Err(err @ HookError::Fatal(_))) => {
+                error!("A {} hook failed fatally: {}", signal, err);
+                return Err(err);
+            }
+            Ok(Err(err)) => error!("A {} hook failed: {}", signal, err),
+            Err(_) => error!("A {} hook panicked", signal),
+        }
+    }
+    Ok(())
+}
+
+/// Run a signal handler, isolating its panics.
+async fn run_handler(signal: &str, handler: impl Future<Output = ()>) {
+    if AssertUnwindSafe(handler).catch_unwind().await.is_err() {
+        error!("A {} signal handler panicked", signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_handlers_run_in_order() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut signals = Signals::default();
+        for index in 0..3 {
+            let calls = calls.clone();
+            signals.task_prerun.push(Box::new(move |task| {
+                let calls = calls.clone();
+                Box::pin(async move {
+                    if index == 1 {
+                        panic!("handler failed");
+                    }
+                    calls.lock().unwrap().push((index, task.id.clone()));
+                })
+            }));
+        }
+
+        let task = TaskInfo {
+            name: "add".into(),
+            id: "abc".into(),
+            headers: HashMap::new(),
+            queue_latency: None,
+        };
+        signals.task_prerun(&task).await;
+        assert_eq!(
+            vec![(0, "abc".to_string()), (2, "abc".to_string())],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hooks_stop_at_fatal_error() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut signals = Signals::default();
+        for index in 0..4 {
+            let calls = calls.clone();
+            signals.broker_connect.push(Box::new(move |_worker| {
+                let calls = calls.clone();
+                Box::pin(async move {
+                    calls.lock().unwrap().push(index);
+                    match index {
+                        0 => Err(HookError::Failed("unavailable".into())),
+                        1 => panic!("hook failed"),
+                        2 => Err(HookError::Fatal("unregistered".into())),
+                        _ => Ok(()),
+                    }
+                })
+            }));
+        }
+
+        let worker = WorkerInfo {
+            hostname: "celery@localhost".into(),
+            queues: vec!["celery".into()],
+            stats: WorkerStats::default(),
+        };
+        let result = signals.broker_connect(&worker).await;
+        assert!(matches!(result, Err(HookError::Fatal(_))));
+        assert_eq!(vec![0, 1, 2], *calls.lock().unwrap());
+        assert!(signals.broker_reconnect(&worker).await.is_ok());
+    }
+}
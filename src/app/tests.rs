@@ -1,10 +1,29 @@
-use super::{Celery, CeleryBuilder};
-use crate::broker::mock::MockBroker;
-use crate::protocol::MessageContentType;
-use crate::task::{Request, Signature, Task, TaskOptions, TaskResult};
+use super::control::{ControlCommand, TaskStats, WorkerStats};
+use super::signals::WorkerInfo;
+use super::{Celery, CeleryBuilder, BACKEND_CLEANUP_TASK};
+use crate::backend::{memory::MemoryBackend, Backend, GroupMetadata, ResultMetadata};
+use crate::broker::{
+    mock::MockBroker, Broker, EtaStrategy, ExchangeKind, LazyBroker, QueueOptions, QueueStrategy,
+};
+use crate::error::{BackendError, CeleryError, HookError, TaskError, TraceError};
+use crate::protocol::{
+    Message, MessageContentType, MessageSigner, SerializedSignature, TryCreateMessage,
+};
+use crate::task::{
+    chord, group, AsyncResult, BatchFailurePolicy, BatchTask, ChordErrorPolicy, RateLimit, Request,
+    RetryOptions, SendOptions, Signature, Task, TaskContext, TaskOptions, TaskResult, TaskState,
+};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
 async fn build_basic_app() -> Celery {
@@ -33,6 +52,31 @@ async fn build_configured_app() -> Celery {
 // We can't use the #[task] macro from inside the crate, unfortunately, so we have
 // to implement tasks the old fashion way.
 
+/// The options of the tasks which don't set them.
+const DEFAULT_TASK_OPTIONS: TaskOptions = TaskOptions {
+    time_limit: None,
+    hard_time_limit: None,
+    soft_time_limit: None,
+    max_retries: None,
+    min_retry_delay: None,
+    max_retry_delay: None,
+    retry_backoff: None,
+    retry_backoff_max: None,
+    retry_jitter: None,
+    retry_for_unexpected: None,
+    acks_late: None,
+    reject_on_worker_lost: None,
+    content_type: None,
+    compression: None,
+    compression_threshold: None,
+    priority: None,
+    delivery_mode: None,
+    rate_limit: None,
+    dedup: None,
+    dedup_ttl: None,
+    ignore_result: None,
+};
+
 struct AddTask {
     request: Request<Self>,
     options: TaskOptions,
@@ -94,11 +138,7 @@ impl Task for MultiplyTask {
         time_limit: Some(5),
         hard_time_limit: Some(10),
         max_retries: Some(1000),
-        min_retry_delay: None,
-        max_retry_delay: None,
-        retry_for_unexpected: None,
-        acks_late: None,
-        content_type: None,
+        ..DEFAULT_TASK_OPTIONS
     };
 
     type Params = MultiplyParams;
@@ -127,156 +167,4101 @@ struct MultiplyParams {
     y: i32,
 }
 
-#[tokio::test]
-async fn test_app_name() {
-    let app = build_basic_app().await;
-    assert!(app.name == "mock-app");
+/// A task which returns the values it was given, e.g. the results of a chord.
+struct CollectTask {
+    request: Request<Self>,
+    options: TaskOptions,
 }
 
-#[tokio::test]
-async fn test_add_task_registered() {
-    let app = build_basic_app().await;
-    assert!(app.task_trace_builders.read().await.contains_key("add"));
+impl CollectTask {
+    fn new() -> Signature<Self> {
+        Signature::<Self>::new(CollectParams { values: vec![] })
+    }
 }
 
-#[tokio::test]
-async fn test_send_task() {
-    let app = build_basic_app().await;
-    let result = app.send_task(AddTask::new(1, 2)).await.unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.task == "add");
+#[async_trait]
+impl Task for CollectTask {
+    const NAME: &'static str = "collect";
+    const ARGS: &'static [&'static str] = &["values"];
+
+    type Params = CollectParams;
+    type Returns = Vec<serde_json::Value>;
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, params: Self::Params) -> TaskResult<Self::Returns> {
+        Ok(params.values)
+    }
 }
 
-#[tokio::test]
-async fn test_send_task_with_countdown() {
-    let app = build_basic_app().await;
-    let result = app
-        .send_task(AddTask::new(1, 2).with_countdown(2))
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.eta.is_some());
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CollectParams {
+    values: Vec<serde_json::Value>,
 }
 
-#[tokio::test]
-async fn test_send_task_with_eta() {
-    let app = build_basic_app().await;
-    let result = app
-        .send_task(AddTask::new(1, 2).with_eta(DateTime::<Utc>::from(SystemTime::now())))
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.eta.is_some());
+struct FailingTask {
+    request: Request<Self>,
+    options: TaskOptions,
 }
 
-#[tokio::test]
-async fn test_send_task_with_expires_in() {
-    let app = build_basic_app().await;
-    let result = app
-        .send_task(AddTask::new(1, 2).with_expires_in(10))
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.expires.is_some());
+#[async_trait]
+impl Task for FailingTask {
+    const NAME: &'static str = "failing";
+    const ARGS: &'static [&'static str] = &[];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        max_retries: Some(1),
+        min_retry_delay: Some(0),
+        max_retry_delay: Some(0),
+        acks_late: Some(true),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        Err(TaskError::ExpectedError("always fails".into()))
+    }
 }
 
-#[tokio::test]
-async fn test_send_task_with_expires() {
-    let app = build_basic_app().await;
-    let dt = DateTime::<Utc>::from(SystemTime::now()) + Duration::from_secs(10);
-    let result = app
-        .send_task(AddTask::new(1, 2).with_expires(dt))
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.expires.is_some());
+/// The retries and whether it was a retry, seen by each execution of a `RetryingTask`.
+static RETRY_ATTEMPTS: Lazy<Mutex<Vec<(u32, bool)>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// A task which fails until it has been retried 3 times.
+struct RetryingTask {
+    request: Request<Self>,
+    options: TaskOptions,
 }
 
-#[tokio::test]
-async fn test_send_task_with_content_type() {
-    let app = build_basic_app().await;
-    let result = app
-        .send_task(AddTask::new(1, 2).with_content_type(MessageContentType::Yaml))
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.properties.content_type == "application/x-yaml");
+#[async_trait]
+impl Task for RetryingTask {
+    const NAME: &'static str = "retrying";
+    const ARGS: &'static [&'static str] = &[];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        max_retries: Some(3),
+        min_retry_delay: Some(0),
+        max_retry_delay: Some(0),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        let request = self.request();
+        RETRY_ATTEMPTS
+            .lock()
+            .unwrap()
+            .push((request.retries, request.is_retry()));
+        if request.retries < 3 {
+            return Err(TaskError::ExpectedError("not yet".into()));
+        }
+        Ok(())
+    }
 }
 
-#[tokio::test]
-async fn test_send_task_with_time_limit() {
-    let app = build_basic_app().await;
-    let result = app
-        .send_task(AddTask::new(1, 2).with_time_limit(5))
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.timelimit == (None, Some(5)));
+/// The token and retries seen by an execution of a `RefreshingTask`.
+type RefreshedToken = (u32, u32);
+
+/// The tokens seen by each execution of the `RefreshingTask`s, by task ID.
+static REFRESHED_TOKENS: Lazy<Mutex<HashMap<String, Vec<RefreshedToken>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A task which retries itself with a new token until its token is 2.
+struct RefreshingTask {
+    request: Request<Self>,
+    options: TaskOptions,
 }
 
-#[tokio::test]
-async fn test_send_task_with_hard_time_limit() {
-    let app = build_basic_app().await;
-    let result = app
-        .send_task(AddTask::new(1, 2).with_hard_time_limit(5))
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.timelimit == (Some(5), None));
+impl RefreshingTask {
+    fn new(token: u32) -> Signature<Self> {
+        Signature::<Self>::new(RefreshingParams { token })
+    }
 }
 
-#[tokio::test]
-async fn test_configured_app_send_task_app_defaults() {
-    let app = build_configured_app().await;
-    let result = app.send_task(AddTask::new(1, 2)).await.unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.timelimit == (None, Some(10)));
-    assert!(message.properties.content_type == "application/x-yaml");
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RefreshingParams {
+    token: u32,
 }
 
-#[tokio::test]
-async fn test_configured_app_send_task_task_defaults() {
-    let app = build_configured_app().await;
-    let result = app.send_task(MultiplyTask::new(1, 2)).await.unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.timelimit == (Some(10), Some(5)));
-    assert!(message.properties.content_type == "application/x-yaml");
+#[async_trait]
+impl Task for RefreshingTask {
+    const NAME: &'static str = "refreshing";
+    const ARGS: &'static [&'static str] = &["token"];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        max_retries: Some(1),
+        min_retry_delay: Some(60),
+        max_retry_delay: Some(60),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = RefreshingParams;
+    type Returns = u32;
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, params: Self::Params) -> TaskResult<Self::Returns> {
+        REFRESHED_TOKENS
+            .lock()
+            .unwrap()
+            .entry(self.request().id.clone())
+            .or_default()
+            .push((params.token, self.request().retries));
+        if params.token < 2 {
+            return self.retry(
+                RetryOptions::new()
+                    .with_countdown(Duration::ZERO)
+                    .with_params(RefreshingParams {
+                        token: params.token + 1,
+                    })
+                    .with_max_retries(5),
+            );
+        }
+        Ok(params.token)
+    }
 }
 
-#[tokio::test]
-async fn test_configured_app_send_task_request_overrides() {
-    let app = build_configured_app().await;
-    let result = app
-        .send_task(
-            MultiplyTask::new(1, 2)
-                .with_time_limit(2)
-                .with_content_type(MessageContentType::Json),
-        )
-        .await
-        .unwrap();
-    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
-    let sent_tasks = mock_broker.sent_tasks.read().await;
-    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
-    assert!(message.headers.timelimit == (Some(10), Some(2)));
-    assert!(message.properties.content_type == "application/json");
+/// A callback run by a `ValidatingTask`, with the params, retries and queue it was given.
+type ValidatingHook = (&'static str, bool, u32, Option<String>);
+
+/// The callbacks run by the `ValidatingTask`s, by task ID.
+static VALIDATING_HOOKS: Lazy<Mutex<HashMap<String, Vec<ValidatingHook>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn record_validating_hook(hook: &'static str, ctx: &TaskContext<'_, ValidatingTask>) {
+    VALIDATING_HOOKS
+        .lock()
+        .unwrap()
+        .entry(ctx.task_id.into())
+        .or_default()
+        .push((hook, *ctx.params, ctx.retries, ctx.queue.map(Into::into)));
+}
+
+/// A task which fails with an `UnexpectedError` when its input is invalid, which is never
+/// retried, or with an `ExpectedError` otherwise.
+struct ValidatingTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for ValidatingTask {
+    const NAME: &'static str = "validating";
+    const ARGS: &'static [&'static str] = &["valid"];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        max_retries: Some(1),
+        min_retry_delay: Some(0),
+        max_retry_delay: Some(0),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = bool;
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, valid: Self::Params) -> TaskResult<Self::Returns> {
+        if valid {
+            Err(TaskError::ExpectedError("service unavailable".into()))
+        } else {
+            Err(TaskError::UnexpectedError("invalid input".into()))
+        }
+    }
+
+    fn should_retry(&self, err: &TaskError) -> bool {
+        matches!(err, TaskError::ExpectedError(_))
+    }
+
+    async fn on_failure(&self, ctx: &TaskContext<'_, Self>, _err: &TaskError) {
+        record_validating_hook("on_failure", ctx);
+    }
+
+    async fn on_retry(&self, ctx: &TaskContext<'_, Self>, _err: &TaskError) {
+        record_validating_hook("on_retry", ctx);
+    }
+}
+
+/// The IDs of the `RecordingTask`s which ran.
+static RECORDED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+struct RecordingTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for RecordingTask {
+    const NAME: &'static str = "recording";
+    const ARGS: &'static [&'static str] = &[];
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        RECORDED.lock().unwrap().insert(self.request.id.clone());
+        Ok(())
+    }
+}
+
+/// A task which takes longer than the tests wait for it.
+struct SlowTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for SlowTask {
+    const NAME: &'static str = "slow";
+    const ARGS: &'static [&'static str] = &[];
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        Ok(())
+    }
+}
+
+/// The number of times an `AbortableTask` stopped early.
+static ABORTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A task which takes longer than the tests wait for it, unless it is aborted.
+struct AbortableTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for AbortableTask {
+    const NAME: &'static str = "abortable";
+    const ARGS: &'static [&'static str] = &[];
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        tokio::select! {
+            _ = self.request.abort_token().cancelled() => {
+                ABORTS.fetch_add(1, Ordering::Relaxed);
+            }
+            _ = tokio::time::sleep(Duration::from_secs(10)) => (),
+        }
+        Ok(())
+    }
+}
+
+/// The sizes of the batches of `PerItemBatchTask`s and `FailAllBatchTask`s which ran.
+static BATCHES: Lazy<Mutex<HashMap<&'static str, Vec<usize>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Run a batch of tasks which double their number, or fail if it is negative.
+fn double_batch<T: Task<Params = i32>>(requests: Vec<Request<T>>) -> Vec<TaskResult<i32>> {
+    BATCHES
+        .lock()
+        .unwrap()
+        .entry(T::NAME)
+        .or_default()
+        .push(requests.len());
+    requests
+        .iter()
+        .map(|request| match request.params {
+            x if x < 0 => Err(TaskError::UnexpectedError(format!("negative: {}", x))),
+            x => Ok(x * 2),
+        })
+        .collect()
+}
+
+/// A batch task which runs in batches of 2, storing the result of each task.
+struct PerItemBatchTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for PerItemBatchTask {
+    const NAME: &'static str = "per_item_batch";
+    const ARGS: &'static [&'static str] = &["x"];
+
+    type Params = i32;
+    type Returns = i32;
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, x: Self::Params) -> TaskResult<Self::Returns> {
+        Ok(x * 2)
+    }
+}
+
+#[async_trait]
+impl BatchTask for PerItemBatchTask {
+    const MAX_SIZE: usize = 2;
+    const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+    const FAILURE_POLICY: BatchFailurePolicy = BatchFailurePolicy::PerItem;
+
+    async fn run_batch(requests: Vec<Request<Self>>) -> TaskResult<Vec<TaskResult<i32>>> {
+        Ok(double_batch(requests))
+    }
+}
+
+/// A batch task whose batches only fill up or run at the shutdown, and fail as a whole.
+struct FailAllBatchTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for FailAllBatchTask {
+    const NAME: &'static str = "fail_all_batch";
+    const ARGS: &'static [&'static str] = &["x"];
+
+    type Params = i32;
+    type Returns = i32;
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, x: Self::Params) -> TaskResult<Self::Returns> {
+        Ok(x * 2)
+    }
+}
+
+#[async_trait]
+impl BatchTask for FailAllBatchTask {
+    const MAX_SIZE: usize = 100;
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(3600);
+
+    async fn run_batch(requests: Vec<Request<Self>>) -> TaskResult<Vec<TaskResult<i32>>> {
+        Ok(double_batch(requests))
+    }
+}
+
+/// A batch task which runs each task alone, for `test_task_failure_report`.
+struct ReportedBatchTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for ReportedBatchTask {
+    const NAME: &'static str = "reported_batch";
+    const ARGS: &'static [&'static str] = &["x"];
+
+    type Params = i32;
+    type Returns = i32;
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, x: Self::Params) -> TaskResult<Self::Returns> {
+        Ok(x * 2)
+    }
+}
+
+#[async_trait]
+impl BatchTask for ReportedBatchTask {
+    const MAX_SIZE: usize = 1;
+    const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+    const FAILURE_POLICY: BatchFailurePolicy = BatchFailurePolicy::PerItem;
+
+    async fn run_batch(requests: Vec<Request<Self>>) -> TaskResult<Vec<TaskResult<i32>>> {
+        Ok(double_batch(requests))
+    }
+}
+
+/// The number of times a `SoftTimeLimitTask` was signalled.
+static SOFT_TIMEOUTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A task which runs until its hard time limit, unless it wraps up at its soft time limit.
+struct SoftTimeLimitTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for SoftTimeLimitTask {
+    const NAME: &'static str = "soft_time_limit";
+    const ARGS: &'static [&'static str] = &["wrap_up"];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        hard_time_limit: Some(2),
+        soft_time_limit: Some(1),
+        max_retries: Some(0),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = bool;
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, wrap_up: Self::Params) -> TaskResult<Self::Returns> {
+        while !(wrap_up && self.request.is_soft_time_limit_exceeded()) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(())
+    }
+
+    async fn on_soft_timeout(&self) {
+        SOFT_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The IDs of the `SleepingTask`s whose `on_failure` callback ran because they timed out.
+static TIMED_OUT: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// A task which sleeps for the given number of milliseconds, with a hard time limit of 1
+/// second.
+struct SleepingTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for SleepingTask {
+    const NAME: &'static str = "sleeping";
+    const ARGS: &'static [&'static str] = &["millis"];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        time_limit: Some(1),
+        max_retries: Some(0),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = u64;
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, millis: Self::Params) -> TaskResult<Self::Returns> {
+        tokio::time::sleep(Duration::from_millis(millis)).await;
+        Ok(())
+    }
+
+    async fn on_failure(&self, ctx: &TaskContext<'_, Self>, err: &TaskError) {
+        if let TaskError::TimeoutError = err {
+            TIMED_OUT.lock().unwrap().insert(ctx.task_id.into());
+        }
+    }
+}
+
+/// The number of times a `PanickingTask` was executed.
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+struct PanickingTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for PanickingTask {
+    const NAME: &'static str = "panicking";
+    const ARGS: &'static [&'static str] = &[];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        acks_late: Some(true),
+        reject_on_worker_lost: Some(true),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        PANICS.fetch_add(1, Ordering::Relaxed);
+        panic!("the worker is lost");
+    }
+}
+
+/// A task which blocks its thread for the given number of milliseconds.
+struct BlockingTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for BlockingTask {
+    const NAME: &'static str = "blocking";
+    const ARGS: &'static [&'static str] = &["millis"];
+    const BLOCKING: bool = true;
+
+    type Params = u64;
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, millis: Self::Params) -> TaskResult<Self::Returns> {
+        crate::task::run_blocking(move || {
+            std::thread::sleep(Duration::from_millis(millis));
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// The retries and redeliveries of each execution of a `RejectingTask`.
+static REJECTIONS: Lazy<Mutex<Vec<(u32, u32)>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// A task which always gives its message back to be requeued.
+struct RejectingTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for RejectingTask {
+    const NAME: &'static str = "rejecting";
+    const ARGS: &'static [&'static str] = &[];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        acks_late: Some(true),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        REJECTIONS
+            .lock()
+            .unwrap()
+            .push((self.request.retries, self.request.redeliveries));
+        self.reject(true)
+    }
+}
+
+/// A backend which keeps the results, the groups, the chord counters and the claims of
+/// the tasks in memory. Claims don't expire.
+#[derive(Default)]
+struct RecordingBackend(
+    Mutex<HashMap<String, ResultMetadata>>,
+    Mutex<HashMap<String, GroupMetadata>>,
+    Mutex<HashMap<String, usize>>,
+    Mutex<HashSet<(String, u32)>>,
+);
+
+#[async_trait]
+impl Backend for RecordingBackend {
+    async fn store_result_inner(
+        &self,
+        task_id: &str,
+        metadata: Option<ResultMetadata>,
+    ) -> Result<(), BackendError> {
+        let mut results = self.0.lock().unwrap();
+        match metadata {
+            Some(metadata) => results.insert(task_id.into(), metadata),
+            None => results.remove(task_id),
+        };
+        Ok(())
+    }
+
+    async fn get_task_meta(&self, task_id: &str) -> Result<ResultMetadata, BackendError> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .cloned()
+            .ok_or_else(|| BackendError::DocumentNotFound(task_id.into()))
+    }
+
+    async fn wait_for_completion(&self, task_id: &str) -> Result<bool, BackendError> {
+        loop {
+            match self.get_state(task_id).await? {
+                TaskState::Success => return Ok(true),
+                TaskState::Failure | TaskState::Revoked | TaskState::Ignored => return Ok(false),
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    async fn store_group_inner(
+        &self,
+        group_id: &str,
+        metadata: Option<GroupMetadata>,
+    ) -> Result<(), BackendError> {
+        let mut groups = self.1.lock().unwrap();
+        match metadata {
+            Some(metadata) => groups.insert(group_id.into(), metadata),
+            None => groups.remove(group_id),
+        };
+        Ok(())
+    }
+
+    async fn get_group_meta(&self, group_id: &str) -> Result<GroupMetadata, BackendError> {
+        self.1
+            .lock()
+            .unwrap()
+            .get(group_id)
+            .cloned()
+            .ok_or_else(|| BackendError::DocumentNotFound(group_id.into()))
+    }
+
+    async fn incr_chord_counter(&self, group_id: &str) -> Result<usize, BackendError> {
+        let mut counters = self.2.lock().unwrap();
+        let counter = counters.entry(group_id.into()).or_default();
+        *counter += 1;
+        Ok(*counter)
+    }
+
+    async fn claim_task(
+        &self,
+        task_id: &str,
+        retries: u32,
+        _: Duration,
+    ) -> Result<bool, BackendError> {
+        Ok(self.3.lock().unwrap().insert((task_id.into(), retries)))
+    }
+
+    async fn release_task(&self, task_id: &str, retries: u32) -> Result<(), BackendError> {
+        self.3.lock().unwrap().remove(&(task_id.into(), retries));
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_app_name() {
+    let app = build_basic_app().await;
+    assert!(app.name == "mock-app");
+}
+
+#[tokio::test]
+async fn test_add_task_registered() {
+    let app = build_basic_app().await;
+    assert!(app.task_trace_builders.read().await.contains_key("add"));
+}
+
+#[tokio::test]
+async fn test_send_task() {
+    let app = build_basic_app().await;
+    let result = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.task == "add");
+}
+
+#[tokio::test]
+async fn test_poll_task_before_registered() {
+    // The producer doesn't register the task with the backend of the worker, so that its
+    // state is polled before the backend knows about it.
+    let producer = CeleryBuilder::new(
+        "mock-app",
+        "memory://test_poll_task_before_registered",
+        None,
+    )
+    .build()
+    .await
+    .unwrap();
+    let task_id = producer
+        .send_task(AddTask::new(1, 2))
+        .await
+        .unwrap()
+        .task_id();
+    let mut worker = CeleryBuilder::new(
+        "mock-app",
+        "memory://test_poll_task_before_registered",
+        None,
+    )
+    .build()
+    .await
+    .unwrap();
+    let backend = Arc::new(MemoryBackend::new());
+    worker.backend = Some(backend.clone());
+    worker.register_task::<AddTask>().await.unwrap();
+
+    let result = AsyncResult::new(&task_id, Some(backend.clone()));
+    assert_eq!(TaskState::Pending, result.state().await.unwrap());
+    assert!(!result.ready().await.unwrap());
+    assert!(!result.successful().await.unwrap());
+    assert!(!result.failed().await.unwrap());
+    // The metadata itself is still missing.
+    assert!(matches!(
+        backend.get_task_meta(&task_id).await,
+        Err(BackendError::DocumentNotFound(_))
+    ));
+
+    let worker = Arc::new(worker);
+    let consumed = tokio::time::timeout(Duration::from_millis(500), worker.consume()).await;
+    assert!(consumed.is_err());
+    assert_eq!(TaskState::Success, result.state().await.unwrap());
+    assert!(result.ready().await.unwrap());
+    assert!(result.successful().await.unwrap());
+    assert_eq!(Some(3), result.result::<i32>().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_wait_for_task_before_registered() {
+    let producer = CeleryBuilder::new(
+        "mock-app",
+        "memory://test_wait_for_task_before_registered",
+        None,
+    )
+    .build()
+    .await
+    .unwrap();
+    let task_id = producer
+        .send_task(AddTask::new(1, 2))
+        .await
+        .unwrap()
+        .task_id();
+    let mut worker = CeleryBuilder::new(
+        "mock-app",
+        "memory://test_wait_for_task_before_registered",
+        None,
+    )
+    .build()
+    .await
+    .unwrap();
+    let backend = Arc::new(MemoryBackend::new());
+    worker.backend = Some(backend.clone());
+    worker.register_task::<AddTask>().await.unwrap();
+
+    // Waiting for the task starts before the backend knows about it.
+    let result = AsyncResult::new(&task_id, Some(backend));
+    let waited = tokio::spawn(async move { result.wait_for_completion().await });
+    let worker = Arc::new(worker);
+    let consumed = tokio::time::timeout(Duration::from_millis(500), worker.consume()).await;
+    assert!(consumed.is_err());
+    assert!(waited.await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn test_send_task_with_countdown() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).with_countdown(Duration::from_secs(2)))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    let countdown = message.headers.eta.unwrap() - Utc::now();
+    assert!(countdown > chrono::Duration::seconds(1) && countdown <= chrono::Duration::seconds(2));
+}
+
+#[tokio::test]
+async fn test_send_task_with_countdown_and_eta() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(
+            AddTask::new(1, 2)
+                .with_countdown(Duration::from_secs(2))
+                .with_eta(Utc::now()),
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(CeleryError::ProtocolError(
+            crate::error::ProtocolError::ConflictingOptions(_, _)
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn test_send_task_with_task_id() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).with_task_id("invoice-12345-generate"))
+        .await
+        .unwrap();
+    assert_eq!("invoice-12345-generate", result.task_id());
+    let by_name = app
+        .send_task_by_name(
+            "add",
+            json!([1, 2]),
+            json!({}),
+            SendOptions::new().with_task_id("invoice-12345-send"),
+        )
+        .await
+        .unwrap();
+    assert_eq!("invoice-12345-send", by_name.task_id());
+    // The result of a chain is the result of its last task.
+    let chain = app
+        .send_task(AddTask::new(1, 2).then(AddTask::new(0, 3).with_task_id("invoice-12345-total")))
+        .await
+        .unwrap();
+    assert_eq!("invoice-12345-total", chain.task_id());
+
+    for invalid in [
+        app.send_task(AddTask::new(1, 2).with_task_id("")).await,
+        app.send_task(AddTask::new(1, 2).then(AddTask::new(0, 3).with_task_id("")))
+            .await,
+        app.send_task_by_name(
+            "add",
+            json!([]),
+            json!({}),
+            SendOptions::new().with_task_id(""),
+        )
+        .await,
+    ] {
+        assert!(matches!(
+            invalid,
+            Err(CeleryError::ProtocolError(
+                crate::error::ProtocolError::InvalidProperty(_)
+            ))
+        ));
+    }
+
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    assert_eq!(3, sent_tasks.len());
+    let message = &sent_tasks.get("invoice-12345-generate").unwrap().0;
+    assert_eq!("invoice-12345-generate", message.properties.correlation_id);
+    assert!(sent_tasks.contains_key("invoice-12345-send"));
+}
+
+#[tokio::test]
+async fn test_send_task_by_name() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .task_route("reports.*", "reports")
+        .build()
+        .await
+        .unwrap();
+    let result = app
+        .send_task_by_name(
+            "reports.build",
+            json!([1, "daily"]),
+            json!({"format": "pdf"}),
+            SendOptions::new().with_countdown(Duration::from_secs(2)),
+        )
+        .await
+        .unwrap();
+    let other = app
+        .send_task_by_name(
+            "cleanup",
+            serde_json::Value::Null,
+            serde_json::Value::Null,
+            SendOptions::new().with_queue("maintenance"),
+        )
+        .await
+        .unwrap();
+    let invalid = app
+        .send_task_by_name("cleanup", json!({}), json!([]), SendOptions::new())
+        .await;
+    assert!(matches!(
+        invalid,
+        Err(CeleryError::ProtocolError(
+            crate::error::ProtocolError::InvalidProperty(_)
+        ))
+    ));
+
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    assert_eq!(2, sent_tasks.len());
+    let (message, queue, _) = sent_tasks.get(&result.task_id()).unwrap();
+    assert_eq!("reports.build", message.headers.task);
+    assert_eq!("reports", queue);
+    assert!(message.headers.eta.is_some());
+    let body: serde_json::Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[0], json!([1, "daily"]));
+    assert_eq!(body[1], json!({"format": "pdf"}));
+
+    let (message, queue, _) = sent_tasks.get(&other.task_id()).unwrap();
+    assert_eq!("cleanup", message.headers.task);
+    assert_eq!("maintenance", queue);
+    let body: serde_json::Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[0], json!([]));
+    assert_eq!(body[1], json!({}));
+}
+
+#[tokio::test]
+async fn test_delayed_task_pending_until_due() {
+    let message =
+        Message::try_from(AddTask::new(1, 2).with_countdown(Duration::from_millis(200))).unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    backend.add_task(message.task_id()).await.unwrap();
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<AddTask>(
+        message.clone(),
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+
+    assert!(tracer.is_delayed());
+    assert!(tracer.countdown().unwrap() <= Duration::from_millis(200));
+    let start = tokio::time::Instant::now();
+    tracer.wait().await;
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert_eq!(
+        TaskState::Pending,
+        backend.get_state(message.task_id()).await.unwrap()
+    );
+    tracer.trace().await.unwrap();
+    assert_eq!(
+        TaskState::Success,
+        backend.get_state(message.task_id()).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_send_task_with_eta() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).with_eta(DateTime::<Utc>::from(SystemTime::now())))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.eta.is_some());
+}
+
+#[tokio::test]
+async fn test_send_task_with_expires_in() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).with_expires_in(Duration::from_secs(10)))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    let expires_in = message.headers.expires.unwrap() - Utc::now();
+    assert!(
+        expires_in > chrono::Duration::seconds(9) && expires_in <= chrono::Duration::seconds(10)
+    );
+}
+
+#[tokio::test]
+async fn test_send_task_with_expires_in_and_expires() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(
+            AddTask::new(1, 2)
+                .with_expires_in(Duration::from_secs(10))
+                .with_expires(Utc::now()),
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(CeleryError::ProtocolError(
+            crate::error::ProtocolError::ConflictingOptions(_, _)
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn test_send_task_with_expires() {
+    let app = build_basic_app().await;
+    let dt = DateTime::<Utc>::from(SystemTime::now()) + Duration::from_secs(10);
+    let result = app
+        .send_task(AddTask::new(1, 2).with_expires(dt))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.expires.is_some());
+}
+
+#[tokio::test]
+async fn test_send_task_with_content_type() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).with_content_type(MessageContentType::Yaml))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.properties.content_type == "application/x-yaml");
+}
+
+/// A task serialized with MessagePack by its definition.
+struct PackedTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+impl PackedTask {
+    fn new(x: i32, y: i32) -> Signature<Self> {
+        Signature::<Self>::new(AddParams { x, y })
+    }
+}
+
+#[async_trait]
+impl Task for PackedTask {
+    const NAME: &'static str = "packed";
+    const ARGS: &'static [&'static str] = &["x", "y"];
+    const DEFAULTS: TaskOptions = TaskOptions {
+        content_type: Some(MessageContentType::MsgPack),
+        ..DEFAULT_TASK_OPTIONS
+    };
+
+    type Params = AddParams;
+    type Returns = i32;
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, params: Self::Params) -> TaskResult<Self::Returns> {
+        Ok(params.x + params.y)
+    }
+}
+
+#[tokio::test]
+async fn test_content_type_precedence() {
+    let app = build_configured_app().await;
+    app.register_task::<PackedTask>().await.unwrap();
+
+    // The content type of the signature wins over the one of the task definition, which
+    // wins over the one of the app.
+    let mut results = vec![];
+    for (task_sig, content_type) in [
+        (PackedTask::new(1, 2), "application/x-msgpack"),
+        (
+            PackedTask::new(1, 2).with_content_type(MessageContentType::Json),
+            "application/json",
+        ),
+    ] {
+        results.push((app.send_task(task_sig).await.unwrap(), content_type));
+    }
+    let default = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    for (result, content_type) in results {
+        let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+        assert_eq!(content_type, message.properties.content_type);
+
+        // Workers read the body back whatever format it was sent with.
+        let request = Request::<PackedTask>::try_from(message.clone()).unwrap();
+        assert_eq!((1, 2), (request.params.x, request.params.y));
+    }
+    let message = &sent_tasks.get(&default.task_id()).unwrap().0;
+    assert_eq!("application/x-yaml", message.properties.content_type);
+}
+
+#[tokio::test]
+async fn test_send_task_by_name_with_content_type() {
+    let app = build_configured_app().await;
+    let options = SendOptions::new().with_content_type(MessageContentType::MsgPack);
+    let packed = app
+        .send_task_by_name("add", json!([1, 2]), json!({}), options)
+        .await
+        .unwrap();
+    let default = app
+        .send_task_by_name("add", json!([1, 2]), json!({}), SendOptions::new())
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&packed.task_id()).unwrap().0;
+    assert_eq!("application/x-msgpack", message.properties.content_type);
+    let request = Request::<AddTask>::try_from(message.clone()).unwrap();
+    assert_eq!((1, 2), (request.params.x, request.params.y));
+    let message = &sent_tasks.get(&default.task_id()).unwrap().0;
+    assert_eq!("application/x-yaml", message.properties.content_type);
+}
+
+#[tokio::test]
+async fn test_send_task_with_time_limit() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).with_time_limit(5))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.timelimit == (None, Some(5)));
+}
+
+#[tokio::test]
+async fn test_send_task_with_hard_time_limit() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).with_hard_time_limit(5))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.timelimit == (Some(5), None));
+}
+
+#[tokio::test]
+async fn test_configured_app_send_task_app_defaults() {
+    let app = build_configured_app().await;
+    let result = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.timelimit == (None, Some(10)));
+    assert!(message.properties.content_type == "application/x-yaml");
+}
+
+#[tokio::test]
+async fn test_configured_app_send_task_task_defaults() {
+    let app = build_configured_app().await;
+    let result = app.send_task(MultiplyTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.timelimit == (Some(10), Some(5)));
+    assert!(message.properties.content_type == "application/x-yaml");
+}
+
+#[tokio::test]
+async fn test_configured_app_send_task_request_overrides() {
+    let app = build_configured_app().await;
+    let result = app
+        .send_task(
+            MultiplyTask::new(1, 2)
+                .with_time_limit(2)
+                .with_content_type(MessageContentType::Json),
+        )
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert!(message.headers.timelimit == (Some(10), Some(2)));
+    assert!(message.properties.content_type == "application/json");
+}
+
+#[tokio::test]
+async fn test_send_task_soft_time_limit_precedence() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .task_time_limit(10)
+        .task_soft_time_limit(5)
+        .build()
+        .await
+        .unwrap();
+    let sent = [
+        // The app defaults apply to tasks without time limits.
+        app.send_task(AddTask::new(1, 2)).await.unwrap(),
+        // The task options take precedence over the app defaults.
+        app.send_task(Signature::<SoftTimeLimitTask>::new(true))
+            .await
+            .unwrap(),
+        // The options of the request take precedence over both.
+        app.send_task(
+            Signature::<SoftTimeLimitTask>::new(true)
+                .with_hard_time_limit(4)
+                .with_soft_time_limit(3),
+        )
+        .await
+        .unwrap(),
+    ];
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let timelimits: Vec<_> = sent
+        .iter()
+        .map(|result| {
+            sent_tasks
+                .get(&result.task_id())
+                .unwrap()
+                .0
+                .headers
+                .timelimit
+        })
+        .collect();
+    assert_eq!(
+        vec![(Some(10), Some(5)), (Some(2), Some(1)), (Some(4), Some(3))],
+        timelimits
+    );
+}
+
+#[test]
+fn test_request_time_limits() {
+    let time_limits = |sig: Signature<AddTask>| {
+        let request = Request::<AddTask>::try_from(Message::try_from(sig).unwrap()).unwrap();
+        (request.time_limit, request.soft_time_limit)
+    };
+    assert_eq!(
+        (Some(4), Some(3)),
+        time_limits(
+            AddTask::new(1, 2)
+                .with_time_limit(4)
+                .with_soft_time_limit(3)
+        )
+    );
+    // A single time limit is a hard time limit.
+    assert_eq!(
+        (Some(5), None),
+        time_limits(AddTask::new(1, 2).with_time_limit(5))
+    );
+    assert_eq!(
+        (Some(3), None),
+        time_limits(AddTask::new(1, 2).with_soft_time_limit(3))
+    );
+    // Like Python workers, a time limit lower than the hard time limit is a soft one.
+    assert_eq!(
+        (Some(10), Some(5)),
+        time_limits(
+            AddTask::new(1, 2)
+                .with_time_limit(5)
+                .with_hard_time_limit(10)
+        )
+    );
+    // A soft time limit which isn't lower than the hard time limit is ignored.
+    assert_eq!(
+        (Some(2), None),
+        time_limits(
+            AddTask::new(1, 2)
+                .with_time_limit(2)
+                .with_soft_time_limit(3)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_soft_time_limit() {
+    let trace = |wrap_up: bool| async move {
+        let message = Message::try_from(Signature::<SoftTimeLimitTask>::new(wrap_up)).unwrap();
+        let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut tracer = super::trace::build_tracer::<SoftTimeLimitTask>(
+            message,
+            TaskOptions::default(),
+            event_tx,
+            "worker@host".into(),
+            None,
+            false,
+            None,
+        )
+        .unwrap();
+        tracer.trace().await
+    };
+
+    // The task wraps up once signalled.
+    assert!(trace(true).await.is_ok());
+    // Otherwise it is interrupted at the hard time limit.
+    assert!(matches!(
+        trace(false).await,
+        Err(TraceError::RetriesExceeded(TaskError::TimeoutError))
+    ));
+    assert_eq!(2, SOFT_TIMEOUTS.load(Ordering::Relaxed));
+}
+
+#[tokio::test]
+async fn test_send_task_above_max_priority() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .queue_max_priority("celery", 5)
+        .build()
+        .await
+        .unwrap();
+    assert!(app
+        .send_task(AddTask::new(1, 2).with_priority(5))
+        .await
+        .is_ok());
+    let result = app.send_task(AddTask::new(1, 2).with_priority(9)).await;
+    assert!(matches!(
+        result,
+        Err(CeleryError::InvalidPriority {
+            priority: 9,
+            max_priority: 5,
+            ..
+        })
+    ));
+    // Queues without a maximum priority accept any priority.
+    let result = app
+        .send_task(AddTask::new(1, 2).with_priority(9).with_queue("other"))
+        .await;
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn test_send_task_to_exchange() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .declare_exchange("math", ExchangeKind::Topic, true)
+        .task_route_to_exchange("add", "math", "math.add")
+        .build()
+        .await
+        .unwrap();
+    let result = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    assert_eq!(
+        "math/math.add",
+        sent_tasks.get(&result.task_id()).unwrap().1
+    );
+}
+
+/// A task routed by its definition.
+struct EmailTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for EmailTask {
+    const NAME: &'static str = "emails.send";
+    const ARGS: &'static [&'static str] = &[];
+    const QUEUE: Option<&'static str> = Some("emails");
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        Ok(())
+    }
+}
+
+/// A task published to an exchange by its definition.
+struct AuditTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for AuditTask {
+    const NAME: &'static str = "emails.audit";
+    const ARGS: &'static [&'static str] = &[];
+    const EXCHANGE: Option<(&'static str, &'static str)> = Some(("events", "events.audit"));
+
+    type Params = ();
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_task_definition_routing() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .default_queue("default")
+        .declare_exchange("events", ExchangeKind::Topic, true)
+        .task_route("emails.*", "routed")
+        .task_route("add", "math")
+        .build()
+        .await
+        .unwrap();
+
+    // The queue of the signature wins over the definition of the task, which wins over
+    // the task routes, which win over the default queue.
+    let results = app
+        .send_tasks(vec![
+            Signature::<EmailTask>::new(()).with_queue("urgent"),
+            Signature::<EmailTask>::new(()),
+        ])
+        .await;
+    let audit = app
+        .send_task(Signature::<AuditTask>::new(()))
+        .await
+        .unwrap();
+    let add = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let multiply = app.send_task(MultiplyTask::new(1, 2)).await.unwrap();
+    let task_ids: Vec<String> = results
+        .into_iter()
+        .map(|result| result.unwrap().task_id())
+        .collect();
+
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let destination = |task_id: &str| sent_tasks.get(task_id).unwrap().1.clone();
+    assert_eq!("urgent", destination(&task_ids[0]));
+    assert_eq!("emails", destination(&task_ids[1]));
+    assert_eq!("events/events.audit", destination(&audit.task_id()));
+    assert_eq!("math", destination(&add.task_id()));
+    assert_eq!("default", destination(&multiply.task_id()));
+}
+
+#[tokio::test]
+async fn test_send_tasks() {
+    let app = build_basic_app().await;
+    let results = app
+        .send_tasks(vec![
+            AddTask::new(1, 2),
+            AddTask::new(3, 4).with_queue("other"),
+        ])
+        .await;
+    let task_ids: Vec<String> = results
+        .into_iter()
+        .map(|result| result.unwrap().task_id())
+        .collect();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    assert_eq!("celery", sent_tasks.get(&task_ids[0]).unwrap().1);
+    assert_eq!("other", sent_tasks.get(&task_ids[1]).unwrap().1);
+}
+
+#[tokio::test]
+async fn test_broadcast_task() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .broadcast_queue("reload")
+        .build()
+        .await
+        .unwrap();
+    let result = app.broadcast_task(AddTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    assert_eq!("reload", sent_tasks.get(&result.task_id()).unwrap().1);
+}
+
+#[tokio::test]
+async fn test_broadcast_task_without_broadcast_queue() {
+    let app = build_basic_app().await;
+    assert!(matches!(
+        app.broadcast_task(AddTask::new(1, 2)).await,
+        Err(CeleryError::NoBroadcastQueue)
+    ));
+}
+
+#[tokio::test]
+async fn test_lazy_connect() {
+    // Nothing listens on port 1, so building only succeeds because the connection is
+    // deferred.
+    let app = CeleryBuilder::new("mock-app", "redis://127.0.0.1:1/", None)
+        .lazy_connect(true)
+        .broker_connection_max_retries(1)
+        .broker_connection_retry_delay(0)
+        .build()
+        .await
+        .unwrap();
+    assert!(app.connect().await.is_err());
+    assert!(app.send_task(AddTask::new(1, 2)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_fallback_broker() {
+    // Nothing listens on port 1, so the broker is down for good.
+    let app = CeleryBuilder::new("mock-app", "redis://127.0.0.1:1/", None)
+        .fallback_broker_url("memory://test_fallback_broker")
+        .lazy_connect(true)
+        .broker_connection_max_retries(1)
+        .broker_connection_retry_delay(0)
+        .task_publish_retry(false)
+        .build()
+        .await
+        .unwrap();
+    app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let results = app
+        .send_tasks(vec![AddTask::new(1, 1), AddTask::new(2, 2)])
+        .await;
+    assert!(results.iter().all(Result::is_ok));
+
+    assert_eq!(3, app.stats().await.fallback_sent);
+    let fallback_broker = app.fallback_broker.as_ref().unwrap();
+    assert_eq!(Some(3), fallback_broker.queue_len("celery").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_set_prefetch_count() {
+    let app = build_basic_app().await;
+    app.set_prefetch_count(1).await.unwrap();
+    assert_eq!(1, app.prefetch_count().await);
+}
+
+#[tokio::test]
+async fn test_prefetch_multiplier() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_prefetch_multiplier", None)
+        .prefetch_count(100)
+        .prefetch_multiplier(4)
+        .autoscale(2, 8)
+        .worker_enable_remote_control(true)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(8, app.prefetch_count().await);
+    assert_eq!(Some(4), app.prefetch_multiplier());
+
+    // A multiplier of 0 lifts the limit.
+    app.set_prefetch_multiplier(0).await.unwrap();
+    assert_eq!(0, app.prefetch_count().await);
+
+    let app = Arc::new(app);
+    let client = async {
+        // Let the worker subscribe to the control queue.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.control_prefetch_multiplier(3, None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    let result = tokio::time::timeout(
+        Duration::from_millis(300),
+        futures::future::join(app.consume(), client),
+    )
+    .await;
+    assert!(result.is_err());
+    assert_eq!(6, app.prefetch_count().await);
+    assert_eq!(Some(3), app.prefetch_multiplier());
+}
+
+#[tokio::test]
+async fn test_queue_concurrency() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_queue_concurrency", None)
+        .queue_concurrency("celery", 1)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<SleepingTask>().await.unwrap();
+    for _ in 0..3 {
+        app.send_task(Signature::<SleepingTask>::new(300))
+            .await
+            .unwrap();
+    }
+
+    // The tasks are executed one at a time, and the worker only prefetches one message
+    // besides the one of the task it executes, which is acknowledged once it starts.
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(Some(1), app.queue_len("celery").await.unwrap());
+    };
+    let result = tokio::time::timeout(
+        Duration::from_millis(450),
+        futures::future::join(app.consume(), client),
+    )
+    .await;
+    assert!(result.is_err());
+    assert_eq!(1, app.processed_tasks.load(Ordering::Relaxed));
+
+    app.set_queue_concurrency("celery", 2);
+    app.set_queue_concurrency("video-encode", 4);
+    assert_eq!(
+        HashMap::from([("celery".to_string(), 2), ("video-encode".to_string(), 4)]),
+        app.queue_concurrency()
+    );
+}
+
+#[tokio::test]
+async fn test_retries_in_task_body() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_retries_in_task_body", None)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RetryingTask>().await.unwrap();
+    app.send_task(Signature::<RetryingTask>::new(()))
+        .await
+        .unwrap();
+
+    // Each retry is sent with its retries incremented.
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume()).await;
+    assert!(result.is_err());
+    assert_eq!(
+        vec![(0, false), (1, true), (2, true), (3, true)],
+        *RETRY_ATTEMPTS.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_retry_with_params() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_retry_with_params", None)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RefreshingTask>().await.unwrap();
+    let task_id = app
+        .send_task(RefreshingTask::new(0))
+        .await
+        .unwrap()
+        .task_id();
+
+    // Each retry is sent right away with the new token, beyond the max retries of the
+    // task since the retries override them.
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume()).await;
+    assert!(result.is_err());
+    assert_eq!(
+        Some(&vec![(0, 0), (1, 1), (2, 2)]),
+        REFRESHED_TOKENS.lock().unwrap().get(&task_id)
+    );
+}
+
+#[tokio::test]
+async fn test_retry_with_params_exceeded() {
+    let mut message = Message::try_from(RefreshingTask::new(0)).unwrap();
+    message.headers.retries = Some(5);
+    let task_id = message.task_id().to_string();
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<RefreshingTask>(
+        message,
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+
+    // Retries past the max retries of the retry fail for good.
+    assert!(matches!(
+        tracer.trace().await,
+        Err(TraceError::RetriesExceeded(TaskError::Retry(_)))
+    ));
+    assert_eq!(None, tracer.take_retry_params());
+    assert_eq!(
+        TaskState::Failure,
+        backend.get_state(&task_id).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_blocking_task() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_blocking_task", None)
+        .blocking_threads(2)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<BlockingTask>().await.unwrap();
+    app.register_task::<RecordingTask>().await.unwrap();
+    app.send_task(Signature::<BlockingTask>::new(1000))
+        .await
+        .unwrap();
+    let task_id = app
+        .send_task(Signature::<RecordingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    // The blocking task runs on its own thread, so it doesn't hold up the async task even
+    // though the test runtime has a single thread.
+    let result = tokio::time::timeout(Duration::from_millis(500), app.consume()).await;
+    assert!(result.is_err());
+    assert!(RECORDED.lock().unwrap().contains(&task_id));
+    assert_eq!(1, app.processed_tasks.load(Ordering::Relaxed));
+
+    let stats = app
+        .run_control_command(&ControlCommand::new("stats", serde_json::Map::new()))
+        .await
+        .unwrap();
+    let stats: WorkerStats = serde_json::from_value(stats).unwrap();
+    assert_eq!(Some(2), stats.blocking_threads);
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_stats", None)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<FailingTask>().await.unwrap();
+    app.send_task(AddTask::new(1, 2)).await.unwrap();
+    app.send_task(AddTask::new(3, 4)).await.unwrap();
+    let task_id = app
+        .send_task(Signature::<FailingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    // The failing task is retried once before it fails.
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume()).await;
+    assert!(result.is_err());
+
+    let stats = app.stats().await;
+    assert_eq!(0, stats.active);
+    assert_eq!(4, stats.received);
+    assert_eq!(4, stats.processed);
+    assert_eq!(2, stats.succeeded);
+    assert_eq!(1, stats.failed);
+    assert_eq!(1, stats.retried);
+    assert_eq!(
+        TaskStats {
+            received: 2,
+            succeeded: 2,
+            queue_latency: stats.tasks["add"].queue_latency.clone(),
+            ..Default::default()
+        },
+        stats.tasks["add"]
+    );
+    assert_eq!(
+        TaskStats {
+            received: 2,
+            failed: 1,
+            retried: 1,
+            queue_latency: stats.tasks["failing"].queue_latency.clone(),
+            ..Default::default()
+        },
+        stats.tasks["failing"]
+    );
+    // The retry of the failing task waited in the queue as well.
+    assert_eq!(2, stats.tasks["add"].queue_latency.count);
+    assert_eq!(2, stats.tasks["failing"].queue_latency.count);
+    assert_eq!(0, stats.negative_queue_latencies);
+    assert_eq!(
+        Some(format!(
+            "failing[{}]: task raised expected error: always fails",
+            task_id
+        )),
+        stats.last_error
+    );
+
+    // The control command replies with the same statistics.
+    let reply = app
+        .run_control_command(&ControlCommand::new("stats", serde_json::Map::new()))
+        .await
+        .unwrap();
+    let reply: WorkerStats = serde_json::from_value(reply).unwrap();
+    assert_eq!(stats.tasks, reply.tasks);
+    assert_eq!(stats.last_error, reply.last_error);
+}
+
+#[tokio::test]
+async fn test_dead_letter_queue() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_dead_letter_queue", None)
+        .queue_options(
+            "celery",
+            QueueOptions::default().with_dead_letter_queue("celery.dlq"),
+        )
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<FailingTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<FailingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+    let acked_early = app
+        .send_task(Signature::<FailingTask>::new(()).with_acks_late(false))
+        .await
+        .unwrap()
+        .task_id();
+
+    // The tasks are retried once, then the message acknowledged late is rejected, and the
+    // one acknowledged early is sent to the dead-letter queue by the worker.
+    let result = tokio::time::timeout(Duration::from_secs(3), app.consume()).await;
+    assert!(result.is_err());
+
+    let (_, mut deliveries) = app
+        .broker
+        .consume("celery.dlq", Box::new(|_| {}))
+        .await
+        .unwrap();
+    let mut dead_lettered = HashSet::new();
+    for _ in 0..2 {
+        let delivery = tokio::time::timeout(Duration::from_secs(1), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .ok()
+            .unwrap();
+        let message = delivery.try_deserialize_message().unwrap();
+        assert_eq!(Some(1), message.headers.retries);
+        dead_lettered.insert(message.task_id().to_string());
+    }
+    assert_eq!(HashSet::from([task_id, acked_early]), dead_lettered);
+    assert_eq!(Some(0), app.queue_len("celery").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_task_events_shadow_name() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_task_events_shadow_name", None)
+        .worker_events(true)
+        .declare_exchange("celeryev", ExchangeKind::Topic, true)
+        .bind_queue("task_events", "celeryev", "task.#")
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<AddTask>().await.unwrap();
+    let task_id = app
+        .send_task(AddTask::new(1, 2).with_shadow("add:shadowed"))
+        .await
+        .unwrap()
+        .task_id();
+
+    let result = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(result.is_err());
+
+    // The events of the task are reported under its shadow name.
+    let (_, mut deliveries) = app
+        .broker
+        .consume("task_events", Box::new(|_| {}))
+        .await
+        .unwrap();
+    let mut event_types = Vec::new();
+    for _ in 0..3 {
+        let delivery = tokio::time::timeout(Duration::from_secs(1), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .ok()
+            .unwrap();
+        let message = delivery.try_deserialize_message().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&message.raw_body).unwrap();
+        assert_eq!(body["uuid"], task_id);
+        assert_eq!(body["name"], "add:shadowed");
+        if body["type"] == "task-started" {
+            // The task waited in its queue since it was sent.
+            assert!(body["sent_at"].is_string());
+            assert!(body["queue_latency"].as_f64().unwrap() >= 0.0);
+        }
+        event_types.push(body["type"].as_str().unwrap().to_string());
+    }
+    assert_eq!(
+        vec!["task-received", "task-started", "task-succeeded"],
+        event_types
+    );
+}
+
+#[tokio::test]
+async fn test_unsigned_task_rejected() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_unsigned_task_rejected", None)
+        .message_signer(MessageSigner::new(b"secret"))
+        .queue_options(
+            "celery",
+            QueueOptions::default().with_dead_letter_queue("celery.dlq"),
+        )
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<AddTask>().await.unwrap();
+    app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let unsigned = Message::try_from(AddTask::new(3, 4)).unwrap();
+    app.broker.send(&unsigned, "celery").await.unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(result.is_err());
+
+    // Only the unsigned task is dead-lettered.
+    let (_, mut deliveries) = app
+        .broker
+        .consume("celery.dlq", Box::new(|_| {}))
+        .await
+        .unwrap();
+    let delivery = tokio::time::timeout(Duration::from_secs(1), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    let message = delivery.try_deserialize_message().unwrap();
+    assert_eq!(unsigned.task_id(), message.task_id());
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), deliveries.next())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_reject_on_worker_lost() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_reject_on_worker_lost", None)
+        .queue_options(
+            "celery",
+            QueueOptions::default().with_dead_letter_queue("celery.dlq"),
+        )
+        .worker_max_redeliveries(2)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<PanickingTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<PanickingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    // The message is requeued twice, then rejected.
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume()).await;
+    assert!(result.is_err());
+    assert_eq!(3, PANICS.load(Ordering::Relaxed));
+
+    let (_, mut deliveries) = app
+        .broker
+        .consume("celery.dlq", Box::new(|_| {}))
+        .await
+        .unwrap();
+    let delivery = tokio::time::timeout(Duration::from_secs(1), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    let message = delivery.try_deserialize_message().unwrap();
+    assert_eq!(task_id, message.task_id());
+    assert_eq!(Some(2), message.headers.redeliveries);
+}
+
+#[tokio::test]
+async fn test_reject_task() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_reject_task", None)
+        .queue_options(
+            "celery",
+            QueueOptions::default().with_dead_letter_queue("celery.dlq"),
+        )
+        .worker_max_redeliveries(2)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RejectingTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<RejectingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    // The message is requeued twice without consuming retries, then rejected.
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume()).await;
+    assert!(result.is_err());
+    assert_eq!(vec![(0, 0), (0, 1), (0, 2)], *REJECTIONS.lock().unwrap());
+    assert_eq!(0, app.failed_tasks.load(Ordering::Relaxed));
+
+    let (_, mut deliveries) = app
+        .broker
+        .consume("celery.dlq", Box::new(|_| {}))
+        .await
+        .unwrap();
+    let delivery = tokio::time::timeout(Duration::from_secs(1), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    let message = delivery.try_deserialize_message().unwrap();
+    assert_eq!(task_id, message.task_id());
+}
+
+#[tokio::test]
+async fn test_exclude_tasks() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_exclude_tasks", None)
+        .queue_options(
+            "celery",
+            QueueOptions::default().with_dead_letter_queue("celery.dlq"),
+        )
+        .worker_max_redeliveries(2)
+        .exclude_tasks(&["multiply"])
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<MultiplyTask>().await.unwrap();
+    app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let task_id = app
+        .send_task(MultiplyTask::new(3, 4))
+        .await
+        .unwrap()
+        .task_id();
+
+    // The excluded task is requeued twice, then rejected without being executed.
+    let result = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(result.is_err());
+    let stats = app.stats().await;
+    assert_eq!(3, stats.excluded);
+    assert_eq!(1, stats.processed);
+    assert!(!stats.tasks.contains_key("multiply"));
+
+    let (_, mut deliveries) = app
+        .broker
+        .consume("celery.dlq", Box::new(|_| {}))
+        .await
+        .unwrap();
+    let delivery = tokio::time::timeout(Duration::from_secs(1), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    let message = delivery.try_deserialize_message().unwrap();
+    assert_eq!(task_id, message.task_id());
+    assert_eq!(Some(2), message.headers.redeliveries);
+}
+
+#[tokio::test]
+async fn test_include_tasks() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .include_tasks(&["add", "multiply"])
+        .exclude_tasks(&["multiply"])
+        .build()
+        .await
+        .unwrap();
+    assert!(!app.is_excluded("add"));
+    assert!(app.is_excluded("multiply"));
+    assert!(app.is_excluded("failing"));
+}
+
+#[tokio::test]
+async fn test_send_task_with_acks_late() {
+    let app = build_basic_app().await;
+    let late = app
+        .send_task(AddTask::new(1, 2).with_acks_late(true))
+        .await
+        .unwrap();
+    let early = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&late.task_id()).unwrap().0;
+    assert_eq!(Some(true), message.headers.acks_late);
+    // The option of the request takes precedence over the ones of the worker.
+    let request = Request::<AddTask>::try_from(message.clone()).unwrap();
+    let task = AddTask::from_request(
+        request,
+        TaskOptions {
+            acks_late: Some(false),
+            ..Default::default()
+        },
+    );
+    assert!(task.acks_late());
+    let message = &sent_tasks.get(&early.task_id()).unwrap().0;
+    assert_eq!(None, message.headers.acks_late);
+}
+
+#[tokio::test]
+async fn test_expired_task_discarded() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_expired_task_discarded", None)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let expired = app
+        .send_task(
+            Signature::<RecordingTask>::new(())
+                .with_expires(Utc::now() - chrono::Duration::seconds(10)),
+        )
+        .await
+        .unwrap()
+        .task_id();
+    // Expired tasks are still executed within the grace period, in case the clock of the
+    // producer is a bit ahead of the one of the worker.
+    let skewed = app
+        .send_task(
+            Signature::<RecordingTask>::new(())
+                .with_expires(Utc::now() - chrono::Duration::milliseconds(100)),
+        )
+        .await
+        .unwrap()
+        .task_id();
+
+    let result = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(result.is_err());
+    assert!(!RECORDED.lock().unwrap().contains(&expired));
+    assert!(RECORDED.lock().unwrap().contains(&skewed));
+
+    // Both messages were acknowledged.
+    let (_, mut deliveries) = app
+        .broker
+        .consume("celery", Box::new(|_| {}))
+        .await
+        .unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), deliveries.next())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_expired_task_revoked() {
+    let message = Message::try_from(
+        AddTask::new(1, 2).with_expires(Utc::now() - chrono::Duration::seconds(10)),
+    )
+    .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<AddTask>(
+        message.clone(),
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+
+    assert!(tracer.is_expired());
+    assert!(matches!(
+        tracer.trace().await,
+        Err(TraceError::ExpirationError)
+    ));
+    assert_eq!(
+        TaskState::Revoked,
+        backend.get_state(message.task_id()).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_backend_cleanup() {
+    // The task is only registered when the app has a backend, and isn't listed.
+    let app = CeleryBuilder::new(
+        "mock-app",
+        "memory://test_backend_cleanup",
+        Some("redis://localhost:6379/"),
+    )
+    .build()
+    .await
+    .unwrap();
+    assert!(app
+        .task_trace_builders
+        .read()
+        .await
+        .contains_key(BACKEND_CLEANUP_TASK));
+    assert!(!super::is_builtin_task("add"));
+    let app = CeleryBuilder::new("mock-app", "memory://test_backend_cleanup", None)
+        .build()
+        .await
+        .unwrap();
+    assert!(!app
+        .task_trace_builders
+        .read()
+        .await
+        .contains_key(BACKEND_CLEANUP_TASK));
+
+    let backend = Arc::new(MemoryBackend::new());
+    let now = Utc::now();
+    backend
+        .mark_as_done("expired", "1", now - chrono::Duration::days(2), None)
+        .await
+        .unwrap();
+    backend
+        .mark_as_done("recent", "2", now, None)
+        .await
+        .unwrap();
+    backend.mark_as_started("running", None).await.unwrap();
+
+    // Python sends the task without arguments.
+    let message = SerializedSignature::by_name(
+        BACKEND_CLEANUP_TASK,
+        json!([]),
+        json!({}),
+        &SendOptions::default(),
+    )
+    .unwrap()
+    .try_create_message()
+    .unwrap();
+    let build_tracer = super::cleanup::cleanup_trace_builder(Duration::from_secs(24 * 60 * 60));
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = build_tracer(
+        message.clone(),
+        TaskOptions::default(),
+        event_tx.clone(),
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+    assert!(tracer.trace().await.is_ok());
+    assert!(matches!(
+        backend.get_task_meta("expired").await,
+        Err(BackendError::DocumentNotFound(_))
+    ));
+    assert_eq!(
+        TaskState::Success,
+        backend.get_state("recent").await.unwrap()
+    );
+    assert_eq!(
+        TaskState::Started,
+        backend.get_state("running").await.unwrap()
+    );
+    // The result of the cleanup itself isn't stored.
+    assert!(backend.get_task_meta(message.task_id()).await.is_err());
+
+    // Backends which don't support cleanup are left as they are.
+    let mut tracer = build_tracer(
+        message,
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(Arc::new(RecordingBackend::default())),
+    )
+    .ok()
+    .unwrap();
+    assert!(tracer.trace().await.is_ok());
+}
+
+#[tokio::test]
+async fn test_worker_hostname() {
+    assert_eq!(
+        "worker1@george.example.com",
+        super::expand_hostname_with("worker1@%h", "george.example.com", 42)
+    );
+    assert_eq!(
+        "george-42@example.com%",
+        super::expand_hostname_with("%n-%p@%d%%", "george.example.com", 42)
+    );
+    assert_eq!(
+        "w@host%x",
+        super::expand_hostname_with("w@%n%x", "host", 42)
+    );
+
+    // The node name defaults to the hostname and the process ID.
+    let app = CeleryBuilder::new("mock-app", "memory://test_worker_hostname", None)
+        .build()
+        .await
+        .unwrap();
+    assert!(app.hostname.starts_with("rust-celery@"));
+    assert!(app.hostname.ends_with(&format!("-{}", std::process::id())));
+
+    // The result of a task records the worker which executed it.
+    let message = Message::try_from(Signature::<SleepingTask>::new(0)).unwrap();
+    let backend = Arc::new(MemoryBackend::new());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<SleepingTask>(
+        message.clone(),
+        TaskOptions::default(),
+        event_tx,
+        "worker1@george".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+    assert!(tracer.trace().await.is_ok());
+    assert_eq!(
+        Some("worker1@george".to_string()),
+        backend.get_worker(message.task_id()).await.unwrap()
+    );
+}
+
+/// Trace a `SleepingTask` with the given app-level options, and get the outcome of its
+/// trace along with the state and the error it ended in.
+async fn trace_sleeping(
+    task_sig: Signature<SleepingTask>,
+    options: TaskOptions,
+) -> (Result<(), TraceError>, TaskState, Option<TaskError>, String) {
+    let message = Message::try_from(task_sig).unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<SleepingTask>(
+        message.clone(),
+        options,
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+    let result = tracer.trace().await;
+    let task_id = message.task_id();
+    let state = backend.get_state(task_id).await.unwrap();
+    let traceback = backend.get_traceback(task_id).await.unwrap();
+    (result, state, traceback, task_id.into())
+}
+
+#[tokio::test]
+async fn test_task_time_limit() {
+    // The time limit of the task takes precedence over the one of the app.
+    let app_options = TaskOptions {
+        time_limit: Some(10),
+        ..Default::default()
+    };
+    let (timed_out, under_limit, sent_limit) = futures::join!(
+        trace_sleeping(Signature::new(1500), app_options),
+        trace_sleeping(Signature::new(800), app_options),
+        // The time limit the task is sent with takes precedence over the one of the task.
+        trace_sleeping(Signature::new(1500).with_time_limit(2), app_options),
+    );
+
+    let (result, state, traceback, task_id) = timed_out;
+    assert!(matches!(
+        result,
+        Err(TraceError::RetriesExceeded(TaskError::TimeoutError))
+    ));
+    assert_eq!(TaskState::Failure, state);
+    assert!(matches!(traceback, Some(TaskError::TimeoutError)));
+    assert!(TIMED_OUT.lock().unwrap().contains(&task_id));
+
+    for (result, state, _, task_id) in [under_limit, sent_limit] {
+        assert!(result.is_ok());
+        assert_eq!(TaskState::Success, state);
+        assert!(!TIMED_OUT.lock().unwrap().contains(&task_id));
+    }
+}
+
+#[tokio::test]
+async fn test_task_expiring_while_running_completes() {
+    // The task runs until its soft time limit, past its expiration time and grace period.
+    let message = Message::try_from(
+        Signature::<SoftTimeLimitTask>::new(true)
+            .with_hard_time_limit(3)
+            .with_soft_time_limit(2)
+            .with_expires_in(Duration::from_millis(500)),
+    )
+    .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<SoftTimeLimitTask>(
+        message.clone(),
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+
+    assert!(!tracer.is_expired());
+    tracer.trace().await.unwrap();
+    assert!(tracer.is_expired());
+    assert_eq!(
+        TaskState::Success,
+        backend.get_state(message.task_id()).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_retry_stored_in_backend() {
+    let mut message = Message::try_from(Signature::<FailingTask>::new(())).unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<FailingTask>(
+        message.clone(),
+        TaskOptions::default(),
+        event_tx.clone(),
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+
+    let eta = match tracer.trace().await {
+        Err(TraceError::Retry(Some(eta))) => eta,
+        _ => panic!("the task should be retried"),
+    };
+    assert!(eta <= Utc::now() + chrono::Duration::seconds(1));
+    assert_eq!(
+        TaskState::Retry,
+        backend.get_state(message.task_id()).await.unwrap()
+    );
+    assert_eq!(
+        Some(eta),
+        backend.get_retry_eta(message.task_id()).await.unwrap()
+    );
+
+    // The last attempt is stored as a failure.
+    message.headers.retries = Some(1);
+    let mut tracer = super::trace::build_tracer::<FailingTask>(
+        message.clone(),
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+    assert!(matches!(
+        tracer.trace().await,
+        Err(TraceError::RetriesExceeded(_))
+    ));
+    assert_eq!(
+        TaskState::Failure,
+        backend.get_state(message.task_id()).await.unwrap()
+    );
+    assert_eq!(
+        None,
+        backend.get_retry_eta(message.task_id()).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_should_retry() {
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let trace = |valid: bool, retries: u32| {
+        let mut message = Message::try_from(Signature::<ValidatingTask>::new(valid)).unwrap();
+        message.headers.retries = Some(retries);
+        let task_id = message.task_id().to_string();
+        let tracer = super::trace::build_tracer::<ValidatingTask>(
+            message,
+            TaskOptions::default(),
+            event_tx.clone(),
+            "localhost".into(),
+            None,
+            false,
+            Some(backend.clone()),
+        )
+        .ok()
+        .unwrap();
+        (task_id, tracer)
+    };
+
+    let (_, mut tracer) = trace(true, 0);
+    assert!(matches!(tracer.trace().await, Err(TraceError::Retry(_))));
+
+    // Errors which aren't retried fail right away.
+    let (task_id, mut tracer) = trace(false, 0);
+    assert!(matches!(
+        tracer.trace().await,
+        Err(TraceError::TaskError(TaskError::UnexpectedError(_)))
+    ));
+    assert_eq!(
+        TaskState::Failure,
+        backend.get_state(&task_id).await.unwrap()
+    );
+
+    // The retries are still limited.
+    let (_, mut tracer) = trace(true, 1);
+    assert!(matches!(
+        tracer.trace().await,
+        Err(TraceError::RetriesExceeded(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_hook_context() {
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let trace = |valid: bool, retries: u32| {
+        let mut message = Message::try_from(Signature::<ValidatingTask>::new(valid)).unwrap();
+        message.headers.retries = Some(retries);
+        let task_id = message.task_id().to_string();
+        let mut tracer = super::trace::build_tracer::<ValidatingTask>(
+            message,
+            TaskOptions::default(),
+            event_tx.clone(),
+            "localhost".into(),
+            Some("validation".into()),
+            false,
+            None,
+        )
+        .ok()
+        .unwrap();
+        async move {
+            let _ = tracer.trace().await;
+            VALIDATING_HOOKS.lock().unwrap().remove(&task_id).unwrap()
+        }
+    };
+
+    let queue = Some("validation".to_string());
+    assert_eq!(
+        vec![("on_retry", true, 0, queue.clone())],
+        trace(true, 0).await
+    );
+    // Exhausting the retries and errors which aren't retried are failures.
+    assert_eq!(
+        vec![("on_failure", true, 1, queue.clone())],
+        trace(true, 1).await
+    );
+    assert_eq!(vec![("on_failure", false, 0, queue)], trace(false, 0).await);
+}
+
+#[tokio::test]
+async fn test_send_chain() {
+    let app = build_basic_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).then(MultiplyTask::new(0, 3)))
+        .await
+        .unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    assert_eq!(1, sent_tasks.len());
+    // The result of a chain is the result of its last task.
+    let message = &sent_tasks.values().next().unwrap().0;
+    assert_eq!("add", message.headers.task);
+    let chain = message.body_embed().unwrap().chain.unwrap();
+    assert_eq!("multiply", chain[0].task);
+    assert_eq!(chain[0].task_id(), Some(result.task_id().as_str()));
+}
+
+#[tokio::test]
+async fn test_chain_continues_with_result() {
+    let mut message = Message::try_from(
+        AddTask::new(1, 2).then(MultiplyTask::new(0, 10).then(AddTask::new(0, 5))),
+    )
+    .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tasks = ["multiply", "add"].iter().copied();
+    let parent_id = loop {
+        let parent_id = message.task_id().to_string();
+        let build_tracer = match message.headers.task.as_str() {
+            "add" => super::trace::build_tracer::<AddTask>,
+            _ => super::trace::build_tracer::<MultiplyTask>,
+        };
+        let mut tracer = build_tracer(
+            message,
+            TaskOptions::default(),
+            event_tx.clone(),
+            "localhost".into(),
+            None,
+            false,
+            Some(backend.clone()),
+        )
+        .ok()
+        .unwrap();
+        tracer.trace().await.unwrap();
+        match tracer.take_triggered().pop() {
+            Some((next, queue)) => {
+                assert_eq!(tasks.next(), Some(next.headers.task.as_str()));
+                assert_eq!(Some(parent_id), next.headers.parent_id);
+                assert_eq!(None, queue);
+                message = next;
+            }
+            None => break parent_id,
+        }
+    };
+    assert_eq!(None, tasks.next());
+    assert_eq!(
+        Some("35".to_string()),
+        backend.get_result(&parent_id).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_chain_stops_on_failure() {
+    let message =
+        Message::try_from(Signature::<ValidatingTask>::new(false).then(AddTask::new(0, 1)))
+            .unwrap();
+    let chain = message.body_embed().unwrap().chain.unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<ValidatingTask>(
+        message,
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(backend.clone()),
+    )
+    .ok()
+    .unwrap();
+
+    assert!(tracer.trace().await.is_err());
+    assert!(tracer.take_triggered().is_empty());
+    // The error is stored as the result of the tasks which didn't run.
+    let chained_id = chain[0].task_id().unwrap();
+    assert_eq!(
+        TaskState::Failure,
+        backend.get_state(chained_id).await.unwrap()
+    );
+    assert!(matches!(
+        backend.get_traceback(chained_id).await.unwrap(),
+        Some(TaskError::UnexpectedError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_links_receive_result() {
+    let message = Message::try_from(
+        AddTask::new(1, 2)
+            .link(MultiplyTask::new(0, 10))
+            .link(AddTask::new(0, 5).with_queue("other"))
+            .link_error(CollectTask::new()),
+    )
+    .unwrap();
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<AddTask>(
+        message,
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(Arc::new(RecordingBackend::default())),
+    )
+    .ok()
+    .unwrap();
+
+    tracer.trace().await.unwrap();
+    let triggered = tracer.take_triggered();
+    assert_eq!(2, triggered.len());
+    let tasks: Vec<_> = triggered
+        .iter()
+        .map(|(message, queue)| (message.headers.task.as_str(), queue.as_deref()))
+        .collect();
+    assert_eq!(vec![("multiply", None), ("add", Some("other"))], tasks);
+    for (message, _) in triggered {
+        let body: serde_json::Value = serde_json::from_slice(&message.raw_body).unwrap();
+        assert_eq!(body[0][0], json!(3));
+    }
+}
+
+#[tokio::test]
+async fn test_links_keep_content_type() {
+    let message = Message::try_from(AddTask::new(1, 2).link(PackedTask::new(0, 10))).unwrap();
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<AddTask>(
+        message,
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        Some(Arc::new(RecordingBackend::default())),
+    )
+    .ok()
+    .unwrap();
+
+    tracer.trace().await.unwrap();
+    let (message, _) = tracer.take_triggered().pop().unwrap();
+    assert_eq!("application/x-msgpack", message.properties.content_type);
+    assert_eq!("binary", message.properties.content_encoding);
+    let request = Request::<PackedTask>::try_from(message).unwrap();
+    assert_eq!((3, 10), (request.params.x, request.params.y));
+}
+
+#[tokio::test]
+async fn test_error_link_receives_error() {
+    let message = Message::try_from(
+        Signature::<ValidatingTask>::new(false)
+            .link(AddTask::new(0, 1))
+            .link_error(CollectTask::new())
+            .link_error(CollectTask::new()),
+    )
+    .unwrap();
+    let task_id = message.task_id().to_string();
+    let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tracer = super::trace::build_tracer::<ValidatingTask>(
+        message,
+        TaskOptions::default(),
+        event_tx,
+        "localhost".into(),
+        None,
+        false,
+        None,
+    )
+    .ok()
+    .unwrap();
+
+    assert!(tracer.trace().await.is_err());
+    let triggered = tracer.take_triggered();
+    assert_eq!(2, triggered.len());
+    for (message, _) in triggered {
+        assert_eq!("collect", message.headers.task);
+        assert_eq!(Some(task_id.clone()), message.headers.parent_id);
+        let body: serde_json::Value = serde_json::from_slice(&message.raw_body).unwrap();
+        assert_eq!(body[0][0], json!(task_id));
+        assert_eq!(
+            body[0][1],
+            json!({
+                "exc_type": "UnexpectedError",
+                "exc_message": ["invalid input"],
+                "exc_module": "celery.exceptions",
+            })
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_send_group() {
+    let mut app = build_basic_app().await;
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    let group =
+        group(vec![AddTask::new(1, 2), AddTask::new(3, 4)]).with_task(MultiplyTask::new(2, 3));
+    let result = app.send_group(group).await.unwrap();
+    let task_ids: Vec<_> = result.results().iter().map(|r| r.task_id()).collect();
+    assert_eq!(3, task_ids.len());
+    assert_eq!(
+        task_ids,
+        backend.get_group(&result.group_id()).await.unwrap()
+    );
+
+    let restored = app.restore_group(&result.group_id()).await.unwrap();
+    let restored_ids: Vec<_> = restored.results().iter().map(|r| r.task_id()).collect();
+    assert_eq!(task_ids, restored_ids);
+
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let tasks: Vec<_> = task_ids
+        .iter()
+        .map(|task_id| {
+            let message = &sent_tasks.get(task_id).unwrap().0;
+            assert_eq!(Some(result.group_id()), message.headers.group);
+            message.headers.task.as_str()
+        })
+        .collect();
+    assert_eq!(vec!["add", "add", "multiply"], tasks);
+}
+
+#[tokio::test]
+async fn test_group_join_with_failure() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_group_join_with_failure", None)
+        .build()
+        .await
+        .unwrap();
+    app.backend = Some(Arc::new(RecordingBackend::default()));
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<ValidatingTask>().await.unwrap();
+    let group = group(vec![AddTask::new(1, 2)]).with_task(Signature::<ValidatingTask>::new(false));
+    let result = app.send_group(group).await.unwrap();
+
+    let app = Arc::new(app);
+    let consumed = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(consumed.is_err());
+
+    assert!(result.ready().await.unwrap());
+    assert_eq!(1, result.completed_count().await.unwrap());
+    let joined = result.join::<serde_json::Value>().await.unwrap();
+    assert_eq!(2, joined.len());
+    assert!(matches!(&joined[0], Ok(sum) if *sum == 3));
+    assert!(matches!(&joined[1], Err(TaskError::UnexpectedError(_))));
+}
+
+#[tokio::test]
+async fn test_chord() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_chord", None)
+        .build()
+        .await
+        .unwrap();
+    let without_backend = chord(group(vec![AddTask::new(1, 2)]), CollectTask::new());
+    assert!(matches!(
+        app.send_chord(without_backend).await,
+        Err(CeleryError::Backend(BackendError::NotSet))
+    ));
+
+    app.backend = Some(Arc::new(RecordingBackend::default()));
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<CollectTask>().await.unwrap();
+    let chord = chord(
+        group(vec![AddTask::new(1, 2), AddTask::new(3, 4)]),
+        CollectTask::new(),
+    );
+    let result = app.send_chord(chord).await.unwrap();
+    assert_eq!(TaskState::Pending, result.callback().state().await.unwrap());
+
+    let app = Arc::new(app);
+    let consumed = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(consumed.is_err());
+
+    assert_eq!(2, result.header().completed_count().await.unwrap());
+    // The results are passed in the order of the group.
+    assert_eq!(
+        Some(vec![json!(3), json!(7)]),
+        result
+            .callback()
+            .result::<Vec<serde_json::Value>>()
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_chord_error_policy() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_chord_error_policy", None)
+        .build()
+        .await
+        .unwrap();
+    app.backend = Some(Arc::new(RecordingBackend::default()));
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<ValidatingTask>().await.unwrap();
+    app.register_task::<CollectTask>().await.unwrap();
+    let header =
+        || group(vec![AddTask::new(1, 2)]).with_task(Signature::<ValidatingTask>::new(false));
+    let propagated = app
+        .send_chord(chord(header(), CollectTask::new()))
+        .await
+        .unwrap();
+    let passed_through = app
+        .send_chord(
+            chord(header(), CollectTask::new()).with_error_policy(ChordErrorPolicy::PassThrough),
+        )
+        .await
+        .unwrap();
+
+    let app = Arc::new(app);
+    let consumed = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(consumed.is_err());
+
+    assert!(propagated.callback().failed().await.unwrap());
+    assert_eq!(
+        Some(vec![
+            json!(3),
+            json!({
+                "exc_type": "UnexpectedError",
+                "exc_message": ["invalid input"],
+                "exc_module": "celery.exceptions",
+            })
+        ]),
+        passed_through
+            .callback()
+            .result::<Vec<serde_json::Value>>()
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_control_revoke_without_remote_control() {
+    let celery = build_basic_app().await;
+    assert!(matches!(
+        celery.control_revoke("abc").await,
+        Err(CeleryError::RemoteControlDisabled)
+    ));
+}
+
+#[tokio::test]
+async fn test_revoked_task_discarded() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_revoked_task_discarded", None)
+        .worker_enable_remote_control(true)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let revoked = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+    let other = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+
+    let client = async {
+        // Let the worker subscribe to the control queue before revoking the task.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.control_revoke(revoked.task_id()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.broker.send(&revoked, "celery").await.unwrap();
+        app.broker.send(&other, "celery").await.unwrap();
+    };
+    let result = tokio::time::timeout(
+        Duration::from_secs(1),
+        futures::future::join(app.consume(), client),
+    )
+    .await;
+    assert!(result.is_err());
+
+    assert!(!RECORDED.lock().unwrap().contains(revoked.task_id()));
+    assert!(RECORDED.lock().unwrap().contains(other.task_id()));
+}
+
+#[tokio::test]
+async fn test_dedup_drops_duplicate() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_dedup_drops_duplicate", None)
+        .task_dedup(true)
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    app.register_task::<RecordingTask>().await.unwrap();
+    let duplicate = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+    let mut retry = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+    retry.headers.retries = Some(1);
+
+    // Another worker already claimed the first task, but not the retry of the second one.
+    let ttl = Duration::from_secs(60);
+    let claimed = backend.claim_task(duplicate.task_id(), 0, ttl).await;
+    assert!(claimed.unwrap());
+    assert!(backend.claim_task(retry.task_id(), 0, ttl).await.unwrap());
+    app.broker.send(&duplicate, "celery").await.unwrap();
+    app.broker.send(&retry, "celery").await.unwrap();
+
+    let app = Arc::new(app);
+    let consumed = tokio::time::timeout(Duration::from_millis(200), app.consume()).await;
+    assert!(consumed.is_err());
+
+    assert!(!RECORDED.lock().unwrap().contains(duplicate.task_id()));
+    assert_eq!(
+        TaskState::Ignored,
+        backend.get_state(duplicate.task_id()).await.unwrap()
+    );
+    assert!(RECORDED.lock().unwrap().contains(retry.task_id()));
+    assert!(!backend.claim_task(retry.task_id(), 1, ttl).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_ignore_result() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_ignore_result", None)
+        .task_ignore_result(true)
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    app.register_task::<AddTask>().await.unwrap();
+    let ignored = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let stored = app
+        .send_task(AddTask::new(3, 4).with_ignore_result(false))
+        .await
+        .unwrap();
+
+    let app = Arc::new(app);
+    let consumed = tokio::time::timeout(Duration::from_millis(200), app.consume()).await;
+    assert!(consumed.is_err());
+
+    assert!(matches!(
+        ignored.state().await,
+        Err(BackendError::ResultIgnored)
+    ));
+    assert!(matches!(
+        backend.get_task_meta(&ignored.task_id()).await,
+        Err(BackendError::DocumentNotFound(_))
+    ));
+    assert_eq!(Some(7), stored.result::<i32>().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_before_publish_signal() {
+    let app = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .on_before_publish(|message| {
+            Box::pin(async move {
+                message.headers.origin = Some("tenant-a".into());
+            })
+        })
+        .on_before_publish(|_| Box::pin(async { panic!("handler failed") }))
+        .on_before_publish(|message| {
+            Box::pin(async move {
+                let origin = message.headers.origin.take().unwrap();
+                message.headers.origin = Some(format!("{}/b", origin));
+            })
+        })
+        .build()
+        .await
+        .unwrap();
+    let result = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let mock_broker = app.broker.into_any().downcast::<MockBroker>().unwrap();
+    let sent_tasks = mock_broker.sent_tasks.read().await;
+    let message = &sent_tasks.get(&result.task_id()).unwrap().0;
+    assert_eq!(Some("tenant-a/b".into()), message.headers.origin);
+}
+
+/// A `task_prerun` signal, or a `task_postrun` signal with the state of the task, along
+/// with the `tenant` header of the task.
+type RunSignal = (String, Option<TaskState>, Option<serde_json::Value>);
+
+/// The signals received in `test_task_run_signals`.
+static RUN_SIGNALS: Lazy<Mutex<Vec<RunSignal>>> = Lazy::new(|| Mutex::new(vec![]));
+
+#[tokio::test]
+async fn test_task_run_signals() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_task_run_signals", None)
+        .on_before_publish(|message| {
+            Box::pin(async move {
+                message
+                    .headers
+                    .extra
+                    .entry("tenant".into())
+                    .or_insert_with(|| json!("a"));
+            })
+        })
+        .on_task_prerun(|task| {
+            Box::pin(async move {
+                let tenant = task.headers.get("tenant").cloned();
+                RUN_SIGNALS
+                    .lock()
+                    .unwrap()
+                    .push((task.id.clone(), None, tenant));
+            })
+        })
+        .on_task_postrun(|task, state| {
+            Box::pin(async move {
+                let tenant = task.headers.get("tenant").cloned();
+                RUN_SIGNALS
+                    .lock()
+                    .unwrap()
+                    .push((task.id.clone(), Some(state), tenant));
+            })
+        })
+        .build()
+        .await
+        .unwrap();
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<ValidatingTask>().await.unwrap();
+    let added = app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let invalid = app
+        .send_task(
+            Signature::<ValidatingTask>::new(false)
+                .with_headers(HashMap::from([("tenant".to_string(), json!("b"))])),
+        )
+        .await
+        .unwrap();
+
+    let app = Arc::new(app);
+    let result = tokio::time::timeout(Duration::from_secs(1), app.consume()).await;
+    assert!(result.is_err());
+
+    let signals = RUN_SIGNALS.lock().unwrap();
+    assert_eq!(
+        vec![
+            (added.task_id(), None, Some(json!("a"))),
+            (added.task_id(), Some(TaskState::Success), Some(json!("a"))),
+            (invalid.task_id(), None, Some(json!("b"))),
+            (
+                invalid.task_id(),
+                Some(TaskState::Failure),
+                Some(json!("b"))
+            ),
+        ],
+        *signals
+    );
+}
+
+#[tokio::test]
+async fn test_task_failure_report() {
+    let reports = Arc::new(Mutex::new(vec![]));
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_task_failure_report", None)
+        .on_task_failure_report({
+            let reports = reports.clone();
+            move |report| reports.lock().unwrap().push(report.clone())
+        })
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<FailingTask>().await.unwrap();
+    app.register_batch_task::<ReportedBatchTask>()
+        .await
+        .unwrap();
+    app.send_task(AddTask::new(1, 2)).await.unwrap();
+    let failed = app
+        .send_task(Signature::<FailingTask>::new(()).with_ignore_result(true))
+        .await
+        .unwrap()
+        .task_id();
+    app.send_task(Signature::<ReportedBatchTask>::new(2))
+        .await
+        .unwrap();
+    let failed_batched = app
+        .send_task(Signature::<ReportedBatchTask>::new(-3))
+        .await
+        .unwrap()
+        .task_id();
+
+    let app = Arc::new(app);
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume()).await;
+    assert!(result.is_err());
+
+    // The failing task is reported once, after its retry, although it ignores its result,
+    // and so is the task of a batch which failed.
+    let reports = reports.lock().unwrap().clone();
+    assert_eq!(2, reports.len());
+    let batched = reports
+        .iter()
+        .find(|report| report.task_name == "reported_batch")
+        .unwrap();
+    assert_eq!(failed_batched, batched.task_id);
+    assert_eq!("celery", batched.queue);
+    // The parameters of the task aren't a struct, so they are sent as its keyword arguments.
+    assert_eq!(json!([]), batched.args);
+    assert_eq!(json!(-3), batched.kwargs);
+    assert_eq!(
+        vec!["task raised unexpected error: negative: -3".to_string()],
+        batched.error_chain
+    );
+    let report = reports
+        .iter()
+        .find(|report| report.task_name == "failing")
+        .unwrap();
+    assert_eq!(failed, report.task_id);
+    assert_eq!("failing", report.task_name);
+    assert_eq!("celery", report.queue);
+    assert_eq!(1, report.retries);
+    assert_eq!(json!([]), report.args);
+    assert!(matches!(report.error, Some(TaskError::ExpectedError(_))));
+    assert_eq!(
+        vec![
+            "task retries exceeded".to_string(),
+            "task raised expected error: always fails".to_string()
+        ],
+        report.error_chain
+    );
+    assert!(!report.panicked);
+    assert!(!report.timed_out);
+    assert!(backend.get_task_meta(&failed).await.is_err());
+    let serialized = serde_json::to_value(report).unwrap();
+    assert_eq!(serialized["task_name"], "failing");
+}
+
+/// A logger which records the log lines of the crate, for `test_task_log_fields`.
+#[cfg(not(feature = "tracing"))]
+struct RecordingLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+#[cfg(not(feature = "tracing"))]
+impl log::Log for RecordingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.lines.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(not(feature = "tracing"))]
+static RECORDING_LOGGER: Lazy<RecordingLogger> = Lazy::new(|| RecordingLogger {
+    lines: Mutex::new(vec![]),
+});
+
+#[cfg(not(feature = "tracing"))]
+#[tokio::test]
+async fn test_task_log_fields() {
+    log::set_logger(&*RECORDING_LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+    let app = CeleryBuilder::new("mock-app", "memory://test_task_log_fields", None)
+        .build()
+        .await
+        .unwrap();
+    app.register_task::<FailingTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<FailingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    let app = Arc::new(app);
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume()).await;
+    assert!(result.is_err());
+
+    // Every line logged by the worker about the task carries its fields, the line of the
+    // producer doesn't.
+    let lines = RECORDING_LOGGER.lines.lock().unwrap();
+    let mut task_lines = lines
+        .iter()
+        .filter(|line| line.contains(&format!("[{}]", task_id)));
+    let sent = task_lines.next().unwrap();
+    assert!(sent.starts_with("Sending task") && !sent.contains("task_id="));
+    let suffix = format!(" task_id={} task_name=failing queue=celery", task_id);
+    let task_lines: Vec<_> = task_lines.collect();
+    assert!(!task_lines.is_empty());
+    assert!(task_lines.iter().all(|line| line.ends_with(&suffix)));
+}
+
+/// The queue latencies received with the `task_prerun` signal in
+/// `test_queue_latency_clock_skew`.
+static QUEUE_LATENCIES: Lazy<Mutex<Vec<Option<Duration>>>> = Lazy::new(|| Mutex::new(vec![]));
+
+#[tokio::test]
+async fn test_queue_latency_clock_skew() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_queue_latency_clock_skew", None)
+        .on_before_publish(|message| {
+            Box::pin(async move {
+                // The clock of this producer is a minute ahead of the worker's.
+                if message.headers.task == "multiply" {
+                    message.headers.sent_at = Some(Utc::now() + chrono::Duration::seconds(60));
+                }
+            })
+        })
+        .on_task_prerun(|task| {
+            Box::pin(async move {
+                QUEUE_LATENCIES.lock().unwrap().push(task.queue_latency);
+            })
+        })
+        .build()
+        .await
+        .unwrap();
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<MultiplyTask>().await.unwrap();
+    app.send_task(AddTask::new(1, 2)).await.unwrap();
+    app.send_task(MultiplyTask::new(3, 4)).await.unwrap();
+
+    let app = Arc::new(app);
+    let result = tokio::time::timeout(Duration::from_millis(500), app.consume()).await;
+    assert!(result.is_err());
+
+    let latencies = QUEUE_LATENCIES.lock().unwrap().clone();
+    assert_eq!(2, latencies.len());
+    assert!(latencies
+        .iter()
+        .all(|latency| latency.unwrap() < Duration::from_millis(500)));
+    // The latency of the task which seems to start before it was sent is clamped at zero.
+    assert!(latencies.contains(&Some(Duration::from_secs(0))));
+
+    let stats = app.stats().await;
+    assert_eq!(1, stats.negative_queue_latencies);
+    assert_eq!(1, stats.tasks["add"].queue_latency.count);
+    assert_eq!(1, stats.tasks["multiply"].queue_latency.count);
+    assert_eq!(1, stats.tasks["multiply"].queue_latency.buckets[0]);
+}
+
+#[tokio::test]
+async fn test_inspect() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_inspect", None)
+        .hostname("worker@test_inspect")
+        .worker_enable_remote_control(true)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<AddTask>().await.unwrap();
+    app.register_task::<MultiplyTask>().await.unwrap();
+
+    let client = async {
+        // Let the worker subscribe to the control queue.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let inspect = app
+            .inspect()
+            .destination(vec!["worker@test_inspect".into()]);
+        let pongs = inspect.ping().await.unwrap();
+        assert_eq!("pong", pongs["worker@test_inspect"].ok);
+        let registered = inspect.registered().await.unwrap();
+        assert_eq!(
+            vec!["add".to_string(), "multiply".to_string()],
+            registered["worker@test_inspect"]
+        );
+        let active = inspect.active().await.unwrap();
+        assert!(active["worker@test_inspect"].is_empty());
+        let stats = inspect.stats().await.unwrap();
+        assert_eq!(std::process::id(), stats["worker@test_inspect"].pid);
+
+        // Other workers don't reply.
+        let pongs = app
+            .inspect()
+            .destination(vec!["other@test_inspect".into()])
+            .timeout(Duration::from_millis(100))
+            .ping()
+            .await
+            .unwrap();
+        assert!(pongs.is_empty());
+    };
+    tokio::select! {
+        _ = app.consume() => panic!("the worker stopped"),
+        _ = client => (),
+    }
+}
+
+#[tokio::test]
+async fn test_control_cancel_consumer_and_shutdown() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_control_shutdown", None)
+        .hostname("worker@test_control_shutdown")
+        .worker_enable_remote_control(true)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let drained = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+    let consumed = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+
+    let client = async {
+        // Let the worker subscribe to the control queue.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.control_cancel_consumer("drained", None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.broker.send(&drained, "drained").await.unwrap();
+        app.broker.send(&consumed, "celery").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Other workers don't shut down.
+        app.control_shutdown(Some(vec!["other@test_control_shutdown".into()]))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.control_shutdown(Some(vec!["worker@test_control_shutdown".into()]))
+            .await
+            .unwrap();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(1),
+        futures::future::join(app.consume_from(&["celery", "drained"]), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    assert!(!RECORDED.lock().unwrap().contains(drained.task_id()));
+    assert!(RECORDED.lock().unwrap().contains(consumed.task_id()));
+}
+
+#[tokio::test]
+async fn test_add_and_remove_queue() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_add_and_remove_queue", None)
+        .hostname("worker@test_add_and_remove_queue")
+        .worker_enable_remote_control(true)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let removed = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+    let added = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.remove_queue("bulk");
+        app.control_add_consumer("priority", None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.broker.send(&removed, "bulk").await.unwrap();
+        app.broker.send(&added, "priority").await.unwrap();
+        let queues = app.inspect().active_queues().await.unwrap();
+        let stats = app.inspect().stats().await.unwrap();
+        app.shutdown();
+        (queues, stats)
+    };
+    let (result, (queues, stats)) = tokio::time::timeout(
+        Duration::from_secs(3),
+        futures::future::join(app.consume_from(&["celery", "bulk"]), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    assert!(!RECORDED.lock().unwrap().contains(removed.task_id()));
+    assert!(RECORDED.lock().unwrap().contains(added.task_id()));
+    let names: Vec<&str> = queues["worker@test_add_and_remove_queue"]
+        .iter()
+        .map(|queue| queue.name.as_str())
+        .collect();
+    assert_eq!(vec!["celery", "priority"], names);
+    assert_eq!(
+        vec!["celery", "priority"],
+        stats["worker@test_add_and_remove_queue"].queues
+    );
+}
+
+#[tokio::test]
+async fn test_shutdown_timeout() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_shutdown_timeout", None)
+        .shutdown_timeout(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    let app = Arc::new(app);
+    app.register_task::<SlowTask>().await.unwrap();
+    let acked_late = app
+        .send_task(Signature::<SlowTask>::new(()).with_acks_late(true))
+        .await
+        .unwrap()
+        .task_id();
+    let acked_early = app
+        .send_task(Signature::<SlowTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(2),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // Only the message of the task acknowledged late is requeued.
+    assert_eq!(
+        TaskState::Retry,
+        backend.get_state(&acked_late).await.unwrap()
+    );
+    assert_eq!(
+        TaskState::Failure,
+        backend.get_state(&acked_early).await.unwrap()
+    );
+    assert_eq!(Some(1), app.queue_len("celery").await.unwrap());
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+#[tokio::test]
+async fn test_max_memory_per_worker() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_max_memory_per_worker", None)
+        .max_memory_per_worker_mb(1)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<RecordingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    // Any process exceeds 1 MB, so the worker stops on the first sample.
+    let result = tokio::time::timeout(Duration::from_secs(3), app.consume())
+        .await
+        .unwrap();
+    match result {
+        Err(CeleryError::MemoryLimitExceeded {
+            resident_memory,
+            max_memory,
+        }) => {
+            assert_eq!(max_memory, 1024 * 1024);
+            assert!(resident_memory > max_memory);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(RECORDED.lock().unwrap().contains(&task_id));
+
+    let stats = app.stats().await;
+    assert_eq!(Some(1024 * 1024), stats.max_memory);
+    assert!(stats.resident_memory.unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_worker_queues() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_worker_queues", None)
+        .worker_queues(&["critical", "bulk"])
+        .worker_queue_strategy(QueueStrategy::Priority)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let mut sent = vec![];
+    for queue in ["critical", "bulk", "celery"] {
+        let signature = Signature::<RecordingTask>::new(()).with_queue(queue);
+        sent.push(app.send_task(signature).await.unwrap().task_id());
+    }
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(2),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // The default queue isn't consumed when worker queues are set.
+    {
+        let recorded = RECORDED.lock().unwrap();
+        assert!(recorded.contains(&sent[0]));
+        assert!(recorded.contains(&sent[1]));
+        assert!(!recorded.contains(&sent[2]));
+    }
+    assert_eq!(Some(1), app.queue_len("celery").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_control_rate_limit() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_control_rate_limit", None)
+        .worker_enable_remote_control(true)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let first = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+    let second = Message::try_from(Signature::<RecordingTask>::new(())).unwrap();
+
+    let client = async {
+        // Let the worker subscribe to the control queue.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.control_rate_limit("recording", Some(RateLimit::per_hour(1)), None)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.broker.send(&first, "celery").await.unwrap();
+        app.broker.send(&second, "celery").await.unwrap();
+    };
+    let result = tokio::time::timeout(
+        Duration::from_millis(500),
+        futures::future::join(app.consume(), client),
+    )
+    .await;
+    assert!(result.is_err());
+
+    // The second task waits for its turn, in an hour.
+    let recorded = RECORDED.lock().unwrap();
+    assert!(recorded.contains(first.task_id()) != recorded.contains(second.task_id()));
+}
+
+#[tokio::test]
+async fn test_queue_len() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_queue_len", None)
+        .build()
+        .await
+        .unwrap();
+    app.send_task(AddTask::new(1, 2)).await.unwrap();
+    app.send_task(AddTask::new(3, 4)).await.unwrap();
+    assert_eq!(Some(2), app.queue_len("celery").await.unwrap());
+    assert_eq!(Some(0), app.queue_len("other").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_autoscale_grows_concurrency() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_autoscale", None)
+        .autoscale(0, 2)
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<RecordingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    // The worker starts without concurrency, and scales up once it sees the task waiting.
+    let result = tokio::time::timeout(Duration::from_millis(1500), app.consume()).await;
+    assert!(result.is_err());
+    assert!(RECORDED.lock().unwrap().contains(&task_id));
+    // It only needed one slot, and keeps it until it has been idle for a while.
+    assert_eq!(1, app.concurrency.as_ref().unwrap().limit());
+}
+
+async fn build_eager_app() -> Celery {
+    let celery = CeleryBuilder::new("mock-app", "mock://localhost:8000", None)
+        .task_always_eager(true)
+        .build()
+        .await
+        .unwrap();
+    celery.register_task::<AddTask>().await.unwrap();
+    celery.register_task::<MultiplyTask>().await.unwrap();
+    celery.register_task::<FailingTask>().await.unwrap();
+    celery
+}
+
+#[tokio::test]
+async fn test_always_eager() {
+    let app = build_eager_app().await;
+    let result = app
+        .send_task(AddTask::new(1, 2).then(MultiplyTask::new(0, 10)))
+        .await
+        .unwrap();
+    // The result of the chain is ready right away, from the implicit in-memory backend.
+    assert!(result.ready().await.unwrap());
+    assert!(result.wait_for_completion().await.unwrap());
+    assert_eq!(Some(30), result.result::<i32>().await.unwrap());
+
+    let results = app
+        .send_tasks(vec![AddTask::new(1, 1), AddTask::new(2, 2)])
+        .await;
+    for (result, expected) in results.into_iter().zip([2, 4]) {
+        assert_eq!(
+            Some(expected),
+            result.unwrap().result::<i32>().await.unwrap()
+        );
+    }
+
+    // The broker wasn't even connected.
+    let broker = app.broker.into_any().downcast::<LazyBroker>().unwrap();
+    assert!(!broker.is_connected());
+}
+
+#[tokio::test]
+async fn test_always_eager_failure() {
+    let app = build_eager_app().await;
+    // The task would be retried once by a worker.
+    let result = app
+        .send_task(Signature::<FailingTask>::new(()))
+        .await
+        .unwrap();
+    assert_eq!(TaskState::Failure, result.state().await.unwrap());
+    assert!(!result.wait_for_completion().await.unwrap());
+    assert!(matches!(
+        result.traceback().await.unwrap(),
+        Some(TaskError::ExpectedError(_))
+    ));
+
+    assert!(matches!(
+        app.send_task(Signature::<ValidatingTask>::new(true)).await,
+        Err(CeleryError::UnregisteredTaskError(_))
+    ));
+}
+
+/// Abort a task running on the worker of an app, like a `revoke` command with `terminate`
+/// does.
+async fn terminate_task(app: &Celery, task_id: &str) {
+    let mut arguments = serde_json::Map::new();
+    arguments.insert("task_id".into(), task_id.into());
+    arguments.insert("terminate".into(), true.into());
+    app.run_control_command(&ControlCommand::new("revoke", arguments))
+        .await;
+}
+
+#[tokio::test]
+async fn test_abort_task() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_abort_task", None)
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    let app = Arc::new(app);
+    app.register_task::<AbortableTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<AbortableTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        terminate_task(&app, &task_id).await;
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(2),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // The task stops by itself and is recorded as revoked although it succeeded.
+    assert_eq!(1, ABORTS.load(Ordering::Relaxed));
+    assert_eq!(
+        TaskState::Revoked,
+        backend.get_state(&task_id).await.unwrap()
+    );
+    assert_eq!(0, app.failed_tasks.load(Ordering::Relaxed));
+}
+
+#[tokio::test]
+async fn test_abort_task_after_grace_period() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_abort_grace_period", None)
+        .worker_abort_grace_period(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    let app = Arc::new(app);
+    app.register_task::<SlowTask>().await.unwrap();
+    let task_id = app
+        .send_task(Signature::<SlowTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        terminate_task(&app, &task_id).await;
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(2),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // The task ignores the abort, so it is terminated instead of being waited for.
+    assert_eq!(
+        TaskState::Revoked,
+        backend.get_state(&task_id).await.unwrap()
+    );
+    assert_eq!(0, app.failed_tasks.load(Ordering::Relaxed));
+}
+
+#[tokio::test]
+async fn test_eta_schedule_strategy() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_eta_schedule_strategy", None)
+        .prefetch_count(10)
+        .worker_eta_strategy(EtaStrategy::Schedule)
+        .worker_eta_poll_interval(Duration::from_millis(100))
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    app.register_task::<RecordingTask>().await.unwrap();
+    // The task due soon is sent first, so that it is received before it is due.
+    let soon = app
+        .send_task(Signature::<RecordingTask>::new(()).with_countdown(Duration::from_secs(1)))
+        .await
+        .unwrap()
+        .task_id();
+    for _ in 0..10_000 {
+        app.send_task(
+            Signature::<RecordingTask>::new(()).with_countdown(Duration::from_secs(3600)),
+        )
+        .await
+        .unwrap();
+    }
+    let immediate = app
+        .send_task(Signature::<RecordingTask>::new(()))
+        .await
+        .unwrap()
+        .task_id();
+
+    let client = async {
+        loop {
+            let recorded = RECORDED.lock().unwrap().clone();
+            if recorded.contains(&immediate) && recorded.contains(&soon) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(10),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // The tasks due later are kept by the broker instead of the worker, which only
+    // received the other tasks once more when they were due.
+    assert_eq!(Some(0), app.queue_len("celery").await.unwrap());
+    assert_eq!(10, app.broker.prefetch_count().await);
+    assert_eq!(0, app.broker.enqueue_due().await.unwrap());
+    let stats = app.stats().await;
+    assert_eq!(10_003, stats.received);
+    assert_eq!(2, stats.processed);
+}
+
+#[tokio::test]
+async fn test_batch_task() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_batch_task", None)
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    let app = Arc::new(app);
+    app.register_batch_task::<PerItemBatchTask>().await.unwrap();
+    let mut task_ids = vec![];
+    for x in [1, 2, -3, 4, 5] {
+        let result = app
+            .send_task(Signature::<PerItemBatchTask>::new(x))
+            .await
+            .unwrap();
+        task_ids.push(result.task_id());
+    }
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(2),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // The last task runs alone once the flush interval elapsed, and only the task which
+    // failed fails.
+    let mut batches = BATCHES.lock().unwrap()[PerItemBatchTask::NAME].clone();
+    batches.sort_unstable();
+    assert_eq!(vec![1, 2, 2], batches);
+    for (i, task_id) in task_ids.iter().enumerate() {
+        let expected = if i == 2 {
+            TaskState::Failure
+        } else {
+            TaskState::Success
+        };
+        assert_eq!(expected, backend.get_state(task_id).await.unwrap());
+    }
+    let stats = app.stats().await;
+    assert_eq!(5, stats.received);
+    assert_eq!(4, stats.succeeded);
+    assert_eq!(1, stats.failed);
+    assert_eq!(5, stats.processed);
+}
+
+#[tokio::test]
+async fn test_batch_task_flushed_on_shutdown() {
+    let mut app = CeleryBuilder::new("mock-app", "memory://test_batch_task_shutdown", None)
+        .build()
+        .await
+        .unwrap();
+    let backend = Arc::new(RecordingBackend::default());
+    app.backend = Some(backend.clone());
+    let app = Arc::new(app);
+    app.register_batch_task::<FailAllBatchTask>().await.unwrap();
+    let mut task_ids = vec![];
+    for x in [1, -2, 3] {
+        let result = app
+            .send_task(Signature::<FailAllBatchTask>::new(x))
+            .await
+            .unwrap();
+        task_ids.push(result.task_id());
+    }
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(2),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // The partial batch runs at the shutdown, and all its tasks fail with the one which
+    // failed.
+    assert_eq!(
+        vec![3],
+        BATCHES.lock().unwrap()[FailAllBatchTask::NAME].clone()
+    );
+    for task_id in &task_ids {
+        assert_eq!(
+            TaskState::Failure,
+            backend.get_state(task_id).await.unwrap()
+        );
+    }
+    assert_eq!(3, app.failed_tasks.load(Ordering::Relaxed));
+}
+
+/// A worker lifecycle hook which records its name and the queues of the worker.
+fn record_hook(
+    calls: &Arc<Mutex<Vec<String>>>,
+    hook: &'static str,
+) -> impl for<'a> Fn(&'a WorkerInfo) -> BoxFuture<'a, Result<(), HookError>> + Send + Sync + 'static
+{
+    let calls = calls.clone();
+    move |worker| {
+        calls
+            .lock()
+            .unwrap()
+            .push(format!("{}: {:?}", hook, worker.queues));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test]
+async fn test_worker_hooks() {
+    let calls = Arc::new(Mutex::new(vec![]));
+    let app = CeleryBuilder::new("mock-app", "memory://test_worker_hooks", None)
+        .on_worker_init(record_hook(&calls, "worker_init"))
+        .on_broker_connect(|_| Box::pin(async { Err(HookError::Failed("unavailable".into())) }))
+        .on_broker_connect(record_hook(&calls, "broker_connect"))
+        .on_worker_shutdown(record_hook(&calls, "worker_shutdown"))
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+
+    let client = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        app.shutdown();
+    };
+    let (result, _) = tokio::time::timeout(
+        Duration::from_secs(2),
+        futures::future::join(app.consume(), client),
+    )
+    .await
+    .unwrap();
+    result.unwrap();
+
+    // The hooks which failed without a fatal error don't stop the next ones.
+    assert_eq!(
+        vec![
+            "worker_init: [\"celery\"]",
+            "broker_connect: [\"celery\"]",
+            "worker_shutdown: [\"celery\"]",
+        ],
+        *calls.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_fatal_worker_hook() {
+    let app = CeleryBuilder::new("mock-app", "memory://test_fatal_worker_hook", None)
+        .on_worker_init(|_| Box::pin(async { Err(HookError::Fatal("unregistered".into())) }))
+        .build()
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+
+    let result = tokio::time::timeout(Duration::from_secs(2), app.consume())
+        .await
+        .unwrap();
+    assert!(matches!(
+        result,
+        Err(CeleryError::HookError(HookError::Fatal(_)))
+    ));
 }
@@ -1,13 +1,16 @@
+use crate::logging::{debug, error, info, warn};
 use async_trait::async_trait;
 use chrono::Utc;
-use log::{debug, error, info, warn};
+use serde_json::Value;
 use std::{convert::TryFrom, sync::Arc};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::time::{self, Duration, Instant};
 
-use crate::error::{ProtocolError, TaskError, TraceError};
-use crate::protocol::Message;
-use crate::task::{Request, Task, TaskEvent, TaskOptions, TaskState};
+use crate::error::{BackendError, ProtocolError, TaskError, TraceError};
+use crate::protocol::{Message, SerializedSignature};
+use crate::task::{
+    CancellationToken, RateLimit, Request, Task, TaskContext, TaskEvent, TaskOptions, TaskState,
+};
 use crate::backend::Backend;
 
 /// A `Tracer` provides the API through which a `Celery` application interacts with its tasks.
@@ -21,28 +24,223 @@ where
     T: Task {
     task: T,
     event_tx: UnboundedSender<TaskEvent>,
-    backend: Option<Arc<dyn Backend>>
+    backend: Option<Arc<dyn Backend>>,
+    /// The tasks triggered by the task, like the next task of its chain or the callback of
+    /// its chord, along with their queues.
+    triggered: Vec<(Message, Option<String>)>,
+    /// The parameters the task retries itself with, if it changed them.
+    retry_params: Option<Value>,
 }
 
 impl<T> Tracer<T>
 where
     T: Task {
-    fn new(task: T, event_tx: UnboundedSender<TaskEvent>, backend: Option<Arc<dyn Backend>>) -> Self {
+    pub(super) fn new(task: T, event_tx: UnboundedSender<TaskEvent>, backend: Option<Arc<dyn Backend>>) -> Self {
         if let Some(eta) = task.request().eta {
             info!(
                 "Task {}[{}] received, ETA: {}",
-                task.name(),
+                task.request().display_name(),
                 task.request().id,
                 eta
             );
         } else {
-            info!("Task {}[{}] received", task.name(), task.request().id);
+            info!(
+                "Task {}[{}] received",
+                task.request().display_name(),
+                task.request().id
+            );
+        }
+
+        Self { task, event_tx, backend, triggered: vec![], retry_params: None }
+    }
+}
+
+impl<T> Tracer<T>
+where
+    T: Task {
+    /// The node name of the worker executing the task, stored along with its result.
+    fn worker(&self) -> Option<&str> {
+        self.task.request().hostname.as_deref()
+    }
+
+    /// The backend to store the result of the task in, unless it is ignored.
+    fn result_backend(&self) -> Option<&Arc<dyn Backend>> {
+        if self.task.ignore_result() {
+            return None;
+        }
+        self.backend.as_ref()
+    }
+
+    /// Run the task, signalling it once it exceeds its soft time limit.
+    async fn run(&self) -> Result<T::Returns, TaskError> {
+        let run = self.task.run(self.task.request().params.clone());
+        let soft_time_limit = match self.task.soft_time_limit() {
+            Some(secs) => Duration::from_secs(secs as u64),
+            None => return run.await,
+        };
+        tokio::pin!(run);
+        tokio::select! {
+            biased;
+            result = &mut run => return result,
+            _ = time::sleep(soft_time_limit) => (),
+        };
+        warn!(
+            "Task {}[{}] exceeded its soft time limit of {}s",
+            self.task.request().display_name(),
+            &self.task.request().id,
+            soft_time_limit.as_secs(),
+        );
+        self.task.request().set_soft_time_limit_exceeded();
+        // The task keeps running alongside the callback until its hard time limit.
+        let (result, _) = futures::join!(run, self.task.on_soft_timeout());
+        result
+    }
+
+    /// Create the messages of the linked tasks and of the next task of the chain, which
+    /// receive the result.
+    fn triggered_by_result(&self, returned: &T::Returns) -> Vec<(Message, Option<String>)> {
+        let request = self.task.request();
+        if request.callbacks.is_empty() && request.chain.is_empty() {
+            return vec![];
+        }
+        let result = match serde_json::to_value(returned) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to serialize the result passed to the linked tasks: {}", e);
+                return vec![];
+            }
+        };
+        let mut triggered: Vec<_> = request
+            .callbacks
+            .iter()
+            .filter_map(|callback| trigger(callback.clone(), &request.id, vec![result.clone()], vec![]))
+            .collect();
+        let mut chain = request.chain.clone();
+        if let Some(next) = chain.pop() {
+            triggered.extend(trigger(next, &request.id, vec![result], chain));
+        }
+        triggered
+    }
+
+    /// Create the messages of the error callbacks, which receive the ID of the task and its
+    /// error.
+    fn triggered_by_error(&self, error: &TaskError) -> Vec<(Message, Option<String>)> {
+        let request = self.task.request();
+        let error = match serde_json::to_value(error) {
+            Ok(error) => error,
+            Err(e) => {
+                error!("Failed to serialize the error passed to the error callbacks: {}", e);
+                return vec![];
+            }
+        };
+        request
+            .errbacks
+            .iter()
+            .filter_map(|errback| {
+                let args = vec![Value::String(request.id.clone()), error.clone()];
+                trigger(errback.clone(), &request.id, args, vec![])
+            })
+            .collect()
+    }
+
+    /// Count the task as finished in its chord, if it is part of one. Once every task of the
+    /// chord finished, the message of its callback is created with their results, unless one
+    /// of them failed and its error is propagated.
+    async fn chord_task_done(&self, error: Option<&TaskError>) -> Option<(Message, Option<String>)> {
+        let request = self.task.request();
+        let (callback, group_id) = match (&request.chord, &request.group) {
+            (Some(callback), Some(group_id)) => (callback, group_id),
+            _ => return None,
+        };
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => {
+                error!(
+                    "Task {}[{}] is part of a chord, which requires a result backend",
+                    self.task.request().display_name(),
+                    &request.id,
+                );
+                return None;
+            }
+        };
+        let propagate = callback
+            .options
+            .get("propagate")
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+
+        // The chord fails as soon as one of its tasks fails.
+        if let (Some(e), true, Some(callback_id)) = (error, propagate, callback.task_id()) {
+            let err = TaskError::UnexpectedError(format!("task {} of the chord failed: {}", request.id, e));
+            if let Err(e) = backend.mark_as_failure(callback_id, err, Utc::now(), None).await {
+                error!("Failed to save result: {}", e);
+            }
+        }
+
+        let finished = match backend.incr_chord_counter(group_id).await {
+            Ok(finished) => finished,
+            Err(e) => {
+                error!("Failed to count the finished tasks of chord {}: {}", group_id, e);
+                return None;
+            }
+        };
+        if finished < callback.chord_size.unwrap_or_default() {
+            return None;
         }
 
-        Self { task, event_tx, backend }
+        let results = match chord_results(&**backend, group_id, propagate).await {
+            Ok(Some(results)) => results,
+            Ok(None) => return None,
+            Err(e) => {
+                error!("Failed to get the results of chord {}: {}", group_id, e);
+                return None;
+            }
+        };
+        trigger(callback.clone(), &request.id, vec![Value::Array(results)], vec![])
     }
 }
 
+/// Create the message of a task triggered by the task `parent_id`, along with the queue it
+/// is sent to if it isn't the default one.
+fn trigger(
+    sig: SerializedSignature,
+    parent_id: &str,
+    args: Vec<Value>,
+    chain: Vec<SerializedSignature>,
+) -> Option<(Message, Option<String>)> {
+    let queue = sig.queue().map(String::from);
+    let task = sig.task.clone();
+    match sig.into_message(Some(parent_id), args, chain) {
+        Ok(message) => Some((message, queue)),
+        Err(e) => {
+            error!("Failed to create the message of task {} triggered by {}: {}", task, parent_id, e);
+            None
+        }
+    }
+}
+
+/// Get the results of the tasks of a chord in order, or `None` if one of them failed and
+/// its error is propagated. Otherwise the errors are passed in place of the results.
+async fn chord_results(
+    backend: &dyn Backend,
+    group_id: &str,
+    propagate: bool,
+) -> Result<Option<Vec<Value>>, BackendError> {
+    let mut results = vec![];
+    for task_id in backend.get_group(group_id).await? {
+        let result = match backend.get_state(&task_id).await? {
+            TaskState::Success => match backend.get_result(&task_id).await? {
+                Some(result) => serde_json::from_str(&result)?,
+                None => Value::Null,
+            },
+            _ if propagate => return Ok(None),
+            _ => serde_json::to_value(backend.get_traceback(&task_id).await?)?,
+        };
+        results.push(result);
+    }
+    Ok(Some(results))
+}
+
 #[async_trait]
 impl<T> TracerTrait for Tracer<T>
 where
@@ -51,14 +249,25 @@ where
         if self.is_expired() {
             warn!(
                 "Task {}[{}] expired, discarding",
-                self.task.name(),
+                self.task.request().display_name(),
                 &self.task.request().id,
             );
+            if let Some(backend) = self.result_backend() {
+                if let Err(e) = backend
+                    .mark_as_revoked(&self.task.request().id, Utc::now())
+                    .await
+                {
+                    error!("Failed to save result: {}", e);
+                }
+            }
+            let err = TaskError::UnexpectedError("task revoked".into());
+            let callback = self.chord_task_done(Some(&err)).await;
+            self.triggered.extend(callback);
             return Err(TraceError::ExpirationError);
         }
 
-        if let Some(backend) = &self.backend {
-            if let Err(e) = backend.mark_as_started(&self.task.request().id).await {
+        if let Some(backend) = self.result_backend() {
+            if let Err(e) = backend.mark_as_started(&self.task.request().id, self.worker()).await {
                 error!("Failed to save result: {}", e);
             }
         }
@@ -76,34 +285,70 @@ where
             Some(secs) => {
                 debug!("Executing task with {} second time limit", secs);
                 let duration = Duration::from_secs(secs as u64);
-                time::timeout(duration, self.task.run(self.task.request().params.clone()))
+                time::timeout(duration, self.run())
                     .await
                     .unwrap_or(Err(TaskError::TimeoutError))
             }
-            None => self.task.run(self.task.request().params.clone()).await,
+            None => self.run().await,
         };
         let duration = start.elapsed();
         let finished = Utc::now();
 
+        // Aborted tasks are revoked whatever they returned once they stopped.
+        if self.task.request().is_aborted() {
+            warn!(
+                "Task {}[{}] aborted after {}s",
+                self.task.request().display_name(),
+                &self.task.request().id,
+                duration.as_secs_f32(),
+            );
+
+            if let Some(backend) = self.result_backend() {
+                if let Err(e) = backend
+                    .mark_as_revoked(&self.task.request().id, finished)
+                    .await
+                {
+                    error!("Failed to save result: {}", e);
+                }
+            }
+            let err = TaskError::UnexpectedError("task revoked".into());
+            let callback = self.chord_task_done(Some(&err)).await;
+            self.triggered.extend(callback);
+
+            self.event_tx
+                .send(TaskEvent::StatusChange(TaskState::Success))
+                .unwrap_or_else(|_| {
+                    error!("Failed sending task event");
+                });
+
+            return Err(TraceError::Aborted);
+        }
+
         match result {
             Ok(returned) => {
                 info!(
                     "Task {}[{}] succeeded in {}s: {:?}",
-                    self.task.name(),
+                    self.task.request().display_name(),
                     &self.task.request().id,
                     duration.as_secs_f32(),
                     returned
                 );
 
-                if let Some(backend) = &self.backend {
+                if let Some(backend) = self.result_backend() {
                     let returned_serialized = serde_json::to_string(&returned);
-                    if let Err(e) = backend.mark_as_done(&self.task.request().id, &returned_serialized.unwrap(), finished).await {
+                    if let Err(e) = backend.mark_as_done(&self.task.request().id, &returned_serialized.unwrap(), finished, self.worker()).await {
                         error!("Failed to save result: {}", e);
                     }
                 }
 
                 // Run success callback.
-                self.task.on_success(&returned).await;
+                let ctx = TaskContext::new(self.task.request(), duration);
+                self.task.on_success(&ctx, &returned).await;
+
+                let triggered = self.triggered_by_result(&returned);
+                self.triggered.extend(triggered);
+                let callback = self.chord_task_done(None).await;
+                self.triggered.extend(callback);
 
                 self.event_tx
                     .send(TaskEvent::StatusChange(TaskState::Success))
@@ -113,53 +358,135 @@ where
 
                 Ok(())
             }
+            // The message of a rejected task is sent back to its queue or rejected by the
+            // worker, and nothing is stored. Eagerly executed tasks have no message, so they
+            // fail instead.
+            Err(TaskError::Reject { requeue }) if !self.task.request().is_eager => {
+                info!(
+                    "Task {}[{}] rejected{}",
+                    self.task.request().display_name(),
+                    &self.task.request().id,
+                    if requeue { ", requeuing it" } else { "" }
+                );
+
+                self.event_tx
+                    .send(TaskEvent::StatusChange(TaskState::Success))
+                    .unwrap_or_else(|_| {
+                        error!("Failed sending task event");
+                    });
+
+                Err(TraceError::Rejected { requeue })
+            }
             Err(e) => {
+                let retry_options = match e {
+                    TaskError::Retry(_) => self.task.request().take_retry_options(),
+                    _ => None,
+                };
                 let (should_retry, retry_eta) = match e {
                     TaskError::ExpectedError(ref reason) => {
                         warn!(
                             "Task {}[{}] failed with expected error: {}",
-                            self.task.name(),
+                            self.task.request().display_name(),
                             &self.task.request().id,
                             reason
                         );
-                        (true, None)
+                        (self.task.should_retry(&e), None)
                     }
                     TaskError::UnexpectedError(ref reason) => {
                         error!(
                             "Task {}[{}] failed with unexpected error: {}",
-                            self.task.name(),
+                            self.task.request().display_name(),
                             &self.task.request().id,
                             reason
                         );
-                        (self.task.retry_for_unexpected(), None)
+                        (self.task.should_retry(&e), None)
                     }
                     TaskError::TimeoutError => {
                         error!(
                             "Task {}[{}] timed out after {}s",
-                            self.task.name(),
+                            self.task.request().display_name(),
                             &self.task.request().id,
                             duration.as_secs_f32(),
                         );
-                        (true, None)
+                        (self.task.should_retry(&e), None)
                     }
                     TaskError::Retry(eta) => {
                         error!(
                             "Task {}[{}] triggered retry",
-                            self.task.name(),
+                            self.task.request().display_name(),
                             &self.task.request().id,
                         );
                         (true, eta)
                     }
+                    TaskError::Reject { .. } => {
+                        error!(
+                            "Task {}[{}] rejected while executed eagerly",
+                            self.task.request().display_name(),
+                            &self.task.request().id,
+                        );
+                        (false, None)
+                    }
                 };
 
-                if let Some(backend) = &self.backend {
-                    if let Err(backend_err) = backend.mark_as_failure(&self.task.request().id, e.clone(), finished).await {
+                let retries = self.task.request().retries;
+                let max_retries = retry_options
+                    .as_ref()
+                    .and_then(|options| options.max_retries)
+                    .or_else(|| self.task.max_retries());
+                // Eagerly executed tasks fail instead of being retried, since there is no
+                // broker to send them back to.
+                let retrying = should_retry
+                    && !self.task.request().is_eager
+                    && !matches!(max_retries, Some(max) if retries >= max);
+                let retry_eta = if retrying {
+                    retry_eta.or_else(|| self.task.retry_eta())
+                } else {
+                    None
+                };
+                if let (true, Some(params)) =
+                    (retrying, retry_options.and_then(|options| options.params))
+                {
+                    match serde_json::to_value(params) {
+                        Ok(params) => self.retry_params = Some(params),
+                        Err(e) => error!("Failed to serialize the parameters of the retry: {}", e),
+                    }
+                }
+
+                if let Some(backend) = self.result_backend() {
+                    let stored = if retrying {
+                        backend.mark_as_retry(&self.task.request().id, e.clone(), retry_eta, self.worker()).await
+                    } else {
+                        backend.mark_as_failure(&self.task.request().id, e.clone(), finished, self.worker()).await
+                    };
+                    if let Err(backend_err) = stored {
                         error!("Failed to save result: {}", backend_err);
                     }
                 }
 
-                // Run failure callback.
-                self.task.on_failure(&e).await;
+                // Like Python Celery, the tasks of the chain which won't run fail as well.
+                if let (false, Some(backend)) = (retrying, &self.backend) {
+                    let chain = self.task.request().chain.iter().filter(|sig| !sig.ignore_result());
+                    for task_id in chain.filter_map(|sig| sig.task_id()) {
+                        if let Err(backend_err) = backend.mark_as_failure(task_id, e.clone(), finished, None).await {
+                            error!("Failed to save result: {}", backend_err);
+                        }
+                    }
+                }
+
+                if !retrying {
+                    let triggered = self.triggered_by_error(&e);
+                    self.triggered.extend(triggered);
+                    let callback = self.chord_task_done(Some(&e)).await;
+                    self.triggered.extend(callback);
+                }
+
+                // Run failure or retry callback.
+                let ctx = TaskContext::new(self.task.request(), duration);
+                if retrying {
+                    self.task.on_retry(&ctx, &e).await;
+                } else {
+                    self.task.on_failure(&ctx, &e).await;
+                }
 
                 self.event_tx
                     .send(TaskEvent::StatusChange(TaskState::Success))
@@ -171,36 +498,28 @@ where
                     return Err(TraceError::TaskError(e));
                 }
 
-                let retries = self.task.request().retries;
-                if let Some(max_retries) = self.task.max_retries() {
-                    if retries >= max_retries {
-                        warn!(
-                            "Task {}[{}] retries exceeded",
-                            self.task.name(),
-                            &self.task.request().id,
-                        );
-
-                        return Err(TraceError::TaskError(e));
-                    }
-                    info!(
-                        "Task {}[{}] retrying ({} / {})",
-                        self.task.name(),
-                        &self.task.request().id,
-                        retries + 1,
-                        max_retries,
-                    );
-                } else {
-                    info!(
-                        "Task {}[{}] retrying ({} / inf)",
-                        self.task.name(),
+                if !retrying {
+                    warn!(
+                        "Task {}[{}] retries exceeded",
+                        self.task.request().display_name(),
                         &self.task.request().id,
-                        retries + 1,
                     );
+
+                    return Err(TraceError::RetriesExceeded(e));
                 }
+                let delay = retry_eta
+                    .map(|eta| (eta - Utc::now()).num_milliseconds().max(0) as f32 / 1000.0)
+                    .unwrap_or(0.0);
+                info!(
+                    "Task {}[{}] retrying ({} / {}) in {:.1}s",
+                    self.task.request().display_name(),
+                    &self.task.request().id,
+                    retries + 1,
+                    max_retries.map_or_else(|| "inf".into(), |max| max.to_string()),
+                    delay,
+                );
 
-                Err(TraceError::Retry(
-                    retry_eta.or_else(|| self.task.retry_eta()),
-                ))
+                Err(TraceError::Retry(retry_eta))
             }
         }
     }
@@ -215,6 +534,10 @@ where
         self.task.request().is_delayed()
     }
 
+    fn countdown(&self) -> Option<Duration> {
+        self.task.request().countdown()
+    }
+
     fn is_expired(&self) -> bool {
         self.task.request().is_expired()
     }
@@ -222,6 +545,38 @@ where
     fn acks_late(&self) -> bool {
         self.task.acks_late()
     }
+
+    fn reject_on_worker_lost(&self) -> bool {
+        self.task.reject_on_worker_lost()
+    }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        self.task.rate_limit()
+    }
+
+    fn dedup_ttl(&self) -> Option<Duration> {
+        self.task.dedup_ttl()
+    }
+
+    fn ignore_result(&self) -> bool {
+        self.task.ignore_result()
+    }
+
+    fn is_blocking(&self) -> bool {
+        T::BLOCKING
+    }
+
+    fn abort_token(&self) -> CancellationToken {
+        self.task.request().abort_token().clone()
+    }
+
+    fn take_triggered(&mut self) -> Vec<(Message, Option<String>)> {
+        std::mem::take(&mut self.triggered)
+    }
+
+    fn take_retry_params(&mut self) -> Option<Value> {
+        self.retry_params.take()
+    }
 }
 
 #[async_trait]
@@ -235,15 +590,43 @@ pub(super) trait TracerTrait: Send + Sync {
 
     fn is_delayed(&self) -> bool;
 
+    /// How long until the task is due, if it has a future ETA.
+    fn countdown(&self) -> Option<Duration>;
+
     fn is_expired(&self) -> bool;
 
     fn acks_late(&self) -> bool;
+
+    fn reject_on_worker_lost(&self) -> bool;
+
+    fn rate_limit(&self) -> Option<RateLimit>;
+
+    /// How long the claim of the task lasts, if its duplicates are dropped.
+    fn dedup_ttl(&self) -> Option<Duration>;
+
+    /// Whether the result of the task isn't stored.
+    fn ignore_result(&self) -> bool;
+
+    /// Whether the task runs on the blocking threads of the runtime.
+    fn is_blocking(&self) -> bool;
+
+    /// The token cancelled to abort the task while it runs.
+    fn abort_token(&self) -> CancellationToken;
+
+    /// Take the messages of the tasks triggered by the task to send once it finished, like
+    /// the next task of its chain, along with the queues they are sent to if they aren't
+    /// routed by their names.
+    fn take_triggered(&mut self) -> Vec<(Message, Option<String>)>;
+
+    /// Take the parameters the task retries itself with, if it changed them (see
+    /// [`Task::retry`]).
+    fn take_retry_params(&mut self) -> Option<Value>;
 }
 
 pub(super) type TraceBuilderResult = Result<Box<dyn TracerTrait>, ProtocolError>;
 
 pub(super) type TraceBuilder<B> = Box<
-    dyn Fn(Message, TaskOptions, UnboundedSender<TaskEvent>, String, Option<Arc<B>>) -> TraceBuilderResult
+    dyn Fn(Message, TaskOptions, UnboundedSender<TaskEvent>, String, Option<String>, bool, Option<Arc<B>>) -> TraceBuilderResult
         + Send
         + Sync
         + 'static,
@@ -251,15 +634,32 @@ pub(super) type TraceBuilder<B> = Box<
 
 pub(super) fn build_tracer<T>(
     message: Message,
-    mut options: TaskOptions,
+    options: TaskOptions,
     event_tx: UnboundedSender<TaskEvent>,
     hostname: String,
+    queue: Option<String>,
+    is_eager: bool,
     backend: Option<Arc<dyn Backend>>
 ) -> TraceBuilderResult
     where T: Task + Send + 'static {
+    let task = build_task::<T>(message, options, hostname, queue, is_eager)?;
+    Ok(Box::new(Tracer::<T>::new(task, event_tx, backend)))
+}
+
+/// Build a task from its message, for a worker with the given app-level options.
+pub(super) fn build_task<T>(
+    message: Message,
+    mut options: TaskOptions,
+    hostname: String,
+    queue: Option<String>,
+    is_eager: bool,
+) -> Result<T, ProtocolError>
+    where T: Task {
     // Build request object.
     let mut request = Request::<T>::try_from(message)?;
     request.hostname = Some(hostname);
+    request.queue = queue;
+    request.is_eager = is_eager;
 
     // Override app-level options with task-level options.
     T::DEFAULTS.override_other(&mut options);
@@ -268,7 +668,5 @@ pub(super) fn build_tracer<T>(
     // It seems redundant to construct a request just to use it to construct a task,
     // but the task keeps the request object so the task implementation can access
     // it.
-    let task = T::from_request(request, options);
-
-    Ok(Box::new(Tracer::<T>::new(task, event_tx, backend)))
+    Ok(T::from_request(request, options))
 }
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::logging::trace;
+use crate::task::TaskState;
+
+use super::{Backend, BackendError, GroupMetadata, ResultMetadata};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::watch;
+
+/// A backend keeping the results in memory, used to store the results of the tasks
+/// executed eagerly when the app has no backend.
+pub(crate) struct MemoryBackend {
+    results: Mutex<HashMap<String, ResultMetadata>>,
+    groups: Mutex<HashMap<String, GroupMetadata>>,
+    chord_counters: Mutex<HashMap<String, usize>>,
+    /// The deadlines of the claims of the tasks, by task ID and retries.
+    claims: Mutex<HashMap<(String, u32), Instant>>,
+    /// Notified whenever a result is updated, to wake up the tasks waiting for completion.
+    updated: watch::Sender<()>,
+}
+
+impl MemoryBackend {
+    pub(crate) fn new() -> Self {
+        Self {
+            results: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashMap::new()),
+            chord_counters: Mutex::new(HashMap::new()),
+            claims: Mutex::new(HashMap::new()),
+            updated: watch::channel(()).0,
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn store_result_inner(
+        &self,
+        task_id: &str,
+        metadata: Option<ResultMetadata>,
+    ) -> Result<(), BackendError> {
+        {
+            let mut results = self.results.lock().unwrap();
+            match metadata {
+                Some(metadata) => results.insert(task_id.into(), metadata),
+                None => results.remove(task_id),
+            };
+        }
+        self.updated.send_replace(());
+        Ok(())
+    }
+
+    async fn get_task_meta(&self, task_id: &str) -> Result<ResultMetadata, BackendError> {
+        self.results
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .cloned()
+            .ok_or_else(|| BackendError::DocumentNotFound(task_id.into()))
+    }
+
+    async fn wait_for_completion(&self, task_id: &str) -> Result<bool, BackendError> {
+        // Subscribe before checking the state so that no update is missed in between.
+        let mut updated = self.updated.subscribe();
+        loop {
+            match self.get_state(task_id).await? {
+                TaskState::Success => break Ok(true),
+                TaskState::Failure | TaskState::Revoked | TaskState::Ignored => break Ok(false),
+                TaskState::Pending | TaskState::Started | TaskState::Retry => {
+                    trace!("waiting for task: task {task_id} isn't finished yet");
+                }
+            }
+            // The sender lives as long as the backend, so this can't fail.
+            let _ = updated.changed().await;
+        }
+    }
+
+    async fn store_group_inner(
+        &self,
+        group_id: &str,
+        metadata: Option<GroupMetadata>,
+    ) -> Result<(), BackendError> {
+        let mut groups = self.groups.lock().unwrap();
+        match metadata {
+            Some(metadata) => groups.insert(group_id.into(), metadata),
+            None => groups.remove(group_id),
+        };
+        Ok(())
+    }
+
+    async fn get_group_meta(&self, group_id: &str) -> Result<GroupMetadata, BackendError> {
+        self.groups
+            .lock()
+            .unwrap()
+            .get(group_id)
+            .cloned()
+            .ok_or_else(|| BackendError::DocumentNotFound(group_id.into()))
+    }
+
+    async fn incr_chord_counter(&self, group_id: &str) -> Result<usize, BackendError> {
+        let mut counters = self.chord_counters.lock().unwrap();
+        let counter = counters.entry(group_id.into()).or_default();
+        *counter += 1;
+        Ok(*counter)
+    }
+
+    async fn cleanup(&self, before: DateTime<Utc>) -> Result<(), BackendError> {
+        self.results
+            .lock()
+            .unwrap()
+            .retain(|_, metadata| !metadata.done_before(before));
+        self.groups
+            .lock()
+            .unwrap()
+            .retain(|_, metadata| metadata.date_done >= before);
+        Ok(())
+    }
+
+    async fn claim_task(
+        &self,
+        task_id: &str,
+        retries: u32,
+        ttl: Duration,
+    ) -> Result<bool, BackendError> {
+        let now = Instant::now();
+        let mut claims = self.claims.lock().unwrap();
+        claims.retain(|_, deadline| *deadline > now);
+        let key = (task_id.to_string(), retries);
+        if claims.contains_key(&key) {
+            return Ok(false);
+        }
+        claims.insert(key, now + ttl);
+        Ok(true)
+    }
+
+    async fn release_task(&self, task_id: &str, retries: u32) -> Result<(), BackendError> {
+        self.claims
+            .lock()
+            .unwrap()
+            .remove(&(task_id.to_string(), retries));
+        Ok(())
+    }
+}
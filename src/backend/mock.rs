@@ -1,6 +1,7 @@
-use super::{Backend, BackendBuilder, BackendError, ResultMetadata};
+use super::{Backend, BackendBuilder, BackendError, GroupMetadata, ResultMetadata};
 
 use async_trait::async_trait;
+use std::time::Duration;
 pub(crate) struct MockBackend;
 pub(crate) struct MockBackendBuilder;
 
@@ -36,4 +37,28 @@ impl Backend for MockBackend {
     async fn wait_for_completion(&self, _task_id: &str) -> Result<bool, BackendError> {
         unimplemented!()
     }
+
+    async fn store_group_inner(
+        &self,
+        _: &str,
+        _: Option<GroupMetadata>,
+    ) -> Result<(), BackendError> {
+        unimplemented!()
+    }
+
+    async fn get_group_meta(&self, _: &str) -> Result<GroupMetadata, BackendError> {
+        unimplemented!()
+    }
+
+    async fn incr_chord_counter(&self, _: &str) -> Result<usize, BackendError> {
+        unimplemented!()
+    }
+
+    async fn claim_task(&self, _: &str, _: u32, _: Duration) -> Result<bool, BackendError> {
+        unimplemented!()
+    }
+
+    async fn release_task(&self, _: &str, _: u32) -> Result<(), BackendError> {
+        unimplemented!()
+    }
 }
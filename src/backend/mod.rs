@@ -1,13 +1,18 @@
 #[cfg(test)]
 pub(crate) mod mock;
 
+pub(crate) mod memory;
 pub(crate) mod redis;
 
+pub use self::redis::{RedisBackend, RedisBackendBuilder};
+
+use crate::metrics;
 use crate::task::TaskState;
 use crate::{error::BackendError, prelude::TaskError};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 /// A results [`Backend`] is used to store and retrive the results and status of the tasks.
 #[async_trait]
@@ -20,28 +25,37 @@ pub trait Backend: Send + Sync {
             result: None,
             traceback: None,
             date_done: None,
+            retry_eta: None,
+            worker: None,
         };
         self.store_result(task_id, metadata).await
     }
 
-    /// Mark task as started to trace
-    async fn mark_as_started(&self, task_id: &str) -> Result<(), BackendError> {
+    /// Mark task as started to trace by the `worker` with this node name
+    async fn mark_as_started(
+        &self,
+        task_id: &str,
+        worker: Option<&str>,
+    ) -> Result<(), BackendError> {
         let metadata = ResultMetadata {
             task_id: task_id.to_string(),
             status: TaskState::Started,
             result: None,
             traceback: None,
             date_done: None,
+            retry_eta: None,
+            worker: worker.map(String::from),
         };
         self.store_result(task_id, metadata).await
     }
 
-    /// Mark task as finished and save result
+    /// Mark task as finished by `worker` and save result
     async fn mark_as_done(
         &self,
         task_id: &str,
         result: &str,
         date_done: DateTime<Utc>,
+        worker: Option<&str>,
     ) -> Result<(), BackendError> {
         let metadata = ResultMetadata {
             task_id: task_id.to_string(),
@@ -49,16 +63,20 @@ pub trait Backend: Send + Sync {
             result: Some(result.to_string()),
             traceback: None,
             date_done: Some(date_done),
+            retry_eta: None,
+            worker: worker.map(String::from),
         };
         self.store_result(task_id, metadata).await
     }
 
-    /// Mark task as failure and save error
+    /// Mark task as failure and save error, along with the `worker` it failed on if it
+    /// was executed
     async fn mark_as_failure(
         &self,
         task_id: &str,
         traceback: TaskError,
         date_done: DateTime<Utc>,
+        worker: Option<&str>,
     ) -> Result<(), BackendError> {
         let metadata = ResultMetadata {
             task_id: task_id.to_string(),
@@ -66,6 +84,64 @@ pub trait Backend: Send + Sync {
             result: None,
             traceback: Some(traceback),
             date_done: Some(date_done),
+            retry_eta: None,
+            worker: worker.map(String::from),
+        };
+        self.store_result(task_id, metadata).await
+    }
+
+    /// Mark task as to be retried at `eta`, after failing with error on `worker`
+    async fn mark_as_retry(
+        &self,
+        task_id: &str,
+        traceback: TaskError,
+        eta: Option<DateTime<Utc>>,
+        worker: Option<&str>,
+    ) -> Result<(), BackendError> {
+        let metadata = ResultMetadata {
+            task_id: task_id.to_string(),
+            status: TaskState::Retry,
+            result: None,
+            traceback: Some(traceback),
+            date_done: None,
+            retry_eta: eta,
+            worker: worker.map(String::from),
+        };
+        self.store_result(task_id, metadata).await
+    }
+
+    /// Mark task as revoked, e.g. because it expired before it could be executed
+    async fn mark_as_revoked(
+        &self,
+        task_id: &str,
+        date_done: DateTime<Utc>,
+    ) -> Result<(), BackendError> {
+        let metadata = ResultMetadata {
+            task_id: task_id.to_string(),
+            status: TaskState::Revoked,
+            result: None,
+            traceback: None,
+            date_done: Some(date_done),
+            retry_eta: None,
+            worker: None,
+        };
+        self.store_result(task_id, metadata).await
+    }
+
+    /// Mark task as ignored, because a duplicate of it was dropped
+    async fn mark_as_ignored(
+        &self,
+        task_id: &str,
+        date_done: DateTime<Utc>,
+    ) -> Result<(), BackendError> {
+        let metadata = ResultMetadata {
+            task_id: task_id.to_string(),
+            status: TaskState::Ignored,
+            result: None,
+            traceback: None,
+            date_done: Some(date_done),
+            retry_eta: None,
+            worker: None,
         };
         self.store_result(task_id, metadata).await
     }
@@ -77,12 +153,16 @@ pub trait Backend: Send + Sync {
         metadata: ResultMetadata,
     ) -> Result<(), BackendError> {
         // TODO: Add retry
-        self.store_result_inner(task_id, Some(metadata)).await
+        metrics::time_backend(
+            "store_result",
+            self.store_result_inner(task_id, Some(metadata)),
+        )
+        .await
     }
 
     /// Forget task result
     async fn forget(&self, task_id: &str) -> Result<(), BackendError> {
-        self.store_result_inner(task_id, None).await
+        metrics::time_backend("forget", self.store_result_inner(task_id, None)).await
     }
 
     /// Update task state and result.
@@ -92,28 +172,104 @@ pub trait Backend: Send + Sync {
         metadata: Option<ResultMetadata>,
     ) -> Result<(), BackendError>;
 
-    /// Get task meta from backend.
+    /// Get task meta from backend, failing with [`BackendError::DocumentNotFound`] if
+    /// the backend has none for the task.
     async fn get_task_meta(&self, task_id: &str) -> Result<ResultMetadata, BackendError>;
 
-    /// Get current state of a given task.
+    /// Get current state of a given task. Like in Python Celery, the tasks the backend has
+    /// no metadata for are `Pending`, e.g. when they are polled right after being sent by
+    /// a producer which doesn't register them.
     async fn get_state(&self, task_id: &str) -> Result<TaskState, BackendError> {
-        Ok(self.get_task_meta(task_id).await?.status)
+        match metrics::time_backend("get_state", self.get_task_meta(task_id)).await {
+            Ok(meta) => Ok(meta.status),
+            Err(BackendError::DocumentNotFound(_)) => Ok(TaskState::Pending),
+            Err(e) => Err(e),
+        }
     }
 
     /// Get result of a given task.
     async fn get_result(&self, task_id: &str) -> Result<Option<String>, BackendError> {
-        Ok(self.get_task_meta(task_id).await?.result)
+        let meta = metrics::time_backend("get_result", self.get_task_meta(task_id)).await?;
+        Ok(meta.result)
     }
 
     /// Get result of a given task.
     async fn get_traceback(&self, task_id: &str) -> Result<Option<TaskError>, BackendError> {
         Ok(self.get_task_meta(task_id).await?.traceback)
     }
+
+    /// Get the time at which a given task is to be retried, while it is in the `Retry` state.
+    async fn get_retry_eta(&self, task_id: &str) -> Result<Option<DateTime<Utc>>, BackendError> {
+        Ok(self.get_task_meta(task_id).await?.retry_eta)
+    }
+
+    /// Get the node name of the worker which executed a given task, if it was executed.
+    async fn get_worker(&self, task_id: &str) -> Result<Option<String>, BackendError> {
+        Ok(self.get_task_meta(task_id).await?.worker)
+    }
     /// Watches the backend and blocks until the state of the task changes to a status (commonly Success)
-    async fn wait_for_completion(
+    async fn wait_for_completion(&self, task_id: &str) -> Result<bool, BackendError>;
+
+    /// Save the IDs of the tasks of a group
+    async fn save_group(&self, group_id: &str, task_ids: &[String]) -> Result<(), BackendError> {
+        let metadata = GroupMetadata {
+            group_id: group_id.to_string(),
+            task_ids: task_ids.to_vec(),
+            date_done: Utc::now(),
+        };
+        metrics::time_backend(
+            "save_group",
+            self.store_group_inner(group_id, Some(metadata)),
+        )
+        .await
+    }
+
+    /// Forget a group, without forgetting the results of its tasks
+    async fn forget_group(&self, group_id: &str) -> Result<(), BackendError> {
+        self.store_group_inner(group_id, None).await
+    }
+
+    /// Update the tasks of a group.
+    async fn store_group_inner(
+        &self,
+        group_id: &str,
+        metadata: Option<GroupMetadata>,
+    ) -> Result<(), BackendError>;
+
+    /// Get group meta from backend.
+    async fn get_group_meta(&self, group_id: &str) -> Result<GroupMetadata, BackendError>;
+
+    /// Get the IDs of the tasks of a given group, in order.
+    async fn get_group(&self, group_id: &str) -> Result<Vec<String>, BackendError> {
+        Ok(self.get_group_meta(group_id).await?.task_ids)
+    }
+
+    /// Count one more finished task in the chord of a group, and get the number of tasks
+    /// which finished so far. The counter must be incremented atomically, since the tasks
+    /// of a chord can finish on different workers at the same time.
+    async fn incr_chord_counter(&self, group_id: &str) -> Result<usize, BackendError>;
+
+    /// Claim a task before executing it, so that its duplicate deliveries are dropped (see
+    /// [`TaskOptions::dedup`](crate::task::TaskOptions::dedup)). Each retry of a task is
+    /// claimed on its own. Returns whether the task was claimed, i.e. whether it wasn't
+    /// already claimed, and the claim expires after `ttl`. The claim must be atomic, since
+    /// the duplicates of a task can be delivered to different workers at the same time.
+    async fn claim_task(
         &self,
         task_id: &str,
+        retries: u32,
+        ttl: Duration,
     ) -> Result<bool, BackendError>;
+
+    /// Release the claim of a task, so that it is executed when it is delivered again.
+    async fn release_task(&self, task_id: &str, retries: u32) -> Result<(), BackendError>;
+
+    /// Delete the results of the tasks and the groups which finished before the given date.
+    /// This is what the [`BACKEND_CLEANUP_TASK`](crate::BACKEND_CLEANUP_TASK) executes.
+    /// Backends which can't delete them return [`BackendError::CleanupNotSupported`].
+    async fn cleanup(&self, _before: DateTime<Utc>) -> Result<(), BackendError> {
+        Err(BackendError::CleanupNotSupported)
+    }
 }
 
 /// Metadata of the task stored in the storage used.
@@ -129,6 +285,37 @@ pub struct ResultMetadata {
     traceback: Option<TaskError>,
     /// Date of culmination of the task
     date_done: Option<DateTime<Utc>>,
+    /// Date at which the task is to be retried
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_eta: Option<DateTime<Utc>>,
+    /// Node name of the worker which executed the task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    worker: Option<String>,
+}
+
+impl ResultMetadata {
+    /// Whether the task finished before `before`.
+    fn done_before(&self, before: DateTime<Utc>) -> bool {
+        self.date_done.is_some_and(|date_done| date_done < before)
+    }
+}
+
+/// Metadata of a group stored in the storage used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMetadata {
+    /// Group's ID.
+    group_id: String,
+    /// IDs of the tasks of the group, in order.
+    task_ids: Vec<String>,
+    /// Date at which the group was saved
+    date_done: DateTime<Utc>,
+}
+
+/// A type of results [`Backend`] which can be named in the [`app!`](crate::app) macro, e.g.
+/// `backend = RedisBackend { url }`.
+pub trait BackendType: Backend {
+    /// The builder of the backend from its URL.
+    type Builder: BackendBuilder + 'static;
 }
 
 /// A [`BackendBuilder`] is used to create a type of results [`Backend`] with a custom configuration.
@@ -1,16 +1,34 @@
 use std::time::Duration;
 
+use crate::logging::trace;
 use crate::task::TaskState;
+use crate::urls::{expand_env_vars, redact_url};
 
-use super::{Backend, BackendBuilder, BackendError, ResultMetadata};
+use super::{Backend, BackendBuilder, BackendError, BackendType, GroupMetadata, ResultMetadata};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use redis::Client;
 use redis::AsyncCommands;
+use std::fmt;
 
+/// How long (in seconds) the counter of a chord is kept after its last update.
+const CHORD_COUNTER_EXPIRY: usize = 24 * 60 * 60;
+
+/// Builds a [`RedisBackend`] from a `redis://` URL.
 pub struct RedisBackendBuilder {
     backend_url: String,
 }
 
+impl fmt::Debug for RedisBackendBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisBackendBuilder")
+            .field("backend_url", &redact_url(&self.backend_url))
+            .finish()
+    }
+}
+
+/// A results [`Backend`] storing the results of the tasks in Redis, compatible with
+/// Python Celery's.
 pub struct RedisBackend(Client);
 
 #[async_trait]
@@ -24,11 +42,17 @@ impl BackendBuilder for RedisBackendBuilder {
 
     /// Create new `RedisBackend`.
     async fn build(self: Box<Self>) -> Result<Box<dyn Backend>, BackendError> {
-        let client = Client::open(self.backend_url.as_str())?;
+        let backend_url =
+            expand_env_vars(&self.backend_url).map_err(BackendError::UnsetUrlVariable)?;
+        let client = Client::open(backend_url.as_str())?;
         Ok(Box::new(RedisBackend(client)))
     }
 }
 
+impl BackendType for RedisBackend {
+    type Builder = RedisBackendBuilder;
+}
+
 #[async_trait]
 impl Backend for RedisBackend {
     async fn store_result_inner(
@@ -69,25 +93,149 @@ impl Backend for RedisBackend {
             let result: ResultMetadata = serde_json::from_str(result.as_str())?;
             match result.status {
                 TaskState::Pending => {
-                    log::trace!("waiting for task: task {task_id} is still pending");
+                    trace!("waiting for task: task {task_id} is still pending");
                 },
                 TaskState::Started => {
-                    log::trace!("waiting for task: task {task_id} is running");
+                    trace!("waiting for task: task {task_id} is running");
                 },
                 TaskState::Retry => {
-                    log::trace!("waiting for task: task {task_id} is going to be retried");
+                    trace!("waiting for task: task {task_id} is going to be retried");
                 },
                 TaskState::Failure => {
-                    log::trace!("waiting for task: task {task_id} returned an error");
+                    trace!("waiting for task: task {task_id} returned an error");
                     break Ok(false);
                 },
                 TaskState::Success => {
-                    log::trace!("waiting for task: task {task_id} finished successfully");
+                    trace!("waiting for task: task {task_id} finished successfully");
                     break Ok(true);
                 },
+                TaskState::Revoked => {
+                    trace!("waiting for task: task {task_id} was revoked");
+                    break Ok(false);
+                },
+                TaskState::Ignored => {
+                    trace!("waiting for task: task {task_id} was ignored");
+                    break Ok(false);
+                },
             }
 
             tokio::time::sleep(Duration::from_millis(200)).await;
         }
     }
+
+    async fn store_group_inner(
+        &self,
+        group_id: &str,
+        metadata: Option<GroupMetadata>,
+    ) -> Result<(), BackendError> {
+        let mut connection = self.0.get_async_connection().await?;
+        match metadata {
+            Some(metadata) => {
+                connection.set(format!("group:{group_id}"), serde_json::to_string(&metadata)?).await?;
+            }
+            None => {
+                connection.del(format!("group:{group_id}")).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_group_meta(&self, group_id: &str) -> Result<GroupMetadata, BackendError> {
+        let mut connection = self.0.get_async_connection().await?;
+        let key = format!("group:{group_id}");
+        if !connection.exists(&key).await? {
+            return Err(BackendError::DocumentNotFound(group_id.to_string()));
+        }
+        let meta: String = connection.get(&key).await?;
+        Ok(serde_json::from_str(&meta)?)
+    }
+
+    async fn incr_chord_counter(&self, group_id: &str) -> Result<usize, BackendError> {
+        let mut connection = self.0.get_async_connection().await?;
+        let key = format!("chord:{group_id}");
+        let count: usize = connection.incr(&key, 1).await?;
+        // The counter is only needed until the last task of the chord finishes.
+        connection
+            .expire::<_, ()>(&key, CHORD_COUNTER_EXPIRY)
+            .await?;
+        Ok(count)
+    }
+
+    async fn claim_task(
+        &self,
+        task_id: &str,
+        retries: u32,
+        ttl: Duration,
+    ) -> Result<bool, BackendError> {
+        let mut connection = self.0.get_async_connection().await?;
+        // `SET NX` only replies `OK` when the key didn't exist.
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(format!("claim:{task_id}:{retries}"))
+            .arg(1)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .query_async(&mut connection)
+            .await?;
+        Ok(claimed.is_some())
+    }
+
+    async fn release_task(&self, task_id: &str, retries: u32) -> Result<(), BackendError> {
+        let mut connection = self.0.get_async_connection().await?;
+        connection
+            .del::<_, ()>(format!("claim:{task_id}:{retries}"))
+            .await?;
+        Ok(())
+    }
+
+    async fn cleanup(&self, before: DateTime<Utc>) -> Result<(), BackendError> {
+        let mut connection = self.0.get_async_connection().await?;
+        let mut expired = vec![];
+        for key in scan_keys(&mut connection, "task:*").await? {
+            let meta: Option<String> = connection.get(&key).await?;
+            if let Some(meta) = meta {
+                let meta: ResultMetadata = serde_json::from_str(&meta)?;
+                if meta.done_before(before) {
+                    expired.push(key);
+                }
+            }
+        }
+        for key in scan_keys(&mut connection, "group:*").await? {
+            let meta: Option<String> = connection.get(&key).await?;
+            if let Some(meta) = meta {
+                let meta: GroupMetadata = serde_json::from_str(&meta)?;
+                if meta.date_done < before {
+                    expired.push(key);
+                }
+            }
+        }
+        if !expired.is_empty() {
+            connection.del::<_, ()>(expired).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the keys matching a pattern, without blocking the server like `KEYS` would.
+async fn scan_keys(
+    connection: &mut redis::aio::Connection,
+    pattern: &str,
+) -> Result<Vec<String>, BackendError> {
+    let mut keys = vec![];
+    let mut iter: redis::AsyncIter<String> = connection.scan_match(pattern).await?;
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_redacted() {
+        let builder = RedisBackendBuilder::new("redis://:secret@localhost:6379/");
+        assert!(!format!("{:?}", builder).contains("secret"));
+    }
 }
@@ -2,6 +2,7 @@
 use super::scheduled_task::ScheduledTask;
 use crate::error::BeatError;
 use std::collections::BinaryHeap;
+use std::time::SystemTime;
 
 /// A `SchedulerBackend` is in charge of keeping track of the internal state of the scheduler
 /// according to some source of truth, such as a database.
@@ -24,6 +25,19 @@ pub trait SchedulerBackend {
     /// This method will not be called if `should_sync` returns `false`.
     fn sync(&mut self, scheduled_tasks: &mut BinaryHeap<ScheduledTask>) -> Result<(), BeatError>;
 
+    /// Get the time of the last execution of the scheduled task `name` known to the
+    /// backend, for example persisted before the beat was restarted.
+    ///
+    /// This is called for each scheduled task when the beat starts, and the tasks which ran
+    /// before are rescheduled from their last run (see
+    /// [`ScheduledTask::restore_last_run_at`]), instead of running right away because of
+    /// [`ScheduleOptions::run_immediately`](super::ScheduleOptions::run_immediately).
+    /// By default, no last run is known.
+    #[allow(unused_variables)]
+    fn last_run_at(&self, name: &str) -> Option<SystemTime> {
+        None
+    }
+
     // Maybe we should consider some methods to inform the backend that a task has been executed.
     // Not sure about what Python does, but at least it keeps a counter with the number of executed tasks,
     // and the backend has access to that.
@@ -44,8 +58,10 @@ impl SchedulerBackend for LocalSchedulerBackend {
         false
     }
 
+    /// There is nothing to synchronize, but this can still be called when
+    /// a [reload](super::BeatHandle::reload) is requested.
     #[allow(unused_variables)]
     fn sync(&mut self, scheduled_tasks: &mut BinaryHeap<ScheduledTask>) -> Result<(), BeatError> {
-        unimplemented!()
+        Ok(())
     }
 }
@@ -0,0 +1,89 @@
+//! This module contains the definition of the events emitted by a [`Beat`](super::Beat).
+use crate::logging::debug;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+/// An event emitted by a [`Beat`](super::Beat) to the channel configured with
+/// [`BeatBuilder::event_channel`](super::BeatBuilder::event_channel).
+#[derive(Clone, Debug)]
+pub enum BeatEvent {
+    /// A scheduled task has been sent to the broker.
+    TaskSent {
+        name: String,
+        task_id: String,
+        queue: String,
+    },
+
+    /// A scheduled task could not be sent to the broker.
+    SendFailed { name: String, error: String },
+
+    /// The connection with the broker was lost and the beat is trying to re-establish it.
+    BrokerReconnecting,
+
+    /// The connection with the broker has been re-established.
+    BrokerReconnected,
+
+    /// The scheduler backend has synchronized the scheduled tasks.
+    Synced { scheduled_tasks: usize },
+}
+
+/// Sends [`BeatEvent`]s to an optional channel without ever blocking the beat loop.
+///
+/// Events which can't be delivered because the channel is full are dropped and counted.
+#[derive(Clone, Default)]
+pub(crate) struct EventEmitter {
+    sender: Option<Sender<BeatEvent>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventEmitter {
+    pub(crate) fn new(sender: Option<Sender<BeatEvent>>) -> Self {
+        Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn emit(&self, event: BeatEvent) {
+        if let Some(sender) = &self.sender {
+            match sender.try_send(event) {
+                Ok(_) => {}
+                Err(TrySendError::Full(event)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    debug!("Event channel is full, dropping event {:?}", event);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_emit_drops_when_full() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let emitter = EventEmitter::new(Some(tx));
+
+        emitter.emit(BeatEvent::BrokerReconnecting);
+        emitter.emit(BeatEvent::BrokerReconnected);
+        assert_eq!(1, emitter.dropped());
+        assert!(matches!(rx.try_recv(), Ok(BeatEvent::BrokerReconnecting)));
+
+        drop(rx);
+        emitter.emit(BeatEvent::BrokerReconnected);
+        assert_eq!(2, emitter.dropped());
+    }
+}
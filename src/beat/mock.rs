@@ -0,0 +1,22 @@
+use super::Schedule;
+use crate::error::ProtocolError;
+use crate::protocol::{Message, TryCreateMessage};
+use std::time::{Duration, SystemTime};
+
+/// A message factory for the scheduled tasks which are never sent.
+pub(crate) struct DummyMessageFactory;
+
+impl TryCreateMessage for DummyMessageFactory {
+    fn try_create_message(&self) -> Result<Message, ProtocolError> {
+        unimplemented!()
+    }
+}
+
+/// A schedule due an hour after the last run, or after now if there wasn't any.
+pub(crate) struct EveryHour;
+
+impl Schedule for EveryHour {
+    fn next_call_at(&self, last_run_at: Option<SystemTime>) -> Option<SystemTime> {
+        Some(last_run_at.unwrap_or_else(SystemTime::now) + Duration::from_secs(3600))
+    }
+}
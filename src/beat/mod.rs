@@ -22,23 +22,30 @@
 //! correspond to the different scheduler implementations in Python.
 
 use crate::broker::{
-    build_and_connect, configure_task_routes, AMQPBrokerBuilder, BrokerBuilder,
-    RedisBrokerBuilder,
+    broker_builder_from_url, build_and_connect, configure_task_routes, Backoff, Broker,
+    BrokerBuilder, ConnectionRetryPolicy, ExchangeKind, LazyBroker, PublishRetryPolicy,
+    QueueOptions,
 };
-use crate::routing::{self, Rule};
+use crate::logging::{debug, error, info};
+use crate::routing::{self, task_destination, Destination, Rule};
 use crate::{
     error::{BeatError, BrokerError},
-    protocol::MessageContentType,
-    task::{Signature, Task, TaskOptions},
+    protocol::{MessageContentType, MessageSigner, SerializedSignature, TryCreateMessage},
+    task::{SendOptions, Signature, Task, TaskOptions},
 };
-use log::{debug, error, info};
+use std::sync::Arc;
 use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::{mpsc::Sender, Notify};
 use tokio::time::{self, Duration};
-use url::Url;
 
 mod scheduler;
 pub use scheduler::Scheduler;
 
+mod event;
+pub use event::BeatEvent;
+use event::EventEmitter;
+
 mod backend;
 pub use backend::{LocalSchedulerBackend, SchedulerBackend};
 
@@ -46,19 +53,29 @@ mod schedule;
 pub use schedule::{CronSchedule, DeltaSchedule, Schedule};
 
 mod scheduled_task;
-pub use scheduled_task::ScheduledTask;
+pub use scheduled_task::{ScheduleOptions, ScheduledTask, ScheduledTaskInfo};
+
+#[cfg(test)]
+mod mock;
 
 struct Config {
     name: String,
     broker_builder: Box<dyn BrokerBuilder>,
     broker_connection_timeout: u32,
     broker_connection_retry: bool,
-    broker_connection_max_retries: u32,
-    broker_connection_retry_delay: u32,
+    broker_connection_retry_policy: ConnectionRetryPolicy,
+    lazy_connect: bool,
     default_queue: String,
-    task_routes: Vec<(String, String)>,
+    task_routes: Vec<(String, Destination)>,
     task_options: TaskOptions,
+    task_publish_retry: bool,
+    task_publish_retry_policy: PublishRetryPolicy,
     max_sleep_duration: Option<Duration>,
+    stagger_equal_intervals: bool,
+    event_channel: Option<Sender<BeatEvent>>,
+    message_signer: Option<MessageSigner>,
+    #[cfg(unix)]
+    reload_on_sighup: bool,
 }
 
 /// Used to create a [`Beat`] app with a custom configuration.
@@ -70,12 +87,12 @@ pub struct BeatBuilder<Sb: SchedulerBackend> {
 impl BeatBuilder<LocalSchedulerBackend> {
     /// Get a `BeatBuilder` for creating a `Beat` app with a default scheduler backend
     /// and a custom configuration.
+    ///
+    /// The `broker_url` can list several URLs separated by `;`, in which case the broker
+    /// connects to the first reachable one and fails over to the next ones when the
+    /// connection is lost (see [`FailoverBroker`](crate::broker::FailoverBroker)).
     pub fn with_default_scheduler_backend(name: &str, broker_url: &str) -> Self {
-        let broker_builder: Box<dyn BrokerBuilder> = match Url::parse(broker_url).unwrap().scheme() {
-            "amqp" => Box::new(AMQPBrokerBuilder::new(broker_url)),
-            "redis" => Box::new(RedisBrokerBuilder::new(broker_url)),
-            _ => panic!("Unsupported broker"),
-        };
+        let broker_builder = broker_builder_from_url(broker_url);
 
         Self {
             config: Config {
@@ -83,12 +100,19 @@ impl BeatBuilder<LocalSchedulerBackend> {
                 broker_builder,
                 broker_connection_timeout: 2,
                 broker_connection_retry: true,
-                broker_connection_max_retries: 5,
-                broker_connection_retry_delay: 5,
+                broker_connection_retry_policy: ConnectionRetryPolicy::default(),
+                lazy_connect: false,
                 default_queue: "celery".into(),
                 task_routes: vec![],
                 task_options: TaskOptions::default(),
+                task_publish_retry: true,
+                task_publish_retry_policy: PublishRetryPolicy::default(),
                 max_sleep_duration: None,
+                stagger_equal_intervals: false,
+                event_channel: None,
+                message_signer: None,
+                #[cfg(unix)]
+                reload_on_sighup: false,
             },
             scheduler_backend: LocalSchedulerBackend::new(),
         }
@@ -97,17 +121,15 @@ impl BeatBuilder<LocalSchedulerBackend> {
 
 impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
     /// Get a `BeatBuilder` for creating a `Beat` app with a custom scheduler backend and
-    /// a custom configuration.
+    /// a custom configuration. See
+    /// [`with_default_scheduler_backend`](BeatBuilder::with_default_scheduler_backend)
+    /// for the format of `broker_url`.
     pub fn with_custom_scheduler_backend(
         name: &str,
         broker_url: &str,
         scheduler_backend: Sb,
     ) -> Self {
-        let broker_builder: Box<dyn BrokerBuilder> = match Url::parse(broker_url).unwrap().scheme() {
-            "amqp" => Box::new(AMQPBrokerBuilder::new(broker_url)),
-            "redis" => Box::new(RedisBrokerBuilder::new(broker_url)),
-            _ => panic!("Unsupported broker"),
-        };
+        let broker_builder = broker_builder_from_url(broker_url);
 
         Self {
             config: Config {
@@ -115,12 +137,19 @@ impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
                 broker_builder,
                 broker_connection_timeout: 2,
                 broker_connection_retry: true,
-                broker_connection_max_retries: 5,
-                broker_connection_retry_delay: 5,
+                broker_connection_retry_policy: ConnectionRetryPolicy::default(),
+                lazy_connect: false,
                 default_queue: "celery".into(),
                 task_routes: vec![],
                 task_options: TaskOptions::default(),
+                task_publish_retry: true,
+                task_publish_retry_policy: PublishRetryPolicy::default(),
                 max_sleep_duration: None,
+                stagger_equal_intervals: false,
+                event_channel: None,
+                message_signer: None,
+                #[cfg(unix)]
+                reload_on_sighup: false,
             },
             scheduler_backend,
         }
@@ -138,9 +167,116 @@ impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
         self
     }
 
+    /// Set whether the broker should wait for the server to confirm each scheduled task
+    /// message. This is only supported by the AMQP broker.
+    ///
+    /// Tasks whose message isn't confirmed are kept in the schedule and sent again
+    /// on the next tick.
+    pub fn broker_publisher_confirms(mut self, enabled: bool) -> Self {
+        self.config.broker_builder = self.config.broker_builder.publisher_confirms(enabled);
+        self
+    }
+
+    /// Set a timeout in seconds before giving up waiting for a publisher confirm. The task
+    /// is then sent again according to the
+    /// [`task_publish_retry_policy`](BeatBuilder::task_publish_retry_policy).
+    pub fn broker_confirm_timeout(mut self, timeout: u32) -> Self {
+        self.config.broker_builder = self.config.broker_builder.confirm_timeout(timeout);
+        self
+    }
+
     /// Add a routing rule.
     pub fn task_route(mut self, pattern: &str, queue: &str) -> Self {
-        self.config.task_routes.push((pattern.into(), queue.into()));
+        self.config
+            .task_routes
+            .push((pattern.into(), Destination::Queue(queue.into())));
+        self
+    }
+
+    /// Add a routing rule which publishes the matching tasks to an exchange with the given
+    /// routing key.
+    pub fn task_route_to_exchange(
+        mut self,
+        pattern: &str,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Self {
+        self.config.task_routes.push((
+            pattern.into(),
+            Destination::Exchange {
+                exchange: exchange.into(),
+                routing_key: routing_key.into(),
+            },
+        ));
+        self
+    }
+
+    /// Declare an exchange of the given kind.
+    pub fn declare_exchange(mut self, name: &str, kind: ExchangeKind, durable: bool) -> Self {
+        self.config.broker_builder = self
+            .config
+            .broker_builder
+            .declare_exchange(name, kind, durable);
+        self
+    }
+
+    /// Bind a queue to an exchange with a binding key, declaring the queue if needed.
+    pub fn bind_queue(mut self, queue: &str, exchange: &str, binding_key: &str) -> Self {
+        self.config.broker_builder =
+            self.config
+                .broker_builder
+                .bind_queue(queue, exchange, binding_key);
+        self
+    }
+
+    /// Declare several exchanges given as `(name, kind, durable)`, like
+    /// [`declare_exchange`](BeatBuilder::declare_exchange) does. This allows declaring them with the
+    /// `declare_exchanges` argument of the macro building the beat.
+    pub fn declare_exchanges<'a>(
+        mut self,
+        exchanges: impl IntoIterator<Item = (&'a str, ExchangeKind, bool)>,
+    ) -> Self {
+        for (name, kind, durable) in exchanges {
+            self = self.declare_exchange(name, kind, durable);
+        }
+        self
+    }
+
+    /// Bind several queues given as `(queue, exchange, binding_key)`, like
+    /// [`bind_queue`](BeatBuilder::bind_queue) does. This allows binding them with the `bind_queues`
+    /// argument of the macro building the beat.
+    pub fn bind_queues<'a>(
+        mut self,
+        bindings: impl IntoIterator<Item = (&'a str, &'a str, &'a str)>,
+    ) -> Self {
+        for (queue, exchange, binding_key) in bindings {
+            self = self.bind_queue(queue, exchange, binding_key);
+        }
+        self
+    }
+
+    /// Declare a broadcast queue, so that the tasks routed to it are delivered to every
+    /// worker consuming from it (see
+    /// [`CeleryBuilder::broadcast_queue`](crate::CeleryBuilder::broadcast_queue)).
+    pub fn broadcast_queue(mut self, queue: &str) -> Self {
+        self.config.broker_builder = self.config.broker_builder.declare_broadcast_queue(queue);
+        self
+    }
+
+    /// Declare a queue with custom options, such as a message TTL or a maximum length.
+    /// This can be used for the default queue as well as for the queues of routing rules.
+    pub fn queue_options(mut self, queue: &str, options: QueueOptions) -> Self {
+        self.config.broker_builder = self
+            .config
+            .broker_builder
+            .declare_queue_with_options(queue, options);
+        self
+    }
+
+    /// Set the options of the queues declared without explicit
+    /// [`queue_options`](BeatBuilder::queue_options).
+    pub fn default_queue_options(mut self, options: QueueOptions) -> Self {
+        self.config.broker_builder = self.config.broker_builder.default_queue_options(options);
         self
     }
 
@@ -159,13 +295,39 @@ impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
     /// Set the maximum number of retries before we give up trying to re-establish connection
     /// to the AMQP broker.
     pub fn broker_connection_max_retries(mut self, max_retries: u32) -> Self {
-        self.config.broker_connection_max_retries = max_retries;
+        self.config.broker_connection_retry_policy.max_retries = Some(max_retries);
         self
     }
 
-    /// Set the number of seconds to wait before re-trying the connection with the broker.
+    /// Set a fixed number of seconds to wait before re-trying the connection with the broker,
+    /// instead of backing off exponentially. The maximum number of retries and time spent
+    /// retrying are kept.
     pub fn broker_connection_retry_delay(mut self, retry_delay: u32) -> Self {
-        self.config.broker_connection_retry_delay = retry_delay;
+        let policy = &mut self.config.broker_connection_retry_policy;
+        *policy = ConnectionRetryPolicy {
+            max_retries: policy.max_retries,
+            max_elapsed: policy.max_elapsed,
+            ..ConnectionRetryPolicy::fixed(Duration::from_secs(retry_delay as u64))
+        };
+        self
+    }
+
+    /// Set how the attempts to establish or re-establish the connection with the broker are
+    /// spaced out, and when to give up. By default the delay starts at 2 seconds and doubles
+    /// up to a minute, with full jitter, for at most 5 retries.
+    pub fn broker_connection_retry_policy(mut self, policy: ConnectionRetryPolicy) -> Self {
+        self.config.broker_connection_retry_policy = policy;
+        self
+    }
+
+    /// Set whether the connection with the broker should only be established when it is
+    /// first needed, e.g. to send a task, instead of when building the app. The connection
+    /// is then retried at that point like it would have been here.
+    ///
+    /// This makes building the app faster and lets it succeed while the broker is down,
+    /// which suits short-lived processes that may not even use the broker.
+    pub fn lazy_connect(mut self, lazy_connect: bool) -> Self {
+        self.config.lazy_connect = lazy_connect;
         self
     }
 
@@ -175,6 +337,28 @@ impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
         self
     }
 
+    /// Set whether a scheduled task which failed to be sent because of an error which
+    /// could go away on its own, e.g. a lost connection or a publisher confirm which timed
+    /// out, is sent again right away. Defaults to `true`.
+    ///
+    /// Tasks which still couldn't be sent are kept in the schedule and sent again once the
+    /// connection with the broker is re-established.
+    pub fn task_publish_retry(mut self, retry: bool) -> Self {
+        self.config.task_publish_retry = retry;
+        self
+    }
+
+    /// Set how many times and how often a scheduled task which failed to be sent is sent
+    /// again. By default it is sent again at most 3 times, 200 milliseconds after the first
+    /// failure and then backing off exponentially up to a second.
+    ///
+    /// When a publisher confirm times out the task may have been delivered anyway, so that
+    /// sending it again delivers it twice. Both messages have the same task ID.
+    pub fn task_publish_retry_policy(mut self, policy: PublishRetryPolicy) -> Self {
+        self.config.task_publish_retry_policy = policy;
+        self
+    }
+
     /// Set a maximum sleep duration, which limits the amount of time that
     /// can pass between ticks. This is useful to ensure that the scheduler backend
     /// implementation is called regularly.
@@ -183,6 +367,46 @@ impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
         self
     }
 
+    /// Spread out the first execution of tasks whose schedule has a fixed interval
+    /// (such as [`DeltaSchedule`]), so that tasks registered with the same interval
+    /// don't all fire at the same time.
+    ///
+    /// The offset of each task is derived deterministically from the name of its schedule
+    /// entry and is smaller than the interval. The entries sharing a name, such as the ones
+    /// scheduled with [`Beat::schedule_task`] for the same task, are also told apart by the
+    /// order they were scheduled in. It can be overridden per task with
+    /// [`ScheduleOptions::initial_offset`], and it is reported by [`Beat::dump_schedule`].
+    pub fn stagger_equal_intervals(mut self, stagger: bool) -> Self {
+        self.config.stagger_equal_intervals = stagger;
+        self
+    }
+
+    /// Set a channel where the beat will emit [`BeatEvent`]s, for example to surface
+    /// its activity in an admin interface.
+    ///
+    /// Events are sent with [`try_send`](Sender::try_send), so a slow receiver never
+    /// blocks the beat: when the channel is full events are dropped, and the number of
+    /// dropped events can be checked with [`Beat::dropped_events`].
+    pub fn event_channel(mut self, sender: Sender<BeatEvent>) -> Self {
+        self.config.event_channel = Some(sender);
+        self
+    }
+
+    /// Sign the messages of the scheduled tasks, for workers which only execute signed
+    /// tasks (see [`CeleryBuilder::message_signer`](crate::CeleryBuilder::message_signer)).
+    pub fn message_signer(mut self, signer: MessageSigner) -> Self {
+        self.config.message_signer = Some(signer);
+        self
+    }
+
+    /// Reload the scheduler backend when the process receives a `SIGHUP` signal,
+    /// like [`BeatHandle::reload`] does.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(mut self, reload_on_sighup: bool) -> Self {
+        self.config.reload_on_sighup = reload_on_sighup;
+        self
+    }
+
     /// Construct a `Beat` app with the current configuration.
     pub async fn build(self) -> Result<Beat<Sb>, BeatError> {
         // Declare default queue to broker.
@@ -194,19 +418,40 @@ impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
         let (broker_builder, task_routes) =
             configure_task_routes(broker_builder, &self.config.task_routes)?;
 
-        let broker = build_and_connect(
-            broker_builder,
-            self.config.broker_connection_timeout,
-            if self.config.broker_connection_retry {
-                self.config.broker_connection_max_retries
-            } else {
-                0
-            },
-            self.config.broker_connection_retry_delay,
-        )
-        .await?;
+        let retry_policy = if self.config.broker_connection_retry {
+            self.config.broker_connection_retry_policy.clone()
+        } else {
+            ConnectionRetryPolicy {
+                max_retries: Some(0),
+                ..self.config.broker_connection_retry_policy.clone()
+            }
+        };
+        let broker: Box<dyn Broker> = if self.config.lazy_connect {
+            Box::new(LazyBroker::new(
+                broker_builder,
+                self.config.broker_connection_timeout,
+                retry_policy,
+            ))
+        } else {
+            build_and_connect(
+                &*broker_builder,
+                self.config.broker_connection_timeout,
+                &retry_policy,
+            )
+            .await?
+        };
 
-        let scheduler = Scheduler::new(broker);
+        let events = EventEmitter::new(self.config.event_channel);
+        let mut scheduler = Scheduler::new(broker);
+        scheduler.set_event_emitter(events.clone());
+        scheduler.set_publish_retry_policy(if self.config.task_publish_retry {
+            self.config.task_publish_retry_policy
+        } else {
+            PublishRetryPolicy::disabled()
+        });
+        if let Some(signer) = self.config.message_signer {
+            scheduler.set_message_signer(signer);
+        }
 
         Ok(Beat {
             name: self.config.name,
@@ -217,9 +462,13 @@ impl<Sb: SchedulerBackend> BeatBuilder<Sb> {
             task_options: self.config.task_options,
             broker_connection_timeout: self.config.broker_connection_timeout,
             broker_connection_retry: self.config.broker_connection_retry,
-            broker_connection_max_retries: self.config.broker_connection_max_retries,
-            broker_connection_retry_delay: self.config.broker_connection_retry_delay,
+            broker_connection_retry_policy: self.config.broker_connection_retry_policy,
             max_sleep_duration: self.config.max_sleep_duration,
+            stagger_equal_intervals: self.config.stagger_equal_intervals,
+            events,
+            reload: Arc::new(Notify::new()),
+            #[cfg(unix)]
+            reload_on_sighup: self.config.reload_on_sighup,
         })
     }
 }
@@ -240,10 +489,28 @@ pub struct Beat<Sb: SchedulerBackend> {
 
     broker_connection_timeout: u32,
     broker_connection_retry: bool,
-    broker_connection_max_retries: u32,
-    broker_connection_retry_delay: u32,
+    broker_connection_retry_policy: ConnectionRetryPolicy,
 
     max_sleep_duration: Option<Duration>,
+    stagger_equal_intervals: bool,
+    events: EventEmitter,
+    reload: Arc<Notify>,
+    #[cfg(unix)]
+    reload_on_sighup: bool,
+}
+
+/// A handle to a [`Beat`] app, which can be used to control it while it is running.
+#[derive(Clone)]
+pub struct BeatHandle {
+    reload: Arc<Notify>,
+}
+
+impl BeatHandle {
+    /// Synchronize the scheduler backend as soon as possible, without waiting for
+    /// [`should_sync`](SchedulerBackend::should_sync) or for the current sleep to end.
+    pub fn reload(&self) {
+        self.reload.notify_one();
+    }
 }
 
 impl Beat<LocalSchedulerBackend> {
@@ -273,32 +540,174 @@ where
         self.schedule_named_task(Signature::<T>::task_name().to_string(), signature, schedule);
     }
 
+    /// Schedule the execution of a task with the given [`ScheduleOptions`].
+    pub fn schedule_task_with_options<T, S>(
+        &mut self,
+        signature: Signature<T>,
+        schedule: S,
+        options: ScheduleOptions,
+    ) where
+        T: Task + Clone + 'static,
+        S: Schedule + 'static,
+    {
+        self.schedule_named_task_with_options(
+            Signature::<T>::task_name().to_string(),
+            signature,
+            schedule,
+            options,
+        );
+    }
+
     /// Schedule the execution of a task with the given `name`.
-    pub fn schedule_named_task<T, S>(
+    pub fn schedule_named_task<T, S>(&mut self, name: String, signature: Signature<T>, schedule: S)
+    where
+        T: Task + Clone + 'static,
+        S: Schedule + 'static,
+    {
+        self.schedule_named_task_with_options(
+            name,
+            signature,
+            schedule,
+            ScheduleOptions::default(),
+        );
+    }
+
+    /// Schedule the execution of a task with the given `name` and [`ScheduleOptions`].
+    pub fn schedule_named_task_with_options<T, S>(
         &mut self,
         name: String,
         mut signature: Signature<T>,
         schedule: S,
+        options: ScheduleOptions,
     ) where
         T: Task + Clone + 'static,
         S: Schedule + 'static,
     {
         signature.options.update(&self.task_options);
-        let queue = match &signature.queue {
-            Some(queue) => queue.to_string(),
-            None => routing::route(T::NAME, &self.task_routes)
-                .unwrap_or(&self.default_queue)
-                .to_string(),
+        let destination = match &signature.queue {
+            Some(queue) => Destination::Queue(queue.to_string()),
+            None => task_destination::<T>().unwrap_or_else(|| self.route(T::NAME)),
         };
-        let message_factory = Box::new(signature);
+        self.schedule_message_factory(name, Box::new(signature), destination, schedule, options);
+    }
 
-        self.scheduler
-            .schedule_task(name, message_factory, queue, schedule);
+    /// Schedule the execution of a task by name, whose type isn't known, like
+    /// [`Celery::send_task_by_name`](crate::Celery::send_task_by_name) sends it. The task
+    /// is scheduled with the name of the task.
+    pub fn schedule_task_by_name<S>(
+        &mut self,
+        name: &str,
+        args: serde_json::Value,
+        kwargs: serde_json::Value,
+        mut options: SendOptions,
+        schedule: S,
+    ) -> Result<(), BeatError>
+    where
+        S: Schedule + 'static,
+    {
+        options.options.update(&self.task_options);
+        let destination = match options.queue.take() {
+            Some(queue) => Destination::Queue(queue),
+            None => self.route(name),
+        };
+        let signature = SerializedSignature::by_name(name, args, kwargs, &options)?;
+        // Fail early rather than every time the task is due.
+        signature.try_create_message()?;
+        self.schedule_message_factory(
+            name.into(),
+            Box::new(signature),
+            destination,
+            schedule,
+            ScheduleOptions::default(),
+        );
+        Ok(())
+    }
+
+    /// Get the destination of a task from the task routes, or the default queue.
+    fn route(&self, task_name: &str) -> Destination {
+        routing::route(task_name, &self.task_routes)
+            .cloned()
+            .unwrap_or_else(|| Destination::Queue(self.default_queue.clone()))
+    }
+
+    /// Schedule the messages created by a factory, staggering them if needed.
+    fn schedule_message_factory<S>(
+        &mut self,
+        name: String,
+        message_factory: Box<dyn TryCreateMessage>,
+        destination: Destination,
+        schedule: S,
+        mut options: ScheduleOptions,
+    ) where
+        S: Schedule + 'static,
+    {
+        if self.stagger_equal_intervals && options.initial_offset.is_none() {
+            // The entries sharing a name, like the ones scheduled with `schedule_task` for
+            // the same task, are told apart by the order they were scheduled in.
+            let stagger_key = match self.scheduler.count_scheduled(&name) {
+                0 => name.clone(),
+                n => format!("{}#{}", name, n),
+            };
+            options.initial_offset = schedule
+                .interval()
+                .map(|interval| scheduler::stagger_offset(&stagger_key, interval));
+        }
+
+        self.scheduler.schedule_task_to_destination(
+            name,
+            message_factory,
+            destination,
+            schedule,
+            options,
+        );
+    }
+
+    /// Get a snapshot of all scheduled tasks, ordered by the time of their next execution.
+    pub fn dump_schedule(&self) -> Vec<ScheduledTaskInfo> {
+        self.scheduler.dump_schedule()
+    }
+
+    /// Get the number of [`BeatEvent`]s which have been dropped because the
+    /// [event channel](BeatBuilder::event_channel) was full or closed.
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
+    /// Get a [`BeatHandle`] which can be used to control the beat while it is running.
+    pub fn handle(&self) -> BeatHandle {
+        BeatHandle {
+            reload: self.reload.clone(),
+        }
+    }
+
+    /// Establish the connection with the broker if it isn't yet, because of
+    /// [`lazy_connect`](BeatBuilder::lazy_connect), or check that it is still alive and
+    /// re-establish it otherwise.
+    pub async fn connect(&self) -> Result<(), BeatError> {
+        Ok(self
+            .scheduler
+            .broker
+            .reconnect(self.broker_connection_timeout)
+            .await?)
     }
 
     /// Start the *beat*.
+    ///
+    /// The tasks whose last run is known to the scheduler backend (see
+    /// [`SchedulerBackend::last_run_at`]) are rescheduled from it first.
     pub async fn start(&mut self) -> Result<(), BeatError> {
         info!("Starting beat service");
+        let scheduler_backend = &self.scheduler_backend;
+        self.scheduler
+            .restore_last_runs(|name| scheduler_backend.last_run_at(name));
+
+        #[cfg(unix)]
+        let _sighup_listener = if self.reload_on_sighup {
+            Some(SighupListener::spawn(self.handle())?)
+        } else {
+            None
+        };
+
         loop {
             let result = self.beat_loop().await;
             if !self.broker_connection_retry {
@@ -308,8 +717,8 @@ where
             if let Err(err) = result {
                 match err {
                     BeatError::BrokerError(broker_err) => {
-                        if broker_err.is_connection_error() {
-                            error!("Broker connection failed");
+                        if broker_err.is_retryable() {
+                            error!("Broker connection failed: {}", broker_err);
                         } else {
                             return Err(BeatError::BrokerError(broker_err));
                         }
@@ -320,13 +729,16 @@ where
                 return result;
             }
 
+            self.events.emit(BeatEvent::BrokerReconnecting);
             let mut reconnect_successful: bool = false;
-            for _ in 0..self.broker_connection_max_retries {
-                info!("Trying to re-establish connection with broker");
-                time::sleep(Duration::from_secs(
-                    self.broker_connection_retry_delay as u64,
-                ))
-                .await;
+            let mut backoff = Backoff::new(&self.broker_connection_retry_policy);
+            while let Some(delay) = backoff.next_delay() {
+                info!(
+                    "Trying to re-establish connection with broker in {:.1?} (retry {})",
+                    delay,
+                    backoff.retries()
+                );
+                time::sleep(delay).await;
 
                 match self
                     .scheduler
@@ -342,6 +754,7 @@ where
                     }
                     Ok(_) => {
                         info!("Successfully reconnected with broker");
+                        self.events.emit(BeatEvent::BrokerReconnected);
                         reconnect_successful = true;
                         break;
                     }
@@ -359,8 +772,7 @@ where
             let next_tick_at = self.scheduler.tick().await?;
 
             if self.scheduler_backend.should_sync() {
-                self.scheduler_backend
-                    .sync(self.scheduler.get_scheduled_tasks())?;
+                self.sync()?;
             }
 
             let now = SystemTime::now();
@@ -373,11 +785,56 @@ where
                     None => sleep_interval,
                 };
                 debug!("Now sleeping for {:?}", sleep_interval);
-                time::sleep(sleep_interval).await;
+                select! {
+                    _ = time::sleep(sleep_interval) => {},
+                    _ = self.reload.notified() => {
+                        // Scheduled tasks may have changed, so the next tick
+                        // happens right after syncing.
+                        info!("Reloading scheduler backend");
+                        self.sync()?;
+                    }
+                }
             }
         }
     }
+
+    fn sync(&mut self) -> Result<(), BeatError> {
+        let scheduled_tasks = self.scheduler.get_scheduled_tasks();
+        self.scheduler_backend.sync(scheduled_tasks)?;
+        self.events.emit(BeatEvent::Synced {
+            scheduled_tasks: scheduled_tasks.len(),
+        });
+        Ok(())
+    }
 }
+
+/// Listens for `SIGHUP` signals and reloads the beat when one is received.
+/// The listener is stopped when this is dropped.
+#[cfg(unix)]
+struct SighupListener(tokio::task::JoinHandle<()>);
+
+#[cfg(unix)]
+impl SighupListener {
+    fn spawn(handle: BeatHandle) -> Result<Self, BeatError> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = signal(SignalKind::hangup())?;
+        Ok(Self(tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP");
+                handle.reload();
+            }
+        })))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SighupListener {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 /* 
 #[cfg(test)]
 mod tests; */
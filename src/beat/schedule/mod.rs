@@ -15,6 +15,13 @@ pub trait Schedule {
     /// never run again and it is safe to remove it from the
     /// list of scheduled tasks.
     fn next_call_at(&self, last_run_at: Option<SystemTime>) -> Option<SystemTime>;
+
+    /// The fixed interval between subsequent executions, if the schedule has one.
+    /// This is used to stagger tasks which share the same interval (see
+    /// [`BeatBuilder::stagger_equal_intervals`](crate::beat::BeatBuilder::stagger_equal_intervals)).
+    fn interval(&self) -> Option<Duration> {
+        None
+    }
 }
 
 /// A schedule that can be used to execute tasks at regular intervals.
@@ -42,4 +49,8 @@ impl Schedule for DeltaSchedule {
             None => Some(SystemTime::now()),
         }
     }
+
+    fn interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
 }
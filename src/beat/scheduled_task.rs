@@ -1,17 +1,62 @@
 use super::Schedule;
 use crate::protocol::TryCreateMessage;
-use std::{cmp::Ordering, time::SystemTime};
+use std::{
+    cmp::Ordering,
+    time::{Duration, SystemTime},
+};
+
+/// Options which can be set for a single scheduled task (what Python calls a *schedule entry*).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScheduleOptions {
+    /// If `true`, the task is considered due on the first tick after it has been scheduled,
+    /// regardless of what its schedule says. After this first run the schedule proceeds normally.
+    ///
+    /// This is useful, for example, for cache-warming tasks which should run once at startup.
+    /// Note that a `last_run_at` restored through
+    /// [`restore_last_run_at`](ScheduledTask::restore_last_run_at), like the beat does with
+    /// the [last runs](super::SchedulerBackend::last_run_at) known to its backend when it
+    /// starts, takes precedence over this option, so that a restart does not trigger a task
+    /// which ran recently.
+    pub run_immediately: bool,
+
+    /// An offset added to the first `next_call_at` computed from the schedule, which can be
+    /// used to spread out tasks which share the same schedule. Subsequent runs are computed
+    /// from the actual last run, so the task keeps its own steady cadence.
+    ///
+    /// If this is `None` and [`stagger_equal_intervals`](super::BeatBuilder::stagger_equal_intervals)
+    /// is enabled, the offset is derived from the name of the schedule entry.
+    /// This option has no effect if `run_immediately` is set.
+    pub initial_offset: Option<Duration>,
+}
+
+/// A snapshot of the state of a [`ScheduledTask`], as returned by
+/// [`Scheduler::dump_schedule`](super::Scheduler::dump_schedule). Mostly useful for debugging.
+#[derive(Clone, Debug)]
+pub struct ScheduledTaskInfo {
+    pub name: String,
+    pub queue: String,
+    pub exchange: Option<String>,
+    pub total_run_count: u32,
+    pub last_run_at: Option<SystemTime>,
+    pub next_call_at: SystemTime,
+    pub initial_offset: Duration,
+}
 
 /// A task which is scheduled for execution. It contains the task to execute,
 /// the queue where to send it and the schedule which determines when to do it.
 pub struct ScheduledTask {
     pub name: String,
     pub message_factory: Box<dyn TryCreateMessage>,
+    /// The queue to send the task to, or the routing key if it is sent to an `exchange`.
     pub queue: String,
+    /// The exchange to publish the task to, if it was routed to one.
+    pub exchange: Option<String>,
     pub schedule: Box<dyn Schedule>,
     pub total_run_count: u32,
     pub last_run_at: Option<SystemTime>,
     pub next_call_at: SystemTime,
+    /// The offset which was applied to the first `next_call_at` of the task.
+    pub initial_offset: Duration,
 }
 
 impl ScheduledTask {
@@ -30,10 +75,25 @@ impl ScheduledTask {
             name,
             message_factory,
             queue,
+            exchange: None,
             schedule: Box::new(schedule),
             total_run_count: 0,
             last_run_at: None,
             next_call_at,
+            initial_offset: Duration::ZERO,
+        }
+    }
+
+    /// Get a snapshot of the current state of the task.
+    pub fn info(&self) -> ScheduledTaskInfo {
+        ScheduledTaskInfo {
+            name: self.name.clone(),
+            queue: self.queue.clone(),
+            exchange: self.exchange.clone(),
+            total_run_count: self.total_run_count,
+            last_run_at: self.last_run_at,
+            next_call_at: self.next_call_at,
+            initial_offset: self.initial_offset,
         }
     }
 
@@ -49,6 +109,18 @@ impl ScheduledTask {
             None => None,
         }
     }
+
+    /// Restore the time of the last execution of the task, for example from the state
+    /// persisted by a [`SchedulerBackend`](super::SchedulerBackend), and recompute
+    /// `next_call_at` according to the schedule.
+    ///
+    /// This overrides [`ScheduleOptions::run_immediately`]: if the task ran recently
+    /// it will not be sent again until it is due according to its schedule.
+    /// If the task is not scheduled to run again, this method will return `None`.
+    pub fn restore_last_run_at(mut self, last_run_at: SystemTime) -> Option<ScheduledTask> {
+        self.last_run_at = Some(last_run_at);
+        self.reschedule_task()
+    }
 }
 
 // We implement PartialEq, Eq, PartialOrd and Ord for ScheduledTask
@@ -76,3 +148,29 @@ impl PartialEq for ScheduledTask {
 }
 
 impl Eq for ScheduledTask {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::mock::{DummyMessageFactory, EveryHour};
+
+    #[test]
+    fn test_restore_last_run_at() {
+        let now = SystemTime::now();
+        let scheduled_task = ScheduledTask::new(
+            "dummy".into(),
+            Box::new(DummyMessageFactory),
+            "celery".into(),
+            EveryHour,
+            now,
+        );
+
+        let last_run_at = now - Duration::from_secs(60);
+        let scheduled_task = scheduled_task.restore_last_run_at(last_run_at).unwrap();
+        assert_eq!(Some(last_run_at), scheduled_task.last_run_at);
+        assert_eq!(
+            last_run_at + Duration::from_secs(3600),
+            scheduled_task.next_call_at
+        );
+    }
+}
@@ -1,6 +1,16 @@
-use super::{scheduled_task::ScheduledTask, Schedule};
-use crate::{broker::Broker, error::BeatError, protocol::TryCreateMessage};
-use log::{debug, info};
+use super::{
+    event::{BeatEvent, EventEmitter},
+    scheduled_task::{ScheduleOptions, ScheduledTask, ScheduledTaskInfo},
+    Schedule,
+};
+use crate::{
+    broker::{send_with_retry, Broker, PublishRetryPolicy},
+    error::BeatError,
+    logging::{debug, info, inject_current_context, outcome, Span},
+    metrics,
+    protocol::{MessageSigner, TryCreateMessage},
+    routing::Destination,
+};
 use std::collections::BinaryHeap;
 use std::time::{Duration, SystemTime};
 
@@ -18,6 +28,9 @@ pub struct Scheduler {
     heap: BinaryHeap<ScheduledTask>,
     default_sleep_interval: Duration,
     pub broker: Box<dyn Broker>,
+    events: EventEmitter,
+    publish_retry_policy: PublishRetryPolicy,
+    message_signer: Option<MessageSigner>,
 }
 
 impl Scheduler {
@@ -27,9 +40,27 @@ impl Scheduler {
             heap: BinaryHeap::new(),
             default_sleep_interval: DEFAULT_SLEEP_INTERVAL,
             broker,
+            events: EventEmitter::default(),
+            publish_retry_policy: PublishRetryPolicy::default(),
+            message_signer: None,
         }
     }
 
+    /// Set how the tasks which failed to be sent are sent again.
+    pub(super) fn set_publish_retry_policy(&mut self, policy: PublishRetryPolicy) {
+        self.publish_retry_policy = policy;
+    }
+
+    /// Set the signer of the messages of the scheduled tasks.
+    pub(super) fn set_message_signer(&mut self, signer: MessageSigner) {
+        self.message_signer = Some(signer);
+    }
+
+    /// Set the emitter used to notify that scheduled tasks have been sent.
+    pub(super) fn set_event_emitter(&mut self, events: EventEmitter) {
+        self.events = events;
+    }
+
     /// Schedule the execution of a task.
     pub fn schedule_task<S>(
         &mut self,
@@ -40,14 +71,73 @@ impl Scheduler {
     ) where
         S: Schedule + 'static,
     {
-        match schedule.next_call_at(None) {
-            Some(next_call_at) => self.heap.push(ScheduledTask::new(
-                name,
-                message_factory,
-                queue,
-                schedule,
-                next_call_at,
-            )),
+        self.schedule_task_with_options(
+            name,
+            message_factory,
+            queue,
+            schedule,
+            ScheduleOptions::default(),
+        );
+    }
+
+    /// Schedule the execution of a task with the given [`ScheduleOptions`].
+    pub fn schedule_task_with_options<S>(
+        &mut self,
+        name: String,
+        message_factory: Box<dyn TryCreateMessage>,
+        queue: String,
+        schedule: S,
+        options: ScheduleOptions,
+    ) where
+        S: Schedule + 'static,
+    {
+        self.schedule_task_to_destination(
+            name,
+            message_factory,
+            Destination::Queue(queue),
+            schedule,
+            options,
+        );
+    }
+
+    /// Schedule the execution of a task which is sent to the given [`Destination`].
+    pub(super) fn schedule_task_to_destination<S>(
+        &mut self,
+        name: String,
+        message_factory: Box<dyn TryCreateMessage>,
+        destination: Destination,
+        schedule: S,
+        options: ScheduleOptions,
+    ) where
+        S: Schedule + 'static,
+    {
+        let (queue, exchange) = match destination {
+            Destination::Queue(queue) => (queue, None),
+            Destination::Exchange {
+                exchange,
+                routing_key,
+            } => (routing_key, Some(exchange)),
+        };
+        let initial_offset = match options.initial_offset {
+            Some(offset) if !options.run_immediately => offset,
+            _ => Duration::ZERO,
+        };
+        let next_call_at = if options.run_immediately {
+            Some(SystemTime::now())
+        } else {
+            schedule
+                .next_call_at(None)
+                .map(|next_call_at| next_call_at + initial_offset)
+        };
+
+        match next_call_at {
+            Some(next_call_at) => {
+                let mut scheduled_task =
+                    ScheduledTask::new(name, message_factory, queue, schedule, next_call_at);
+                scheduled_task.exchange = exchange;
+                scheduled_task.initial_offset = initial_offset;
+                self.heap.push(scheduled_task);
+            }
             None => debug!(
                 "The schedule of task {} never scheduled the task to run, so it has been dropped.",
                 name
@@ -55,11 +145,44 @@ impl Scheduler {
         }
     }
 
+    /// Reschedule the tasks whose last execution is given by `last_run_at` from it (see
+    /// [`ScheduledTask::restore_last_run_at`]).
+    pub(super) fn restore_last_runs<F>(&mut self, last_run_at: F)
+    where
+        F: Fn(&str) -> Option<SystemTime>,
+    {
+        let scheduled_tasks = std::mem::take(&mut self.heap);
+        for scheduled_task in scheduled_tasks {
+            match last_run_at(&scheduled_task.name) {
+                Some(last_run_at) => match scheduled_task.restore_last_run_at(last_run_at) {
+                    Some(scheduled_task) => self.heap.push(scheduled_task),
+                    None => debug!("A task is not scheduled to run anymore and will be dropped"),
+                },
+                None => self.heap.push(scheduled_task),
+            }
+        }
+    }
+
+    /// Get the number of scheduled tasks named `name`.
+    pub(super) fn count_scheduled(&self, name: &str) -> usize {
+        self.heap
+            .iter()
+            .filter(|scheduled_task| scheduled_task.name == name)
+            .count()
+    }
+
     /// Get all scheduled tasks.
     pub fn get_scheduled_tasks(&mut self) -> &mut BinaryHeap<ScheduledTask> {
         &mut self.heap
     }
 
+    /// Get a snapshot of all scheduled tasks, ordered by the time of their next execution.
+    pub fn dump_schedule(&self) -> Vec<ScheduledTaskInfo> {
+        let mut schedule: Vec<_> = self.heap.iter().map(ScheduledTask::info).collect();
+        schedule.sort_by_key(|info| info.next_call_at);
+        schedule
+    }
+
     /// Get the time when the next task should be executed.
     fn next_task_time(&self, now: SystemTime) -> SystemTime {
         if let Some(scheduled_task) = self.heap.peek() {
@@ -89,11 +212,21 @@ impl Scheduler {
                 .heap
                 .pop()
                 .expect("No scheduled tasks found even though there should be");
-            let result = self.send_scheduled_task(&mut scheduled_task).await;
+            let span = Span::beat_tick();
+            let result = span
+                .instrument(self.send_scheduled_task(&mut scheduled_task))
+                .await;
+            span.record_outcome(outcome(&result));
 
-            // Reschedule the task before checking if the task execution was successful.
-            // TODO: we may have more fine-grained logic here and reschedule the task
-            // only after examining the type of error.
+            // If the message could not be delivered to the broker, keep the task due so
+            // that it is sent again once the broker is reachable. Otherwise reschedule
+            // it before checking if the task execution was successful.
+            if let Err(BeatError::BrokerError(err)) = &result {
+                if err.is_retryable() {
+                    self.heap.push(scheduled_task);
+                    return result.map(|_| now);
+                }
+            }
             if let Some(rescheduled_task) = scheduled_task.reschedule_task() {
                 self.heap.push(rescheduled_task);
             } else {
@@ -112,19 +245,365 @@ impl Scheduler {
         &self,
         scheduled_task: &mut ScheduledTask,
     ) -> Result<(), BeatError> {
+        let span = Span::beat_send(&scheduled_task.name, &scheduled_task.queue);
+        let result = span
+            .instrument(self.try_send_scheduled_task(scheduled_task))
+            .await;
+        span.record_outcome(outcome(&result));
+        metrics::count_beat_send(&scheduled_task.name, outcome(&result));
+        match &result {
+            Ok(task_id) => self.events.emit(BeatEvent::TaskSent {
+                name: scheduled_task.name.clone(),
+                task_id: task_id.clone(),
+                queue: scheduled_task.queue.clone(),
+            }),
+            Err(err) => self.events.emit(BeatEvent::SendFailed {
+                name: scheduled_task.name.clone(),
+                error: format!("{:?}", err),
+            }),
+        }
+        result.map(|_| ())
+    }
+
+    /// Send a task to the broker, returning the ID of the task which has been sent.
+    async fn try_send_scheduled_task(
+        &self,
+        scheduled_task: &mut ScheduledTask,
+    ) -> Result<String, BeatError> {
         let queue = &scheduled_task.queue;
 
-        let message = scheduled_task.message_factory.try_create_message()?;
+        let mut message = scheduled_task.message_factory.try_create_message()?;
+        inject_current_context(&mut message.headers.extra);
+        if let Some(signer) = &self.message_signer {
+            signer.sign(&mut message);
+        }
 
-        info!(
-            "Sending task {}[{}] to {} queue",
-            scheduled_task.name,
-            message.task_id(),
-            queue
-        );
-        self.broker.send(&message, queue).await?;
+        let destination = match &scheduled_task.exchange {
+            Some(exchange) => {
+                info!(
+                    "Sending task {}[{}] to exchange {} with routing key {}",
+                    scheduled_task.name,
+                    message.task_id(),
+                    exchange,
+                    queue
+                );
+                Destination::Exchange {
+                    exchange: exchange.clone(),
+                    routing_key: queue.clone(),
+                }
+            }
+            None => {
+                info!(
+                    "Sending task {}[{}] to {} queue",
+                    scheduled_task.name,
+                    message.task_id(),
+                    queue
+                );
+                Destination::Queue(queue.clone())
+            }
+        };
+        send_with_retry(
+            &*self.broker,
+            &message,
+            &destination,
+            &self.publish_retry_policy,
+        )
+        .await?;
         scheduled_task.last_run_at.replace(SystemTime::now());
         scheduled_task.total_run_count += 1;
-        Ok(())
+        Ok(message.task_id().to_string())
+    }
+}
+
+/// Compute a deterministic offset in `[0, interval)` from the name of a schedule entry, so
+/// that the entries registered with the same interval are spread out over the interval.
+///
+/// A FNV-1a hash is used instead of the standard library hasher, whose output
+/// is not guaranteed to be stable across Rust releases.
+pub(super) fn stagger_offset(name: &str, interval: Duration) -> Duration {
+    let interval_millis = interval.as_millis() as u64;
+    if interval_millis == 0 {
+        return Duration::ZERO;
+    }
+
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    Duration::from_millis(hash % interval_millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::mock::{DummyMessageFactory, EveryHour};
+    use crate::beat::{Beat, DeltaSchedule, SchedulerBackend};
+    use crate::task::SendOptions;
+    use crate::{
+        broker::mock::MockBroker,
+        error::{BrokerError, ProtocolError},
+        protocol::{Message, MessageHeaders, MessageProperties},
+    };
+    use std::sync::atomic::Ordering;
+
+    struct StaticMessageFactory;
+
+    impl TryCreateMessage for StaticMessageFactory {
+        fn try_create_message(&self) -> Result<Message, ProtocolError> {
+            Ok(Message {
+                properties: MessageProperties {
+                    correlation_id: "task-id".into(),
+                    content_type: "application/json".into(),
+                    content_encoding: "utf-8".into(),
+                    reply_to: None,
+                    priority: None,
+                    delivery_mode: None,
+                },
+                headers: MessageHeaders {
+                    id: "task-id".into(),
+                    task: "task".into(),
+                    ..Default::default()
+                },
+                raw_body: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_run_immediately() {
+        let mut scheduler = Scheduler::new(Box::new(MockBroker::new()));
+        let now = SystemTime::now();
+
+        scheduler.schedule_task(
+            "later".into(),
+            Box::new(DummyMessageFactory),
+            "celery".into(),
+            EveryHour,
+        );
+        assert!(scheduler.next_task_time(now) > now + Duration::from_secs(60));
+
+        scheduler.schedule_task_with_options(
+            "now".into(),
+            Box::new(DummyMessageFactory),
+            "celery".into(),
+            EveryHour,
+            ScheduleOptions {
+                run_immediately: true,
+                ..Default::default()
+            },
+        );
+        assert!(scheduler.next_task_time(now) < now + Duration::from_secs(60));
+        assert_eq!("now", scheduler.heap.peek().unwrap().name);
+    }
+
+    #[test]
+    fn test_restore_last_run_at_overrides_immediate_run() {
+        let mut scheduler = Scheduler::new(Box::new(MockBroker::new()));
+        let now = SystemTime::now();
+        for name in ["restored", "unknown"] {
+            scheduler.schedule_task_with_options(
+                name.into(),
+                Box::new(DummyMessageFactory),
+                "celery".into(),
+                EveryHour,
+                ScheduleOptions {
+                    run_immediately: true,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let last_run_at = now - Duration::from_secs(60);
+        scheduler.restore_last_runs(|name| (name == "restored").then_some(last_run_at));
+
+        // The task which ran a minute ago waits for its next run, while the task which
+        // never ran still runs right away.
+        let schedule = scheduler.dump_schedule();
+        assert_eq!("unknown", schedule[0].name);
+        assert!(schedule[0].next_call_at < now + Duration::from_secs(60));
+        assert_eq!("restored", schedule[1].name);
+        assert_eq!(Some(last_run_at), schedule[1].last_run_at);
+        assert_eq!(
+            last_run_at + Duration::from_secs(3600),
+            schedule[1].next_call_at
+        );
+    }
+
+    #[tokio::test]
+    async fn test_beat_restores_last_runs() {
+        struct RestoringSchedulerBackend;
+
+        impl SchedulerBackend for RestoringSchedulerBackend {
+            fn should_sync(&self) -> bool {
+                false
+            }
+
+            fn sync(
+                &mut self,
+                _scheduled_tasks: &mut BinaryHeap<ScheduledTask>,
+            ) -> Result<(), BeatError> {
+                Ok(())
+            }
+
+            fn last_run_at(&self, name: &str) -> Option<SystemTime> {
+                (name == "restored").then(|| SystemTime::now() - Duration::from_secs(60))
+            }
+        }
+
+        let mut beat = Beat::custom_builder(
+            "beat",
+            "memory://test_beat_restores_last_runs",
+            RestoringSchedulerBackend,
+        )
+        .build()
+        .await
+        .unwrap();
+        for name in ["restored", "unknown"] {
+            beat.scheduler.schedule_task_with_options(
+                name.into(),
+                Box::new(StaticMessageFactory),
+                "celery".into(),
+                EveryHour,
+                ScheduleOptions {
+                    run_immediately: true,
+                    ..Default::default()
+                },
+            );
+        }
+
+        // Only the task whose last run isn't known to the backend is sent on start.
+        let result = tokio::time::timeout(Duration::from_millis(100), beat.start()).await;
+        assert!(result.is_err());
+        assert_eq!(
+            Some(1),
+            beat.scheduler.broker.queue_len("celery").await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_initial_offset() {
+        let mut scheduler = Scheduler::new(Box::new(MockBroker::new()));
+        let before = SystemTime::now();
+
+        scheduler.schedule_task_with_options(
+            "offset".into(),
+            Box::new(DummyMessageFactory),
+            "celery".into(),
+            EveryHour,
+            ScheduleOptions {
+                initial_offset: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        );
+
+        let schedule = scheduler.dump_schedule();
+        assert_eq!(1, schedule.len());
+        assert_eq!(Duration::from_secs(60), schedule[0].initial_offset);
+        assert!(schedule[0].next_call_at >= before + Duration::from_secs(3660));
+    }
+
+    #[test]
+    fn test_stagger_offset() {
+        let interval = Duration::from_secs(300);
+        let offsets: Vec<_> = (0..20)
+            .map(|i| stagger_offset(&format!("tenant_{}", i), interval))
+            .collect();
+
+        assert!(offsets.iter().all(|offset| *offset < interval));
+        assert_eq!(offsets[0], stagger_offset("tenant_0", interval));
+        assert!(offsets.iter().any(|offset| *offset != offsets[0]));
+        assert_eq!(Duration::ZERO, stagger_offset("tenant_0", Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_stagger_entries_of_same_task() {
+        let mut beat = Beat::default_builder("beat", "memory://test_stagger_entries_of_same_task")
+            .stagger_equal_intervals(true)
+            .build()
+            .await
+            .unwrap();
+        let interval = Duration::from_secs(300);
+        for tenant in ["a", "b"] {
+            beat.schedule_task_by_name(
+                "sync_tenant",
+                serde_json::json!([tenant]),
+                serde_json::Value::Null,
+                SendOptions::new(),
+                DeltaSchedule::new(interval),
+            )
+            .unwrap();
+        }
+
+        // Both entries are named after the task, but they are still spread out.
+        let schedule = beat.dump_schedule();
+        assert_eq!(2, schedule.len());
+        assert!(schedule.iter().all(|info| info.name == "sync_tenant"));
+        assert_ne!(schedule[0].initial_offset, schedule[1].initial_offset);
+        assert!(schedule.iter().all(|info| info.initial_offset < interval));
+    }
+
+    #[tokio::test]
+    async fn test_retryable_send_error_keeps_task_due() {
+        let broker = MockBroker::new();
+        let nack_sends = broker.nack_sends.clone();
+        let mut scheduler = Scheduler::new(Box::new(broker));
+        scheduler.set_publish_retry_policy(PublishRetryPolicy::disabled());
+        scheduler.schedule_task_with_options(
+            "now".into(),
+            Box::new(StaticMessageFactory),
+            "celery".into(),
+            EveryHour,
+            ScheduleOptions {
+                run_immediately: true,
+                ..Default::default()
+            },
+        );
+
+        nack_sends.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            scheduler.tick().await,
+            Err(BeatError::BrokerError(BrokerError::PublishNack(_)))
+        ));
+        let schedule = scheduler.dump_schedule();
+        assert_eq!(1, schedule.len());
+        assert_eq!(0, schedule[0].total_run_count);
+        assert!(schedule[0].next_call_at <= SystemTime::now());
+
+        nack_sends.store(false, Ordering::SeqCst);
+        scheduler.tick().await.unwrap();
+        let schedule = scheduler.dump_schedule();
+        assert_eq!(1, schedule[0].total_run_count);
+        assert!(schedule[0].next_call_at > SystemTime::now() + Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_retryable_send_error_retried() {
+        let broker = MockBroker::new();
+        let nack_sends = broker.nack_sends.clone();
+        let mut scheduler = Scheduler::new(Box::new(broker));
+        scheduler.set_publish_retry_policy(PublishRetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(100),
+            jitter: false,
+            ..Default::default()
+        });
+        scheduler.schedule_task_with_options(
+            "now".into(),
+            Box::new(StaticMessageFactory),
+            "celery".into(),
+            EveryHour,
+            ScheduleOptions {
+                run_immediately: true,
+                ..Default::default()
+            },
+        );
+
+        // The broker recovers while the task is being sent again.
+        nack_sends.store(true, Ordering::SeqCst);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            nack_sends.store(false, Ordering::SeqCst);
+        });
+        scheduler.tick().await.unwrap();
+        assert_eq!(1, scheduler.dump_schedule()[0].total_run_count);
     }
 }
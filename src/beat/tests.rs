@@ -34,8 +34,7 @@ async fn test_task_with_delta_schedule() {
         task_options: TaskOptions::default(),
         broker_connection_timeout: 5,
         broker_connection_retry: true,
-        broker_connection_max_retries: 5,
-        broker_connection_retry_delay: 5,
+        broker_connection_retry_policy: ConnectionRetryPolicy::default(),
         max_sleep_duration: None,
     };
 
@@ -97,8 +96,7 @@ async fn test_scheduling_two_tasks() {
         task_options: TaskOptions::default(),
         broker_connection_timeout: 5,
         broker_connection_retry: true,
-        broker_connection_max_retries: 5,
-        broker_connection_retry_delay: 5,
+        broker_connection_retry_policy: ConnectionRetryPolicy::default(),
         max_sleep_duration: None,
     };
 
@@ -169,8 +167,7 @@ async fn test_task_with_delayed_first_run() {
         task_options: TaskOptions::default(),
         broker_connection_timeout: 5,
         broker_connection_retry: true,
-        broker_connection_max_retries: 5,
-        broker_connection_retry_delay: 5,
+        broker_connection_retry_policy: ConnectionRetryPolicy::default(),
         max_sleep_duration: None,
     };
 
@@ -244,8 +241,7 @@ async fn test_beat_max_sleep_duration() {
         task_options: TaskOptions::default(),
         broker_connection_timeout: 5,
         broker_connection_retry: true,
-        broker_connection_max_retries: 5,
-        broker_connection_retry_delay: 5,
+        broker_connection_retry_policy: ConnectionRetryPolicy::default(),
         max_sleep_duration: Some(max_sleep_duration),
     };
 
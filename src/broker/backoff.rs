@@ -0,0 +1,243 @@
+//! Spacing out of the attempts to establish the connection with a broker, or to publish
+//! a message.
+
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// How the attempts to establish or re-establish the connection with a broker are spaced
+/// out, both when building an app or a beat and when the connection is lost while running.
+///
+/// The delay before the `n`th retry (starting from `0`) is `initial_delay * multiplier^n`,
+/// capped at `max_delay`. With `jitter`, a random delay between zero and that value is
+/// waited instead ("full jitter"), so that the workers which lost their connection at the
+/// same time, e.g. because the broker restarted, don't all reconnect at the same time.
+///
+/// Retrying stops after `max_retries` retries or once `max_elapsed` has passed since the
+/// first failure, whichever comes first. When both are `None`, it never stops.
+///
+/// A multiplier of `1` without jitter, as in [`ConnectionRetryPolicy::fixed`], waits the
+/// same delay between all the attempts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionRetryPolicy {
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+
+    /// The factor by which the delay grows after each retry.
+    pub multiplier: f64,
+
+    /// The upper bound of the delay.
+    pub max_delay: Duration,
+
+    /// Whether to wait a random delay between zero and the computed one.
+    pub jitter: bool,
+
+    /// The maximum number of retries.
+    pub max_retries: Option<u32>,
+
+    /// The maximum time spent retrying.
+    pub max_elapsed: Option<Duration>,
+}
+
+impl Default for ConnectionRetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(2),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: true,
+            max_retries: Some(5),
+            max_elapsed: None,
+        }
+    }
+}
+
+impl ConnectionRetryPolicy {
+    /// A policy waiting the same `delay` before each of at most 5 retries.
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            initial_delay: delay,
+            multiplier: 1.0,
+            max_delay: delay,
+            jitter: false,
+            ..Self::default()
+        }
+    }
+
+    /// The delay to wait before the `retry`th retry, starting from `0`.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponent = retry.min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        // `min` also takes care of an infinite or NaN delay.
+        let delay = delay.min(self.max_delay.as_secs_f64()).max(0.0);
+        if self.jitter && delay > 0.0 {
+            Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..=delay))
+        } else {
+            Duration::from_secs_f64(delay)
+        }
+    }
+}
+
+/// How a message which failed to be published is published again, after the errors which
+/// could go away on their own, e.g. a lost connection or a confirmation which timed out.
+///
+/// The delay before the `n`th retry is computed like with a [`ConnectionRetryPolicy`].
+///
+/// A message whose confirmation timed out may still have been delivered, so that retrying
+/// can deliver it twice. The same message is published on each attempt, with the same task
+/// ID, so that consumers can recognize the duplicates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishRetryPolicy {
+    /// The maximum number of retries, `0` disabling them.
+    pub max_retries: u32,
+
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+
+    /// The factor by which the delay grows after each retry.
+    pub multiplier: f64,
+
+    /// The upper bound of the delay.
+    pub max_delay: Duration,
+
+    /// Whether to wait a random delay between zero and the computed one.
+    pub jitter: bool,
+}
+
+impl Default for PublishRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(200),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(1),
+            jitter: true,
+        }
+    }
+}
+
+impl PublishRetryPolicy {
+    /// A policy which never retries.
+    pub fn disabled() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// The equivalent policy to space out the retries with a [`Backoff`].
+    pub(crate) fn backoff_policy(&self) -> ConnectionRetryPolicy {
+        ConnectionRetryPolicy {
+            initial_delay: self.initial_delay,
+            multiplier: self.multiplier,
+            max_delay: self.max_delay,
+            jitter: self.jitter,
+            max_retries: Some(self.max_retries),
+            max_elapsed: None,
+        }
+    }
+}
+
+/// The state of a series of retries following a [`ConnectionRetryPolicy`].
+pub(crate) struct Backoff<'a> {
+    policy: &'a ConnectionRetryPolicy,
+    retries: u32,
+    started_at: Instant,
+}
+
+impl<'a> Backoff<'a> {
+    pub(crate) fn new(policy: &'a ConnectionRetryPolicy) -> Self {
+        Self {
+            policy,
+            retries: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// The delay to wait before the next retry, or `None` if the policy doesn't allow any
+    /// more of them.
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        if matches!(self.policy.max_retries, Some(max_retries) if self.retries >= max_retries) {
+            return None;
+        }
+        let mut delay = self.policy.delay(self.retries);
+        if let Some(max_elapsed) = self.policy.max_elapsed {
+            let remaining = max_elapsed.checked_sub(self.started_at.elapsed())?;
+            if remaining.is_zero() {
+                return None;
+            }
+            // The last retry happens right when the time runs out.
+            delay = delay.min(remaining);
+        }
+        self.retries += 1;
+        Some(delay)
+    }
+
+    /// The number of retries so far, including the one [`Backoff::next_delay`] just
+    /// returned the delay of.
+    pub(crate) fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_delay() {
+        let policy = ConnectionRetryPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            jitter: false,
+            max_retries: None,
+            max_elapsed: None,
+        };
+        let delays: Vec<u64> = (0..6).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let policy = ConnectionRetryPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            ..ConnectionRetryPolicy::default()
+        };
+        for n in 0..10 {
+            assert!(policy.delay(n) <= Duration::from_secs(10).min(Duration::from_secs(1 << n)));
+        }
+    }
+
+    #[test]
+    fn test_fixed_policy() {
+        let policy = ConnectionRetryPolicy::fixed(Duration::from_secs(5));
+        let mut backoff = Backoff::new(&policy);
+        let mut delays = vec![];
+        while let Some(delay) = backoff.next_delay() {
+            delays.push(delay);
+        }
+        assert_eq!(delays, vec![Duration::from_secs(5); 5]);
+        assert_eq!(backoff.retries(), 5);
+    }
+
+    #[test]
+    fn test_max_elapsed() {
+        let policy = ConnectionRetryPolicy {
+            max_retries: None,
+            max_elapsed: Some(Duration::from_secs(3)),
+            ..ConnectionRetryPolicy::fixed(Duration::from_secs(2))
+        };
+        let mut backoff = Backoff::new(&policy);
+        assert_eq!(backoff.next_delay(), Some(Duration::from_secs(2)));
+        // Only the time left is waited before the last retry.
+        assert!(backoff.next_delay().unwrap() <= Duration::from_secs(3));
+
+        let policy = ConnectionRetryPolicy {
+            max_elapsed: Some(Duration::ZERO),
+            ..policy
+        };
+        assert_eq!(Backoff::new(&policy).next_delay(), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Exchanges, for routing task messages to queues by routing key.
+
+use std::collections::HashMap;
+
+use crate::error::BrokerError;
+
+/// The type of an exchange, which determines how it routes messages to the queues bound to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExchangeKind {
+    /// Route messages to the queues bound with a binding key equal to the routing key.
+    Direct,
+
+    /// Route messages to the queues bound with a pattern matching the routing key. Patterns
+    /// are dot-separated words, where `*` matches exactly one word and `#` zero or more words,
+    /// e.g. `events.#` matches `events.user.created`.
+    Topic,
+
+    /// Route messages to all the queues bound to the exchange, regardless of the routing key.
+    Fanout,
+}
+
+impl ExchangeKind {
+    /// Check whether a message with the given routing key is routed to a queue bound
+    /// with `binding_key`.
+    pub fn matches(&self, binding_key: &str, routing_key: &str) -> bool {
+        match self {
+            ExchangeKind::Direct => binding_key == routing_key,
+            ExchangeKind::Topic => {
+                let pattern: Vec<&str> = binding_key.split('.').collect();
+                let words: Vec<&str> = routing_key.split('.').collect();
+                topic_matches(&pattern, &words)
+            }
+            ExchangeKind::Fanout => true,
+        }
+    }
+}
+
+fn topic_matches(pattern: &[&str], words: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => words.is_empty(),
+        Some((&"#", rest)) => (0..=words.len()).any(|skip| topic_matches(rest, &words[skip..])),
+        Some((&word, rest)) => match words.split_first() {
+            Some((first, remaining)) if word == "*" || word == *first => {
+                topic_matches(rest, remaining)
+            }
+            _ => false,
+        },
+    }
+}
+
+/// The exchanges and bindings of brokers which don't have exchanges of their own, and
+/// route messages to queues when publishing them instead.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Exchanges {
+    exchanges: HashMap<String, (ExchangeKind, Vec<(String, String)>)>,
+}
+
+impl Exchanges {
+    pub(crate) fn declare(&mut self, name: &str, kind: ExchangeKind) {
+        self.exchanges
+            .entry(name.into())
+            .or_insert_with(|| (kind, vec![]))
+            .0 = kind;
+    }
+
+    pub(crate) fn bind(&mut self, queue: &str, exchange: &str, binding_key: &str) {
+        if let Some((_, bindings)) = self.exchanges.get_mut(exchange) {
+            let binding = (queue.into(), binding_key.into());
+            if !bindings.contains(&binding) {
+                bindings.push(binding);
+            }
+        }
+    }
+
+    /// Get the queues a message published to `exchange` with `routing_key` is routed to.
+    pub(crate) fn route(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<Vec<&str>, BrokerError> {
+        let (kind, bindings) = self
+            .exchanges
+            .get(exchange)
+            .ok_or_else(|| BrokerError::UnknownExchange(exchange.into()))?;
+        let mut queues: Vec<&str> = bindings
+            .iter()
+            .filter(|(_, binding_key)| kind.matches(binding_key, routing_key))
+            .map(|(queue, _)| queue.as_str())
+            .collect();
+        // A queue bound several times with matching keys only gets the message once.
+        queues.sort_unstable();
+        queues.dedup();
+        Ok(queues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_matches() {
+        let topic = ExchangeKind::Topic;
+        assert!(topic.matches("events.#", "events.user.created"));
+        assert!(topic.matches("events.#", "events"));
+        assert!(topic.matches("events.*", "events.user"));
+        assert!(!topic.matches("events.*", "events.user.created"));
+        assert!(topic.matches("*.user.*", "events.user.created"));
+        assert!(topic.matches("#.created", "events.user.created"));
+        assert!(!topic.matches("events.#", "metrics.cpu"));
+        assert!(topic.matches("#", "anything.at.all"));
+    }
+
+    #[test]
+    fn test_route() {
+        let mut exchanges = Exchanges::default();
+        exchanges.declare("events", ExchangeKind::Topic);
+        exchanges.bind("audit", "events", "events.#");
+        exchanges.bind("users", "events", "events.user.*");
+        exchanges.declare("broadcast", ExchangeKind::Fanout);
+        exchanges.bind("worker1", "broadcast", "");
+        exchanges.bind("worker2", "broadcast", "");
+
+        assert_eq!(
+            vec!["audit", "users"],
+            exchanges.route("events", "events.user.created").unwrap()
+        );
+        assert_eq!(
+            vec!["audit"],
+            exchanges.route("events", "events.order.paid").unwrap()
+        );
+        assert_eq!(
+            vec!["worker1", "worker2"],
+            exchanges.route("broadcast", "ignored").unwrap()
+        );
+        assert!(matches!(
+            exchanges.route("missing", "key"),
+            Err(BrokerError::UnknownExchange(_))
+        ));
+    }
+}
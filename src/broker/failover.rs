@@ -0,0 +1,383 @@
+//! Broker failover between several URLs.
+//!
+//! A broker URL made of several URLs separated by `;`, like Python Celery accepts, is
+//! handled by a [`FailoverBroker`]. It connects to the first reachable URL, and switches
+//! to the next ones in turn when the connection is lost.
+
+use crate::logging::{info, warn};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::RwLock;
+
+use super::{
+    broker_builder_from_url, Broker, BrokerBuilder, ConnectionState, Delivery, DeliveryStream,
+    ExchangeKind, QueueOptions,
+};
+use crate::error::BrokerError;
+use crate::protocol::Message;
+
+#[cfg(test)]
+use std::any::Any;
+
+/// Builds a [`FailoverBroker`] from a list of broker URLs separated by `;`.
+///
+/// The configuration is applied to the builders of all the URLs.
+pub struct FailoverBrokerBuilder {
+    builders: Arc<Vec<Box<dyn BrokerBuilder>>>,
+}
+
+impl FailoverBrokerBuilder {
+    fn configure(
+        mut self: Box<Self>,
+        f: impl Fn(Box<dyn BrokerBuilder>) -> Box<dyn BrokerBuilder>,
+    ) -> Box<dyn BrokerBuilder> {
+        let builders = Arc::get_mut(&mut self.builders)
+            .expect("a FailoverBrokerBuilder can't be configured once it has built a broker");
+        *builders = std::mem::take(builders).into_iter().map(f).collect();
+        self
+    }
+}
+
+/// Try building a broker with each builder in turn, starting with the builder at `start`,
+/// and return the first broker which could connect along with the index of its builder.
+async fn connect_any(
+    builders: &[Box<dyn BrokerBuilder>],
+    start: usize,
+    connection_timeout: u32,
+) -> Result<(usize, Box<dyn Broker>), BrokerError> {
+    let mut last_err = BrokerError::NotConnected;
+    for offset in 0..builders.len() {
+        let index = (start + offset) % builders.len();
+        match builders[index].build(connection_timeout).await {
+            Ok(broker) => return Ok((index, broker)),
+            Err(err) if err.is_connection_error() => {
+                warn!("Failed to connect to broker #{}: {}", index + 1, err);
+                last_err = err;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_err)
+}
+
+#[async_trait]
+impl BrokerBuilder for FailoverBrokerBuilder {
+    /// Create a new `FailoverBrokerBuilder` from URLs separated by `;`.
+    fn new(broker_url: &str) -> Self {
+        Self {
+            builders: Arc::new(
+                broker_url
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(broker_builder_from_url)
+                    .collect(),
+            ),
+        }
+    }
+
+    fn prefetch_count(self: Box<Self>, prefetch_count: u16) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.prefetch_count(prefetch_count))
+    }
+
+    fn prefetch_global(self: Box<Self>, global: bool) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.prefetch_global(global))
+    }
+
+    fn declare_queue(self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.declare_queue(name))
+    }
+
+    fn declare_queue_with_options(
+        self: Box<Self>,
+        name: &str,
+        options: QueueOptions,
+    ) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.declare_queue_with_options(name, options.clone()))
+    }
+
+    fn default_queue_options(self: Box<Self>, options: QueueOptions) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.default_queue_options(options.clone()))
+    }
+
+    fn declare_broadcast_queue(self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.declare_broadcast_queue(name))
+    }
+
+    fn queue_max_priority(self: Box<Self>, name: &str, max_priority: u8) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.queue_max_priority(name, max_priority))
+    }
+
+    fn declare_exchange(
+        self: Box<Self>,
+        name: &str,
+        kind: ExchangeKind,
+        durable: bool,
+    ) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.declare_exchange(name, kind, durable))
+    }
+
+    fn bind_queue(
+        self: Box<Self>,
+        queue: &str,
+        exchange: &str,
+        binding_key: &str,
+    ) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.bind_queue(queue, exchange, binding_key))
+    }
+
+    fn heartbeat(self: Box<Self>, heartbeat: Option<u16>) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.heartbeat(heartbeat))
+    }
+
+    fn delayed_delivery(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.delayed_delivery(enabled))
+    }
+
+    fn publisher_confirms(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.publisher_confirms(enabled))
+    }
+
+    fn confirm_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.confirm_timeout(timeout))
+    }
+
+    fn visibility_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.visibility_timeout(timeout))
+    }
+
+    fn pool_limit(self: Box<Self>, limit: u16) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.pool_limit(limit))
+    }
+
+    fn separate_producer_connection(self: Box<Self>, separate: bool) -> Box<dyn BrokerBuilder> {
+        self.configure(|builder| builder.separate_producer_connection(separate))
+    }
+
+    /// Connect to the first reachable URL.
+    async fn build(&self, connection_timeout: u32) -> Result<Box<dyn Broker>, BrokerError> {
+        let (index, broker) = connect_any(&self.builders, 0, connection_timeout).await?;
+        info!("Connected to broker {}", broker.safe_url());
+        Ok(Box::new(FailoverBroker {
+            builders: self.builders.clone(),
+            safe_url: StdMutex::new(broker.safe_url()),
+            current: RwLock::new((index, broker)),
+            declared_queues: StdMutex::new(vec![]),
+        }))
+    }
+}
+
+/// A [`Broker`] which is connected to one of several URLs at a time, and fails over to
+/// the next URL when its connection is lost.
+pub struct FailoverBroker {
+    builders: Arc<Vec<Box<dyn BrokerBuilder>>>,
+
+    /// The broker currently in use, along with the index of its URL.
+    current: RwLock<(usize, Box<dyn Broker>)>,
+
+    /// The redacted URL of the current broker, which is kept apart so that it can be read
+    /// without awaiting the lock.
+    safe_url: StdMutex<String>,
+
+    /// The queues declared after connecting, which are declared again when failing over.
+    declared_queues: StdMutex<Vec<String>>,
+}
+
+#[async_trait]
+impl Broker for FailoverBroker {
+    /// Return the redacted URL of the broker currently in use.
+    fn safe_url(&self) -> String {
+        self.safe_url.lock().unwrap().clone()
+    }
+
+    /// The current broker is only locked while failing over, when it isn't connected.
+    fn connection_state(&self) -> ConnectionState {
+        match self.current.try_read() {
+            Ok(current) => current.1.connection_state(),
+            Err(_) => ConnectionState::NotConnected,
+        }
+    }
+
+    async fn consume(
+        &self,
+        queue: &str,
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        self.current
+            .read()
+            .await
+            .1
+            .consume(queue, error_handler)
+            .await
+    }
+
+    async fn consume_with_priority(
+        &self,
+        queue: &str,
+        higher_priority_queues: &[&str],
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        self.current
+            .read()
+            .await
+            .1
+            .consume_with_priority(queue, higher_priority_queues, error_handler)
+            .await
+    }
+
+    /// The queue is also declared to the next brokers when failing over.
+    async fn declare_queue(&self, queue: &str) -> Result<(), BrokerError> {
+        self.current.read().await.1.declare_queue(queue).await?;
+        let mut declared_queues = self.declared_queues.lock().unwrap();
+        if !declared_queues.iter().any(|declared| declared == queue) {
+            declared_queues.push(queue.into());
+        }
+        Ok(())
+    }
+
+    async fn cancel(&self, consumer_tag: &str) -> Result<(), BrokerError> {
+        self.current.read().await.1.cancel(consumer_tag).await
+    }
+
+    async fn ack(&self, delivery: &dyn Delivery) -> Result<(), BrokerError> {
+        self.current.read().await.1.ack(delivery).await
+    }
+
+    async fn reject(&self, delivery: &dyn Delivery) -> Result<(), BrokerError> {
+        self.current.read().await.1.reject(delivery).await
+    }
+
+    async fn retry(
+        &self,
+        delivery: &dyn Delivery,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        self.current.read().await.1.retry(delivery, eta).await
+    }
+
+    async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError> {
+        self.current.read().await.1.send(message, queue).await
+    }
+
+    async fn send_batch(&self, batch: &[(&Message, &str)]) -> Vec<Result<(), BrokerError>> {
+        self.current.read().await.1.send_batch(batch).await
+    }
+
+    async fn queue_len(&self, queue: &str) -> Result<Option<usize>, BrokerError> {
+        self.current.read().await.1.queue_len(queue).await
+    }
+
+    async fn supports_scheduling(&self) -> bool {
+        self.current.read().await.1.supports_scheduling().await
+    }
+
+    async fn send_scheduled(
+        &self,
+        message: &Message,
+        queue: &str,
+        eta: DateTime<Utc>,
+    ) -> Result<(), BrokerError> {
+        self.current
+            .read()
+            .await
+            .1
+            .send_scheduled(message, queue, eta)
+            .await
+    }
+
+    async fn enqueue_due(&self) -> Result<usize, BrokerError> {
+        self.current.read().await.1.enqueue_due().await
+    }
+
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError> {
+        self.current
+            .read()
+            .await
+            .1
+            .send_to_exchange(message, exchange, routing_key)
+            .await
+    }
+
+    async fn increase_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.current.read().await.1.increase_prefetch_count().await
+    }
+
+    async fn decrease_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.current.read().await.1.decrease_prefetch_count().await
+    }
+
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError> {
+        self.current
+            .read()
+            .await
+            .1
+            .set_prefetch_count(prefetch_count)
+            .await
+    }
+
+    async fn prefetch_count(&self) -> u16 {
+        self.current.read().await.1.prefetch_count().await
+    }
+
+    async fn close(&self) -> Result<(), BrokerError> {
+        self.current.read().await.1.close().await
+    }
+
+    /// Connect to the next reachable URL, starting with the one after the current URL
+    /// so that a host which just went down isn't tried again first.
+    async fn reconnect(&self, connection_timeout: u32) -> Result<(), BrokerError> {
+        let mut current = self.current.write().await;
+        let prefetch_count = current.1.prefetch_count().await;
+        let (index, broker) =
+            connect_any(&self.builders, current.0 + 1, connection_timeout).await?;
+        broker.set_prefetch_count(prefetch_count).await?;
+        let declared_queues = self.declared_queues.lock().unwrap().clone();
+        for queue in &declared_queues {
+            broker.declare_queue(queue).await?;
+        }
+        if index != current.0 {
+            warn!("Failing over to broker {}", broker.safe_url());
+        }
+        *self.safe_url.lock().unwrap() = broker.safe_url();
+        *current = (index, broker);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_failover() {
+        // Nothing listens on port 1, so the connection is refused.
+        let builder: Box<dyn BrokerBuilder> = Box::new(FailoverBrokerBuilder::new(
+            "redis://127.0.0.1:1/;memory://test_failover",
+        ));
+        let broker = builder
+            .prefetch_count(3)
+            .declare_queue("celery")
+            .build(1)
+            .await
+            .unwrap();
+        assert_eq!("memory://test_failover", broker.safe_url());
+        assert_eq!(3, broker.prefetch_count().await);
+
+        // Reconnecting moves on to the next URL, which wraps around to the same one here.
+        broker.set_prefetch_count(5).await.unwrap();
+        broker.reconnect(1).await.unwrap();
+        assert_eq!("memory://test_failover", broker.safe_url());
+        assert_eq!(5, broker.prefetch_count().await);
+    }
+}
@@ -0,0 +1,801 @@
+//! Filesystem broker.
+//!
+//! This broker stores messages as files in a directory, which makes it possible to run the
+//! full produce/consume loop on a single machine without any external service. It is meant
+//! for local development only.
+//!
+//! The base directory is taken from the broker URL, e.g. `filesystem:///tmp/celery` or
+//! `filesystem://./celery-data`, and every queue is a sub-directory of it. Publishing writes
+//! a new `<queue>/<timestamp>-<uuid>.msg` file, consuming claims the oldest message file by
+//! renaming it with a `.processing` suffix, and acknowledging deletes it. Files are created
+//! and claimed with atomic renames, so one producer and one consumer process can safely
+//! share the same directory.
+
+use crate::logging::{debug, warn};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc, Mutex as StdMutex,
+};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+use super::{
+    Broker, BrokerBuilder, DeliveryError, DeliveryStream, ExchangeKind, Exchanges, QueueOptions,
+};
+use crate::error::{BrokerError, ProtocolError};
+use crate::protocol::{Delivery, Message, TryDeserializeMessage};
+
+#[cfg(test)]
+use std::any::Any;
+
+const URL_PREFIX: &str = "filesystem://";
+const MESSAGE_EXTENSION: &str = "msg";
+const PROCESSING_SUFFIX: &str = ".processing";
+
+/// How often an empty queue directory is checked for new messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn base_dir(broker_url: &str) -> Result<PathBuf, BrokerError> {
+    match broker_url.strip_prefix(URL_PREFIX) {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(BrokerError::InvalidBrokerUrl(broker_url.into())),
+    }
+}
+
+fn processing_path(path: &Path) -> PathBuf {
+    let mut processing = path.as_os_str().to_owned();
+    processing.push(PROCESSING_SUFFIX);
+    PathBuf::from(processing)
+}
+
+struct Config {
+    broker_url: String,
+    prefetch_count: u16,
+    queues: HashSet<String>,
+    exchanges: Exchanges,
+}
+
+/// Builds a [`FilesystemBroker`] with a custom configuration.
+pub struct FilesystemBrokerBuilder {
+    config: Config,
+}
+
+#[async_trait]
+impl BrokerBuilder for FilesystemBrokerBuilder {
+    /// Create a new `FilesystemBrokerBuilder`.
+    fn new(broker_url: &str) -> Self {
+        Self {
+            config: Config {
+                broker_url: broker_url.into(),
+                prefetch_count: 10,
+                queues: HashSet::new(),
+                exchanges: Exchanges::default(),
+            },
+        }
+    }
+
+    /// Set the worker prefetch count, i.e. the maximum number of unacknowledged
+    /// deliveries. A value of 0 means no limit.
+    fn prefetch_count(mut self: Box<Self>, prefetch_count: u16) -> Box<dyn BrokerBuilder> {
+        self.config.prefetch_count = prefetch_count;
+        self
+    }
+
+    /// The prefetch count is always shared by the consumers with this broker, so this has
+    /// no effect.
+    #[allow(unused)]
+    fn prefetch_global(self: Box<Self>, global: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Declare a queue.
+    fn declare_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(name.into());
+        self
+    }
+
+    /// Broadcast queues aren't supported by this broker, so this declares a regular queue,
+    /// whose messages are distributed among its consumers.
+    fn declare_broadcast_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        warn!("Broadcast queue {} is declared as a regular queue", name);
+        self.config.queues.insert(name.into());
+        self
+    }
+
+    /// Queues are plain directories with this broker, so the options are ignored and the queue is
+    /// declared like with [`declare_queue`](Self::declare_queue).
+    #[allow(unused)]
+    fn declare_queue_with_options(
+        self: Box<Self>,
+        name: &str,
+        options: QueueOptions,
+    ) -> Box<dyn BrokerBuilder> {
+        self.declare_queue(name)
+    }
+
+    /// See [`declare_queue_with_options`](Self::declare_queue_with_options).
+    #[allow(unused)]
+    fn default_queue_options(self: Box<Self>, options: QueueOptions) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Priorities aren't supported by this broker, so this has no effect.
+    #[allow(unused)]
+    fn queue_max_priority(self: Box<Self>, name: &str, max_priority: u8) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Declare an exchange. The messages sent to it are routed to the queues bound to it
+    /// by the sender, according to the bindings declared on its own builder. The `durable`
+    /// flag has no effect.
+    #[allow(unused)]
+    fn declare_exchange(
+        mut self: Box<Self>,
+        name: &str,
+        kind: ExchangeKind,
+        durable: bool,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.exchanges.declare(name, kind);
+        self
+    }
+
+    /// Bind a queue to an exchange with a binding key.
+    fn bind_queue(
+        mut self: Box<Self>,
+        queue: &str,
+        exchange: &str,
+        binding_key: &str,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(queue.into());
+        self.config.exchanges.bind(queue, exchange, binding_key);
+        self
+    }
+
+    /// This has no effect, since there is no connection to keep alive.
+    #[allow(unused)]
+    fn heartbeat(self: Box<Self>, heartbeat: Option<u16>) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Delayed delivery isn't supported by this broker, so this has no effect.
+    #[allow(unused)]
+    fn delayed_delivery(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages are always stored by the time they are sent with this broker,
+    /// so this has no effect.
+    #[allow(unused)]
+    fn publisher_confirms(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// See [`publisher_confirms`](Self::publisher_confirms).
+    #[allow(unused)]
+    fn confirm_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Unacknowledged messages are moved back to their queue whenever a consumer starts
+    /// consuming it, so this has no effect.
+    #[allow(unused)]
+    fn visibility_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages are written to files directly with this broker, so this has no effect.
+    #[allow(unused)]
+    fn pool_limit(self: Box<Self>, limit: u16) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages are written to files directly with this broker, so this has no effect.
+    #[allow(unused)]
+    fn separate_producer_connection(self: Box<Self>, separate: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Build a `FilesystemBroker`, creating the directories of the declared queues.
+    #[allow(unused)]
+    async fn build(&self, connection_timeout: u32) -> Result<Box<dyn Broker>, BrokerError> {
+        let broker = FilesystemBroker {
+            uri: self.config.broker_url.clone(),
+            base_dir: base_dir(&self.config.broker_url)?,
+            exchanges: self.config.exchanges.clone(),
+            prefetch_count: Arc::new(AtomicU16::new(self.config.prefetch_count)),
+            pending_tasks: Arc::new(AtomicU16::new(0)),
+            acked: Arc::new(Notify::new()),
+            consumers: StdMutex::new(HashMap::new()),
+        };
+        for queue in &self.config.queues {
+            broker.queue_dir(queue).await?;
+        }
+
+        Ok(Box::new(broker))
+    }
+}
+
+/// A [`Broker`] which stores messages in a directory, created with a `filesystem://` URL.
+pub struct FilesystemBroker {
+    uri: String,
+    base_dir: PathBuf,
+    exchanges: Exchanges,
+    prefetch_count: Arc<AtomicU16>,
+    pending_tasks: Arc<AtomicU16>,
+    /// Notified when a delivery is acknowledged or the prefetch count changes,
+    /// so that consumers waiting on the prefetch limit can check it again.
+    acked: Arc<Notify>,
+    /// Cancellation signals of the consumers, by consumer tag.
+    consumers: StdMutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl FilesystemBroker {
+    /// Get the directory of a queue, creating it if needed.
+    async fn queue_dir(&self, queue: &str) -> Result<PathBuf, BrokerError> {
+        let dir = self.base_dir.join(queue);
+        fs::create_dir_all(&dir).await?;
+        Ok(dir)
+    }
+
+    /// Move the messages which were claimed but never acknowledged (e.g. because the
+    /// consumer crashed) back to the queue.
+    ///
+    /// This assumes that there is a single consumer per queue.
+    async fn requeue_unacked(dir: &Path) -> Result<(), BrokerError> {
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if let Some(original) = path
+                .to_str()
+                .and_then(|path| path.strip_suffix(PROCESSING_SUFFIX))
+            {
+                warn!("Requeuing unacknowledged message {}", original);
+                fs::rename(&path, original).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A message delivered by a [`FilesystemBroker`].
+#[derive(Clone, Debug)]
+pub struct FilesystemDelivery {
+    /// The path of the claimed message file (with the `.processing` suffix).
+    path: PathBuf,
+    queue: String,
+    delivery: Delivery,
+}
+
+impl FilesystemDelivery {
+    /// Put the message back in its queue, so that it can be consumed again.
+    pub async fn requeue(&self) -> Result<(), BrokerError> {
+        let original = self
+            .path
+            .to_str()
+            .and_then(|path| path.strip_suffix(PROCESSING_SUFFIX))
+            .unwrap_or_default();
+        fs::rename(&self.path, original).await?;
+        Ok(())
+    }
+}
+
+impl TryDeserializeMessage for FilesystemDelivery {
+    fn try_deserialize_message(&self) -> Result<Message, ProtocolError> {
+        self.delivery.try_deserialize_message()
+    }
+}
+
+#[async_trait]
+impl super::Delivery for FilesystemDelivery {
+    async fn resend(
+        &self,
+        broker: &dyn Broker,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        let mut message = self.try_deserialize_message()?;
+        message.headers.eta = eta;
+        // Increment the number of retries.
+        message.headers.retries = Some(message.headers.retries.map_or(1, |retry| retry + 1));
+        broker.send(&message, &self.queue).await
+    }
+
+    async fn remove(&self) -> Result<(), BrokerError> {
+        match fs::remove_file(&self.path).await {
+            // The message could have been acknowledged already.
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    async fn ack(&self) -> Result<(), BrokerError> {
+        self.remove().await
+    }
+
+    /// Dead-letter queues aren't supported by this broker, so the message is deleted.
+    async fn reject(&self) -> Result<(), BrokerError> {
+        self.remove().await
+    }
+}
+
+struct FilesystemDeliveryError(BrokerError);
+
+impl std::fmt::Display for FilesystemDeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl DeliveryError for FilesystemDeliveryError {}
+
+type DeliveryResult = Result<Box<dyn super::Delivery>, Box<dyn DeliveryError>>;
+
+struct Consumer {
+    wrapped: Pin<Box<dyn Stream<Item = DeliveryResult> + Send>>,
+}
+
+impl DeliveryStream for Consumer {}
+
+impl Stream for Consumer {
+    type Item = DeliveryResult;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.wrapped.as_mut().poll_next(cx)
+    }
+}
+
+struct ConsumerState {
+    dir: PathBuf,
+    queue: String,
+    prefetch_count: Arc<AtomicU16>,
+    pending_tasks: Arc<AtomicU16>,
+    acked: Arc<Notify>,
+    cancelled: Arc<Notify>,
+}
+
+impl ConsumerState {
+    fn prefetch_limit_reached(&self) -> bool {
+        let prefetch_count = self.prefetch_count.load(Ordering::SeqCst);
+        prefetch_count > 0 && self.pending_tasks.load(Ordering::SeqCst) >= prefetch_count
+    }
+
+    /// Claim the oldest message in the queue, if any.
+    async fn try_claim(&self) -> Result<Option<FilesystemDelivery>, BrokerError> {
+        let mut paths = vec![];
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(MESSAGE_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        // File names start with the time they were published at.
+        paths.sort();
+
+        for path in paths {
+            let processing = processing_path(&path);
+            match fs::rename(&path, &processing).await {
+                Ok(_) => {
+                    let content = fs::read(&processing).await?;
+                    let delivery: Delivery = serde_json::from_slice(&content)?;
+                    return Ok(Some(FilesystemDelivery {
+                        path: processing,
+                        queue: self.queue.clone(),
+                        delivery,
+                    }));
+                }
+                // Another consumer claimed the message first.
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Wait for the next message, returning `None` if the consumer has been cancelled.
+    async fn next_delivery(&self) -> Option<Result<FilesystemDelivery, BrokerError>> {
+        loop {
+            let acked = self.acked.notified();
+            if self.prefetch_limit_reached() {
+                debug!("Pending tasks limit reached");
+                tokio::select! {
+                    _ = acked => continue,
+                    _ = self.cancelled.notified() => return None,
+                }
+            }
+
+            match self.try_claim().await {
+                Ok(Some(delivery)) => {
+                    self.pending_tasks.fetch_add(1, Ordering::SeqCst);
+                    return Some(Ok(delivery));
+                }
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+
+            tokio::select! {
+                _ = time::sleep(POLL_INTERVAL) => {},
+                _ = self.cancelled.notified() => return None,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Broker for FilesystemBroker {
+    fn safe_url(&self) -> String {
+        self.uri.clone()
+    }
+
+    #[allow(unused)]
+    async fn consume(
+        &self,
+        queue: &str,
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        let dir = self.queue_dir(queue).await?;
+        Self::requeue_unacked(&dir).await?;
+
+        // Create unique consumer tag.
+        let mut buffer = Uuid::encode_buffer();
+        let uuid = Uuid::new_v4().hyphenated().encode_lower(&mut buffer);
+        let consumer_tag = uuid.to_owned();
+
+        let cancelled = Arc::new(Notify::new());
+        self.consumers
+            .lock()
+            .unwrap()
+            .insert(consumer_tag.clone(), cancelled.clone());
+
+        let state = ConsumerState {
+            dir,
+            queue: queue.into(),
+            prefetch_count: self.prefetch_count.clone(),
+            pending_tasks: self.pending_tasks.clone(),
+            acked: self.acked.clone(),
+            cancelled,
+        };
+        let wrapped = stream::unfold(state, |state| async move {
+            let result = match state.next_delivery().await? {
+                Ok(delivery) => Ok(Box::new(delivery) as Box<dyn super::Delivery>),
+                Err(err) => Err(Box::new(FilesystemDeliveryError(err)) as Box<dyn DeliveryError>),
+            };
+            Some((result, state))
+        });
+
+        Ok((
+            consumer_tag,
+            Box::new(Consumer {
+                wrapped: Box::pin(wrapped),
+            }),
+        ))
+    }
+
+    async fn cancel(&self, consumer_tag: &str) -> Result<(), BrokerError> {
+        if let Some(cancelled) = self.consumers.lock().unwrap().remove(consumer_tag) {
+            cancelled.notify_one();
+        }
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.ack().await?;
+        self.pending_tasks
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                pending.checked_sub(1)
+            })
+            .ok();
+        self.acked.notify_waiters();
+        Ok(())
+    }
+
+    async fn reject(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.reject().await?;
+        self.pending_tasks
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                pending.checked_sub(1)
+            })
+            .ok();
+        self.acked.notify_waiters();
+        Ok(())
+    }
+
+    async fn retry(
+        &self,
+        delivery: &dyn super::Delivery,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        delivery.resend(self, eta).await
+    }
+
+    async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError> {
+        let dir = self.queue_dir(queue).await?;
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let name = format!("{:020}-{}", timestamp, Uuid::new_v4());
+
+        // Write to a temporary file first so that consumers never see partial messages.
+        let tmp_path = dir.join(format!(".{}.tmp", name));
+        fs::write(&tmp_path, message.json_serialized()?).await?;
+        fs::rename(
+            &tmp_path,
+            dir.join(format!("{}.{}", name, MESSAGE_EXTENSION)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError> {
+        for queue in self.exchanges.route(exchange, routing_key)? {
+            self.send(message, queue).await?;
+        }
+        Ok(())
+    }
+
+    async fn increase_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                // A prefetch count of 0 means there is no limit.
+                if count > 0 {
+                    count.checked_add(1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        self.acked.notify_waiters();
+        Ok(())
+    }
+
+    async fn decrease_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count > 1 {
+                    Some(count - 1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        Ok(())
+    }
+
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError> {
+        self.prefetch_count.store(prefetch_count, Ordering::SeqCst);
+        self.acked.notify_waiters();
+        Ok(())
+    }
+
+    async fn prefetch_count(&self) -> u16 {
+        self.prefetch_count.load(Ordering::SeqCst)
+    }
+
+    async fn close(&self) -> Result<(), BrokerError> {
+        let tags: Vec<String> = self.consumers.lock().unwrap().keys().cloned().collect();
+        for tag in tags {
+            self.cancel(&tag).await?;
+        }
+        Ok(())
+    }
+
+    /// There is no connection to re-establish, this only checks that the base directory exists.
+    #[allow(unused)]
+    async fn reconnect(&self, connection_timeout: u32) -> Result<(), BrokerError> {
+        fs::create_dir_all(&self.base_dir).await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MessageContentType;
+    use crate::task::{Request, Signature, Task, TaskOptions, TaskResult};
+    use futures::StreamExt;
+    use std::convert::TryFrom;
+
+    #[derive(Clone)]
+    struct NoopTask {
+        request: Request<Self>,
+        options: TaskOptions,
+    }
+
+    #[async_trait]
+    impl Task for NoopTask {
+        const NAME: &'static str = "noop";
+        const ARGS: &'static [&'static str] = &[];
+        const DEFAULTS: TaskOptions = TaskOptions {
+            time_limit: None,
+            hard_time_limit: None,
+            soft_time_limit: None,
+            max_retries: None,
+            min_retry_delay: None,
+            max_retry_delay: None,
+            retry_backoff: None,
+            retry_backoff_max: None,
+            retry_jitter: None,
+            retry_for_unexpected: None,
+            acks_late: None,
+            reject_on_worker_lost: None,
+            content_type: Some(MessageContentType::Json),
+            compression: None,
+            compression_threshold: None,
+            priority: None,
+            delivery_mode: None,
+            rate_limit: None,
+            dedup: None,
+            dedup_ttl: None,
+            ignore_result: None,
+        };
+
+        type Params = ();
+        type Returns = ();
+
+        fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+            Self { request, options }
+        }
+
+        fn request(&self) -> &Request<Self> {
+            &self.request
+        }
+
+        fn options(&self) -> &TaskOptions {
+            &self.options
+        }
+
+        async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+            Ok(())
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("celery-{}-{}", name, Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn build(dir: &Path, prefetch_count: u16) -> Box<dyn Broker> {
+        Box::new(FilesystemBrokerBuilder::new(&format!(
+            "filesystem://{}",
+            dir.display()
+        )))
+        .prefetch_count(prefetch_count)
+        .declare_queue("celery")
+        .build(0)
+        .await
+        .unwrap()
+    }
+
+    fn message() -> Message {
+        Message::try_from(Signature::<NoopTask>::new(())).unwrap()
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_base_dir() {
+        assert_eq!(
+            PathBuf::from("/tmp/celery"),
+            base_dir("filesystem:///tmp/celery").unwrap()
+        );
+        assert_eq!(
+            PathBuf::from("./data"),
+            base_dir("filesystem://./data").unwrap()
+        );
+        assert!(base_dir("filesystem://").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_and_consume() {
+        let dir = temp_dir("send-and-consume");
+        let producer = build(&dir, 10).await;
+        let consumer = build(&dir, 10).await;
+        let first = message();
+        let second = message();
+        producer.send(&first, "celery").await.unwrap();
+        producer.send(&second, "celery").await.unwrap();
+        assert_eq!(2, files(&dir.join("celery")).len());
+
+        let (_, mut deliveries) = consumer.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        assert_eq!(
+            first.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+        assert!(files(&dir.join("celery"))[0].ends_with(".msg.processing"));
+
+        consumer.ack(delivery.as_ref()).await.unwrap();
+        assert_eq!(1, files(&dir.join("celery")).len());
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        assert_eq!(
+            second.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+        consumer.ack(delivery.as_ref()).await.unwrap();
+        assert!(files(&dir.join("celery")).is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requeue_unacked() {
+        let dir = temp_dir("requeue");
+        let broker = build(&dir, 10).await;
+        let message = message();
+        broker.send(&message, "celery").await.unwrap();
+
+        let (tag, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        deliveries.next().await.unwrap().ok().unwrap();
+        broker.cancel(&tag).await.unwrap();
+        drop(deliveries);
+
+        // A new consumer picks up the message which was never acknowledged.
+        let (_, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        assert_eq!(
+            message.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let dir = temp_dir("retry");
+        let broker = build(&dir, 10).await;
+        broker.send(&message(), "celery").await.unwrap();
+
+        let (_, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        broker.retry(delivery.as_ref(), None).await.unwrap();
+        broker.ack(delivery.as_ref()).await.unwrap();
+
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        let message = delivery.try_deserialize_message().unwrap();
+        assert_eq!(Some(1), message.headers.retries);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let dir = temp_dir("cancel");
+        let broker = build(&dir, 10).await;
+        let (tag, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        broker.cancel(&tag).await.unwrap();
+        assert!(deliveries.next().await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
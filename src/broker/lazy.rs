@@ -0,0 +1,245 @@
+//! Lazy broker connection.
+
+use crate::logging::error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::OnceCell;
+
+use super::{
+    build_and_connect, Broker, BrokerBuilder, ConnectionRetryPolicy, ConnectionState, Delivery,
+    DeliveryStream,
+};
+use crate::error::BrokerError;
+use crate::protocol::Message;
+
+#[cfg(test)]
+use std::any::Any;
+
+/// A [`Broker`] which only connects when it is first used, e.g. to send or consume a
+/// message, instead of when it is built.
+///
+/// The connection is established with the same retries as an eager connection. If it
+/// can't be established, the call which needed it fails and the next one tries again.
+pub struct LazyBroker {
+    builder: Box<dyn BrokerBuilder>,
+    connection_timeout: u32,
+    retry_policy: ConnectionRetryPolicy,
+    broker: OnceCell<Box<dyn Broker>>,
+}
+
+impl LazyBroker {
+    pub(crate) fn new(
+        builder: Box<dyn BrokerBuilder>,
+        connection_timeout: u32,
+        retry_policy: ConnectionRetryPolicy,
+    ) -> Self {
+        Self {
+            builder,
+            connection_timeout,
+            retry_policy,
+            broker: OnceCell::new(),
+        }
+    }
+
+    /// Get the underlying broker, connecting it first if needed.
+    async fn broker(&self) -> Result<&dyn Broker, BrokerError> {
+        let broker = self
+            .broker
+            .get_or_try_init(|| {
+                build_and_connect(&*self.builder, self.connection_timeout, &self.retry_policy)
+            })
+            .await?;
+        Ok(broker.as_ref())
+    }
+
+    /// Whether the connection was established.
+    pub fn is_connected(&self) -> bool {
+        self.broker.initialized()
+    }
+}
+
+#[async_trait]
+impl Broker for LazyBroker {
+    /// Return the redacted URL of the underlying broker once it is connected.
+    fn safe_url(&self) -> String {
+        match self.broker.get() {
+            Some(broker) => broker.safe_url(),
+            None => "(not connected yet)".into(),
+        }
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        match self.broker.get() {
+            Some(broker) => broker.connection_state(),
+            None => ConnectionState::NotConnected,
+        }
+    }
+
+    async fn consume(
+        &self,
+        queue: &str,
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        self.broker().await?.consume(queue, error_handler).await
+    }
+
+    async fn consume_with_priority(
+        &self,
+        queue: &str,
+        higher_priority_queues: &[&str],
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        self.broker()
+            .await?
+            .consume_with_priority(queue, higher_priority_queues, error_handler)
+            .await
+    }
+
+    async fn declare_queue(&self, queue: &str) -> Result<(), BrokerError> {
+        self.broker().await?.declare_queue(queue).await
+    }
+
+    async fn cancel(&self, consumer_tag: &str) -> Result<(), BrokerError> {
+        self.broker().await?.cancel(consumer_tag).await
+    }
+
+    async fn ack(&self, delivery: &dyn Delivery) -> Result<(), BrokerError> {
+        self.broker().await?.ack(delivery).await
+    }
+
+    async fn reject(&self, delivery: &dyn Delivery) -> Result<(), BrokerError> {
+        self.broker().await?.reject(delivery).await
+    }
+
+    async fn retry(
+        &self,
+        delivery: &dyn Delivery,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        self.broker().await?.retry(delivery, eta).await
+    }
+
+    async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError> {
+        self.broker().await?.send(message, queue).await
+    }
+
+    /// If the connection can't be established, the first send fails with the connection
+    /// error and the others as not connected.
+    async fn send_batch(&self, batch: &[(&Message, &str)]) -> Vec<Result<(), BrokerError>> {
+        match self.broker().await {
+            Ok(broker) => broker.send_batch(batch).await,
+            Err(err) => std::iter::once(err)
+                .chain(std::iter::repeat_with(|| BrokerError::NotConnected))
+                .take(batch.len())
+                .map(Err)
+                .collect(),
+        }
+    }
+
+    async fn queue_len(&self, queue: &str) -> Result<Option<usize>, BrokerError> {
+        self.broker().await?.queue_len(queue).await
+    }
+
+    async fn supports_scheduling(&self) -> bool {
+        match self.broker().await {
+            Ok(broker) => broker.supports_scheduling().await,
+            Err(_) => false,
+        }
+    }
+
+    async fn send_scheduled(
+        &self,
+        message: &Message,
+        queue: &str,
+        eta: DateTime<Utc>,
+    ) -> Result<(), BrokerError> {
+        self.broker()
+            .await?
+            .send_scheduled(message, queue, eta)
+            .await
+    }
+
+    async fn enqueue_due(&self) -> Result<usize, BrokerError> {
+        self.broker().await?.enqueue_due().await
+    }
+
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError> {
+        self.broker()
+            .await?
+            .send_to_exchange(message, exchange, routing_key)
+            .await
+    }
+
+    async fn increase_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.broker().await?.increase_prefetch_count().await
+    }
+
+    async fn decrease_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.broker().await?.decrease_prefetch_count().await
+    }
+
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError> {
+        self.broker()
+            .await?
+            .set_prefetch_count(prefetch_count)
+            .await
+    }
+
+    /// Get the prefetch count of the underlying broker, or 0 if it can't connect.
+    async fn prefetch_count(&self) -> u16 {
+        match self.broker().await {
+            Ok(broker) => broker.prefetch_count().await,
+            Err(err) => {
+                error!("Failed to establish connection with broker: {}", err);
+                0
+            }
+        }
+    }
+
+    /// Close the connection, if it was established.
+    async fn close(&self) -> Result<(), BrokerError> {
+        match self.broker.get() {
+            Some(broker) => broker.close().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Establish the connection if it wasn't yet, or re-establish it otherwise.
+    async fn reconnect(&self, connection_timeout: u32) -> Result<(), BrokerError> {
+        match self.broker.get() {
+            Some(broker) => broker.reconnect(connection_timeout).await,
+            None => self.broker().await.map(|_| ()),
+        }
+    }
+
+    #[cfg(test)]
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::InMemoryBrokerBuilder;
+
+    #[tokio::test]
+    async fn test_lazy_connect() {
+        let builder = Box::new(InMemoryBrokerBuilder::new("memory://test_lazy_connect"))
+            .declare_queue("celery");
+        let broker = LazyBroker::new(builder, 1, ConnectionRetryPolicy::default());
+        assert!(!broker.is_connected());
+        assert_eq!("(not connected yet)", broker.safe_url());
+        broker.close().await.unwrap();
+        assert!(!broker.is_connected());
+
+        broker.reconnect(1).await.unwrap();
+        assert!(broker.is_connected());
+        assert_eq!("memory://test_lazy_connect", broker.safe_url());
+    }
+}
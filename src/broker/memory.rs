@@ -0,0 +1,1108 @@
+//! In-memory broker.
+//!
+//! This broker doesn't need any external service, which makes it useful for tests and examples.
+//! Messages are kept in per-queue buffers which are shared by all brokers built with the
+//! same URL in the current process, so an app producing tasks and a worker consuming
+//! them can talk to each other as long as they live in the same process.
+//!
+//! Like with RabbitMQ, messages with a higher priority are delivered first from the queues
+//! declared with a maximum priority.
+//!
+//! Each consumer of a broadcast queue gets its own queue, which only lives as long as the
+//! consumer, and the messages sent to the broadcast queue are pushed to all of them.
+//!
+//! Messages [scheduled](super::Broker::send_scheduled) for later are kept by ETA until a
+//! worker moves them to their queues with [`enqueue_due`](super::Broker::enqueue_due).
+
+use crate::logging::debug;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use once_cell::sync::Lazy;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicU16, AtomicU8, Ordering},
+    Arc, Mutex as StdMutex,
+};
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use super::{
+    broadcast_consumer_queue, Broker, BrokerBuilder, DeliveryError, DeliveryStream, ExchangeKind,
+    Exchanges, QueueOptions,
+};
+use crate::error::{BrokerError, ProtocolError};
+use crate::protocol::{Message, TryDeserializeMessage};
+
+#[cfg(test)]
+use std::any::Any;
+
+/// The queues of all the in-memory "servers" in the current process, keyed by URL.
+static SERVERS: Lazy<StdMutex<HashMap<String, Arc<Server>>>> =
+    Lazy::new(|| StdMutex::new(HashMap::new()));
+
+/// Messages scheduled for later, by ETA and ID, along with their queues.
+type Scheduled = BTreeMap<(DateTime<Utc>, String), (String, Message)>;
+
+/// The state shared by all brokers connected to the same URL.
+#[derive(Default)]
+struct Server {
+    queues: StdMutex<HashMap<String, Arc<Queue>>>,
+    /// The queues of the consumers of each broadcast queue, by consumer tag.
+    subscribers: StdMutex<HashMap<String, HashMap<String, String>>>,
+    /// The scheduled messages.
+    scheduled: StdMutex<Scheduled>,
+}
+
+impl Server {
+    fn get(broker_url: &str) -> Arc<Server> {
+        SERVERS
+            .lock()
+            .unwrap()
+            .entry(broker_url.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn queue(&self, name: &str) -> Arc<Queue> {
+        self.queues
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Create the queue of a consumer of a broadcast queue.
+    fn subscribe(&self, broadcast_queue: &str, consumer_tag: &str) -> String {
+        let queue = broadcast_consumer_queue(broadcast_queue);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(broadcast_queue.into())
+            .or_default()
+            .insert(consumer_tag.into(), queue.clone());
+        queue
+    }
+
+    /// Delete the queue of a consumer, if it consumed from a broadcast queue.
+    fn unsubscribe(&self, consumer_tag: &str) {
+        for consumers in self.subscribers.lock().unwrap().values_mut() {
+            if let Some(queue) = consumers.remove(consumer_tag) {
+                self.queues.lock().unwrap().remove(&queue);
+            }
+        }
+    }
+
+    /// Take the scheduled messages whose ETA passed, earliest first.
+    fn take_due(&self, now: DateTime<Utc>) -> Vec<(String, Message)> {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        let later = scheduled.split_off(&(now, String::new()));
+        std::mem::replace(&mut *scheduled, later)
+            .into_values()
+            .collect()
+    }
+
+    /// Get the queues of the consumers of a broadcast queue.
+    fn subscriber_queues(&self, broadcast_queue: &str) -> Vec<Arc<Queue>> {
+        let queues: Vec<String> = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .get(broadcast_queue)
+            .map(|consumers| consumers.values().cloned().collect())
+            .unwrap_or_default();
+        queues.iter().map(|queue| self.queue(queue)).collect()
+    }
+}
+
+#[derive(Default, Debug)]
+struct Queue {
+    /// The messages of the queue by priority, highest first.
+    messages: StdMutex<BTreeMap<Reverse<u8>, VecDeque<Message>>>,
+    /// The maximum priority of the queue, 0 meaning that priorities are ignored.
+    max_priority: AtomicU8,
+    /// Notified when a message is pushed to the queue.
+    pushed: Notify,
+    /// The queue where rejected messages are moved to.
+    dead_letter_queue: StdMutex<Option<Arc<Queue>>>,
+}
+
+impl Queue {
+    fn push(&self, message: Message) {
+        let priority = message
+            .properties
+            .priority
+            .unwrap_or_default()
+            .min(self.max_priority.load(Ordering::SeqCst));
+        self.messages
+            .lock()
+            .unwrap()
+            .entry(Reverse(priority))
+            .or_default()
+            .push_back(message);
+        self.pushed.notify_one();
+    }
+
+    fn len(&self) -> usize {
+        self.messages
+            .lock()
+            .unwrap()
+            .values()
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    fn pop(&self) -> Option<Message> {
+        let mut messages = self.messages.lock().unwrap();
+        let mut entry = messages.first_entry()?;
+        let message = entry.get_mut().pop_front();
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        // Only one permit is stored when nobody is waiting, so pass it on to
+        // another consumer if there are more messages.
+        if !messages.is_empty() {
+            self.pushed.notify_one();
+        }
+        message
+    }
+}
+
+struct Config {
+    broker_url: String,
+    prefetch_count: u16,
+    prefetch_global: bool,
+    queues: HashSet<String>,
+    queue_options: HashMap<String, QueueOptions>,
+    default_queue_options: QueueOptions,
+    queue_max_priorities: HashMap<String, u8>,
+    exchanges: Exchanges,
+    broadcast_queues: HashSet<String>,
+}
+
+/// Builds an [`InMemoryBroker`] with a custom configuration.
+pub struct InMemoryBrokerBuilder {
+    config: Config,
+}
+
+#[async_trait]
+impl BrokerBuilder for InMemoryBrokerBuilder {
+    /// Create a new `InMemoryBrokerBuilder`.
+    fn new(broker_url: &str) -> Self {
+        Self {
+            config: Config {
+                broker_url: broker_url.into(),
+                prefetch_count: 10,
+                prefetch_global: true,
+                queues: HashSet::new(),
+                queue_options: HashMap::new(),
+                default_queue_options: QueueOptions::default(),
+                queue_max_priorities: HashMap::new(),
+                exchanges: Exchanges::default(),
+                broadcast_queues: HashSet::new(),
+            },
+        }
+    }
+
+    /// Set the worker [prefetch count](https://www.rabbitmq.com/confirms.html#channel-qos-prefetch),
+    /// i.e. the maximum number of unacknowledged deliveries. A value of 0 means no limit.
+    fn prefetch_count(mut self: Box<Self>, prefetch_count: u16) -> Box<dyn BrokerBuilder> {
+        self.config.prefetch_count = prefetch_count;
+        self
+    }
+
+    /// Set whether the prefetch count is shared by all the consumers, or applies to each
+    /// consumer on its own.
+    fn prefetch_global(mut self: Box<Self>, global: bool) -> Box<dyn BrokerBuilder> {
+        self.config.prefetch_global = global;
+        self
+    }
+
+    /// Declare a queue.
+    fn declare_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(name.into());
+        self
+    }
+
+    /// Declare a queue with the given options. Queues only live in memory with this broker,
+    /// so only `dead_letter_queue` has an effect: rejected messages are moved to that queue.
+    fn declare_queue_with_options(
+        mut self: Box<Self>,
+        name: &str,
+        options: QueueOptions,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(name.into());
+        self.config.queue_options.insert(name.into(), options);
+        self
+    }
+
+    /// Set the options of the queues declared without explicit options.
+    fn default_queue_options(mut self: Box<Self>, options: QueueOptions) -> Box<dyn BrokerBuilder> {
+        self.config.default_queue_options = options;
+        self
+    }
+
+    /// Declare a broadcast queue.
+    fn declare_broadcast_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.config.broadcast_queues.insert(name.into());
+        self
+    }
+
+    /// Set the maximum priority of a queue.
+    fn queue_max_priority(
+        mut self: Box<Self>,
+        name: &str,
+        max_priority: u8,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config
+            .queue_max_priorities
+            .insert(name.into(), max_priority);
+        self
+    }
+
+    /// Declare an exchange. The messages sent to it are routed to the queues bound to it
+    /// by the sender, according to the bindings declared on its own builder. The `durable`
+    /// flag has no effect.
+    #[allow(unused)]
+    fn declare_exchange(
+        mut self: Box<Self>,
+        name: &str,
+        kind: ExchangeKind,
+        durable: bool,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.exchanges.declare(name, kind);
+        self
+    }
+
+    /// Bind a queue to an exchange with a binding key.
+    fn bind_queue(
+        mut self: Box<Self>,
+        queue: &str,
+        exchange: &str,
+        binding_key: &str,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(queue.into());
+        self.config.exchanges.bind(queue, exchange, binding_key);
+        self
+    }
+
+    /// This has no effect, since there is no connection to keep alive.
+    #[allow(unused)]
+    fn heartbeat(self: Box<Self>, heartbeat: Option<u16>) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Delayed delivery isn't supported by this broker, so this has no effect.
+    #[allow(unused)]
+    fn delayed_delivery(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages are always stored by the time they are sent with this broker,
+    /// so this has no effect.
+    #[allow(unused)]
+    fn publisher_confirms(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// See [`publisher_confirms`](Self::publisher_confirms).
+    #[allow(unused)]
+    fn confirm_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages don't outlive the process with this broker, so this has no effect.
+    #[allow(unused)]
+    fn visibility_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages are sent without going through a connection with this broker, so this has
+    /// no effect.
+    #[allow(unused)]
+    fn pool_limit(self: Box<Self>, limit: u16) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages are sent without going through a connection with this broker, so this has
+    /// no effect.
+    #[allow(unused)]
+    fn separate_producer_connection(self: Box<Self>, separate: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Build an `InMemoryBroker`.
+    #[allow(unused)]
+    async fn build(&self, connection_timeout: u32) -> Result<Box<dyn Broker>, BrokerError> {
+        let server = Server::get(&self.config.broker_url);
+        let mut queue_prefetch_counts = HashMap::new();
+        for queue in &self.config.queues {
+            let options = self
+                .config
+                .queue_options
+                .get(queue)
+                .unwrap_or(&self.config.default_queue_options);
+            let dead_letter_queue = options
+                .dead_letter_queue
+                .as_ref()
+                .map(|dead_letter_queue| server.queue(dead_letter_queue));
+            *server.queue(queue).dead_letter_queue.lock().unwrap() = dead_letter_queue;
+            if let Some(prefetch_count) = options.prefetch_count {
+                queue_prefetch_counts.insert(queue.clone(), prefetch_count);
+            }
+        }
+        for (queue, max_priority) in &self.config.queue_max_priorities {
+            server
+                .queue(queue)
+                .max_priority
+                .store(*max_priority, Ordering::SeqCst);
+        }
+
+        Ok(Box::new(InMemoryBroker {
+            uri: self.config.broker_url.clone(),
+            server,
+            exchanges: self.config.exchanges.clone(),
+            broadcast_queues: self.config.broadcast_queues.clone(),
+            prefetch_count: Arc::new(AtomicU16::new(self.config.prefetch_count)),
+            prefetch_global: self.config.prefetch_global,
+            queue_prefetch_counts,
+            pending_tasks: Arc::new(AtomicU16::new(0)),
+            acked: Arc::new(Notify::new()),
+            consumers: StdMutex::new(HashMap::new()),
+        }))
+    }
+}
+
+/// An in-memory [`Broker`], which can be created with a `memory://` URL.
+pub struct InMemoryBroker {
+    uri: String,
+    server: Arc<Server>,
+    exchanges: Exchanges,
+    broadcast_queues: HashSet<String>,
+    prefetch_count: Arc<AtomicU16>,
+    /// Whether `prefetch_count` limits the deliveries of all the consumers together.
+    prefetch_global: bool,
+    /// The prefetch counts of the consumers of some queues, by queue name.
+    queue_prefetch_counts: HashMap<String, u16>,
+    pending_tasks: Arc<AtomicU16>,
+    /// Notified when a delivery is acknowledged or the prefetch count changes,
+    /// so that consumers waiting on the prefetch limit can check it again.
+    acked: Arc<Notify>,
+    /// Cancellation signals of the consumers, by consumer tag.
+    consumers: StdMutex<HashMap<String, Arc<Notify>>>,
+}
+
+/// A message delivered by an [`InMemoryBroker`].
+#[derive(Clone, Debug)]
+pub struct InMemoryDelivery {
+    message: Message,
+    queue: String,
+    dead_letter_queue: Option<Arc<Queue>>,
+    /// The number of unacknowledged deliveries of the consumer.
+    consumer_pending_tasks: Arc<AtomicU16>,
+}
+
+impl InMemoryDelivery {
+    /// Release the prefetch slot of the delivery in its consumer.
+    fn release(&self) {
+        release_slot(&self.consumer_pending_tasks);
+    }
+}
+
+/// Release a prefetch slot. Saturate at zero, since a delivery could be acknowledged more
+/// than once.
+fn release_slot(pending_tasks: &AtomicU16) {
+    pending_tasks
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+            pending.checked_sub(1)
+        })
+        .ok();
+}
+
+impl TryDeserializeMessage for InMemoryDelivery {
+    fn try_deserialize_message(&self) -> Result<Message, ProtocolError> {
+        Ok(self.message.clone())
+    }
+}
+
+#[async_trait]
+impl super::Delivery for InMemoryDelivery {
+    async fn resend(
+        &self,
+        broker: &dyn Broker,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        let mut message = self.message.clone();
+        message.headers.eta = eta;
+        // Increment the number of retries.
+        message.headers.retries = Some(message.headers.retries.map_or(1, |retry| retry + 1));
+        broker.send(&message, &self.queue).await
+    }
+
+    /// The message was removed from the queue when it was delivered, so this is a no-op.
+    async fn remove(&self) -> Result<(), BrokerError> {
+        Ok(())
+    }
+
+    /// The message was removed from the queue when it was delivered, so this only releases
+    /// its prefetch slot in its consumer.
+    async fn ack(&self) -> Result<(), BrokerError> {
+        self.release();
+        Ok(())
+    }
+
+    /// Move the message to the dead-letter queue, if there is one.
+    async fn reject(&self) -> Result<(), BrokerError> {
+        self.release();
+        if let Some(dead_letter_queue) = &self.dead_letter_queue {
+            dead_letter_queue.push(self.message.clone());
+        }
+        Ok(())
+    }
+}
+
+type DeliveryResult = Result<Box<dyn super::Delivery>, Box<dyn DeliveryError>>;
+
+struct Consumer {
+    wrapped: Pin<Box<dyn Stream<Item = DeliveryResult> + Send>>,
+}
+
+impl DeliveryStream for Consumer {}
+
+impl Stream for Consumer {
+    type Item = DeliveryResult;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.wrapped.as_mut().poll_next(cx)
+    }
+}
+
+struct ConsumerState {
+    queue: Arc<Queue>,
+    queue_name: String,
+    prefetch_count: Arc<AtomicU16>,
+    prefetch_global: bool,
+    /// The prefetch count of the queue, which limits this consumer on its own.
+    queue_prefetch_count: Option<u16>,
+    pending_tasks: Arc<AtomicU16>,
+    consumer_pending_tasks: Arc<AtomicU16>,
+    acked: Arc<Notify>,
+    cancelled: Arc<Notify>,
+}
+
+impl ConsumerState {
+    fn prefetch_limit_reached(&self) -> bool {
+        let prefetch_count = self.prefetch_count.load(Ordering::SeqCst);
+        let limit_reached = |limit: u16, pending_tasks: &AtomicU16| {
+            limit > 0 && pending_tasks.load(Ordering::SeqCst) >= limit
+        };
+        let consumer_prefetch_count = match self.queue_prefetch_count {
+            Some(queue_prefetch_count) => queue_prefetch_count,
+            None if !self.prefetch_global => prefetch_count,
+            None => 0,
+        };
+        (self.prefetch_global && limit_reached(prefetch_count, &self.pending_tasks))
+            || limit_reached(consumer_prefetch_count, &self.consumer_pending_tasks)
+    }
+
+    /// Wait for the next message, returning `None` if the consumer has been cancelled.
+    async fn next_delivery(&self) -> Option<InMemoryDelivery> {
+        loop {
+            let acked = self.acked.notified();
+            if self.prefetch_limit_reached() {
+                debug!("Pending tasks limit reached");
+                tokio::select! {
+                    _ = acked => continue,
+                    _ = self.cancelled.notified() => return None,
+                }
+            }
+
+            if let Some(message) = self.queue.pop() {
+                self.pending_tasks.fetch_add(1, Ordering::SeqCst);
+                self.consumer_pending_tasks.fetch_add(1, Ordering::SeqCst);
+                return Some(InMemoryDelivery {
+                    message,
+                    queue: self.queue_name.clone(),
+                    dead_letter_queue: self.queue.dead_letter_queue.lock().unwrap().clone(),
+                    consumer_pending_tasks: self.consumer_pending_tasks.clone(),
+                });
+            }
+            tokio::select! {
+                _ = self.queue.pushed.notified() => {},
+                _ = self.cancelled.notified() => return None,
+            }
+        }
+    }
+}
+
+impl InMemoryBroker {
+    fn wake_consumers(&self) {
+        self.acked.notify_waiters();
+    }
+
+    /// Release the prefetch slot of an acknowledged or rejected delivery.
+    fn settle_delivery(&self) {
+        release_slot(&self.pending_tasks);
+        self.wake_consumers();
+    }
+}
+
+#[async_trait]
+impl Broker for InMemoryBroker {
+    fn safe_url(&self) -> String {
+        self.uri.clone()
+    }
+
+    #[allow(unused)]
+    async fn consume(
+        &self,
+        queue: &str,
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        // Create unique consumer tag.
+        let mut buffer = Uuid::encode_buffer();
+        let uuid = Uuid::new_v4().hyphenated().encode_lower(&mut buffer);
+        let consumer_tag = uuid.to_owned();
+
+        let cancelled = Arc::new(Notify::new());
+        self.consumers
+            .lock()
+            .unwrap()
+            .insert(consumer_tag.clone(), cancelled.clone());
+
+        let queue_name = if self.broadcast_queues.contains(queue) {
+            self.server.subscribe(queue, &consumer_tag)
+        } else {
+            queue.into()
+        };
+        let state = ConsumerState {
+            queue: self.server.queue(&queue_name),
+            queue_prefetch_count: self.queue_prefetch_counts.get(&queue_name).copied(),
+            queue_name,
+            prefetch_count: self.prefetch_count.clone(),
+            prefetch_global: self.prefetch_global,
+            pending_tasks: self.pending_tasks.clone(),
+            consumer_pending_tasks: Arc::new(AtomicU16::new(0)),
+            acked: self.acked.clone(),
+            cancelled,
+        };
+        let wrapped = stream::unfold(state, |state| async move {
+            let delivery = state.next_delivery().await?;
+            Some((Ok(Box::new(delivery) as Box<dyn super::Delivery>), state))
+        });
+
+        Ok((
+            consumer_tag,
+            Box::new(Consumer {
+                wrapped: Box::pin(wrapped),
+            }),
+        ))
+    }
+
+    async fn cancel(&self, consumer_tag: &str) -> Result<(), BrokerError> {
+        if let Some(cancelled) = self.consumers.lock().unwrap().remove(consumer_tag) {
+            // `notify_one` stores a permit, so the consumer is cancelled even
+            // if it isn't waiting for a message right now.
+            cancelled.notify_one();
+        }
+        self.server.unsubscribe(consumer_tag);
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.ack().await?;
+        self.settle_delivery();
+        Ok(())
+    }
+
+    async fn reject(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.reject().await?;
+        self.settle_delivery();
+        Ok(())
+    }
+
+    async fn retry(
+        &self,
+        delivery: &dyn super::Delivery,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        delivery.resend(self, eta).await
+    }
+
+    async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError> {
+        if self.broadcast_queues.contains(queue) {
+            for queue in self.server.subscriber_queues(queue) {
+                queue.push(message.clone());
+            }
+        } else {
+            self.server.queue(queue).push(message.clone());
+        }
+        Ok(())
+    }
+
+    async fn queue_len(&self, queue: &str) -> Result<Option<usize>, BrokerError> {
+        if self.broadcast_queues.contains(queue) {
+            return Ok(None);
+        }
+        Ok(Some(self.server.queue(queue).len()))
+    }
+
+    async fn supports_scheduling(&self) -> bool {
+        true
+    }
+
+    /// Keep a message until it is due, unless it is sent to a broadcast queue, in which case
+    /// it is sent right away.
+    async fn send_scheduled(
+        &self,
+        message: &Message,
+        queue: &str,
+        eta: DateTime<Utc>,
+    ) -> Result<(), BrokerError> {
+        if self.broadcast_queues.contains(queue) {
+            return self.send(message, queue).await;
+        }
+        self.server.scheduled.lock().unwrap().insert(
+            (eta, message.task_id().into()),
+            (queue.into(), message.clone()),
+        );
+        Ok(())
+    }
+
+    async fn enqueue_due(&self) -> Result<usize, BrokerError> {
+        let due = self.server.take_due(Utc::now());
+        for (queue, message) in &due {
+            self.server.queue(queue).push(message.clone());
+        }
+        Ok(due.len())
+    }
+
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError> {
+        for queue in self.exchanges.route(exchange, routing_key)? {
+            self.send(message, queue).await?;
+        }
+        Ok(())
+    }
+
+    async fn increase_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                // A prefetch count of 0 means there is no limit.
+                if count > 0 {
+                    count.checked_add(1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        self.wake_consumers();
+        Ok(())
+    }
+
+    async fn decrease_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count > 1 {
+                    Some(count - 1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        Ok(())
+    }
+
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError> {
+        self.prefetch_count.store(prefetch_count, Ordering::SeqCst);
+        self.wake_consumers();
+        Ok(())
+    }
+
+    async fn prefetch_count(&self) -> u16 {
+        self.prefetch_count.load(Ordering::SeqCst)
+    }
+
+    async fn close(&self) -> Result<(), BrokerError> {
+        let tags: Vec<String> = self.consumers.lock().unwrap().keys().cloned().collect();
+        for tag in tags {
+            self.cancel(&tag).await?;
+        }
+        Ok(())
+    }
+
+    /// There is no connection to re-establish, so this is a no-op.
+    #[allow(unused)]
+    async fn reconnect(&self, connection_timeout: u32) -> Result<(), BrokerError> {
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MessageContentType;
+    use crate::task::{Request, Signature, Task, TaskOptions, TaskResult};
+    use futures::StreamExt;
+    use std::convert::TryFrom;
+    use tokio::time::{self, Duration};
+
+    #[derive(Clone)]
+    struct NoopTask {
+        request: Request<Self>,
+        options: TaskOptions,
+    }
+
+    #[async_trait]
+    impl Task for NoopTask {
+        const NAME: &'static str = "noop";
+        const ARGS: &'static [&'static str] = &[];
+        const DEFAULTS: TaskOptions = TaskOptions {
+            time_limit: None,
+            hard_time_limit: None,
+            soft_time_limit: None,
+            max_retries: None,
+            min_retry_delay: None,
+            max_retry_delay: None,
+            retry_backoff: None,
+            retry_backoff_max: None,
+            retry_jitter: None,
+            retry_for_unexpected: None,
+            acks_late: None,
+            reject_on_worker_lost: None,
+            content_type: Some(MessageContentType::Json),
+            compression: None,
+            compression_threshold: None,
+            priority: None,
+            delivery_mode: None,
+            rate_limit: None,
+            dedup: None,
+            dedup_ttl: None,
+            ignore_result: None,
+        };
+
+        type Params = ();
+        type Returns = ();
+
+        fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+            Self { request, options }
+        }
+
+        fn request(&self) -> &Request<Self> {
+            &self.request
+        }
+
+        fn options(&self) -> &TaskOptions {
+            &self.options
+        }
+
+        async fn run(&self, _params: Self::Params) -> TaskResult<Self::Returns> {
+            Ok(())
+        }
+    }
+
+    async fn build(url: &str, prefetch_count: u16) -> Box<dyn Broker> {
+        Box::new(InMemoryBrokerBuilder::new(url))
+            .prefetch_count(prefetch_count)
+            .declare_queue("celery")
+            .build(0)
+            .await
+            .unwrap()
+    }
+
+    fn message() -> Message {
+        Message::try_from(Signature::<NoopTask>::new(())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send_and_consume() {
+        let producer = build("memory://test_send_and_consume", 0).await;
+        let consumer = build("memory://test_send_and_consume", 0).await;
+        let message = message();
+        producer.send(&message, "celery").await.unwrap();
+
+        let (_, mut deliveries) = consumer.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        let received = delivery.try_deserialize_message().unwrap();
+        assert_eq!(message.task_id(), received.task_id());
+        consumer.ack(&*delivery).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_count() {
+        let broker = build("memory://test_prefetch_count", 1).await;
+        broker.send(&message(), "celery").await.unwrap();
+        broker.send(&message(), "celery").await.unwrap();
+
+        let (_, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        assert!(time::timeout(Duration::from_millis(50), deliveries.next())
+            .await
+            .is_err());
+
+        broker.ack(&*delivery).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(50), deliveries.next())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_set_prefetch_count() {
+        let broker = build("memory://test_set_prefetch_count", 1).await;
+        broker.send(&message(), "celery").await.unwrap();
+        broker.send(&message(), "celery").await.unwrap();
+
+        let (_, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        deliveries.next().await.unwrap().ok().unwrap();
+        assert!(time::timeout(Duration::from_millis(50), deliveries.next())
+            .await
+            .is_err());
+
+        broker.set_prefetch_count(2).await.unwrap();
+        assert_eq!(2, broker.prefetch_count().await);
+        assert!(time::timeout(Duration::from_millis(50), deliveries.next())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queue_prefetch_count() {
+        let broker = Box::new(InMemoryBrokerBuilder::new(
+            "memory://test_queue_prefetch_count",
+        ))
+        .prefetch_count(10)
+        .declare_queue_with_options(
+            "backlog",
+            QueueOptions {
+                prefetch_count: Some(5),
+                ..Default::default()
+            },
+        )
+        .declare_queue("other")
+        .build(0)
+        .await
+        .unwrap();
+        for _ in 0..1000 {
+            broker.send(&message(), "backlog").await.unwrap();
+        }
+        broker.send(&message(), "other").await.unwrap();
+
+        // Consume both queues at once like a worker does, without acknowledging anything.
+        let mut deliveries = tokio_stream::StreamMap::new();
+        for queue in &["backlog", "other"] {
+            let (_, consumer) = broker.consume(queue, Box::new(|_| {})).await.unwrap();
+            deliveries.insert(*queue, consumer);
+        }
+        let mut received = HashMap::new();
+        while let Ok(Some((queue, delivery))) =
+            time::timeout(Duration::from_millis(50), deliveries.next()).await
+        {
+            assert!(delivery.is_ok());
+            *received.entry(queue).or_insert(0) += 1;
+        }
+        // The backlog only takes the slots of its own prefetch count.
+        assert_eq!(Some(&5), received.get("backlog"));
+        assert_eq!(Some(&1), received.get("other"));
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_not_global() {
+        let broker = Box::new(InMemoryBrokerBuilder::new(
+            "memory://test_prefetch_not_global",
+        ))
+        .prefetch_count(1)
+        .prefetch_global(false)
+        .declare_queue("first")
+        .declare_queue("second")
+        .build(0)
+        .await
+        .unwrap();
+        for queue in &["first", "first", "second"] {
+            broker.send(&message(), queue).await.unwrap();
+        }
+
+        let (_, mut first) = broker.consume("first", Box::new(|_| {})).await.unwrap();
+        let (_, mut second) = broker.consume("second", Box::new(|_| {})).await.unwrap();
+        let delivery = first.next().await.unwrap().ok().unwrap();
+        assert!(time::timeout(Duration::from_millis(50), first.next())
+            .await
+            .is_err());
+        // The unacknowledged delivery of the first consumer doesn't hold the second one.
+        assert!(time::timeout(Duration::from_millis(50), second.next())
+            .await
+            .is_ok());
+
+        broker.ack(&*delivery).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(50), first.next())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let broker = build("memory://test_retry", 0).await;
+        broker.send(&message(), "celery").await.unwrap();
+
+        let (_, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        broker.retry(&*delivery, None).await.unwrap();
+        broker.ack(&*delivery).await.unwrap();
+
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        let message = delivery.try_deserialize_message().unwrap();
+        assert_eq!(Some(1), message.headers.retries);
+    }
+
+    #[tokio::test]
+    async fn test_send_scheduled() {
+        let broker = build("memory://test_send_scheduled", 0).await;
+        let due = message();
+        let later = message();
+        broker
+            .send_scheduled(&later, "celery", Utc::now() + chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        broker
+            .send_scheduled(&due, "celery", Utc::now() - chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(Some(0), broker.queue_len("celery").await.unwrap());
+
+        // Only the message which is due is moved to its queue.
+        assert_eq!(1, broker.enqueue_due().await.unwrap());
+        assert_eq!(0, broker.enqueue_due().await.unwrap());
+        let (_, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        let message = delivery.try_deserialize_message().unwrap();
+        assert_eq!(due.task_id(), message.task_id());
+        assert_eq!(Some(0), broker.queue_len("celery").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_send_to_exchange() {
+        let broker = Box::new(InMemoryBrokerBuilder::new("memory://test_send_to_exchange"))
+            .prefetch_count(0)
+            .declare_exchange("events", ExchangeKind::Topic, true)
+            .bind_queue("audit", "events", "events.#")
+            .bind_queue("users", "events", "events.user.*")
+            .build(0)
+            .await
+            .unwrap();
+        let message = message();
+        broker
+            .send_to_exchange(&message, "events", "events.order.paid")
+            .await
+            .unwrap();
+
+        let (_, mut deliveries) = broker.consume("audit", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        assert_eq!(
+            message.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+        let (_, mut deliveries) = broker.consume("users", Box::new(|_| {})).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(50), deliveries.next())
+            .await
+            .is_err());
+
+        assert!(matches!(
+            broker.send_to_exchange(&message, "missing", "key").await,
+            Err(BrokerError::UnknownExchange(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_queue() {
+        let build = || async {
+            Box::new(InMemoryBrokerBuilder::new("memory://test_broadcast_queue"))
+                .prefetch_count(0)
+                .declare_broadcast_queue("reload")
+                .build(0)
+                .await
+                .unwrap()
+        };
+        let (worker1, worker2) = (build().await, build().await);
+        let (_, mut deliveries1) = worker1.consume("reload", Box::new(|_| {})).await.unwrap();
+        let (_, mut deliveries2) = worker2.consume("reload", Box::new(|_| {})).await.unwrap();
+        let message = message();
+        worker1.send(&message, "reload").await.unwrap();
+
+        // Each worker gets its own copy of the message.
+        for deliveries in [&mut deliveries1, &mut deliveries2] {
+            let delivery = deliveries.next().await.unwrap().ok().unwrap();
+            assert_eq!(
+                message.task_id(),
+                delivery.try_deserialize_message().unwrap().task_id()
+            );
+        }
+
+        // Retries only go back to the worker which retried the message.
+        let delivery = {
+            worker1.send(&message, "reload").await.unwrap();
+            deliveries2.next().await.unwrap().ok().unwrap();
+            deliveries1.next().await.unwrap().ok().unwrap()
+        };
+        worker1.retry(&*delivery, None).await.unwrap();
+        let retried = deliveries1.next().await.unwrap().ok().unwrap();
+        assert_eq!(
+            Some(1),
+            retried.try_deserialize_message().unwrap().headers.retries
+        );
+        assert!(time::timeout(Duration::from_millis(50), deliveries2.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let broker = build("memory://test_cancel", 0).await;
+        let (consumer_tag, mut deliveries) =
+            broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        broker.cancel(&consumer_tag).await.unwrap();
+        assert!(deliveries.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let broker = Box::new(InMemoryBrokerBuilder::new("memory://test_priority"))
+            .prefetch_count(0)
+            .queue_max_priority("celery", 5)
+            .declare_queue("celery")
+            .build(0)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            broker.send(&message(), "celery").await.unwrap();
+        }
+        let urgent = Message::try_from(Signature::<NoopTask>::new(()).with_priority(9)).unwrap();
+        broker.send(&urgent, "celery").await.unwrap();
+
+        // The urgent message overtakes the backlog.
+        let (_, mut deliveries) = broker.consume("celery", Box::new(|_| {})).await.unwrap();
+        let delivery = deliveries.next().await.unwrap().ok().unwrap();
+        assert_eq!(
+            urgent.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+        for _ in 0..3 {
+            let delivery = deliveries.next().await.unwrap().ok().unwrap();
+            assert_ne!(
+                urgent.task_id(),
+                delivery.try_deserialize_message().unwrap().task_id()
+            );
+        }
+    }
+}
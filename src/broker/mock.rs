@@ -10,6 +10,10 @@ use futures::{
     Stream,
 };
 use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU16, Ordering},
+    Arc,
+};
 use std::time::SystemTime;
 use tokio::sync::RwLock;
 
@@ -51,8 +55,14 @@ pub struct MockBroker {
     /// Holds a mapping of all sent tasks.
     ///
     /// The keys are the task IDs, and the values are tuples of the message object,
-    /// queue it was sent to, and time it was sent.
+    /// queue it was sent to, and time it was sent. Messages sent to an exchange are
+    /// recorded with `"<exchange>/<routing_key>"` as their queue.
     pub sent_tasks: RwLock<HashMap<String, (Message, String, SystemTime)>>,
+
+    /// When set, sending fails as if the message had not been confirmed by the broker.
+    pub nack_sends: Arc<AtomicBool>,
+
+    prefetch_count: AtomicU16,
 }
 
 impl MockBroker {
@@ -90,6 +100,11 @@ impl Broker for MockBroker {
         Ok(())
     }
 
+    #[allow(unused)]
+    async fn reject(&self, delivery: &dyn Delivery) -> Result<(), BrokerError> {
+        Ok(())
+    }
+
     #[allow(unused)]
     async fn retry(
         &self,
@@ -101,6 +116,9 @@ impl Broker for MockBroker {
 
     #[allow(unused)]
     async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError> {
+        if self.nack_sends.load(Ordering::SeqCst) {
+            return Err(BrokerError::PublishNack(message.task_id().into()));
+        }
         self.sent_tasks.write().await.insert(
             message.task_id().into(),
             (message.clone(), queue.into(), SystemTime::now()),
@@ -108,6 +126,16 @@ impl Broker for MockBroker {
         Ok(())
     }
 
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError> {
+        self.send(message, &format!("{}/{}", exchange, routing_key))
+            .await
+    }
+
     async fn increase_prefetch_count(&self) -> Result<(), BrokerError> {
         Ok(())
     }
@@ -116,6 +144,15 @@ impl Broker for MockBroker {
         Ok(())
     }
 
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError> {
+        self.prefetch_count.store(prefetch_count, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn prefetch_count(&self) -> u16 {
+        self.prefetch_count.load(Ordering::SeqCst)
+    }
+
     async fn close(&self) -> Result<(), BrokerError> {
         Ok(())
     }
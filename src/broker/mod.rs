@@ -1,22 +1,57 @@
 //! The broker is an integral part of a [`Celery`](crate::Celery) app. It provides the transport for messages that
 //! encode tasks.
 
+use crate::logging::{error, warn};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use futures::Stream;
-use log::error;
-use tokio::time::{self, Duration};
+use tokio::time;
+use uuid::Uuid;
 
 use crate::error::BrokerError;
 use crate::{
     protocol::{Message, TryDeserializeMessage},
-    routing::Rule,
+    routing::{Destination, Rule},
+    urls::expand_env_vars,
 };
 
 mod amqp;
+mod backoff;
+mod exchange;
+mod failover;
+mod filesystem;
+mod lazy;
+mod memory;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "pubsub")]
+mod pubsub;
 mod redis;
-pub use self::redis::{RedisBroker, RedisBrokerBuilder};
+mod registry;
+mod scheduling;
+#[cfg(feature = "servicebus")]
+mod servicebus;
+pub use self::redis::{RedisBroker, RedisBrokerBuilder, RedisTransport};
 pub use amqp::{AMQPBroker, AMQPBrokerBuilder};
+pub(crate) use backoff::Backoff;
+pub use backoff::{ConnectionRetryPolicy, PublishRetryPolicy};
+pub use exchange::ExchangeKind;
+pub(crate) use exchange::Exchanges;
+pub use failover::{FailoverBroker, FailoverBrokerBuilder};
+pub use filesystem::{FilesystemBroker, FilesystemBrokerBuilder, FilesystemDelivery};
+pub use lazy::LazyBroker;
+pub use memory::{InMemoryBroker, InMemoryBrokerBuilder, InMemoryDelivery};
+#[cfg(feature = "nats")]
+pub use nats::{NatsBroker, NatsBrokerBuilder, NatsDelivery};
+#[cfg(feature = "pubsub")]
+pub use pubsub::{PubSubBroker, PubSubBrokerBuilder, PubSubDelivery};
+pub use registry::{register_broker_scheme, registered_broker_schemes, BrokerBuilderFactory};
+pub(crate) use scheduling::QueueStreams;
+pub use scheduling::{EtaStrategy, QueueStrategy};
+#[cfg(feature = "servicebus")]
+pub use servicebus::{
+    AzureServiceBusBroker, AzureServiceBusBrokerBuilder, AzureServiceBusDelivery,
+};
 
 #[cfg(test)]
 pub mod mock;
@@ -33,6 +68,7 @@ pub trait Delivery: TryDeserializeMessage + Send + Sync + std::fmt::Debug {
     ) -> Result<(), BrokerError>;
     async fn remove(&self) -> Result<(), BrokerError>;
     async fn ack(&self) -> Result<(), BrokerError>;
+    async fn reject(&self) -> Result<(), BrokerError>;
 }
 
 /// The error type of an unsuccessful delivery.
@@ -65,12 +101,43 @@ pub trait Broker: Send + Sync {
         error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
     ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError>;
 
+    /// Consume messages from a queue which has a lower priority than `higher_priority_queues`
+    /// (see [`QueueStrategy::Priority`]).
+    ///
+    /// Brokers which can leave the messages of the queue in it while the queues with a
+    /// higher priority have messages override this. By default the queue is consumed like
+    /// with [`consume`](Broker::consume).
+    #[allow(unused_variables)]
+    async fn consume_with_priority(
+        &self,
+        queue: &str,
+        higher_priority_queues: &[&str],
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        self.consume(queue, error_handler).await
+    }
+
+    /// Declare a queue after connecting, e.g. to start consuming from a queue which wasn't
+    /// declared with the [`BrokerBuilder`]. Queues which are already declared are left as
+    /// they are.
+    ///
+    /// Brokers which need queues to be declared before consuming from them override this.
+    /// By default it does nothing.
+    #[allow(unused_variables)]
+    async fn declare_queue(&self, queue: &str) -> Result<(), BrokerError> {
+        Ok(())
+    }
+
     /// Cancel the consumer with the given `consumer_tag`.
     async fn cancel(&self, consumer_tag: &str) -> Result<(), BrokerError>;
 
     /// Acknowledge a [`Delivery`](trait.Broker.html#associatedtype.Delivery) for deletion.
     async fn ack(&self, delivery: &dyn Delivery) -> Result<(), BrokerError>;
 
+    /// Reject a delivery without requeuing it, so that it is moved to the dead-letter
+    /// queue of its queue if there is one (see [`QueueOptions::dead_letter_queue`]).
+    async fn reject(&self, delivery: &dyn Delivery) -> Result<(), BrokerError>;
+
     /// Retry a delivery.
     async fn retry(
         &self,
@@ -81,6 +148,69 @@ pub trait Broker: Send + Sync {
     /// Send a [`Message`](protocol/struct.Message.html) into a queue.
     async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError>;
 
+    /// Send a batch of [`Message`](protocol/struct.Message.html)s, each into its queue, and
+    /// return the outcome of each send in the same order.
+    ///
+    /// Brokers which can pipeline their sends override this, e.g. to publish all the
+    /// messages before waiting for their confirmations. By default the messages are sent
+    /// one after the other.
+    async fn send_batch(&self, batch: &[(&Message, &str)]) -> Vec<Result<(), BrokerError>> {
+        let mut results = Vec::with_capacity(batch.len());
+        for (message, queue) in batch {
+            results.push(self.send(message, queue).await);
+        }
+        results
+    }
+
+    /// Get the number of messages waiting in a queue, or `None` if the broker can't tell,
+    /// e.g. for broadcast queues.
+    ///
+    /// Brokers which can count the messages of their queues override this. By default the
+    /// length is unknown.
+    #[allow(unused_variables)]
+    async fn queue_len(&self, queue: &str) -> Result<Option<usize>, BrokerError> {
+        Ok(None)
+    }
+
+    /// Whether the broker can keep messages until they are due with
+    /// [`send_scheduled`](Broker::send_scheduled) (see [`EtaStrategy::Schedule`]). By
+    /// default it can't.
+    async fn supports_scheduling(&self) -> bool {
+        false
+    }
+
+    /// Send a [`Message`](protocol/struct.Message.html) into a queue once its `eta` passed,
+    /// keeping it in the broker until then.
+    ///
+    /// Brokers which can keep messages override this along with
+    /// [`supports_scheduling`](Broker::supports_scheduling), and with
+    /// [`enqueue_due`](Broker::enqueue_due) if the workers have to move the messages which
+    /// are due to their queues. By default the message is sent right away.
+    #[allow(unused_variables)]
+    async fn send_scheduled(
+        &self,
+        message: &Message,
+        queue: &str,
+        eta: DateTime<Utc>,
+    ) -> Result<(), BrokerError> {
+        self.send(message, queue).await
+    }
+
+    /// Move the messages kept with [`send_scheduled`](Broker::send_scheduled) which are due
+    /// to their queues, and return how many were moved. By default there are none.
+    async fn enqueue_due(&self) -> Result<usize, BrokerError> {
+        Ok(0)
+    }
+
+    /// Publish a [`Message`](protocol/struct.Message.html) to an exchange, which routes it
+    /// to the queues bound to it according to the `routing_key`.
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError>;
+
     /// Increase the `prefetch_count`. This has to be done when a task with a future
     /// ETA is consumed.
     async fn increase_prefetch_count(&self) -> Result<(), BrokerError>;
@@ -89,19 +219,142 @@ pub trait Broker: Send + Sync {
     /// ETA is executed.
     async fn decrease_prefetch_count(&self) -> Result<(), BrokerError>;
 
+    /// Set the `prefetch_count` of the consumers while they are running, for instance to
+    /// throttle a worker without restarting it. A value of 0 means no limit.
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError>;
+
+    /// Get the effective `prefetch_count`, which includes the tasks with a future ETA
+    /// that are currently held by the consumers.
+    async fn prefetch_count(&self) -> u16;
+
     /// Clone all channels and connection.
     async fn close(&self) -> Result<(), BrokerError>;
 
     /// Try reconnecting in the event of some sort of connection error.
     async fn reconnect(&self, connection_timeout: u32) -> Result<(), BrokerError>;
 
+    /// Get the state of the connection with the broker, e.g. to report it in a health check.
+    ///
+    /// Brokers which don't monitor their connection always report
+    /// [`ConnectionState::Connected`].
+    fn connection_state(&self) -> ConnectionState {
+        ConnectionState::Connected
+    }
+
     #[cfg(test)]
     fn into_any(self: Box<Self>) -> Box<dyn Any>;
 }
 
+/// The state of the connection of a [`Broker`] (see [`Broker::connection_state`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection is up.
+    Connected,
+    /// The connection was lost, and is re-established when reconnecting the broker or
+    /// before the next message is sent.
+    NotConnected,
+    /// The broker was closed with [`Broker::close`].
+    Closed,
+}
+
+/// Properties of a queue declared by a [`Broker`].
+///
+/// Brokers that don't have an equivalent for some of these properties ignore them, as
+/// described by their `declare_queue_with_options` implementation.
+///
+/// # Examples
+///
+/// ```rust
+/// use celery::broker::QueueOptions;
+///
+/// // An ephemeral queue which drops messages that weren't consumed within a minute.
+/// let options = QueueOptions {
+///     durable: false,
+///     message_ttl: Some(60_000),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueOptions {
+    /// Whether the queue survives a restart of the broker. Defaults to `true`.
+    pub durable: bool,
+
+    /// Whether the queue is deleted once its last consumer is cancelled.
+    pub auto_delete: bool,
+
+    /// Whether the queue can only be used by the connection that declared it.
+    pub exclusive: bool,
+
+    /// The number of milliseconds a message can stay in the queue before it is discarded
+    /// (`x-message-ttl`).
+    pub message_ttl: Option<u32>,
+
+    /// The maximum number of messages in the queue (`x-max-length`). Once it is reached,
+    /// the oldest messages are discarded to make room for new ones.
+    pub max_length: Option<u32>,
+
+    /// The exchange that [rejected](Broker::reject) or expired messages are republished to
+    /// (`x-dead-letter-exchange`), `""` being the default exchange.
+    pub dead_letter_exchange: Option<String>,
+
+    /// The routing key of the dead-lettered messages (`x-dead-letter-routing-key`). Defaults
+    /// to the routing key the messages were published with.
+    pub dead_letter_routing_key: Option<String>,
+
+    /// A queue that is declared along with this one to store its dead-lettered messages.
+    pub dead_letter_queue: Option<String>,
+
+    /// The ordering key the messages sent to the queue are published with, so that they
+    /// are delivered in the order they were sent. Only supported by the Pub/Sub broker.
+    pub ordering_key: Option<String>,
+
+    /// The maximum number of unacknowledged deliveries of the consumers of the queue, on
+    /// top of the [prefetch count](BrokerBuilder::prefetch_count) of the broker, so that
+    /// a backlog in this queue can't take all the prefetch slots. Only supported by the
+    /// AMQP, Redis and in-memory brokers.
+    pub prefetch_count: Option<u16>,
+}
+
+/// The exchange declared by [`QueueOptions::with_dead_letter_queue`].
+pub const DEAD_LETTER_EXCHANGE: &str = "celery.dead_letter";
+
+impl QueueOptions {
+    /// Park the dead-lettered messages of the queue in the queue `name`, for instance
+    /// to inspect the tasks which failed after exhausting their retries.
+    ///
+    /// With the AMQP broker the messages go through the [`DEAD_LETTER_EXCHANGE`] direct
+    /// exchange, which is declared and bound to the dead-letter queue along with it.
+    pub fn with_dead_letter_queue(mut self, name: &str) -> Self {
+        self.dead_letter_exchange = Some(DEAD_LETTER_EXCHANGE.into());
+        self.dead_letter_routing_key = Some(name.into());
+        self.dead_letter_queue = Some(name.into());
+        self
+    }
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            durable: true,
+            auto_delete: false,
+            exclusive: false,
+            message_ttl: None,
+            max_length: None,
+            dead_letter_exchange: None,
+            dead_letter_routing_key: None,
+            dead_letter_queue: None,
+            ordering_key: None,
+            prefetch_count: None,
+        }
+    }
+}
+
 /// A [`BrokerBuilder`] is used to create a type of broker with a custom configuration.
+///
+/// Only the prefetch count, the queues, the heartbeat and the building of the broker have
+/// to be implemented. The other settings are ignored unless the builder overrides them.
 #[async_trait]
-pub trait BrokerBuilder: Send + Sync {
+pub trait BrokerBuilder: Send + Sync + IntoDynBrokerBuilder {
     /// Create a new `BrokerBuilder`.
     fn new(broker_url: &str) -> Self
     where
@@ -110,68 +363,258 @@ pub trait BrokerBuilder: Send + Sync {
     /// Set the prefetch count.
     fn prefetch_count(self: Box<Self>, prefetch_count: u16) -> Box<dyn BrokerBuilder>;
 
-    /// Declare a queue.
+    /// Set whether the prefetch count is shared by the consumers of all the queues, which
+    /// is the default, or applies to each consumer on its own.
+    #[allow(unused)]
+    fn prefetch_global(self: Box<Self>, global: bool) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Declare a queue with the [default options](BrokerBuilder::default_queue_options).
+    /// This doesn't change the options of a queue which was already declared.
     fn declare_queue(self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder>;
 
+    /// Declare a queue with the given options.
+    #[allow(unused)]
+    fn declare_queue_with_options(
+        self: Box<Self>,
+        name: &str,
+        options: QueueOptions,
+    ) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Set the options of the queues declared without explicit options.
+    #[allow(unused)]
+    fn default_queue_options(self: Box<Self>, options: QueueOptions) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Declare a broadcast queue. Instead of being distributed among the consumers of the
+    /// queue, the messages sent to it are delivered to every consumer, each of them getting
+    /// its own copy which it acknowledges and retries independently of the others.
+    ///
+    /// The messages are only delivered to the consumers which are running when they are sent.
+    #[allow(unused)]
+    fn declare_broadcast_queue(self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Set the maximum priority of a queue, which enables message priorities for it.
+    #[allow(unused)]
+    fn queue_max_priority(self: Box<Self>, name: &str, max_priority: u8) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Declare an exchange of the given kind.
+    #[allow(unused)]
+    fn declare_exchange(
+        self: Box<Self>,
+        name: &str,
+        kind: ExchangeKind,
+        durable: bool,
+    ) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Bind a queue to an exchange with a binding key, declaring the queue with the
+    /// [default options](BrokerBuilder::default_queue_options) if it wasn't already.
+    /// The exchange has to be declared first with [`BrokerBuilder::declare_exchange`].
+    #[allow(unused)]
+    fn bind_queue(
+        self: Box<Self>,
+        queue: &str,
+        exchange: &str,
+        binding_key: &str,
+    ) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
     /// Set the heartbeat.
     fn heartbeat(self: Box<Self>, heartbeat: Option<u16>) -> Box<dyn BrokerBuilder>;
 
+    /// Set whether messages with a countdown or an ETA should be delayed by the broker,
+    /// instead of being held by the consumers until they are due.
+    #[allow(unused)]
+    fn delayed_delivery(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Set whether sending a message should wait for the broker to confirm it.
+    #[allow(unused)]
+    fn publisher_confirms(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Set the number of seconds to wait for a publisher confirmation.
+    #[allow(unused)]
+    fn confirm_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Set the number of seconds a consumed message can stay unacknowledged before the
+    /// broker delivers it again, e.g. because the worker which received it died.
+    #[allow(unused)]
+    fn visibility_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Set the number of channels or connections messages are published from.
+    #[allow(unused)]
+    fn pool_limit(self: Box<Self>, limit: u16) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
+    /// Set whether messages are published on a connection of their own, instead of the
+    /// one messages are consumed from.
+    #[allow(unused)]
+    fn separate_producer_connection(self: Box<Self>, separate: bool) -> Box<dyn BrokerBuilder> {
+        self.into_dyn()
+    }
+
     /// Construct the `Broker` with the given configuration.
     async fn build(&self, connection_timeout: u32) -> Result<Box<dyn Broker>, BrokerError>;
 }
 
+/// Turns a boxed [`BrokerBuilder`] into a trait object, which lets the settings of the
+/// trait return the builder unchanged by default. It is implemented for every builder.
+#[doc(hidden)]
+pub trait IntoDynBrokerBuilder {
+    fn into_dyn(self: Box<Self>) -> Box<dyn BrokerBuilder>;
+}
+
+impl<T: BrokerBuilder + 'static> IntoDynBrokerBuilder for T {
+    fn into_dyn(self: Box<Self>) -> Box<dyn BrokerBuilder> {
+        self
+    }
+}
+
+/// A utility function to get the [`BrokerBuilder`] registered for the scheme of a broker
+/// URL (see [`register_broker_scheme`]).
+///
+/// Several URLs separated by `;` give a [`FailoverBrokerBuilder`]. The builder of a URL
+/// with an unknown scheme fails to build with [`BrokerError::UnsupportedScheme`].
+pub(crate) fn broker_builder_from_url(broker_url: &str) -> Box<dyn BrokerBuilder> {
+    // The scheme may come from an environment variable too. When a variable isn't set the
+    // builder gets the URL as it is, and reports the error when building the broker.
+    let expanded_url = expand_env_vars(broker_url);
+    let broker_url = expanded_url.as_deref().unwrap_or(broker_url);
+    if broker_url.contains(';') {
+        return Box::new(FailoverBrokerBuilder::new(broker_url));
+    }
+    registry::broker_builder_for_scheme(broker_url)
+}
 
 // TODO: this function consumes the broker_builder, which results in a not so ergonomic API.
 // Can it be improved?
 /// A utility function to configure the task routes on a broker builder.
 pub(crate) fn configure_task_routes(
     mut broker_builder: Box<dyn BrokerBuilder>,
-    task_routes: &[(String, String)],
+    task_routes: &[(String, Destination)],
 ) -> Result<(Box<dyn BrokerBuilder>, Vec<Rule>), BrokerError> {
     let mut rules: Vec<Rule> = Vec::with_capacity(task_routes.len());
-    for (pattern, queue) in task_routes {
-        let rule = Rule::new(pattern, queue)?;
+    for (pattern, destination) in task_routes {
+        let rule = match destination {
+            Destination::Queue(queue) => {
+                // Ensure all other queues mentioned in task_routes are declared to the broker.
+                broker_builder = broker_builder.declare_queue(queue);
+                Rule::new(pattern, queue)?
+            }
+            // Exchanges and their bindings have to be declared explicitly.
+            Destination::Exchange { .. } => Rule::with_destination(pattern, destination.clone())?,
+        };
         rules.push(rule);
-        // Ensure all other queues mentioned in task_routes are declared to the broker.
-        broker_builder = broker_builder.declare_queue(queue);
     }
 
     Ok((broker_builder, rules))
 }
 
+/// A utility function to get a unique name for the queue of a single consumer of a
+/// broadcast queue, such as `reload.8c5d...`.
+pub(crate) fn broadcast_consumer_queue(queue: &str) -> String {
+    format!("{}.{}", queue, Uuid::new_v4())
+}
+
+/// A utility function to send a message to the destination it was routed to.
+pub(crate) async fn send_to_destination(
+    broker: &dyn Broker,
+    message: &Message,
+    destination: &Destination,
+) -> Result<(), BrokerError> {
+    match destination {
+        Destination::Queue(queue) => broker.send(message, queue).await,
+        Destination::Exchange {
+            exchange,
+            routing_key,
+        } => {
+            broker
+                .send_to_exchange(message, exchange, routing_key)
+                .await
+        }
+    }
+}
+
+/// A utility function to send a message to the destination it was routed to, and send it
+/// again after the retryable errors as allowed by the `retry_policy`. The same message is
+/// sent each time, so that its duplicates have the same task ID.
+pub(crate) async fn send_with_retry(
+    broker: &dyn Broker,
+    message: &Message,
+    destination: &Destination,
+    retry_policy: &PublishRetryPolicy,
+) -> Result<(), BrokerError> {
+    let policy = retry_policy.backoff_policy();
+    let mut backoff = Backoff::new(&policy);
+    loop {
+        match send_to_destination(broker, message, destination).await {
+            Err(err) if err.is_retryable() => match backoff.next_delay() {
+                Some(delay) => {
+                    warn!(
+                        "Failed to send task {}: {}, sending it again in {:.1?} (retry {})",
+                        message.task_id(),
+                        err,
+                        delay,
+                        backoff.retries()
+                    );
+                    time::sleep(delay).await;
+                }
+                None => return Err(err),
+            },
+            result => return result,
+        }
+    }
+}
+
 /// A utility function that can be used to build a broker
 /// and initialize the connection.
 pub(crate) async fn build_and_connect(
-    broker_builder: Box<dyn BrokerBuilder>,
+    broker_builder: &dyn BrokerBuilder,
     connection_timeout: u32,
-    connection_max_retries: u32,
-    connection_retry_delay: u32,
+    retry_policy: &ConnectionRetryPolicy,
 ) -> Result<Box<dyn Broker>, BrokerError> {
-    let mut broker: Option<Box<dyn Broker>> = None;
-
-    for _ in 0..connection_max_retries {
-        match broker_builder.build(connection_timeout).await {
-            Err(err) => {
-                if err.is_connection_error() {
-                    error!("{}", err);
-                    error!(
-                        "Failed to establish connection with broker, trying again in {}s...",
-                        connection_retry_delay
-                    );
-                    time::sleep(Duration::from_secs(connection_retry_delay as u64)).await;
-                    continue;
-                }
-                return Err(err);
+    let mut backoff = Backoff::new(retry_policy);
+    loop {
+        let err = match broker_builder.build(connection_timeout).await {
+            Ok(broker) => return Ok(broker),
+            Err(err) => err,
+        };
+        if !err.is_connection_error() {
+            return Err(err);
+        }
+        error!("{}", err);
+        match backoff.next_delay() {
+            Some(delay) => {
+                error!(
+                    "Failed to establish connection with broker, trying again in {:.1?} (retry {})...",
+                    delay,
+                    backoff.retries()
+                );
+                time::sleep(delay).await;
             }
-            Ok(b) => {
-                broker = Some(b);
-                break;
+            None => {
+                error!("Failed to establish connection with broker");
+                return Err(BrokerError::NotConnected);
             }
-        };
+        }
     }
-
-    broker.ok_or_else(|| {
-        error!("Failed to establish connection with broker");
-        BrokerError::NotConnected
-    })
 }
@@ -0,0 +1,578 @@
+//! NATS JetStream broker.
+//!
+//! Every queue is backed by a JetStream stream (named `CELERY_<queue>`, with work queue
+//! retention) which captures the subject `celery.<queue>`, and is consumed through a durable
+//! pull consumer with explicit acks shared by all the workers consuming from the queue.
+//! Messages are encoded with the same JSON envelope used by the Redis broker.
+
+use crate::logging::{debug, warn};
+use async_nats::connection::State;
+use async_nats::jetstream::{
+    self,
+    consumer::pull,
+    stream::{RetentionPolicy, StorageType},
+    AckKind,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::stream::TakeUntil;
+use futures::{FutureExt, Stream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+use super::{
+    Broker, BrokerBuilder, DeliveryError, DeliveryStream, ExchangeKind, Exchanges, QueueOptions,
+};
+use crate::error::{BrokerError, ProtocolError};
+use crate::protocol::{Delivery, Message, TryDeserializeMessage};
+use crate::urls::{expand_env_vars, redact_url};
+
+#[cfg(test)]
+use std::any::Any;
+
+/// The prefix of the subjects where messages are published.
+const SUBJECT_PREFIX: &str = "celery";
+
+fn subject_name(queue: &str) -> String {
+    format!("{}.{}", SUBJECT_PREFIX, queue)
+}
+
+/// Stream names can't contain `.`, `*`, `>` or whitespace, so these are replaced with `_`.
+fn stream_name(queue: &str) -> String {
+    let queue: String = queue
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect();
+    format!("CELERY_{}", queue)
+}
+
+fn nats_error<E: std::fmt::Display>(err: E) -> BrokerError {
+    BrokerError::NatsError(err.to_string())
+}
+
+struct Config {
+    broker_url: String,
+    prefetch_count: u16,
+    queues: HashSet<String>,
+    queue_options: HashMap<String, QueueOptions>,
+    default_queue_options: QueueOptions,
+    exchanges: Exchanges,
+    heartbeat: Option<u16>,
+    visibility_timeout: Option<u32>,
+}
+
+/// Builds a [`NatsBroker`] with a custom configuration.
+pub struct NatsBrokerBuilder {
+    config: Config,
+}
+
+#[async_trait]
+impl BrokerBuilder for NatsBrokerBuilder {
+    /// Create a new `NatsBrokerBuilder`.
+    fn new(broker_url: &str) -> Self {
+        Self {
+            config: Config {
+                broker_url: broker_url.into(),
+                prefetch_count: 10,
+                queues: HashSet::new(),
+                queue_options: HashMap::new(),
+                default_queue_options: QueueOptions::default(),
+                exchanges: Exchanges::default(),
+                heartbeat: Some(60),
+                visibility_timeout: None,
+            },
+        }
+    }
+
+    /// Set the worker prefetch count, which corresponds to the `max_ack_pending`
+    /// setting of the JetStream consumers. A value of 0 means no limit.
+    fn prefetch_count(mut self: Box<Self>, prefetch_count: u16) -> Box<dyn BrokerBuilder> {
+        self.config.prefetch_count = prefetch_count;
+        self
+    }
+
+    /// The prefetch count always applies to each JetStream consumer with this broker, so
+    /// this has no effect.
+    #[allow(unused)]
+    fn prefetch_global(self: Box<Self>, global: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Declare a queue.
+    fn declare_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(name.into());
+        self
+    }
+
+    /// Broadcast queues aren't supported by this broker, so this declares a regular queue,
+    /// whose messages are distributed among its consumers.
+    fn declare_broadcast_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        warn!("Broadcast queue {} is declared as a regular queue", name);
+        self.config.queues.insert(name.into());
+        self
+    }
+
+    /// Declare a queue with the given options. `message_ttl` and `max_length` map to the
+    /// `max_age` and `max_messages` limits of the stream, and non-durable queues use
+    /// memory storage. `auto_delete` and `exclusive` have no equivalent.
+    fn declare_queue_with_options(
+        mut self: Box<Self>,
+        name: &str,
+        options: QueueOptions,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(name.into());
+        self.config.queue_options.insert(name.into(), options);
+        self
+    }
+
+    /// Set the options of the queues declared without explicit options.
+    fn default_queue_options(mut self: Box<Self>, options: QueueOptions) -> Box<dyn BrokerBuilder> {
+        self.config.default_queue_options = options;
+        self
+    }
+
+    /// Priorities aren't supported by this broker, so this has no effect.
+    #[allow(unused)]
+    fn queue_max_priority(self: Box<Self>, name: &str, max_priority: u8) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Declare an exchange. The messages sent to it are routed to the queues bound to it
+    /// by the sender, according to the bindings declared on its own builder. The `durable`
+    /// flag has no effect.
+    #[allow(unused)]
+    fn declare_exchange(
+        mut self: Box<Self>,
+        name: &str,
+        kind: ExchangeKind,
+        durable: bool,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.exchanges.declare(name, kind);
+        self
+    }
+
+    /// Bind a queue to an exchange with a binding key.
+    fn bind_queue(
+        mut self: Box<Self>,
+        queue: &str,
+        exchange: &str,
+        binding_key: &str,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(queue.into());
+        self.config.exchanges.bind(queue, exchange, binding_key);
+        self
+    }
+
+    /// Set the interval in seconds between pings to the server.
+    fn heartbeat(mut self: Box<Self>, heartbeat: Option<u16>) -> Box<dyn BrokerBuilder> {
+        self.config.heartbeat = heartbeat;
+        self
+    }
+
+    /// Delayed delivery isn't supported by this broker, so this has no effect.
+    #[allow(unused)]
+    fn delayed_delivery(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Messages are always stored by the time they are sent with this broker,
+    /// so this has no effect.
+    #[allow(unused)]
+    fn publisher_confirms(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// See [`publisher_confirms`](Self::publisher_confirms).
+    #[allow(unused)]
+    fn confirm_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Set the `ack_wait` of the JetStream consumers, after which unacknowledged messages
+    /// are delivered again. Defaults to the server's default of 30 seconds.
+    fn visibility_timeout(mut self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self.config.visibility_timeout = Some(timeout);
+        self
+    }
+
+    /// The NATS client multiplexes all the messages over a single connection, so this has
+    /// no effect.
+    #[allow(unused)]
+    fn pool_limit(self: Box<Self>, limit: u16) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// The NATS client multiplexes all the messages over a single connection, so this has
+    /// no effect.
+    #[allow(unused)]
+    fn separate_producer_connection(self: Box<Self>, separate: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Build a `NatsBroker`.
+    async fn build(&self, connection_timeout: u32) -> Result<Box<dyn Broker>, BrokerError> {
+        let broker_url =
+            expand_env_vars(&self.config.broker_url).map_err(BrokerError::UnsetUrlVariable)?;
+        let mut options = async_nats::ConnectOptions::new()
+            .connection_timeout(Duration::from_secs(connection_timeout as u64));
+        if let Some(heartbeat) = self.config.heartbeat {
+            options = options.ping_interval(Duration::from_secs(heartbeat as u64));
+        }
+        let client = options
+            .connect(broker_url.as_str())
+            .await
+            .map_err(|_| BrokerError::NotConnected)?;
+        let context = jetstream::new(client.clone());
+
+        let broker = NatsBroker {
+            uri: broker_url,
+            client,
+            context,
+            prefetch_count: AtomicU16::new(self.config.prefetch_count),
+            queue_options: self.config.queue_options.clone(),
+            default_queue_options: self.config.default_queue_options.clone(),
+            exchanges: self.config.exchanges.clone(),
+            visibility_timeout: self.config.visibility_timeout,
+            consumers: StdMutex::new(HashMap::new()),
+        };
+        for queue in &self.config.queues {
+            broker.get_or_create_stream(queue).await?;
+        }
+
+        Ok(Box::new(broker))
+    }
+}
+
+/// A NATS JetStream [`Broker`], which can be created with a `nats://` URL.
+///
+/// The client reconnects automatically when the connection is lost, so
+/// [`reconnect`](Broker::reconnect) just waits for the connection to be re-established.
+pub struct NatsBroker {
+    uri: String,
+    client: async_nats::Client,
+    context: jetstream::Context,
+    prefetch_count: AtomicU16,
+    queue_options: HashMap<String, QueueOptions>,
+    default_queue_options: QueueOptions,
+    exchanges: Exchanges,
+    visibility_timeout: Option<u32>,
+    /// The cancellation notifiers of the consumers, by consumer tag.
+    consumers: StdMutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl NatsBroker {
+    fn check_connection(&self) -> Result<(), BrokerError> {
+        match self.client.connection_state() {
+            State::Connected => Ok(()),
+            _ => Err(BrokerError::NotConnected),
+        }
+    }
+
+    async fn get_or_create_stream(
+        &self,
+        queue: &str,
+    ) -> Result<jetstream::stream::Stream, BrokerError> {
+        self.check_connection()?;
+        let options = self
+            .queue_options
+            .get(queue)
+            .unwrap_or(&self.default_queue_options);
+        let mut config = jetstream::stream::Config {
+            name: stream_name(queue),
+            subjects: vec![subject_name(queue)],
+            retention: RetentionPolicy::WorkQueue,
+            ..Default::default()
+        };
+        if !options.durable {
+            config.storage = StorageType::Memory;
+        }
+        if let Some(message_ttl) = options.message_ttl {
+            config.max_age = Duration::from_millis(message_ttl as u64);
+        }
+        if let Some(max_length) = options.max_length {
+            config.max_messages = max_length as i64;
+        }
+        self.context
+            .get_or_create_stream(config)
+            .await
+            .map_err(nats_error)
+    }
+}
+
+/// A message delivered by a [`NatsBroker`].
+pub struct NatsDelivery {
+    message: jetstream::Message,
+    queue: String,
+}
+
+impl std::fmt::Debug for NatsDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NatsDelivery")
+            .field("subject", &self.message.subject)
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+impl TryDeserializeMessage for NatsDelivery {
+    fn try_deserialize_message(&self) -> Result<Message, ProtocolError> {
+        let delivery: Delivery = serde_json::from_slice(&self.message.payload)?;
+        delivery.try_deserialize_message()
+    }
+}
+
+#[async_trait]
+impl super::Delivery for NatsDelivery {
+    async fn resend(
+        &self,
+        broker: &dyn Broker,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        let mut message = self.try_deserialize_message()?;
+        message.headers.eta = eta;
+        // Increment the number of retries.
+        message.headers.retries = Some(message.headers.retries.map_or(1, |retry| retry + 1));
+        broker.send(&message, &self.queue).await
+    }
+
+    /// Messages are removed from the stream when they are acknowledged, so this is a no-op.
+    async fn remove(&self) -> Result<(), BrokerError> {
+        Ok(())
+    }
+
+    async fn ack(&self) -> Result<(), BrokerError> {
+        self.message.ack().await.map_err(nats_error)
+    }
+
+    /// Terminate the message, so that it is never redelivered.
+    async fn reject(&self) -> Result<(), BrokerError> {
+        self.message
+            .ack_with(AckKind::Term)
+            .await
+            .map_err(nats_error)
+    }
+}
+
+struct NatsDeliveryError(jetstream::consumer::pull::MessagesError);
+
+impl std::fmt::Display for NatsDeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl DeliveryError for NatsDeliveryError {}
+
+/// The messages of a pull consumer, until it is cancelled.
+struct Consumer {
+    wrapped: TakeUntil<pull::Stream, BoxFuture<'static, ()>>,
+    queue: String,
+}
+
+impl DeliveryStream for Consumer {}
+
+impl Stream for Consumer {
+    type Item = Result<Box<dyn super::Delivery>, Box<dyn DeliveryError>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.wrapped.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(message))) => {
+                let queue = self.queue.clone();
+                Poll::Ready(Some(Ok(Box::new(NatsDelivery { message, queue }))))
+            }
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(Box::new(NatsDeliveryError(err))))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[async_trait]
+impl Broker for NatsBroker {
+    fn safe_url(&self) -> String {
+        redact_url(&self.uri)
+    }
+
+    #[allow(unused)]
+    async fn consume(
+        &self,
+        queue: &str,
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        let stream = self.get_or_create_stream(queue).await?;
+        let durable_name = format!("celery-{}", stream_name(queue));
+        let max_ack_pending = match self.prefetch_count.load(Ordering::SeqCst) {
+            0 => -1,
+            prefetch_count => prefetch_count as i64,
+        };
+        let mut config = pull::Config {
+            durable_name: Some(durable_name.clone()),
+            ack_policy: jetstream::consumer::AckPolicy::Explicit,
+            max_ack_pending,
+            ..Default::default()
+        };
+        if let Some(visibility_timeout) = self.visibility_timeout {
+            config.ack_wait = Duration::from_secs(visibility_timeout as u64);
+        }
+        let consumer = stream
+            .get_or_create_consumer(&durable_name, config)
+            .await
+            .map_err(nats_error)?;
+        let messages = consumer.messages().await.map_err(nats_error)?;
+
+        // Create unique consumer tag.
+        let mut buffer = Uuid::encode_buffer();
+        let uuid = Uuid::new_v4().hyphenated().encode_lower(&mut buffer);
+        let consumer_tag = uuid.to_owned();
+
+        // The pull consumer stops fetching messages once its stream ends.
+        let cancelled = Arc::new(Notify::new());
+        self.consumers
+            .lock()
+            .unwrap()
+            .insert(consumer_tag.clone(), cancelled.clone());
+        let messages = messages.take_until(async move { cancelled.notified().await }.boxed());
+
+        Ok((
+            consumer_tag,
+            Box::new(Consumer {
+                wrapped: messages,
+                queue: queue.into(),
+            }),
+        ))
+    }
+
+    async fn cancel(&self, consumer_tag: &str) -> Result<(), BrokerError> {
+        if let Some(cancelled) = self.consumers.lock().unwrap().remove(consumer_tag) {
+            // `notify_one` stores a permit, so the consumer is cancelled even
+            // if it isn't waiting for a message right now.
+            cancelled.notify_one();
+        }
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.ack().await
+    }
+
+    /// JetStream has no dead-letter queues, so the rejected messages are just terminated.
+    async fn reject(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.reject().await
+    }
+
+    async fn retry(
+        &self,
+        delivery: &dyn super::Delivery,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        delivery.resend(self, eta).await
+    }
+
+    async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError> {
+        self.check_connection()?;
+        debug!("Sending NATS message to {}", subject_name(queue));
+        self.context
+            .publish(subject_name(queue), message.json_serialized()?.into())
+            .await
+            .map_err(nats_error)?
+            .await
+            .map_err(nats_error)?;
+        Ok(())
+    }
+
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError> {
+        for queue in self.exchanges.route(exchange, routing_key)? {
+            self.send(message, queue).await?;
+        }
+        Ok(())
+    }
+
+    /// The `max_ack_pending` of a JetStream consumer is fixed when the consumer is created,
+    /// so this only affects consumers created afterwards.
+    async fn increase_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count > 0 {
+                    count.checked_add(1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        Ok(())
+    }
+
+    /// See [`increase_prefetch_count`](NatsBroker::increase_prefetch_count).
+    async fn decrease_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count > 1 {
+                    Some(count - 1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        Ok(())
+    }
+
+    /// See [`increase_prefetch_count`](NatsBroker::increase_prefetch_count).
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError> {
+        self.prefetch_count.store(prefetch_count, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn prefetch_count(&self) -> u16 {
+        self.prefetch_count.load(Ordering::SeqCst)
+    }
+
+    async fn close(&self) -> Result<(), BrokerError> {
+        self.client.flush().await.map_err(nats_error)
+    }
+
+    async fn reconnect(&self, connection_timeout: u32) -> Result<(), BrokerError> {
+        let deadline = time::Instant::now() + Duration::from_secs(connection_timeout as u64);
+        loop {
+            if self.check_connection().is_ok() {
+                return Ok(());
+            }
+            if time::Instant::now() >= deadline {
+                return Err(BrokerError::NotConnected);
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[cfg(test)]
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_name() {
+        assert_eq!("CELERY_celery", stream_name("celery"));
+        assert_eq!("CELERY_backend_high", stream_name("backend.high"));
+        assert_eq!("celery.backend.high", subject_name("backend.high"));
+    }
+}
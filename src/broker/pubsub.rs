@@ -0,0 +1,931 @@
+//! Google Cloud Pub/Sub broker.
+//!
+//! Every queue is backed by a topic (named `celery-<queue>`) and by a subscription of the
+//! same name, which is shared by all the workers consuming from the queue so that its
+//! messages are distributed among them. Each consumer of a broadcast queue gets a
+//! subscription of its own instead, which is deleted when the consumer is cancelled.
+//!
+//! Messages are published with the same JSON envelope used by the Redis broker, and their
+//! Celery headers are also copied to the message attributes, so that they can be used to
+//! filter or inspect the messages without decoding them. Consumers pull the messages with
+//! explicit acknowledgements through the REST API.
+//!
+//! The broker URL is `pubsub://<project-id>`. The application default credentials are used
+//! to authenticate, and also provide the project when the URL doesn't name one. When the
+//! `PUBSUB_EMULATOR_HOST` environment variable is set, the broker connects to the emulator
+//! at that address without authenticating instead.
+
+use crate::logging::{debug, warn};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as ENGINE, Engine};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use google_cloud_auth::project::{self, create_token_source_from_project};
+use google_cloud_auth::token_source::TokenSource;
+use reqwest::{header::AUTHORIZATION, Method, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+use super::{
+    Broker, BrokerBuilder, DeliveryError, DeliveryStream, ExchangeKind, Exchanges, QueueOptions,
+};
+use crate::error::{BrokerError, ProtocolError};
+use crate::protocol::{Delivery, Message, TryDeserializeMessage};
+
+#[cfg(test)]
+use std::any::Any;
+
+/// The endpoint of the Pub/Sub REST API.
+const PUBSUB_URL: &str = "https://pubsub.googleapis.com/v1";
+
+/// The OAuth scopes requested for the application default credentials.
+const SCOPES: [&str; 1] = ["https://www.googleapis.com/auth/pubsub"];
+
+/// The maximum number of messages returned by a single pull.
+const MAX_PULL_MESSAGES: u32 = 1000;
+
+/// The bounds of the acknowledgement deadline of a subscription, in seconds.
+const MIN_ACK_DEADLINE: u32 = 10;
+const MAX_ACK_DEADLINE: u32 = 600;
+
+/// The bounds of the message retention duration of a subscription, in seconds.
+const MIN_MESSAGE_RETENTION: u32 = 600;
+const MAX_MESSAGE_RETENTION: u32 = 604_800;
+
+/// The number of seconds without consumers after which the subscriptions of non-durable
+/// queues and broadcast consumers are deleted, which is the minimum allowed.
+const EXPIRATION_TTL: u32 = 86_400;
+
+/// Topic and subscription IDs can only contain letters, numbers and `-_.~+%`, so other
+/// characters are replaced with `_`.
+fn resource_id(queue: &str) -> String {
+    let queue: String = queue
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c,
+            '-' | '_' | '.' | '~' | '+' | '%' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("celery-{}", queue)
+}
+
+/// The attributes a message is published with, which mirror its Celery headers.
+fn message_attributes(message: &Message) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    attributes.insert("id".into(), message.headers.id.clone());
+    attributes.insert("task".into(), message.headers.task.clone());
+    attributes.insert(
+        "content-type".into(),
+        message.properties.content_type.clone(),
+    );
+    attributes.insert(
+        "content-encoding".into(),
+        message.properties.content_encoding.clone(),
+    );
+    let optional = [
+        ("lang", message.headers.lang.clone()),
+        ("root_id", message.headers.root_id.clone()),
+        ("parent_id", message.headers.parent_id.clone()),
+        ("group", message.headers.group.clone()),
+        ("shadow", message.headers.shadow.clone()),
+        ("retries", message.headers.retries.map(|r| r.to_string())),
+        ("eta", message.headers.eta.map(|eta| eta.to_rfc3339())),
+        (
+            "expires",
+            message.headers.expires.map(|expires| expires.to_rfc3339()),
+        ),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            attributes.insert(key.into(), value);
+        }
+    }
+    attributes
+}
+
+fn pubsub_error<E: std::fmt::Display>(err: E) -> BrokerError {
+    BrokerError::PubSubError(err.to_string())
+}
+
+fn request_error(err: reqwest::Error) -> BrokerError {
+    if err.is_connect() || err.is_timeout() {
+        BrokerError::NotConnected
+    } else {
+        pubsub_error(err)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PullResponse {
+    #[serde(default)]
+    received_messages: Vec<ReceivedMessage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReceivedMessage {
+    ack_id: String,
+    message: PubsubMessage,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PubsubMessage {
+    #[serde(default)]
+    data: String,
+    #[serde(default)]
+    message_id: String,
+}
+
+/// A client of the Pub/Sub REST API for a single project.
+struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    project_id: String,
+    /// `None` when connected to the emulator.
+    token_source: Option<Box<dyn TokenSource>>,
+}
+
+impl Client {
+    fn topic(&self, queue: &str) -> String {
+        format!("projects/{}/topics/{}", self.project_id, resource_id(queue))
+    }
+
+    fn subscription(&self, id: &str) -> String {
+        format!("projects/{}/subscriptions/{}", self.project_id, id)
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Response, BrokerError> {
+        let mut request = self
+            .http
+            .request(method, format!("{}/{}", self.base_url, path));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        if let Some(token_source) = &self.token_source {
+            let token = token_source.token().await.map_err(pubsub_error)?;
+            request = request.header(AUTHORIZATION, token.value());
+        }
+        request.send().await.map_err(request_error)
+    }
+
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, BrokerError> {
+        let response = self.request(method, path, body).await?;
+        Self::check(response).await
+    }
+
+    async fn check(response: Response) -> Result<Value, BrokerError> {
+        let status = response.status();
+        if status.is_success() {
+            response.json().await.map_err(request_error)
+        } else {
+            let text = response.text().await.unwrap_or_default();
+            Err(BrokerError::PubSubError(format!("{}: {}", status, text)))
+        }
+    }
+
+    /// Create a resource, unless it already exists.
+    async fn create(&self, path: &str, body: Value) -> Result<(), BrokerError> {
+        let response = self.request(Method::PUT, path, Some(body)).await?;
+        if response.status() == StatusCode::CONFLICT {
+            return Ok(());
+        }
+        Self::check(response).await.map(|_| ())
+    }
+
+    async fn publish(
+        &self,
+        queue: &str,
+        data: &[u8],
+        attributes: &HashMap<String, String>,
+        ordering_key: Option<&str>,
+    ) -> Result<Response, BrokerError> {
+        let mut message = json!({
+            "data": ENGINE.encode(data),
+            "attributes": attributes,
+        });
+        if let Some(ordering_key) = ordering_key {
+            message["orderingKey"] = json!(ordering_key);
+        }
+        self.request(
+            Method::POST,
+            &format!("{}:publish", self.topic(queue)),
+            Some(json!({ "messages": [message] })),
+        )
+        .await
+    }
+
+    async fn pull(
+        &self,
+        subscription: &str,
+        max_messages: u32,
+    ) -> Result<Vec<ReceivedMessage>, BrokerError> {
+        let response = self
+            .call(
+                Method::POST,
+                &format!("{}:pull", subscription),
+                Some(json!({ "maxMessages": max_messages })),
+            )
+            .await?;
+        let response: PullResponse = serde_json::from_value(response).map_err(pubsub_error)?;
+        Ok(response.received_messages)
+    }
+
+    async fn acknowledge(&self, subscription: &str, ack_id: &str) -> Result<(), BrokerError> {
+        self.call(
+            Method::POST,
+            &format!("{}:acknowledge", subscription),
+            Some(json!({ "ackIds": [ack_id] })),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Reset the acknowledgement deadline of a message, so that it's redelivered right away.
+    async fn nack(&self, subscription: &str, ack_id: &str) -> Result<(), BrokerError> {
+        self.call(
+            Method::POST,
+            &format!("{}:modifyAckDeadline", subscription),
+            Some(json!({ "ackIds": [ack_id], "ackDeadlineSeconds": 0 })),
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+struct Config {
+    broker_url: String,
+    project_id: Option<String>,
+    prefetch_count: u16,
+    queues: HashSet<String>,
+    broadcast_queues: HashSet<String>,
+    queue_options: HashMap<String, QueueOptions>,
+    default_queue_options: QueueOptions,
+    exchanges: Exchanges,
+    ack_deadline: u32,
+}
+
+/// Builds a [`PubSubBroker`] with a custom configuration.
+pub struct PubSubBrokerBuilder {
+    config: Config,
+}
+
+#[async_trait]
+impl BrokerBuilder for PubSubBrokerBuilder {
+    /// Create a new `PubSubBrokerBuilder`.
+    fn new(broker_url: &str) -> Self {
+        let project_id = url::Url::parse(broker_url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .filter(|host| !host.is_empty());
+        Self {
+            config: Config {
+                broker_url: broker_url.into(),
+                project_id,
+                prefetch_count: 10,
+                queues: HashSet::new(),
+                broadcast_queues: HashSet::new(),
+                queue_options: HashMap::new(),
+                default_queue_options: QueueOptions::default(),
+                exchanges: Exchanges::default(),
+                ack_deadline: MAX_ACK_DEADLINE,
+            },
+        }
+    }
+
+    /// Set the worker prefetch count, which is the maximum number of messages pulled at
+    /// once by the consumers. A value of 0 means as many as a pull can return.
+    fn prefetch_count(mut self: Box<Self>, prefetch_count: u16) -> Box<dyn BrokerBuilder> {
+        self.config.prefetch_count = prefetch_count;
+        self
+    }
+
+    /// The prefetch count always applies to each consumer with this broker, so this has
+    /// no effect.
+    #[allow(unused)]
+    fn prefetch_global(self: Box<Self>, global: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Declare a queue.
+    fn declare_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(name.into());
+        self
+    }
+
+    /// Declare a broadcast queue, whose consumers each get their own subscription to the
+    /// topic of the queue.
+    fn declare_broadcast_queue(mut self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self.config.broadcast_queues.insert(name.into());
+        self
+    }
+
+    /// Declare a queue with the given options. `message_ttl` maps to the message retention
+    /// duration of the subscription, which is at least 10 minutes, and the subscriptions
+    /// of non-durable queues are deleted after a day without consumers. Rejected messages
+    /// are republished to the `dead_letter_queue`, and the `ordering_key` is set on the
+    /// published messages and enables message ordering on the subscription. The other
+    /// options have no equivalent.
+    fn declare_queue_with_options(
+        mut self: Box<Self>,
+        name: &str,
+        options: QueueOptions,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(name.into());
+        self.config.queue_options.insert(name.into(), options);
+        self
+    }
+
+    /// Set the options of the queues declared without explicit options.
+    fn default_queue_options(mut self: Box<Self>, options: QueueOptions) -> Box<dyn BrokerBuilder> {
+        self.config.default_queue_options = options;
+        self
+    }
+
+    /// Priorities aren't supported by this broker, so this has no effect.
+    #[allow(unused)]
+    fn queue_max_priority(self: Box<Self>, name: &str, max_priority: u8) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Declare an exchange. The messages sent to it are routed to the queues bound to it
+    /// by the sender, according to the bindings declared on its own builder. The `durable`
+    /// flag has no effect.
+    #[allow(unused)]
+    fn declare_exchange(
+        mut self: Box<Self>,
+        name: &str,
+        kind: ExchangeKind,
+        durable: bool,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.exchanges.declare(name, kind);
+        self
+    }
+
+    /// Bind a queue to an exchange with a binding key.
+    fn bind_queue(
+        mut self: Box<Self>,
+        queue: &str,
+        exchange: &str,
+        binding_key: &str,
+    ) -> Box<dyn BrokerBuilder> {
+        self.config.queues.insert(queue.into());
+        self.config.exchanges.bind(queue, exchange, binding_key);
+        self
+    }
+
+    /// Requests are sent over HTTP, so there is no connection to keep alive and this has
+    /// no effect.
+    #[allow(unused)]
+    fn heartbeat(self: Box<Self>, heartbeat: Option<u16>) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Delayed delivery isn't supported by this broker, so this has no effect.
+    #[allow(unused)]
+    fn delayed_delivery(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Publishing a message always waits for it to be stored with this broker,
+    /// so this has no effect.
+    #[allow(unused)]
+    fn publisher_confirms(self: Box<Self>, enabled: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// See [`publisher_confirms`](Self::publisher_confirms).
+    #[allow(unused)]
+    fn confirm_timeout(self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Set the acknowledgement deadline of the subscriptions created by the broker, after
+    /// which unacknowledged messages are delivered again. It is clamped between 10 seconds
+    /// and its default of 10 minutes.
+    fn visibility_timeout(mut self: Box<Self>, timeout: u32) -> Box<dyn BrokerBuilder> {
+        self.config.ack_deadline = timeout.clamp(MIN_ACK_DEADLINE, MAX_ACK_DEADLINE);
+        self
+    }
+
+    /// The HTTP client keeps a pool of connections by itself, so this has no effect.
+    #[allow(unused)]
+    fn pool_limit(self: Box<Self>, limit: u16) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// The HTTP client keeps a pool of connections by itself, so this has no effect.
+    #[allow(unused)]
+    fn separate_producer_connection(self: Box<Self>, separate: bool) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    /// Build a `PubSubBroker`.
+    async fn build(&self, connection_timeout: u32) -> Result<Box<dyn Broker>, BrokerError> {
+        let (base_url, token_source, default_project_id) =
+            match std::env::var("PUBSUB_EMULATOR_HOST") {
+                Ok(host) => (format!("http://{}/v1", host), None, None),
+                Err(_) => {
+                    let project = project::project().await.map_err(pubsub_error)?;
+                    let config = project::Config::default().with_scopes(&SCOPES);
+                    let token_source = create_token_source_from_project(&project, config)
+                        .await
+                        .map_err(pubsub_error)?;
+                    let project_id = project.project_id().cloned();
+                    (PUBSUB_URL.to_string(), Some(token_source), project_id)
+                }
+            };
+        let project_id = self
+            .config
+            .project_id
+            .clone()
+            .or(default_project_id)
+            .ok_or_else(|| {
+                BrokerError::PubSubError("the broker URL doesn't name a project".into())
+            })?;
+        let http = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(connection_timeout as u64))
+            .build()
+            .map_err(pubsub_error)?;
+
+        let broker = PubSubBroker {
+            broker_url: self.config.broker_url.clone(),
+            client: Arc::new(Client {
+                http,
+                base_url,
+                project_id,
+                token_source,
+            }),
+            prefetch_count: Arc::new(AtomicU16::new(self.config.prefetch_count)),
+            broadcast_queues: self.config.broadcast_queues.clone(),
+            queue_options: self.config.queue_options.clone(),
+            default_queue_options: self.config.default_queue_options.clone(),
+            exchanges: self.config.exchanges.clone(),
+            ack_deadline: self.config.ack_deadline,
+            consumers: Mutex::new(HashMap::new()),
+        };
+        broker.check_connection(connection_timeout).await?;
+        for queue in &self.config.queues {
+            broker.create_queue(queue).await?;
+        }
+        for queue in &self.config.broadcast_queues {
+            broker
+                .client
+                .create(&broker.client.topic(queue), json!({}))
+                .await?;
+        }
+
+        Ok(Box::new(broker))
+    }
+}
+
+/// The task pulling the messages of a consumer, along with its subscription if it consumes
+/// from a broadcast queue.
+type PullConsumer = (JoinHandle<()>, Option<String>);
+
+/// A Google Cloud Pub/Sub [`Broker`], which can be created with a `pubsub://` URL.
+pub struct PubSubBroker {
+    broker_url: String,
+    client: Arc<Client>,
+    prefetch_count: Arc<AtomicU16>,
+    broadcast_queues: HashSet<String>,
+    queue_options: HashMap<String, QueueOptions>,
+    default_queue_options: QueueOptions,
+    exchanges: Exchanges,
+    ack_deadline: u32,
+    consumers: Mutex<HashMap<String, PullConsumer>>,
+}
+
+impl PubSubBroker {
+    fn queue_options(&self, queue: &str) -> &QueueOptions {
+        self.queue_options
+            .get(queue)
+            .unwrap_or(&self.default_queue_options)
+    }
+
+    async fn check_connection(&self, connection_timeout: u32) -> Result<(), BrokerError> {
+        let path = format!("projects/{}/topics?pageSize=1", self.client.project_id);
+        time::timeout(
+            Duration::from_secs(connection_timeout as u64),
+            self.client.call(Method::GET, &path, None),
+        )
+        .await
+        .map_err(|_| BrokerError::NotConnected)?
+        .map(|_| ())
+    }
+
+    /// Create a subscription to the topic of a queue.
+    async fn create_subscription(&self, queue: &str, id: &str) -> Result<(), BrokerError> {
+        let options = self.queue_options(queue);
+        let mut subscription = json!({
+            "topic": self.client.topic(queue),
+            "ackDeadlineSeconds": self.ack_deadline,
+            "enableMessageOrdering": options.ordering_key.is_some(),
+        });
+        if let Some(message_ttl) = options.message_ttl {
+            let retention =
+                (message_ttl / 1000).clamp(MIN_MESSAGE_RETENTION, MAX_MESSAGE_RETENTION);
+            subscription["messageRetentionDuration"] = json!(format!("{}s", retention));
+        }
+        // An expiration policy without a TTL means that the subscription never expires.
+        subscription["expirationPolicy"] =
+            if options.durable && !self.broadcast_queues.contains(queue) {
+                json!({})
+            } else {
+                json!({ "ttl": format!("{}s", EXPIRATION_TTL) })
+            };
+        self.client
+            .create(&self.client.subscription(id), subscription)
+            .await
+    }
+
+    /// Create the topic of a queue, and the subscription shared by its consumers.
+    async fn create_queue(&self, queue: &str) -> Result<(), BrokerError> {
+        self.client
+            .create(&self.client.topic(queue), json!({}))
+            .await?;
+        self.create_subscription(queue, &resource_id(queue)).await
+    }
+}
+
+/// A message delivered by a [`PubSubBroker`].
+pub struct PubSubDelivery {
+    client: Arc<Client>,
+    subscription: String,
+    ack_id: String,
+    message_id: String,
+    data: Vec<u8>,
+    queue: String,
+    dead_letter_queue: Option<String>,
+}
+
+impl PubSubDelivery {
+    /// Negatively acknowledge the message, so that it is delivered again right away.
+    pub async fn nack(&self) -> Result<(), BrokerError> {
+        self.client.nack(&self.subscription, &self.ack_id).await
+    }
+}
+
+impl std::fmt::Debug for PubSubDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PubSubDelivery")
+            .field("message_id", &self.message_id)
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+impl TryDeserializeMessage for PubSubDelivery {
+    fn try_deserialize_message(&self) -> Result<Message, ProtocolError> {
+        let delivery: Delivery = serde_json::from_slice(&self.data)?;
+        delivery.try_deserialize_message()
+    }
+}
+
+#[async_trait]
+impl super::Delivery for PubSubDelivery {
+    async fn resend(
+        &self,
+        broker: &dyn Broker,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        let mut message = self.try_deserialize_message()?;
+        message.headers.eta = eta;
+        // Increment the number of retries.
+        message.headers.retries = Some(message.headers.retries.map_or(1, |retry| retry + 1));
+        broker.send(&message, &self.queue).await
+    }
+
+    /// Messages are removed from the subscription when they are acknowledged, so this is a
+    /// no-op.
+    async fn remove(&self) -> Result<(), BrokerError> {
+        Ok(())
+    }
+
+    async fn ack(&self) -> Result<(), BrokerError> {
+        self.client
+            .acknowledge(&self.subscription, &self.ack_id)
+            .await
+    }
+
+    /// Republish the message to the dead-letter queue of its queue if it has one, and
+    /// acknowledge it so that it is never redelivered.
+    async fn reject(&self) -> Result<(), BrokerError> {
+        if let Some(dead_letter_queue) = &self.dead_letter_queue {
+            let response = self
+                .client
+                .publish(dead_letter_queue, &self.data, &HashMap::new(), None)
+                .await?;
+            Client::check(response).await?;
+        }
+        self.ack().await
+    }
+}
+
+struct Consumer {
+    wrapped: ReceiverStream<PubSubDelivery>,
+}
+
+impl DeliveryStream for Consumer {}
+
+impl Stream for Consumer {
+    type Item = Result<Box<dyn super::Delivery>, Box<dyn DeliveryError>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.wrapped.poll_next_unpin(cx) {
+            Poll::Ready(Some(delivery)) => Poll::Ready(Some(Ok(Box::new(delivery)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Pull the messages of a subscription into a consumer stream, until the stream is dropped.
+async fn pull_messages(
+    client: Arc<Client>,
+    subscription: String,
+    queue: String,
+    dead_letter_queue: Option<String>,
+    prefetch_count: Arc<AtomicU16>,
+    sender: mpsc::Sender<PubSubDelivery>,
+    error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+) {
+    loop {
+        let max_messages = match prefetch_count.load(Ordering::SeqCst) {
+            0 => MAX_PULL_MESSAGES,
+            prefetch_count => (prefetch_count as u32).min(MAX_PULL_MESSAGES),
+        };
+        let messages = match client.pull(&subscription, max_messages).await {
+            Ok(messages) => messages,
+            Err(err) => {
+                error_handler(err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let mut messages = messages.into_iter();
+        while let Some(received) = messages.next() {
+            let delivery = PubSubDelivery {
+                client: client.clone(),
+                subscription: subscription.clone(),
+                ack_id: received.ack_id,
+                message_id: received.message.message_id,
+                data: ENGINE.decode(received.message.data).unwrap_or_default(),
+                queue: queue.clone(),
+                dead_letter_queue: dead_letter_queue.clone(),
+            };
+            if let Err(mpsc::error::SendError(delivery)) = sender.send(delivery).await {
+                // The consumer is gone, so the messages it didn't get are released for the
+                // other consumers.
+                debug!("Releasing the messages pulled from {}", subscription);
+                delivery.nack().await.ok();
+                for received in messages {
+                    client.nack(&subscription, &received.ack_id).await.ok();
+                }
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Broker for PubSubBroker {
+    fn safe_url(&self) -> String {
+        self.broker_url.clone()
+    }
+
+    async fn consume(
+        &self,
+        queue: &str,
+        error_handler: Box<dyn Fn(BrokerError) + Send + Sync + 'static>,
+    ) -> Result<(String, Box<dyn DeliveryStream>), BrokerError> {
+        // Create unique consumer tag.
+        let mut buffer = Uuid::encode_buffer();
+        let uuid = Uuid::new_v4().hyphenated().encode_lower(&mut buffer);
+        let consumer_tag = uuid.to_owned();
+
+        let broadcast_subscription = if self.broadcast_queues.contains(queue) {
+            let id = format!("{}-{}", resource_id(queue), consumer_tag);
+            self.create_subscription(queue, &id).await?;
+            Some(self.client.subscription(&id))
+        } else {
+            self.create_queue(queue).await?;
+            None
+        };
+        let subscription = broadcast_subscription
+            .clone()
+            .unwrap_or_else(|| self.client.subscription(&resource_id(queue)));
+
+        let (sender, receiver) = mpsc::channel(1);
+        let puller = tokio::spawn(pull_messages(
+            self.client.clone(),
+            subscription,
+            queue.into(),
+            self.queue_options(queue).dead_letter_queue.clone(),
+            self.prefetch_count.clone(),
+            sender,
+            error_handler,
+        ));
+        self.consumers
+            .lock()
+            .unwrap()
+            .insert(consumer_tag.clone(), (puller, broadcast_subscription));
+
+        Ok((
+            consumer_tag,
+            Box::new(Consumer {
+                wrapped: ReceiverStream::new(receiver),
+            }),
+        ))
+    }
+
+    /// Stop pulling messages for a consumer, and delete its subscription if it consumes
+    /// from a broadcast queue.
+    async fn cancel(&self, consumer_tag: &str) -> Result<(), BrokerError> {
+        let consumer = self.consumers.lock().unwrap().remove(consumer_tag);
+        if let Some((puller, subscription)) = consumer {
+            puller.abort();
+            if let Some(subscription) = subscription {
+                self.client
+                    .call(Method::DELETE, &subscription, None)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.ack().await
+    }
+
+    async fn reject(&self, delivery: &dyn super::Delivery) -> Result<(), BrokerError> {
+        delivery.reject().await
+    }
+
+    async fn retry(
+        &self,
+        delivery: &dyn super::Delivery,
+        eta: Option<DateTime<Utc>>,
+    ) -> Result<(), BrokerError> {
+        delivery.resend(self, eta).await
+    }
+
+    /// Publish a message to the topic of a queue, which is created first if it doesn't
+    /// exist yet.
+    async fn send(&self, message: &Message, queue: &str) -> Result<(), BrokerError> {
+        debug!("Sending Pub/Sub message to {}", self.client.topic(queue));
+        let data = message.json_serialized()?;
+        let attributes = message_attributes(message);
+        let ordering_key = self.queue_options(queue).ordering_key.as_deref();
+        let mut response = self
+            .client
+            .publish(queue, &data, &attributes, ordering_key)
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            warn!("Creating the topic of undeclared queue {}", queue);
+            if self.broadcast_queues.contains(queue) {
+                self.client
+                    .create(&self.client.topic(queue), json!({}))
+                    .await?;
+            } else {
+                self.create_queue(queue).await?;
+            }
+            response = self
+                .client
+                .publish(queue, &data, &attributes, ordering_key)
+                .await?;
+        }
+        Client::check(response).await.map(|_| ())
+    }
+
+    async fn send_to_exchange(
+        &self,
+        message: &Message,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), BrokerError> {
+        for queue in self.exchanges.route(exchange, routing_key)? {
+            self.send(message, queue).await?;
+        }
+        Ok(())
+    }
+
+    /// The consumers read the prefetch count before each pull, so this takes effect on
+    /// their next pull.
+    async fn increase_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count > 0 {
+                    count.checked_add(1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        Ok(())
+    }
+
+    /// See [`increase_prefetch_count`](PubSubBroker::increase_prefetch_count).
+    async fn decrease_prefetch_count(&self) -> Result<(), BrokerError> {
+        self.prefetch_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count > 1 {
+                    Some(count - 1)
+                } else {
+                    None
+                }
+            })
+            .ok();
+        Ok(())
+    }
+
+    /// See [`increase_prefetch_count`](PubSubBroker::increase_prefetch_count).
+    async fn set_prefetch_count(&self, prefetch_count: u16) -> Result<(), BrokerError> {
+        self.prefetch_count.store(prefetch_count, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn prefetch_count(&self) -> u16 {
+        self.prefetch_count.load(Ordering::SeqCst)
+    }
+
+    /// Stop pulling messages for all the consumers.
+    async fn close(&self) -> Result<(), BrokerError> {
+        for (_, (puller, _)) in self.consumers.lock().unwrap().drain() {
+            puller.abort();
+        }
+        Ok(())
+    }
+
+    /// Requests are stateless, so this just checks that the API can be reached.
+    async fn reconnect(&self, connection_timeout: u32) -> Result<(), BrokerError> {
+        self.check_connection(connection_timeout).await
+    }
+
+    #[cfg(test)]
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{MessageHeaders, MessageProperties};
+
+    #[test]
+    fn test_resource_id() {
+        assert_eq!("celery-celery", resource_id("celery"));
+        assert_eq!("celery-backend.high", resource_id("backend.high"));
+        assert_eq!("celery-ml_gpu_1", resource_id("ml/gpu:1"));
+    }
+
+    #[test]
+    fn test_message_attributes() {
+        let message = Message {
+            properties: MessageProperties {
+                correlation_id: "id".into(),
+                content_type: "application/json".into(),
+                content_encoding: "utf-8".into(),
+                reply_to: None,
+                priority: None,
+                delivery_mode: None,
+            },
+            headers: MessageHeaders {
+                id: "id".into(),
+                task: "add".into(),
+                retries: Some(2),
+                ..Default::default()
+            },
+            raw_body: vec![],
+        };
+        let attributes = message_attributes(&message);
+        assert_eq!("id", attributes["id"]);
+        assert_eq!("add", attributes["task"]);
+        assert_eq!("application/json", attributes["content-type"]);
+        assert_eq!("2", attributes["retries"]);
+        assert!(!attributes.contains_key("eta"));
+    }
+}
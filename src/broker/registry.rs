@@ -0,0 +1,168 @@
+//! The registry of the broker URL schemes, which maps each scheme to the
+//! [`BrokerBuilder`] its URLs are handled by.
+//!
+//! Brokers defined outside of this crate can be plugged in by registering their scheme
+//! with [`register_broker_scheme`] before building the app or the beat.
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::{
+    AMQPBrokerBuilder, Broker, BrokerBuilder, FilesystemBrokerBuilder, InMemoryBrokerBuilder,
+    RedisBrokerBuilder,
+};
+use crate::error::BrokerError;
+
+#[cfg(feature = "servicebus")]
+use super::AzureServiceBusBrokerBuilder;
+#[cfg(feature = "nats")]
+use super::NatsBrokerBuilder;
+#[cfg(feature = "pubsub")]
+use super::PubSubBrokerBuilder;
+
+/// A function creating a [`BrokerBuilder`] from a broker URL.
+pub type BrokerBuilderFactory = fn(&str) -> Box<dyn BrokerBuilder>;
+
+static REGISTRY: Lazy<RwLock<HashMap<String, BrokerBuilderFactory>>> = Lazy::new(|| {
+    let mut registry: HashMap<String, BrokerBuilderFactory> = HashMap::new();
+    registry.insert("amqp".into(), |url| Box::new(AMQPBrokerBuilder::new(url)));
+    registry.insert("amqps".into(), |url| Box::new(AMQPBrokerBuilder::new(url)));
+    registry.insert("redis".into(), |url| Box::new(RedisBrokerBuilder::new(url)));
+    registry.insert("redis+sentinel".into(), |url| {
+        Box::new(RedisBrokerBuilder::new(url))
+    });
+    registry.insert("memory".into(), |url| {
+        Box::new(InMemoryBrokerBuilder::new(url))
+    });
+    registry.insert("filesystem".into(), |url| {
+        Box::new(FilesystemBrokerBuilder::new(url))
+    });
+    #[cfg(feature = "nats")]
+    registry.insert("nats".into(), |url| Box::new(NatsBrokerBuilder::new(url)));
+    #[cfg(feature = "pubsub")]
+    registry.insert("pubsub".into(), |url| {
+        Box::new(PubSubBrokerBuilder::new(url))
+    });
+    #[cfg(feature = "servicebus")]
+    registry.insert("azureservicebus".into(), |url| {
+        Box::new(AzureServiceBusBrokerBuilder::new(url))
+    });
+    #[cfg(test)]
+    registry.insert("mock".into(), |url| {
+        Box::new(super::mock::MockBrokerBuilder::new(url))
+    });
+    RwLock::new(registry)
+});
+
+/// Register the [`BrokerBuilder`] handling the broker URLs with the given scheme, e.g.
+/// `mybus` for `mybus://host/`. This replaces the builder of a scheme which was already
+/// registered, including the built-in ones.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use celery::broker::{register_broker_scheme, BrokerBuilder, RedisBrokerBuilder};
+///
+/// // A Redis-compatible bus, which only differs by its scheme.
+/// register_broker_scheme("mybus", |url| {
+///     Box::new(RedisBrokerBuilder::new(&url.replacen("mybus", "redis", 1)))
+/// });
+/// ```
+pub fn register_broker_scheme(scheme: &str, factory: BrokerBuilderFactory) {
+    REGISTRY.write().unwrap().insert(scheme.into(), factory);
+}
+
+/// Get the registered schemes, in alphabetical order.
+pub fn registered_broker_schemes() -> Vec<String> {
+    let mut schemes: Vec<String> = REGISTRY.read().unwrap().keys().cloned().collect();
+    schemes.sort_unstable();
+    schemes
+}
+
+/// Get the [`BrokerBuilder`] registered for the scheme of a broker URL.
+///
+/// An [`UnsupportedBrokerBuilder`] is returned for unknown schemes, so that the error is
+/// reported when building the broker.
+pub(crate) fn broker_builder_for_scheme(broker_url: &str) -> Box<dyn BrokerBuilder> {
+    // Sentinel URLs list several hosts, so they can't be parsed with `Url`.
+    let scheme = broker_url.split("://").next().unwrap_or_default();
+    match REGISTRY.read().unwrap().get(scheme) {
+        Some(factory) => factory(broker_url),
+        None => Box::new(UnsupportedBrokerBuilder::new(broker_url)),
+    }
+}
+
+/// The builder of a broker URL whose scheme isn't registered, which fails to build with
+/// [`BrokerError::UnsupportedScheme`].
+pub(crate) struct UnsupportedBrokerBuilder {
+    scheme: String,
+}
+
+#[async_trait]
+impl BrokerBuilder for UnsupportedBrokerBuilder {
+    fn new(broker_url: &str) -> Self {
+        Self {
+            scheme: broker_url
+                .split("://")
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    #[allow(unused)]
+    fn prefetch_count(self: Box<Self>, prefetch_count: u16) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    #[allow(unused)]
+    fn declare_queue(self: Box<Self>, name: &str) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    #[allow(unused)]
+    fn heartbeat(self: Box<Self>, heartbeat: Option<u16>) -> Box<dyn BrokerBuilder> {
+        self
+    }
+
+    #[allow(unused)]
+    async fn build(&self, connection_timeout: u32) -> Result<Box<dyn Broker>, BrokerError> {
+        Err(BrokerError::UnsupportedScheme(
+            self.scheme.clone(),
+            registered_broker_schemes().join(", "),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register_broker_scheme() {
+        // The settings the builder doesn't implement are ignored.
+        let err = broker_builder_for_scheme("custombus://host/")
+            .prefetch_global(false)
+            .delayed_delivery(true)
+            .build(1)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, BrokerError::UnsupportedScheme(ref scheme, _) if scheme == "custombus")
+        );
+        assert!(err.to_string().contains("amqp, amqps, "));
+
+        register_broker_scheme("custombus", |_| {
+            Box::new(InMemoryBrokerBuilder::new("memory://custombus"))
+        });
+        assert!(registered_broker_schemes().contains(&"custombus".to_string()));
+        let broker = broker_builder_for_scheme("custombus://host/")
+            .build(1)
+            .await
+            .unwrap();
+        assert_eq!("memory://custombus", broker.safe_url());
+    }
+}
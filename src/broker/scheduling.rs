@@ -0,0 +1,288 @@
+use futures::Stream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// How a worker consuming several queues picks the next delivery among the queues which
+/// have messages.
+///
+/// Each queue is consumed on its own, and the worker schedules the deliveries of the
+/// consumers locally. With the Redis broker the consumers of the lower priority queues
+/// also leave their messages in the queue while a higher priority queue has messages, so
+/// that they aren't held by a worker busy with the higher priority ones.
+///
+/// # Examples
+///
+/// ```rust
+/// use celery::broker::QueueStrategy;
+///
+/// // Consume 5 messages from `critical` for each message from `bulk`.
+/// let strategy = QueueStrategy::weighted(&[("critical", 5), ("bulk", 1)]);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum QueueStrategy {
+    /// Take the deliveries from the queues in turn.
+    #[default]
+    RoundRobin,
+
+    /// Always take the deliveries from the first queue which has some, in the order the
+    /// queues are consumed, so that the last queues are only consumed when the first ones
+    /// are empty.
+    Priority,
+
+    /// Take deliveries from the queues in proportion to their weights, while they all have
+    /// messages. Queues without a weight have a weight of 1.
+    Weighted(HashMap<String, u32>),
+}
+
+impl QueueStrategy {
+    /// Get a [`Weighted`](QueueStrategy::Weighted) strategy from the weights of the queues.
+    pub fn weighted(weights: &[(&str, u32)]) -> Self {
+        QueueStrategy::Weighted(
+            weights
+                .iter()
+                .map(|(queue, weight)| (queue.to_string(), *weight))
+                .collect(),
+        )
+    }
+
+    fn weight(&self, queue: &str) -> u32 {
+        match self {
+            QueueStrategy::Weighted(weights) => weights.get(queue).copied().unwrap_or(1),
+            _ => 1,
+        }
+    }
+}
+
+/// How a worker handles the tasks it receives before their countdown or ETA is due.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use celery::broker::EtaStrategy;
+/// # use tokio::time::Duration;
+/// # async fn example() -> Result<(), celery::error::CeleryError> {
+/// let app = celery::CeleryBuilder::new("my_app", "redis://127.0.0.1:6379/", None)
+///     .worker_eta_strategy(EtaStrategy::Schedule)
+///     .worker_eta_poll_interval(Duration::from_secs(5))
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EtaStrategy {
+    /// Hold the tasks until they are due without acknowledging them, like Python Celery
+    /// does. The prefetch count is raised for each task held, so that the worker keeps
+    /// receiving the tasks which are due, and the tasks held are delivered again if the
+    /// worker is lost.
+    ///
+    /// Many tasks due later take up the memory of the worker, and delay the tasks sent
+    /// after them until they were all received.
+    #[default]
+    Hold,
+
+    /// Hand the tasks back to the broker to keep until they are due, and acknowledge them,
+    /// so that the workers only receive the tasks which are due.
+    ///
+    /// The tasks due within the [poll interval](crate::CeleryBuilder::worker_eta_poll_interval)
+    /// are held anyway. With the AMQP broker, this requires
+    /// [delayed delivery](crate::CeleryBuilder::broker_delayed_delivery). The Redis broker
+    /// keeps the tasks in a sorted set by ETA, from which the workers move the tasks which
+    /// are due back to their queues every poll interval. With the other brokers, the tasks
+    /// are held.
+    Schedule,
+}
+
+/// The streams of the consumers of several queues, merged according to a
+/// [`QueueStrategy`]. Like a [`StreamMap`](tokio_stream::StreamMap), it yields the items
+/// along with the queue they come from, and drops the streams which end.
+pub(crate) struct QueueStreams<S> {
+    strategy: QueueStrategy,
+    /// The queues in the order they are consumed, with their streams, weights and the
+    /// credits of the weighted strategy.
+    streams: Vec<QueueStream<S>>,
+    /// The queue polled first with the round robin strategy.
+    next: usize,
+}
+
+struct QueueStream<S> {
+    queue: String,
+    stream: S,
+    weight: i64,
+    credit: i64,
+}
+
+impl<S> QueueStreams<S>
+where
+    S: Stream + Unpin,
+{
+    pub(crate) fn new(strategy: QueueStrategy) -> Self {
+        Self {
+            strategy,
+            streams: vec![],
+            next: 0,
+        }
+    }
+
+    /// Add the stream of a queue, after the streams added so far.
+    pub(crate) fn insert(&mut self, queue: &str, stream: S) {
+        self.remove(queue);
+        self.streams.push(QueueStream {
+            queue: queue.into(),
+            stream,
+            weight: self.strategy.weight(queue) as i64,
+            credit: 0,
+        });
+    }
+
+    /// Remove the stream of a queue.
+    pub(crate) fn remove(&mut self, queue: &str) -> Option<S> {
+        let index = self.streams.iter().position(|s| s.queue == queue)?;
+        Some(self.streams.remove(index).stream)
+    }
+
+    /// The indices of the streams in the order they are polled.
+    fn poll_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.streams.len()).collect();
+        let len = order.len().max(1);
+        match self.strategy {
+            QueueStrategy::RoundRobin => order.rotate_left(self.next % len),
+            QueueStrategy::Priority => (),
+            // Like the smooth weighted round robin of nginx, the stream with the most
+            // credits once they all earned their weight goes first.
+            QueueStrategy::Weighted(_) => order.sort_by_key(|&index| {
+                let stream = &self.streams[index];
+                std::cmp::Reverse(stream.credit + stream.weight)
+            }),
+        }
+        order
+    }
+
+    /// Update the credits of the streams once a stream yielded an item.
+    fn picked(&mut self, picked: usize, pending: &[usize]) {
+        self.next = picked + 1;
+        if let QueueStrategy::Weighted(_) = self.strategy {
+            // Queues without messages don't save up credits for when they have some.
+            let mut total = 0;
+            for (index, stream) in self.streams.iter_mut().enumerate() {
+                if pending.contains(&index) {
+                    stream.credit = 0;
+                } else {
+                    stream.credit += stream.weight;
+                    total += stream.weight;
+                }
+            }
+            self.streams[picked].credit -= total;
+        }
+    }
+}
+
+impl<S> Stream for QueueStreams<S>
+where
+    S: Stream + Unpin,
+{
+    type Item = (String, S::Item);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut pending = vec![];
+        let mut ended = vec![];
+        let mut picked = None;
+        for index in self.poll_order() {
+            match Pin::new(&mut self.streams[index].stream).poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    picked = Some((index, item));
+                    break;
+                }
+                Poll::Ready(None) => ended.push(index),
+                Poll::Pending => pending.push(index),
+            }
+        }
+        if let Some((index, item)) = picked {
+            self.picked(index, &pending);
+            let queue = self.streams[index].queue.clone();
+            self.remove_ended(ended);
+            return Poll::Ready(Some((queue, item)));
+        }
+        self.remove_ended(ended);
+        if self.streams.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<S> QueueStreams<S> {
+    fn remove_ended(&mut self, mut ended: Vec<usize>) {
+        ended.sort_unstable();
+        for index in ended.into_iter().rev() {
+            self.streams.remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::{self, StreamExt};
+
+    /// Consume `count` items from queues which all have a backlog, and count the items
+    /// consumed from each queue.
+    async fn consume_backlog(strategy: QueueStrategy, queues: &[&str], count: usize) -> Vec<usize> {
+        let mut streams = QueueStreams::new(strategy);
+        for queue in queues {
+            streams.insert(queue, stream::repeat(()));
+        }
+        let mut consumed = vec![0; queues.len()];
+        for _ in 0..count {
+            let (queue, _) = streams.next().await.unwrap();
+            consumed[queues.iter().position(|q| *q == queue).unwrap()] += 1;
+        }
+        consumed
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let consumed = consume_backlog(QueueStrategy::RoundRobin, &["a", "b", "c"], 30).await;
+        assert_eq!(vec![10, 10, 10], consumed);
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let consumed = consume_backlog(QueueStrategy::Priority, &["critical", "bulk"], 30).await;
+        assert_eq!(vec![30, 0], consumed);
+
+        // The next queue is consumed once the first one is empty.
+        let mut streams = QueueStreams::new(QueueStrategy::Priority);
+        streams.insert("critical", stream::iter(vec![1, 2]).boxed());
+        streams.insert("bulk", stream::iter(vec![3, 4]).boxed());
+        let items: Vec<(String, i32)> = streams.collect().await;
+        assert_eq!(
+            vec![
+                ("critical".to_string(), 1),
+                ("critical".to_string(), 2),
+                ("bulk".to_string(), 3),
+                ("bulk".to_string(), 4)
+            ],
+            items
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weighted() {
+        let strategy = QueueStrategy::weighted(&[("critical", 5), ("default", 3)]);
+        let consumed = consume_backlog(strategy, &["critical", "default", "bulk"], 90).await;
+        assert_eq!(vec![50, 30, 10], consumed);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_queue_without_messages() {
+        let mut streams = QueueStreams::new(QueueStrategy::weighted(&[("critical", 3)]));
+        streams.insert("critical", stream::pending::<()>().boxed());
+        streams.insert("bulk", stream::repeat(()).boxed());
+        for _ in 0..10 {
+            assert_eq!("bulk", streams.next().await.unwrap().0);
+        }
+    }
+}
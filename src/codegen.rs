@@ -34,6 +34,40 @@ macro_rules! __app_internal {
 
         _build_app(builder)
     }};
+    (
+        $broker_type:ty { $broker_url:expr },
+        $backend_type:ident { $backend_url:expr },
+        [ $( $t:ty ),* ],
+        [ $( $pattern:expr => $queue:expr ),* ],
+        $( $x:ident = $y:expr, )*
+    ) => {{
+        async fn _build_app(mut builder: $crate::CeleryBuilder) ->
+            $crate::export::Result<$crate::export::Arc<$crate::Celery>> {
+            let celery: $crate::Celery = builder.build().await?;
+
+            $(
+                celery.register_task::<$t>().await?;
+            )*
+
+            Ok($crate::export::Arc::new(celery))
+        }
+
+        let broker_url = $broker_url;
+        let backend_url = $backend_url;
+
+        let mut builder = $crate::Celery::builder("celery", &broker_url, None)
+            .backend::<$backend_type>(&backend_url);
+
+        $(
+            builder = builder.$x($y);
+        )*
+
+        $(
+            builder = builder.task_route($pattern, $queue);
+        )*
+
+        _build_app(builder)
+    }};
     (
         $broker_type:ty { $broker_url:expr },
         $backend_url:expr,
@@ -115,10 +149,19 @@ macro_rules! __beat_internal {
 /// A macro for creating a [`Celery`](struct.Celery.html) app.
 ///
 /// At a minimum the `app!` macro requires these 3 arguments (in order):
-/// - `broker`: a broker type (currently only AMQP is supported) with an expression for the broker URL in brackets,
+/// - `broker`: a broker type (`AMQPBroker`, `RedisBroker` or `InMemoryBroker`) with an expression for the broker URL in brackets,
 /// - `tasks`: a list of tasks to register, and
 /// - `task_routes`: a list of routing rules in the form of `pattern => queue`.
 ///
+/// # Results backend
+///
+/// A results backend can be given right after the broker, either as a backend type
+/// (`RedisBackend`) with an expression for the backend URL in brackets, like the broker, or
+/// as an expression for the backend URL alone, `backend = expr` or `backend_url = expr`, in
+/// which case the type of backend is picked from the scheme of the URL. The
+/// [`AsyncResult`](task/struct.AsyncResult.html)s of the tasks sent by the app then get
+/// their results from the backend.
+///
 /// # Optional parameters
 ///
 /// Following the task routing rules there are a number of other optional parameters that
@@ -126,22 +169,95 @@ macro_rules! __beat_internal {
 /// [`CeleryBuilder`](struct.CeleryBuilder.html) struct):
 ///
 /// - `default_queue`: Set the
-/// [`CeleryBuilder::default_queue`](struct.CeleryBuilder.html#method.default_queue).
+///   [`CeleryBuilder::default_queue`](struct.CeleryBuilder.html#method.default_queue).
 /// - `prefetch_count`: Set the [`CeleryBuilder::prefect_count`](struct.CeleryBuilder.html#method.prefect_count).
+/// - `prefetch_global`: Set the [`CeleryBuilder::prefetch_global`](struct.CeleryBuilder.html#method.prefetch_global).
+/// - `prefetch_multiplier`: Set the [`CeleryBuilder::prefetch_multiplier`](struct.CeleryBuilder.html#method.prefetch_multiplier).
 /// - `heartbeat`: Set the [`CeleryBuilder::heartbeat`](struct.CeleryBuilder.html#method.heartbeat).
 /// - `task_time_limit`: Set an app-level [`TaskOptions::time_limit`](task/struct.TaskOptions.html#structfield.time_limit).
 /// - `task_hard_time_limit`: Set an app-level [`TaskOptions::hard_time_limit`](task/struct.TaskOptions.html#structfield.hard_time_limit).
+/// - `task_soft_time_limit`: Set an app-level [`TaskOptions::soft_time_limit`](task/struct.TaskOptions.html#structfield.soft_time_limit).
 /// - `task_max_retries`: Set an app-level [`TaskOptions::max_retries`](task/struct.TaskOptions.html#structfield.max_retries).
 /// - `task_min_retry_delay`: Set an app-level [`TaskOptions::min_retry_delay`](task/struct.TaskOptions.html#structfield.min_retry_delay).
 /// - `task_max_retry_delay`: Set an app-level [`TaskOptions::max_retry_delay`](task/struct.TaskOptions.html#structfield.max_retry_delay).
+/// - `task_retry_backoff`: Set an app-level [`TaskOptions::retry_backoff`](task/struct.TaskOptions.html#structfield.retry_backoff).
+/// - `task_retry_backoff_max`: Set an app-level [`TaskOptions::retry_backoff_max`](task/struct.TaskOptions.html#structfield.retry_backoff_max).
+/// - `task_retry_jitter`: Set an app-level [`TaskOptions::retry_jitter`](task/struct.TaskOptions.html#structfield.retry_jitter).
 /// - `task_retry_for_unexpected`: Set an app-level [`TaskOptions::retry_for_unexpected`](task/struct.TaskOptions.html#structfield.retry_for_unexpected).
 /// - `acks_late`: Set an app-level [`TaskOptions::acks_late`](task/struct.TaskOptions.html#structfield.acks_late).
+/// - `task_reject_on_worker_lost`: Set an app-level [`TaskOptions::reject_on_worker_lost`](task/struct.TaskOptions.html#structfield.reject_on_worker_lost).
+/// - `task_compression`: Set an app-level [`TaskOptions::compression`](task/struct.TaskOptions.html#structfield.compression).
+/// - `task_compression_threshold`: Set an app-level [`TaskOptions::compression_threshold`](task/struct.TaskOptions.html#structfield.compression_threshold).
+/// - `task_default_priority`: Set an app-level [`TaskOptions::priority`](task/struct.TaskOptions.html#structfield.priority).
+/// - `task_default_delivery_mode`: Set an app-level [`TaskOptions::delivery_mode`](task/struct.TaskOptions.html#structfield.delivery_mode).
+/// - `task_rate_limit`: Set an app-level [`TaskOptions::rate_limit`](task/struct.TaskOptions.html#structfield.rate_limit).
+/// - `task_dedup`: Set an app-level [`TaskOptions::dedup`](task/struct.TaskOptions.html#structfield.dedup).
+/// - `task_dedup_ttl`: Set an app-level [`TaskOptions::dedup_ttl`](task/struct.TaskOptions.html#structfield.dedup_ttl).
+/// - `task_ignore_result`: Set an app-level [`TaskOptions::ignore_result`](task/struct.TaskOptions.html#structfield.ignore_result).
+/// - `worker_hostname`: Set the node name with
+///   [`CeleryBuilder::worker_hostname`](struct.CeleryBuilder.html#method.worker_hostname).
+/// - `worker_events`: Set the
+///   [`CeleryBuilder::worker_events`](struct.CeleryBuilder.html#method.worker_events).
+/// - `worker_heartbeat_interval`: Set the
+///   [`CeleryBuilder::worker_heartbeat_interval`](struct.CeleryBuilder.html#method.worker_heartbeat_interval).
+/// - `worker_enable_remote_control`: Set the
+///   [`CeleryBuilder::worker_enable_remote_control`](struct.CeleryBuilder.html#method.worker_enable_remote_control).
+/// - `worker_persistent_revokes`: Set the
+///   [`CeleryBuilder::worker_persistent_revokes`](struct.CeleryBuilder.html#method.worker_persistent_revokes).
+/// - `worker_max_redeliveries`: Set the
+///   [`CeleryBuilder::worker_max_redeliveries`](struct.CeleryBuilder.html#method.worker_max_redeliveries).
+/// - `include_tasks`: Set the
+///   [`CeleryBuilder::include_tasks`](struct.CeleryBuilder.html#method.include_tasks).
+/// - `exclude_tasks`: Set the
+///   [`CeleryBuilder::exclude_tasks`](struct.CeleryBuilder.html#method.exclude_tasks).
+/// - `worker_abort_grace_period`: Set the
+///   [`CeleryBuilder::worker_abort_grace_period`](struct.CeleryBuilder.html#method.worker_abort_grace_period).
+/// - `max_memory_per_worker_mb`: Set the
+///   [`CeleryBuilder::max_memory_per_worker_mb`](struct.CeleryBuilder.html#method.max_memory_per_worker_mb).
+/// - `blocking_threads`: Set the
+///   [`CeleryBuilder::blocking_threads`](struct.CeleryBuilder.html#method.blocking_threads).
+/// - `task_publish_retry`: Set the
+///   [`CeleryBuilder::task_publish_retry`](struct.CeleryBuilder.html#method.task_publish_retry).
+/// - `task_publish_retry_policy`: Set the
+///   [`CeleryBuilder::task_publish_retry_policy`](struct.CeleryBuilder.html#method.task_publish_retry_policy).
+/// - `broker_delayed_delivery`: Set the
+///   [`CeleryBuilder::broker_delayed_delivery`](struct.CeleryBuilder.html#method.broker_delayed_delivery).
+/// - `worker_eta_strategy`: Set the
+///   [`CeleryBuilder::worker_eta_strategy`](struct.CeleryBuilder.html#method.worker_eta_strategy).
+/// - `worker_eta_poll_interval`: Set the
+///   [`CeleryBuilder::worker_eta_poll_interval`](struct.CeleryBuilder.html#method.worker_eta_poll_interval).
+/// - `default_queue_options`: Set the
+///   [`CeleryBuilder::default_queue_options`](struct.CeleryBuilder.html#method.default_queue_options).
+/// - `broadcast_queue`: Declare a
+///   [`CeleryBuilder::broadcast_queue`](struct.CeleryBuilder.html#method.broadcast_queue).
+/// - `declare_exchanges`: Declare exchanges with
+///   [`CeleryBuilder::declare_exchanges`](struct.CeleryBuilder.html#method.declare_exchanges).
+/// - `bind_queues`: Bind queues to exchanges with
+///   [`CeleryBuilder::bind_queues`](struct.CeleryBuilder.html#method.bind_queues).
+/// - `broker_publisher_confirms`: Set the
+///   [`CeleryBuilder::broker_publisher_confirms`](struct.CeleryBuilder.html#method.broker_publisher_confirms).
+/// - `broker_confirm_timeout`: Set the
+///   [`CeleryBuilder::broker_confirm_timeout`](struct.CeleryBuilder.html#method.broker_confirm_timeout).
+/// - `broker_visibility_timeout`: Set the
+///   [`CeleryBuilder::broker_visibility_timeout`](struct.CeleryBuilder.html#method.broker_visibility_timeout).
+/// - `broker_pool_limit`: Set the
+///   [`CeleryBuilder::broker_pool_limit`](struct.CeleryBuilder.html#method.broker_pool_limit).
+/// - `broker_separate_producer_connection`: Set the
+///   [`CeleryBuilder::broker_separate_producer_connection`](struct.CeleryBuilder.html#method.broker_separate_producer_connection).
 /// - `broker_connection_timeout`: Set the
-/// [`CeleryBuilder::broker_connection_timeout`](struct.CeleryBuilder.html#method.broker_connection_timeout).
+///   [`CeleryBuilder::broker_connection_timeout`](struct.CeleryBuilder.html#method.broker_connection_timeout).
 /// - `broker_connection_retry`: Set the
-/// [`CeleryBuilder::broker_connection_retry`](struct.CeleryBuilder.html#method.broker_connection_retry).
+///   [`CeleryBuilder::broker_connection_retry`](struct.CeleryBuilder.html#method.broker_connection_retry).
 /// - `broker_connection_max_retries`: Set the
-/// [`CeleryBuilder::broker_connection_max_retries`](struct.CeleryBuilder.html#method.broker_connection_max_retries).
+///   [`CeleryBuilder::broker_connection_max_retries`](struct.CeleryBuilder.html#method.broker_connection_max_retries).
+/// - `broker_connection_retry_policy`: Set the
+///   [`CeleryBuilder::broker_connection_retry_policy`](struct.CeleryBuilder.html#method.broker_connection_retry_policy).
+/// - `lazy_connect`: Set the
+///   [`CeleryBuilder::lazy_connect`](struct.CeleryBuilder.html#method.lazy_connect).
+/// - `task_always_eager`: Set the
+///   [`CeleryBuilder::task_always_eager`](struct.CeleryBuilder.html#method.task_always_eager).
+/// - `result_expires`: Set the
+///   [`CeleryBuilder::result_expires`](struct.CeleryBuilder.html#method.result_expires).
 ///
 /// # Examples
 ///
@@ -181,6 +297,46 @@ macro_rules! __beat_internal {
 /// # Ok(())
 /// # }
 /// ```
+///
+/// Store the results of the tasks in Redis:
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate celery;
+/// # use anyhow::Result;
+/// # use celery::prelude::*;
+/// # #[tokio::main]
+/// # async fn main() -> Result<()> {
+/// let app = celery::app!(
+///     broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap() },
+///     backend = RedisBackend { std::env::var("REDIS_ADDR").unwrap() },
+///     tasks = [],
+///     task_routes = [],
+/// ).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Declare exchanges and bind queues to them when connecting, so that a fresh virtual host
+/// works without any manual setup:
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate celery;
+/// # use anyhow::Result;
+/// # use celery::prelude::*;
+/// use celery::broker::ExchangeKind;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<()> {
+/// let app = celery::app!(
+///     broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap() },
+///     tasks = [],
+///     task_routes = [],
+///     declare_exchanges = [("events", ExchangeKind::Topic, true)],
+///     bind_queues = [("audit", "events", "events.#")],
+/// ).await?;
+/// # Ok(())
+/// # }
+/// ```
 #[macro_export]
 macro_rules! app {
     (
@@ -196,6 +352,21 @@ macro_rules! app {
             $( $x = $y, )*
         );
     };
+    (
+        broker = $broker_type:ty { $broker_url:expr },
+        backend = $backend_type:ident { $backend_url:expr },
+        tasks = [ $( $t:ty ),* $(,)? ],
+        task_routes = [ $( $pattern:expr => $queue:expr ),* $(,)? ]
+        $(, $x:ident = $y:expr )* $(,)?
+    ) => {
+        $crate::__app_internal!(
+            $broker_type { $broker_url },
+            $backend_type { $backend_url },
+            [ $( $t ),* ],
+            [ $( $pattern => $queue ),* ],
+            $( $x = $y, )*
+        );
+    };
     (
         broker = $broker_type:ty { $broker_url:expr },
         backend = $backend_url:expr,
@@ -211,12 +382,27 @@ macro_rules! app {
             $( $x = $y, )*
         );
     };
+    (
+        broker = $broker_type:ty { $broker_url:expr },
+        backend_url = $backend_url:expr,
+        tasks = [ $( $t:ty ),* $(,)? ],
+        task_routes = [ $( $pattern:expr => $queue:expr ),* $(,)? ]
+        $(, $x:ident = $y:expr )* $(,)?
+    ) => {
+        $crate::__app_internal!(
+            $broker_type { $broker_url },
+            $backend_url,
+            [ $( $t ),* ],
+            [ $( $pattern => $queue ),* ],
+            $( $x = $y, )*
+        );
+    };
 }
 
 /// A macro for creating a [`Beat`](beat/struct.Beat.html) app.
 ///
 /// At a minimum the `beat!` macro requires these 3 arguments (in order):
-/// - `broker`: a broker type (currently only AMQP is supported) with an expression for the broker URL in brackets,
+/// - `broker`: a broker type (`AMQPBroker`, `RedisBroker` or `InMemoryBroker`) with an expression for the broker URL in brackets,
 /// - `tasks`: a list of tasks together with their relative schedules (can be empty),
 /// - `task_routes`: a list of routing rules in the form of `pattern => queue`.
 ///
@@ -240,14 +426,34 @@ macro_rules! app {
 /// (all of which correspond to a method on the [`BeatBuilder`](beat/struct.BeatBuilder.html) struct):
 ///
 /// - `default_queue`: Set the
-/// [`BeatBuilder::default_queue`](beat/struct.BeatBuilder.html#method.default_queue).
+///   [`BeatBuilder::default_queue`](beat/struct.BeatBuilder.html#method.default_queue).
 /// - `heartbeat`: Set the [`BeatBuilder::heartbeat`](beat/struct.BeatBuilder.html#method.heartbeat).
+/// - `default_queue_options`: Set the
+///   [`BeatBuilder::default_queue_options`](beat/struct.BeatBuilder.html#method.default_queue_options).
+/// - `broadcast_queue`: Declare a
+///   [`BeatBuilder::broadcast_queue`](beat/struct.BeatBuilder.html#method.broadcast_queue).
+/// - `declare_exchanges`: Declare exchanges with
+///   [`BeatBuilder::declare_exchanges`](beat/struct.BeatBuilder.html#method.declare_exchanges).
+/// - `bind_queues`: Bind queues to exchanges with
+///   [`BeatBuilder::bind_queues`](beat/struct.BeatBuilder.html#method.bind_queues).
+/// - `task_publish_retry`: Set the
+///   [`BeatBuilder::task_publish_retry`](beat/struct.BeatBuilder.html#method.task_publish_retry).
+/// - `task_publish_retry_policy`: Set the
+///   [`BeatBuilder::task_publish_retry_policy`](beat/struct.BeatBuilder.html#method.task_publish_retry_policy).
+/// - `broker_publisher_confirms`: Set the
+///   [`BeatBuilder::broker_publisher_confirms`](beat/struct.BeatBuilder.html#method.broker_publisher_confirms).
+/// - `broker_confirm_timeout`: Set the
+///   [`BeatBuilder::broker_confirm_timeout`](beat/struct.BeatBuilder.html#method.broker_confirm_timeout).
 /// - `broker_connection_timeout`: Set the
-/// [`BeatBuilder::broker_connection_timeout`](beat/struct.BeatBuilder.html#method.broker_connection_timeout).
+///   [`BeatBuilder::broker_connection_timeout`](beat/struct.BeatBuilder.html#method.broker_connection_timeout).
 /// - `broker_connection_retry`: Set the
-/// [`BeatBuilder::broker_connection_retry`](beat/struct.BeatBuilder.html#method.broker_connection_retry).
+///   [`BeatBuilder::broker_connection_retry`](beat/struct.BeatBuilder.html#method.broker_connection_retry).
 /// - `broker_connection_max_retries`: Set the
-/// [`BeatBuilder::broker_connection_max_retries`](beat/struct.BeatBuilder.html#method.broker_connection_max_retries).
+///   [`BeatBuilder::broker_connection_max_retries`](beat/struct.BeatBuilder.html#method.broker_connection_max_retries).
+/// - `broker_connection_retry_policy`: Set the
+///   [`BeatBuilder::broker_connection_retry_policy`](beat/struct.BeatBuilder.html#method.broker_connection_retry_policy).
+/// - `lazy_connect`: Set the
+///   [`BeatBuilder::lazy_connect`](beat/struct.BeatBuilder.html#method.lazy_connect).
 ///
 /// # Examples
 ///
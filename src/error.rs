@@ -2,6 +2,7 @@
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use thiserror::Error;
 
 /// Errors that can occur while creating or using a `Celery` app.
@@ -11,10 +12,39 @@ pub enum CeleryError {
     #[error("at least one queue required to consume from")]
     NoQueueToConsume,
 
+    /// Raised when `Celery::broadcast_task` is called on an app without broadcast queues.
+    #[error("no broadcast queue to send the task to")]
+    NoBroadcastQueue,
+
+    /// Raised when a remote control command is sent from an app without
+    /// [`worker_enable_remote_control`](crate::CeleryBuilder::worker_enable_remote_control).
+    #[error("remote control is disabled")]
+    RemoteControlDisabled,
+
+    /// Raised when a task is sent to a priority queue with a priority above the maximum
+    /// priority of the queue (see
+    /// [`queue_max_priority`](crate::CeleryBuilder::queue_max_priority)).
+    #[error("priority {priority} of task {task} exceeds the maximum priority {max_priority} of queue {queue}")]
+    InvalidPriority {
+        task: String,
+        priority: u8,
+        queue: String,
+        max_priority: u8,
+    },
+
     /// Forced shutdown.
     #[error("forced shutdown")]
     ForcedShutdown,
 
+    /// The worker stopped because its resident memory exceeded
+    /// [`max_memory_per_worker_mb`](crate::CeleryBuilder::max_memory_per_worker_mb), in
+    /// bytes. The process is expected to exit and be restarted.
+    #[error("resident memory of {resident_memory} bytes exceeded the limit of {max_memory} bytes")]
+    MemoryLimitExceeded {
+        resident_memory: u64,
+        max_memory: u64,
+    },
+
     /// Any other broker-level error that could happen when initializing or with an open
     /// connection.
     #[error("broker error")]
@@ -38,6 +68,24 @@ pub enum CeleryError {
     /// Raised when failed to store state or result to backend.
     #[error("backend_error")]
     Backend(#[from] BackendError),
+
+    /// A fatal error returned by a worker lifecycle hook, which stops the worker.
+    #[error("worker hook error")]
+    HookError(#[from] HookError),
+}
+
+/// Errors returned by the worker lifecycle hooks (see [`signals`](crate::signals)).
+#[derive(Error, Debug)]
+pub enum HookError {
+    /// The hook failed, which is logged without stopping the worker.
+    #[error("hook failed: {0}")]
+    Failed(String),
+
+    /// The hook failed in a way the worker can't run with, e.g. the worker couldn't
+    /// register itself with a service it depends on. The worker stops with a
+    /// [`CeleryError::HookError`], unless it is already shutting down.
+    #[error("hook failed fatally: {0}")]
+    Fatal(String),
 }
 
 /// Errors that can occur while creating or using a `Beat` app.
@@ -54,6 +102,10 @@ pub enum BeatError {
     /// An error with a task schedule.
     #[error("task schedule error")]
     ScheduleError(#[from] ScheduleError),
+
+    /// Any IO error that could occur.
+    #[error("IO error")]
+    IoError(#[from] std::io::Error),
 }
 
 /// Errors that are related to task schedules.
@@ -64,7 +116,14 @@ pub enum ScheduleError {
     CronScheduleError(String),
 }
 
+/// The error of a task.
+///
+/// It is serialized in the result backend, in the events and for the error callbacks as an
+/// [`ExceptionInfo`], the structure of the exceptions of Python Celery, so that Python
+/// consumers can read it. The errors serialized as this enum by older versions of the crate
+/// are still deserialized.
 #[derive(Error, Debug, Serialize, Deserialize, Clone)]
+#[serde(into = "ExceptionInfo", from = "SerializedTaskError")]
 pub enum TaskError {
     /// An error that is expected to happen every once in a while.
     ///
@@ -107,6 +166,163 @@ pub enum TaskError {
     /// to manually trigger a retry from within a task.
     #[error("task retry triggered")]
     Retry(Option<DateTime<Utc>>),
+
+    /// A task can return this error variant to give its message back, e.g. when it finds
+    /// out that it was delivered to the wrong worker.
+    ///
+    /// With `requeue`, the message is sent back to its queue for another worker to execute
+    /// the task, up to [`worker_max_redeliveries`](crate::CeleryBuilder::worker_max_redeliveries)
+    /// times, after which it is rejected. Otherwise it is rejected right away, so that it
+    /// ends up in the dead-letter queue of its queue if there is one. Either way, the task
+    /// doesn't consume a retry and nothing is stored in the result backend. Tasks executed
+    /// eagerly fail instead, since they have no message.
+    ///
+    /// This error variant can be returned with the `Task::reject` trait method.
+    #[error("task rejected")]
+    Reject { requeue: bool },
+}
+
+/// The module of the Python exceptions which task errors are serialized as.
+const EXCEPTIONS_MODULE: &str = "celery.exceptions";
+
+/// The serialized form of a [`TaskError`], the one of the exceptions of Python Celery.
+///
+/// Each variant of [`TaskError`] has its own exception type, the ones with an equivalent in
+/// Python Celery having its type, e.g. `TimeLimitExceeded`. The exceptions of other types,
+/// e.g. raised by Python tasks, are converted to [`TaskError::UnexpectedError`]s with their
+/// type and message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExceptionInfo {
+    /// The name of the type of the exception.
+    pub exc_type: String,
+
+    /// The arguments of the exception.
+    #[serde(default)]
+    pub exc_message: Vec<Value>,
+
+    /// The module of the type of the exception.
+    #[serde(default)]
+    pub exc_module: String,
+}
+
+impl ExceptionInfo {
+    fn new(exc_type: &str, exc_message: Vec<Value>) -> Self {
+        Self {
+            exc_type: exc_type.into(),
+            exc_message,
+            exc_module: EXCEPTIONS_MODULE.into(),
+        }
+    }
+
+    /// The message of the exception, made of its arguments.
+    fn message(&self) -> String {
+        self.exc_message
+            .iter()
+            .map(|arg| match arg {
+                Value::String(arg) => arg.clone(),
+                arg => arg.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl From<TaskError> for ExceptionInfo {
+    fn from(error: TaskError) -> Self {
+        match error {
+            TaskError::ExpectedError(message) => {
+                Self::new("ExpectedError", vec![Value::String(message)])
+            }
+            TaskError::UnexpectedError(message) => {
+                Self::new("UnexpectedError", vec![Value::String(message)])
+            }
+            TaskError::TimeoutError => Self::new("TimeLimitExceeded", vec![]),
+            // Like `Retry(message, exc, when)` in Python.
+            TaskError::Retry(eta) => Self::new(
+                "Retry",
+                vec![
+                    Value::Null,
+                    Value::Null,
+                    eta.map_or(Value::Null, |eta| Value::String(eta.to_rfc3339())),
+                ],
+            ),
+            // Like `Reject(reason, requeue)` in Python.
+            TaskError::Reject { requeue } => {
+                Self::new("Reject", vec![Value::Null, Value::Bool(requeue)])
+            }
+        }
+    }
+}
+
+impl From<ExceptionInfo> for TaskError {
+    fn from(exception: ExceptionInfo) -> Self {
+        match (exception.exc_module.as_str(), exception.exc_type.as_str()) {
+            (EXCEPTIONS_MODULE, "ExpectedError") => TaskError::ExpectedError(exception.message()),
+            (EXCEPTIONS_MODULE, "UnexpectedError") => {
+                TaskError::UnexpectedError(exception.message())
+            }
+            (EXCEPTIONS_MODULE, "TimeLimitExceeded") => TaskError::TimeoutError,
+            (EXCEPTIONS_MODULE, "Retry") => TaskError::Retry(
+                exception
+                    .exc_message
+                    .get(2)
+                    .and_then(Value::as_str)
+                    .and_then(|eta| DateTime::parse_from_rfc3339(eta).ok())
+                    .map(|eta| eta.with_timezone(&Utc)),
+            ),
+            (EXCEPTIONS_MODULE, "Reject") => TaskError::Reject {
+                requeue: exception
+                    .exc_message
+                    .get(1)
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            },
+            (_, exc_type) if exception.exc_message.is_empty() => {
+                TaskError::UnexpectedError(exc_type.into())
+            }
+            (_, exc_type) => {
+                TaskError::UnexpectedError(format!("{}: {}", exc_type, exception.message()))
+            }
+        }
+    }
+}
+
+/// A [`TaskError`] as it is deserialized: as an [`ExceptionInfo`], or as the enum it was
+/// serialized as by older versions of the crate.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SerializedTaskError {
+    Exception(ExceptionInfo),
+    Legacy(LegacyTaskError),
+}
+
+/// The variants of [`TaskError`], as they were serialized by older versions of the crate.
+#[derive(Deserialize)]
+enum LegacyTaskError {
+    ExpectedError(String),
+    UnexpectedError(String),
+    TimeoutError,
+    Retry(Option<DateTime<Utc>>),
+    Reject { requeue: bool },
+}
+
+impl From<SerializedTaskError> for TaskError {
+    fn from(error: SerializedTaskError) -> Self {
+        match error {
+            SerializedTaskError::Exception(exception) => exception.into(),
+            SerializedTaskError::Legacy(LegacyTaskError::ExpectedError(message)) => {
+                TaskError::ExpectedError(message)
+            }
+            SerializedTaskError::Legacy(LegacyTaskError::UnexpectedError(message)) => {
+                TaskError::UnexpectedError(message)
+            }
+            SerializedTaskError::Legacy(LegacyTaskError::TimeoutError) => TaskError::TimeoutError,
+            SerializedTaskError::Legacy(LegacyTaskError::Retry(eta)) => TaskError::Retry(eta),
+            SerializedTaskError::Legacy(LegacyTaskError::Reject { requeue }) => {
+                TaskError::Reject { requeue }
+            }
+        }
+    }
 }
 
 /// Errors that can occur while tracing a task.
@@ -124,11 +340,32 @@ pub(crate) enum TraceError {
     #[error("retrying task")]
     Retry(Option<DateTime<Utc>>),
 
+    /// Raised when a task failed and has exhausted its retries.
+    #[error("task retries exceeded")]
+    RetriesExceeded(TaskError),
+
+    /// Raised when a task was aborted while it ran.
+    #[error("task aborted")]
+    Aborted,
+
+    /// Raised when a task gave its message back.
+    #[error("task rejected")]
+    Rejected { requeue: bool },
+
+    /// Raised when a task panicked, taking down the worker executing it.
+    #[error("worker lost")]
+    WorkerLost,
+
     /// Raised when failed to store state or result to backend.
     #[error("backend_error")]
     Backend(#[from] BackendError),
 }
 
+/// Raised when a [`RateLimit`](crate::task::RateLimit) can't be parsed.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid rate limit '{0}', expected e.g. '10/s', '100/m' or '1000/h'")]
+pub struct ParseRateLimitError(pub(crate) String);
+
 /// Errors that can occur at the broker level.
 #[derive(Error, Debug)]
 pub enum BrokerError {
@@ -136,14 +373,41 @@ pub enum BrokerError {
     #[error("invalid broker URL '{0}'")]
     InvalidBrokerUrl(String),
 
+    /// Raised when a broker URL refers to an environment variable which isn't set.
+    #[error("environment variable '{0}' of the broker URL is not set")]
+    UnsetUrlVariable(String),
+
+    /// Raised when no broker is registered for the scheme of a broker URL, along with the
+    /// registered schemes.
+    #[error("unsupported broker scheme '{0}', the registered schemes are: {1}")]
+    UnsupportedScheme(String, String),
+
     /// The queue you're attempting to use has not been defined.
     #[error("unknown queue '{0}'")]
     UnknownQueue(String),
 
+    /// The exchange you're attempting to publish to has not been declared.
+    #[error("unknown exchange '{0}'")]
+    UnknownExchange(String),
+
+    /// An exchange being declared already exists on the broker with different properties,
+    /// e.g. another kind. It has to be deleted first for the new properties to apply.
+    #[error("exchange '{0}' already exists with different properties: {1}")]
+    ExchangeMismatch(String, String),
+
     /// Broker is disconnected.
     #[error("broker not connected")]
     NotConnected,
 
+    /// The broker cancelled a consumer of the queue, e.g. because the queue was deleted.
+    #[error("consumer of queue '{0}' cancelled by the broker")]
+    ConsumerCancelled(String),
+
+    /// A published message wasn't confirmed by the broker, either because it was negatively
+    /// acknowledged or because the confirmation timed out.
+    #[error("publish not confirmed: {0}")]
+    PublishNack(String),
+
     /// Any IO error that could occur.
     #[error("IO error \"{0}\"")]
     IoError(#[from] std::io::Error),
@@ -167,24 +431,52 @@ pub enum BrokerError {
     /// Any other Redis error that could happen.
     #[error("Redis error \"{0}\"")]
     RedisError(#[from] redis::RedisError),
+
+    /// Any other NATS error that could happen.
+    #[cfg(feature = "nats")]
+    #[error("NATS error \"{0}\"")]
+    NatsError(String),
+
+    /// Any other Google Cloud Pub/Sub error that could happen.
+    #[cfg(feature = "pubsub")]
+    #[error("Pub/Sub error \"{0}\"")]
+    PubSubError(String),
+
+    /// Any other Azure Service Bus error that could happen.
+    #[cfg(feature = "servicebus")]
+    #[error("Service Bus error \"{0}\"")]
+    ServiceBusError(String),
 }
 
 impl BrokerError {
     pub fn is_connection_error(&self) -> bool {
         match self {
-            BrokerError::IoError(_) | BrokerError::NotConnected => true,
+            // Consumers cancelled by the broker are started again after reconnecting.
+            BrokerError::IoError(_)
+            | BrokerError::NotConnected
+            | BrokerError::ConsumerCancelled(_) => true,
             BrokerError::AMQPError(err) => matches!(
                 err,
-                lapin::Error::ProtocolError(_)
+                lapin::Error::IOError(_)
+                    | lapin::Error::ProtocolError(_)
                     | lapin::Error::InvalidConnectionState(_)
                     | lapin::Error::InvalidChannelState(_)
             ),
             BrokerError::RedisError(err) => {
-                err.is_connection_dropped() || err.is_connection_refusal()
+                // A read-only error means that the master was demoted after a failover.
+                err.is_connection_dropped()
+                    || err.is_connection_refusal()
+                    || err.kind() == redis::ErrorKind::ReadOnly
             }
             _ => false,
         }
     }
+
+    /// Whether the operation which failed with this error could succeed if it was retried,
+    /// possibly after reconnecting.
+    pub fn is_retryable(&self) -> bool {
+        self.is_connection_error() || matches!(self, BrokerError::PublishNack(_))
+    }
 }
 
 /// Errors that can occur at the result backend level.
@@ -194,6 +486,10 @@ pub enum BackendError {
     #[error("Invalid backend URL '{0}'")]
     InvalidBackendUrl(String),
 
+    /// Raised when a backend URL refers to an environment variable which isn't set.
+    #[error("Environment variable '{0}' of the backend URL is not set")]
+    UnsetUrlVariable(String),
+
     /// Backend is disconnected.
     #[error("Backend not connected")]
     NotConnected,
@@ -222,6 +518,15 @@ pub enum BackendError {
     /// Backend is not set.
     #[error("Backend not set")]
     NotSet,
+
+    /// The result of the task isn't stored (see
+    /// [`TaskOptions::ignore_result`](crate::task::TaskOptions::ignore_result)).
+    #[error("Result of the task is ignored")]
+    ResultIgnored,
+
+    /// The backend can't delete the expired results (see [`Backend::cleanup`](crate::backend::Backend::cleanup)).
+    #[error("Backend doesn't support cleanup")]
+    CleanupNotSupported,
 }
 
 /// An invalid glob pattern for a routing rule.
@@ -251,6 +556,26 @@ pub enum ProtocolError {
     /// Raised when field value is invalid.
     #[error("invalid property '{0}'")]
     InvalidProperty(String),
+
+    /// Raised when two options which exclude each other are both set.
+    #[error("options '{0}' and '{1}' can't be set together")]
+    ConflictingOptions(String, String),
+
+    /// Raised when compressing or decompressing a message body fails.
+    #[error("message body compression error")]
+    CompressionError(#[from] std::io::Error),
+
+    /// Raised when a message body is compressed with an unknown algorithm.
+    #[error("unknown compression '{0}'")]
+    UnknownCompression(String),
+
+    /// Raised when a message which should be signed has no signature.
+    #[error("missing message signature")]
+    MissingSignature,
+
+    /// Raised when a message is not signed with any of the accepted keys.
+    #[error("invalid message signature")]
+    InvalidSignature,
 }
 
 impl From<serde_json::Error> for ProtocolError {
@@ -322,3 +647,87 @@ pub enum ContentTypeError {
     #[error("Unknown content type error")]
     Unknown,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn exception(exc_type: &str, exc_message: Value) -> Value {
+        json!({
+            "exc_type": exc_type,
+            "exc_message": exc_message,
+            "exc_module": "celery.exceptions",
+        })
+    }
+
+    #[test]
+    fn test_task_error_serialization() {
+        let eta = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+        let errors = vec![
+            (
+                TaskError::ExpectedError("invalid input".into()),
+                exception("ExpectedError", json!(["invalid input"])),
+            ),
+            (
+                TaskError::TimeoutError,
+                exception("TimeLimitExceeded", json!([])),
+            ),
+            (
+                TaskError::Retry(Some(eta)),
+                exception("Retry", json!([null, null, "2023-01-01T12:00:00+00:00"])),
+            ),
+            (
+                TaskError::Reject { requeue: true },
+                exception("Reject", json!([null, true])),
+            ),
+        ];
+        for (error, serialized) in errors {
+            assert_eq!(serialized, serde_json::to_value(&error).unwrap());
+            let deserialized: TaskError = serde_json::from_value(serialized).unwrap();
+            assert_eq!(error.to_string(), deserialized.to_string());
+        }
+        let retried: TaskError = serde_json::from_value(exception(
+            "Retry",
+            json!([null, null, "2023-01-01T12:00:00+00:00"]),
+        ))
+        .unwrap();
+        assert!(matches!(retried, TaskError::Retry(Some(time)) if time == eta));
+    }
+
+    #[test]
+    fn test_task_error_from_python() {
+        let error: TaskError = serde_json::from_value(
+            json!({"exc_type": "ValueError", "exc_message": ["invalid literal", 10], "exc_module": "builtins"}),
+        )
+        .unwrap();
+        assert!(matches!(
+            error,
+            TaskError::UnexpectedError(ref message) if message == "ValueError: invalid literal, 10"
+        ));
+        let error: TaskError = serde_json::from_value(
+            json!({"exc_type": "ExpectedError", "exc_message": [], "exc_module": "app.errors"}),
+        )
+        .unwrap();
+        assert!(matches!(
+            error,
+            TaskError::UnexpectedError(ref message) if message == "ExpectedError"
+        ));
+    }
+
+    #[test]
+    fn test_task_error_legacy_deserialization() {
+        let error: TaskError =
+            serde_json::from_value(json!({"UnexpectedError": "invalid input"})).unwrap();
+        assert!(matches!(
+            error,
+            TaskError::UnexpectedError(ref message) if message == "invalid input"
+        ));
+        let error: TaskError = serde_json::from_value(json!("TimeoutError")).unwrap();
+        assert!(matches!(error, TaskError::TimeoutError));
+        let error: TaskError =
+            serde_json::from_value(json!({"Reject": {"requeue": true}})).unwrap();
+        assert!(matches!(error, TaskError::Reject { requeue: true }));
+    }
+}
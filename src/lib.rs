@@ -87,12 +87,18 @@
 )]
 
 mod app;
+mod logging;
 mod routing;
+mod urls;
 pub mod backend;
-pub use app::{Celery, CeleryBuilder};
+pub use app::{control, signals, Celery, CeleryBuilder, BACKEND_CLEANUP_TASK};
 pub mod beat;
 pub mod broker;
 pub mod error;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(not(feature = "metrics"))]
+mod metrics;
 pub mod prelude;
 pub mod protocol;
 pub mod task;
@@ -108,22 +114,67 @@ mod codegen;
 /// # Parameters
 ///
 /// - `name`: The name to use when registering the task. Should be unique. If not given the name
-/// will be set to the name of the function being decorated.
+///   will be set to the name of the function being decorated.
 /// - `time_limit`: Set a task-level [`TaskOptions::time_limit`](task/struct.TaskOptions.html#structfield.time_limit).
 /// - `hard_time_limit`: Set a task-level [`TaskOptions::hard_time_limit`](task/struct.TaskOptions.html#structfield.hard_time_limit).
+/// - `soft_time_limit`: Set a task-level [`TaskOptions::soft_time_limit`](task/struct.TaskOptions.html#structfield.soft_time_limit).
 /// - `max_retries`: Set a task-level [`TaskOptions::max_retries`](task/struct.TaskOptions.html#structfield.max_retries).
 /// - `min_retry_delay`: Set a task-level [`TaskOptions::min_retry_delay`](task/struct.TaskOptions.html#structfield.min_retry_delay).
 /// - `max_retry_delay`: Set a task-level [`TaskOptions::max_retry_delay`](task/struct.TaskOptions.html#structfield.max_retry_delay).
+/// - `retry_backoff`: Set a task-level [`TaskOptions::retry_backoff`](task/struct.TaskOptions.html#structfield.retry_backoff).
+/// - `retry_backoff_max`: Set a task-level [`TaskOptions::retry_backoff_max`](task/struct.TaskOptions.html#structfield.retry_backoff_max),
+///   in seconds.
+/// - `retry_jitter`: Set a task-level [`TaskOptions::retry_jitter`](task/struct.TaskOptions.html#structfield.retry_jitter).
 /// - `retry_for_unexpected`: Set a task-level [`TaskOptions::retry_for_unexpected`](task/struct.TaskOptions.html#structfield.retry_for_unexpected).
 /// - `acks_late`: Set a task-level [`TaskOptions::acks_late`](task/struct.TaskOptions.html#structfield.acks_late).
+/// - `reject_on_worker_lost`: Set a task-level [`TaskOptions::reject_on_worker_lost`](task/struct.TaskOptions.html#structfield.reject_on_worker_lost).
 /// - `content_type`: Set a task-level [`TaskOptions::content_type`](task/struct.TaskOptions.html#structfield.content_type).
+/// - `compression`: Set a task-level [`TaskOptions::compression`](task/struct.TaskOptions.html#structfield.compression),
+///   either `Zlib`, `Gzip` or `Zstd`.
+/// - `priority`: Set a task-level [`TaskOptions::priority`](task/struct.TaskOptions.html#structfield.priority).
+/// - `delivery_mode`: Set a task-level [`TaskOptions::delivery_mode`](task/struct.TaskOptions.html#structfield.delivery_mode),
+///   either `Transient` or `Persistent`.
+/// - `rate_limit`: Set a task-level [`TaskOptions::rate_limit`](task/struct.TaskOptions.html#structfield.rate_limit)
+///   from a string like `"10/s"`, `"100/m"` or `"1000/h"`.
+/// - `dedup`: Set a task-level [`TaskOptions::dedup`](task/struct.TaskOptions.html#structfield.dedup).
+/// - `dedup_ttl`: Set a task-level [`TaskOptions::dedup_ttl`](task/struct.TaskOptions.html#structfield.dedup_ttl),
+///   in seconds.
+/// - `ignore_result`: Set a task-level [`TaskOptions::ignore_result`](task/struct.TaskOptions.html#structfield.ignore_result).
+/// - `queue`: The queue to send the task to by default, see [`Task::QUEUE`](task/trait.Task.html#associatedconstant.QUEUE).
+/// - `exchange` and `routing_key`: The exchange to publish the task to by default and its routing
+///   key, which are set together instead of a `queue`, see [`Task::EXCHANGE`](task/trait.Task.html#associatedconstant.EXCHANGE).
+///
+///   A task is routed to the queue of its signature if it [has one](task/struct.Signature.html#method.with_queue),
+///   then to the queue or the exchange of its definition, then according to the
+///   [task routes](struct.CeleryBuilder.html#method.task_route) of the app, and lastly to the
+///   [default queue](struct.CeleryBuilder.html#method.default_queue).
 /// - `bind`: A bool. If true, the task will be run like an instance method and so the function's
-/// first argument should be a reference to `Self`. Note however that Rust won't allow you to call
-/// the argument `self`. Instead, you could use `task` or just `t`.
-/// - `on_failure`: An async callback function to run when the task fails. Should accept a reference to
-/// a task instance and a reference to a [`TaskError`](error/enum.TaskError.html).
+///   first argument should be a reference to `Self`. Note however that Rust won't allow you to call
+///   the argument `self`. Instead, you could use `task` or just `t`.
+/// - `blocking`: A bool. If true, the function, which can't be async, runs on the blocking threads
+///   of the runtime (see [`Task::BLOCKING`](task/trait.Task.html#associatedconstant.BLOCKING)), so
+///   that a synchronous or CPU-bound body doesn't hold up the other tasks of the worker. The time
+///   limits still apply, but the function keeps running on its thread after the task timed out.
+///   A panic of the function is handled like the panic of an async task. The number of blocking
+///   tasks executed at once can be limited with
+///   [`CeleryBuilder::blocking_threads`](struct.CeleryBuilder.html#method.blocking_threads).
+/// - `on_failure`: An async callback function to run when the task fails for good. Should accept a reference to
+///   a task instance, a reference to a [`TaskContext`](task/struct.TaskContext.html) and a reference to a
+///   [`TaskError`](error/enum.TaskError.html).
+/// - `on_retry`: An async callback function to run when the task fails and is retried. Should accept the same
+///   arguments as `on_failure`.
 /// - `on_success`: An async callback function to run when the task succeeds. Should accept a reference to
-/// a task instance and a reference to the value returned by the task.
+///   a task instance, a reference to a [`TaskContext`](task/struct.TaskContext.html) and a reference to the
+///   value returned by the task.
+/// - `on_soft_timeout`: An async callback function to run alongside the task once it exceeds its
+///   soft time limit. Should accept a reference to a task instance.
+/// - `should_retry`: A function deciding whether the task is retried after failing, overriding
+///   [`Task::should_retry`](task/trait.Task.html#method.should_retry). Should accept a reference to a
+///   [`TaskError`](error/enum.TaskError.html) and return a bool.
+///
+/// Unknown parameters are compile errors. The task-level options take precedence over the
+/// app-level ones, and are overridden by the ones set on a signature where supported (see
+/// [`TaskOptions`](task/struct.TaskOptions.html)).
 ///
 /// For more information see the [tasks chapter](https://rusty-celery.github.io/guide/defining-tasks.html)
 /// in the Rusty Celery Book.
@@ -180,17 +231,28 @@ mod codegen;
 /// Run custom callbacks on failure and on success:
 ///
 /// ```rust
-/// # use celery::task::{Task, TaskResult};
+/// # use celery::task::{Task, TaskContext, TaskResult};
 /// # use celery::error::TaskError;
 /// #[celery::task(on_failure = failure_callback, on_success = success_callback)]
 /// fn task_with_callbacks() {}
 ///
-/// async fn failure_callback<T: Task>(task: &T, err: &TaskError) {
-///     println!("{} failed with {:?}", task.name(), err);
+/// async fn failure_callback<T: Task>(task: &T, ctx: &TaskContext<'_, T>, err: &TaskError) {
+///     println!(
+///         "{}[{}] failed after {} retries with {:?}",
+///         task.name(),
+///         ctx.task_id,
+///         ctx.retries,
+///         err
+///     );
 /// }
 ///
-/// async fn success_callback<T: Task>(task: &T, ret: &T::Returns) {
-///     println!("{} succeeded: {:?}", task.name(), ret);
+/// async fn success_callback<T: Task>(task: &T, ctx: &TaskContext<'_, T>, ret: &T::Returns) {
+///     println!(
+///         "{} succeeded in {:?}: {:?}",
+///         task.name(),
+///         ctx.elapsed,
+///         ret
+///     );
 /// }
 /// ```
 #[cfg(feature = "codegen")]
@@ -0,0 +1,320 @@
+//! The logging of the crate, with the [`log`](https://docs.rs/log) macros or, with the
+//! `tracing` feature, with the [`tracing`](https://docs.rs/tracing) ones.
+//!
+//! With the `tracing` feature the worker also executes each task within a span, and the
+//! sends of the producers and of the beat are done within spans, whose `outcome` field is
+//! recorded once they are done. The events are still recorded as `log` records when no
+//! `tracing` subscriber is set, so that the apps which only set a logger keep their logs.
+//!
+//! The log lines emitted while the worker handles a task carry the ID and the name of the
+//! task and its queue: as fields of the span of the task with the `tracing` feature, and
+//! otherwise as a `task_id=... task_name=... queue=...` suffix.
+//!
+//! With the `otel` feature, the trace context of the span of the publishing of a task is
+//! sent in its W3C `traceparent` and `tracestate` headers, like Python Celery's OpenTelemetry
+//! instrumentation does, and the span of its execution is a child of this remote context.
+//! The headers are injected and extracted by the global propagator of OpenTelemetry, which
+//! has to be a `TraceContextPropagator`, and nothing is sent without an active context.
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, trace, warn};
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+
+/// Like `log::debug!`, adding the [`TaskFields`] of the task being handled to the record.
+#[cfg(not(feature = "tracing"))]
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        ::log::debug!(
+            "{}{}",
+            format_args!($($arg)+),
+            $crate::logging::FieldsSuffix
+        )
+    };
+}
+
+/// Like `log::error!`, adding the [`TaskFields`] of the task being handled to the record.
+#[cfg(not(feature = "tracing"))]
+macro_rules! log_error {
+    ($($arg:tt)+) => {
+        ::log::error!(
+            "{}{}",
+            format_args!($($arg)+),
+            $crate::logging::FieldsSuffix
+        )
+    };
+}
+
+/// Like `log::info!`, adding the [`TaskFields`] of the task being handled to the record.
+#[cfg(not(feature = "tracing"))]
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        ::log::info!(
+            "{}{}",
+            format_args!($($arg)+),
+            $crate::logging::FieldsSuffix
+        )
+    };
+}
+
+/// Like `log::trace!`, adding the [`TaskFields`] of the task being handled to the record.
+#[cfg(not(feature = "tracing"))]
+macro_rules! log_trace {
+    ($($arg:tt)+) => {
+        ::log::trace!(
+            "{}{}",
+            format_args!($($arg)+),
+            $crate::logging::FieldsSuffix
+        )
+    };
+}
+
+/// Like `log::warn!`, adding the [`TaskFields`] of the task being handled to the record.
+#[cfg(not(feature = "tracing"))]
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        ::log::warn!(
+            "{}{}",
+            format_args!($($arg)+),
+            $crate::logging::FieldsSuffix
+        )
+    };
+}
+
+// The macros are named after the `log` ones only once imported, as a `macro_rules!` named
+// `warn` would be ambiguous with the built-in `#[warn]` attribute.
+#[cfg(not(feature = "tracing"))]
+pub(crate) use {
+    log_debug as debug, log_error as error, log_info as info, log_trace as trace, log_warn as warn,
+};
+
+#[cfg(not(feature = "tracing"))]
+tokio::task_local! {
+    static TASK_FIELDS: TaskFields;
+}
+
+/// The fields of the task being handled by the worker, which are added to the log lines
+/// of the crate emitted meanwhile as a `task_id=... task_name=... queue=...` suffix, so that
+/// they can be correlated. With the `tracing` feature, they are the fields of the span of
+/// the task instead.
+pub(crate) struct TaskFields {
+    #[cfg(not(feature = "tracing"))]
+    suffix: String,
+}
+
+impl TaskFields {
+    #[allow(unused_variables)]
+    pub(crate) fn new(task_id: &str, task_name: &str, queue: &str) -> Self {
+        Self {
+            #[cfg(not(feature = "tracing"))]
+            suffix: format!(
+                " task_id={} task_name={} queue={}",
+                task_id, task_name, queue
+            ),
+        }
+    }
+
+    /// Run a future with the fields added to its log lines.
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) async fn scope<F: Future>(self, future: F) -> F::Output {
+        TASK_FIELDS.scope(self, future).await
+    }
+
+    /// Run a future with the fields added to its log lines.
+    #[cfg(feature = "tracing")]
+    pub(crate) async fn scope<F: Future>(self, future: F) -> F::Output {
+        future.await
+    }
+}
+
+/// The suffix of the log lines with the fields of the task being handled, if any.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct FieldsSuffix;
+
+#[cfg(not(feature = "tracing"))]
+impl Display for FieldsSuffix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        TASK_FIELDS
+            .try_with(|fields| f.write_str(&fields.suffix))
+            .unwrap_or(Ok(()))
+    }
+}
+
+/// A span of the crate, which does nothing without the `tracing` feature.
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    inner: tracing::Span,
+}
+
+impl Span {
+    /// The span of the execution of a task by the worker.
+    #[allow(unused_variables)]
+    pub(crate) fn task(task_name: &str, task_id: &str, queue: &str, retries: u32) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            inner: tracing::info_span!(
+                "task",
+                task_name,
+                task_id,
+                queue,
+                retries,
+                outcome = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// The span of the publishing of a task by a producer.
+    #[allow(unused_variables)]
+    pub(crate) fn publish(task_name: &str, task_id: &str, destination: &dyn Display) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            inner: tracing::info_span!(
+                "publish",
+                task_name,
+                task_id,
+                destination = %destination,
+                outcome = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// The span of a tick of the beat which sends a task that is due.
+    pub(crate) fn beat_tick() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            inner: tracing::info_span!("beat_tick", outcome = tracing::field::Empty),
+        }
+    }
+
+    /// The span of the sending of a scheduled task by the beat.
+    #[allow(unused_variables)]
+    pub(crate) fn beat_send(task_name: &str, queue: &str) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            inner: tracing::info_span!(
+                "beat_send",
+                task_name,
+                queue,
+                outcome = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// Inject the trace context of the span in the headers of a message.
+    #[allow(unused_variables)]
+    pub(crate) fn inject_context(&self, headers: &mut HashMap<String, Value>) {
+        #[cfg(feature = "otel")]
+        otel::inject_context(&self.inner, headers);
+    }
+
+    /// Make the span a child of the trace context in the headers of a message, if there
+    /// is one.
+    #[allow(unused_variables)]
+    pub(crate) fn set_remote_parent(&self, headers: &HashMap<String, Value>) {
+        #[cfg(feature = "otel")]
+        otel::set_remote_parent(&self.inner, headers);
+    }
+
+    /// Record the outcome of the span, e.g. `ok` or `error`.
+    #[allow(unused_variables)]
+    pub(crate) fn record_outcome(&self, outcome: &str) {
+        #[cfg(feature = "tracing")]
+        self.inner.record("outcome", outcome);
+    }
+
+    /// Run a future within the span.
+    pub(crate) async fn instrument<F: Future>(&self, future: F) -> F::Output {
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(future, self.inner.clone());
+        future.await
+    }
+}
+
+/// Inject the trace context of the current span in the headers of a message.
+#[allow(unused_variables)]
+pub(crate) fn inject_current_context(headers: &mut HashMap<String, Value>) {
+    #[cfg(feature = "otel")]
+    otel::inject_context(&tracing::Span::current(), headers);
+}
+
+/// The outcome of a span whose operation returned `result`.
+pub(crate) fn outcome<T, E>(result: &Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::global;
+    use opentelemetry::propagation::{Extractor, Injector};
+    use opentelemetry::trace::TraceContextExt;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    struct HeaderInjector<'a>(&'a mut HashMap<String, Value>);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            self.0.insert(key.into(), Value::String(value));
+        }
+    }
+
+    struct HeaderExtractor<'a>(&'a HashMap<String, Value>);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(Value::as_str)
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(String::as_str).collect()
+        }
+    }
+
+    pub(super) fn inject_context(span: &tracing::Span, headers: &mut HashMap<String, Value>) {
+        let context = span.context();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut HeaderInjector(headers))
+        });
+    }
+
+    pub(super) fn set_remote_parent(span: &tracing::Span, headers: &HashMap<String, Value>) {
+        let context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        // Without a remote context, the span keeps its local parent.
+        if context.span().span_context().is_valid() {
+            span.set_parent(context);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        #[test]
+        fn test_header_extractor() {
+            let mut headers = HashMap::new();
+            HeaderInjector(&mut headers).set(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".into(),
+            );
+            headers.insert("retries".into(), json!(1));
+            let extractor = HeaderExtractor(&headers);
+            assert_eq!(
+                extractor.get("traceparent"),
+                Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+            );
+            // Headers which aren't strings can't be trace contexts.
+            assert_eq!(extractor.get("retries"), None);
+            assert_eq!(extractor.get("tracestate"), None);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Prometheus metrics of the workers, the beats and the backends, with the `metrics`
+//! feature.
+//!
+//! The metrics are registered in a registry of their own, which the apps of the process
+//! share. The crate doesn't serve them: [`encode`] returns them in the text exposition
+//! format, e.g. for a `/metrics` route of the HTTP server of the application, and
+//! [`registry`] gives access to the registry itself.
+//!
+//! | Metric | Type | Labels |
+//! | --- | --- | --- |
+//! | `celery_tasks_total` | counter | `name`, `state` |
+//! | `celery_task_duration_seconds` | histogram | `name` |
+//! | `celery_queue_latency_seconds` | histogram | `name` |
+//! | `celery_broker_reconnects_total` | counter | |
+//! | `celery_backend_operation_duration_seconds` | histogram | `operation` |
+//! | `celery_beat_sends_total` | counter | `name`, `outcome` |
+//!
+//! Without the feature, the metrics aren't recorded.
+
+use std::future::Future;
+use std::time::Duration;
+
+use crate::task::TaskState;
+
+#[cfg(feature = "metrics")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "metrics")]
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+#[cfg(feature = "metrics")]
+struct Metrics {
+    registry: Registry,
+    tasks_total: IntCounterVec,
+    task_duration_seconds: HistogramVec,
+    queue_latency_seconds: HistogramVec,
+    broker_reconnects_total: IntCounter,
+    backend_operation_duration_seconds: HistogramVec,
+    beat_sends_total: IntCounterVec,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("celery".into()), None)?;
+        let tasks_total = IntCounterVec::new(
+            Opts::new("tasks_total", "The number of tasks executed, by state."),
+            &["name", "state"],
+        )?;
+        let task_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("task_duration_seconds", "How long the tasks ran."),
+            &["name"],
+        )?;
+        let queue_latency_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "queue_latency_seconds",
+                "How long the tasks waited in their queue once they were due.",
+            ),
+            &["name"],
+        )?;
+        let broker_reconnects_total = IntCounter::new(
+            "broker_reconnects_total",
+            "The number of times the workers reconnected to the broker.",
+        )?;
+        let backend_operation_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "backend_operation_duration_seconds",
+                "How long the operations of the result backends took.",
+            ),
+            &["operation"],
+        )?;
+        let beat_sends_total = IntCounterVec::new(
+            Opts::new(
+                "beat_sends_total",
+                "The number of scheduled tasks sent by the beats.",
+            ),
+            &["name", "outcome"],
+        )?;
+        registry.register(Box::new(tasks_total.clone()))?;
+        registry.register(Box::new(task_duration_seconds.clone()))?;
+        registry.register(Box::new(queue_latency_seconds.clone()))?;
+        registry.register(Box::new(broker_reconnects_total.clone()))?;
+        registry.register(Box::new(backend_operation_duration_seconds.clone()))?;
+        registry.register(Box::new(beat_sends_total.clone()))?;
+        Ok(Self {
+            registry,
+            tasks_total,
+            task_duration_seconds,
+            queue_latency_seconds,
+            broker_reconnects_total,
+            backend_operation_duration_seconds,
+            beat_sends_total,
+        })
+    }
+}
+
+#[cfg(feature = "metrics")]
+static METRICS: Lazy<Metrics> =
+    Lazy::new(|| Metrics::new().expect("The metrics are registered once"));
+
+/// Get the registry of the metrics, e.g. to gather them along with the other metrics of
+/// the application.
+#[cfg(feature = "metrics")]
+pub fn registry() -> &'static Registry {
+    &METRICS.registry
+}
+
+/// Encode the metrics in the Prometheus text exposition format.
+#[cfg(feature = "metrics")]
+pub fn encode() -> String {
+    let mut buffer = vec![];
+    // Encoding the metrics gathered from a registry into a vector can't fail.
+    TextEncoder::new()
+        .encode(&registry().gather(), &mut buffer)
+        .unwrap_or_default();
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Record a task executed by a worker, with the state it ended in and how long it ran.
+#[allow(unused_variables)]
+pub(crate) fn observe_task(task_name: &str, state: &TaskState, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        let state = format!("{:?}", state).to_uppercase();
+        METRICS
+            .tasks_total
+            .with_label_values(&[task_name, &state])
+            .inc();
+        METRICS
+            .task_duration_seconds
+            .with_label_values(&[task_name])
+            .observe(duration.as_secs_f64());
+    }
+}
+
+/// Record how long a task waited in its queue.
+#[allow(unused_variables)]
+pub(crate) fn observe_queue_latency(task_name: &str, latency: Duration) {
+    #[cfg(feature = "metrics")]
+    METRICS
+        .queue_latency_seconds
+        .with_label_values(&[task_name])
+        .observe(latency.as_secs_f64());
+}
+
+/// Record a reconnection of a worker to the broker.
+pub(crate) fn count_broker_reconnect() {
+    #[cfg(feature = "metrics")]
+    METRICS.broker_reconnects_total.inc();
+}
+
+/// Record a scheduled task sent by a beat, with the outcome of the send.
+#[allow(unused_variables)]
+pub(crate) fn count_beat_send(task_name: &str, outcome: &str) {
+    #[cfg(feature = "metrics")]
+    METRICS
+        .beat_sends_total
+        .with_label_values(&[task_name, outcome])
+        .inc();
+}
+
+/// Run an operation of a backend, recording how long it took.
+#[allow(unused_variables)]
+pub(crate) async fn time_backend<F: Future>(operation: &str, future: F) -> F::Output {
+    #[cfg(feature = "metrics")]
+    let _timer = METRICS
+        .backend_operation_duration_seconds
+        .with_label_values(&[operation])
+        .start_timer();
+    future.await
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        observe_task(
+            "metrics_test",
+            &TaskState::Success,
+            Duration::from_millis(20),
+        );
+        count_beat_send("metrics_test", "ok");
+        let encoded = encode();
+        assert!(encoded.contains("celery_tasks_total{name=\"metrics_test\",state=\"SUCCESS\"} 1"));
+        assert!(encoded.contains("celery_task_duration_seconds_count{name=\"metrics_test\"} 1"));
+        assert!(encoded.contains("celery_beat_sends_total{name=\"metrics_test\",outcome=\"ok\"} 1"));
+    }
+}
@@ -1,6 +1,9 @@
 //! A "prelude" for users of the `celery` crate.
 
-pub use crate::broker::{AMQPBroker, RedisBroker};
+pub use crate::backend::RedisBackend;
+pub use crate::broker::{AMQPBroker, FilesystemBroker, InMemoryBroker, RedisBroker};
+#[cfg(feature = "nats")]
+pub use crate::broker::NatsBroker;
 #[cfg(feature = "backend_mongo")]
 pub use crate::backend::mongo::MongoBackend;
 pub use crate::error::*;
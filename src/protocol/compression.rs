@@ -0,0 +1,76 @@
+//! Compression of message bodies.
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{Read, Write};
+
+use crate::error::ProtocolError;
+
+/// Algorithms to compress message bodies with, which mostly pays off for large payloads.
+///
+/// Like kombu, the algorithm is sent in the `compression` header of the message, while its
+/// `content-encoding` remains the encoding of the uncompressed body. Workers decompress
+/// the bodies according to that header, whatever compression they send tasks with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// zlib, which Python Celery calls both `zlib` and `gzip`.
+    Zlib,
+
+    /// gzip, which Python workers can't decompress: use [`Zlib`](Compression::Zlib) for them.
+    Gzip,
+
+    /// Zstandard, which Python Celery calls `zstd`.
+    Zstd,
+}
+
+impl Compression {
+    /// The MIME type of the algorithm, sent in the `compression` header.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Compression::Zlib => "application/x-gzip",
+            Compression::Gzip => "application/gzip",
+            Compression::Zstd => "application/zstd",
+        }
+    }
+
+    /// Get the algorithm from the value of a `compression` header.
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type {
+            "application/x-gzip" | "zlib" => Some(Compression::Zlib),
+            "application/gzip" | "gzip" => Some(Compression::Gzip),
+            "application/zstd" | "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let compressed = match self {
+            Compression::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Compression::Zstd => zstd::encode_all(data, 0)?,
+        };
+        Ok(compressed)
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let mut decompressed = Vec::new();
+        match self {
+            Compression::Zlib => {
+                ZlibDecoder::new(data).read_to_end(&mut decompressed)?;
+            }
+            Compression::Gzip => {
+                GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            }
+            Compression::Zstd => decompressed = zstd::decode_all(data)?,
+        }
+        Ok(decompressed)
+    }
+}
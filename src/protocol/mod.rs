@@ -4,24 +4,32 @@
 //! top of the protocol for a broker. This is why a broker's [`Delivery`](crate::broker::Broker::Delivery)
 //! type must implement [`TryCreateMessage`].
 
+use crate::logging::{debug, warn};
 use base64::{
     alphabet,
     engine::{general_purpose::PAD, GeneralPurpose},
     Engine,
 };
 use chrono::{DateTime, Utc};
-use std::time::Duration;
-use log::{debug, warn};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_slice, from_value, json, Value};
+use serde_json::{from_slice, from_value, json, Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::process;
+use std::time::Duration;
 use std::time::SystemTime;
 use uuid::Uuid;
 
 use crate::error::{ContentTypeError, ProtocolError};
-use crate::task::{Signature, Task};
+use crate::task::{check_task_id, ChainLink, SendOptions, Signature, Task};
+
+mod compression;
+mod signing;
+mod v1;
+pub use compression::Compression;
+pub use signing::MessageSigner;
 
 pub(crate) const ENGINE: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, PAD);
 
@@ -32,16 +40,145 @@ static ORIGIN: Lazy<Option<String>> = Lazy::new(|| {
         .map(|sys_hostname| format!("gen{}@{}", process::id(), sys_hostname))
 });
 
+/// The headers of the Celery protocol, which custom headers can't override.
+pub const RESERVED_HEADERS: &[&str] = &[
+    "id",
+    "task",
+    "lang",
+    "root_id",
+    "parent_id",
+    "group",
+    "meth",
+    "shadow",
+    "eta",
+    "expires",
+    "retries",
+    "timelimit",
+    "argsrepr",
+    "kwargsrepr",
+    "origin",
+    "acks_late",
+    "ignore_result",
+    "redeliveries",
+    "compression",
+    "signature",
+    "sent_at",
+];
+
+/// Check if a header is one of the [`RESERVED_HEADERS`].
+pub fn is_reserved_header(key: &str) -> bool {
+    RESERVED_HEADERS.contains(&key)
+}
+
 /// Serialization formats supported for message body.
+///
+/// Workers deserialize each message according to its `content-type`, whatever format they
+/// send tasks with.
 #[derive(Default, Copy, Clone)]
 pub enum MessageContentType {
     #[default]
     Json,
+    /// YAML, compatible with the `yaml` serializer of Python Celery.
     Yaml,
     Pickle,
+    /// MessagePack, which is more compact and faster to encode than JSON for large
+    /// payloads. Like Python Celery, structs are encoded as maps, so that the body can be
+    /// read by Python workers with the `msgpack` serializer enabled.
+    ///
+    /// Byte buffers are only encoded as MessagePack binaries, like Python's `bytes`, with
+    /// [`serde_bytes`](https://docs.rs/serde_bytes), otherwise they are arrays of integers.
     MsgPack,
 }
 
+impl MessageContentType {
+    /// The MIME type and the encoding of message bodies serialized in this format.
+    fn properties(self) -> (&'static str, &'static str) {
+        use MessageContentType::*;
+        // Like kombu, binary formats are sent with the "binary" encoding, otherwise Python
+        // workers try decoding them as text.
+        match self {
+            Json => ("application/json", "utf-8"),
+            Yaml => ("application/x-yaml", "utf-8"),
+            Pickle => ("application/x-python-serialize", "binary"),
+            MsgPack => ("application/x-msgpack", "binary"),
+        }
+    }
+
+    /// The name of the format in the `serializer` option of a Python signature.
+    pub(crate) fn serializer_name(self) -> &'static str {
+        use MessageContentType::*;
+        match self {
+            Json => "json",
+            Yaml => "yaml",
+            Pickle => "pickle",
+            MsgPack => "msgpack",
+        }
+    }
+
+    fn from_serializer_name(name: &str) -> Option<Self> {
+        use MessageContentType::*;
+        match name {
+            "json" => Some(Json),
+            "yaml" => Some(Yaml),
+            "pickle" => Some(Pickle),
+            "msgpack" => Some(MsgPack),
+            _ => None,
+        }
+    }
+}
+
+/// Serialize a message body according to its MIME type.
+fn serialize_body<B: Serialize>(content_type: &str, body: &B) -> Result<Vec<u8>, ProtocolError> {
+    match content_type {
+        "application/json" => Ok(serde_json::to_vec(body)?),
+        #[cfg(any(test, feature = "extra_content_types"))]
+        "application/x-yaml" => {
+            let mut vec = Vec::with_capacity(128);
+            serde_yaml::to_writer(&mut vec, body)?;
+            Ok(vec)
+        }
+        #[cfg(any(test, feature = "extra_content_types"))]
+        "application/x-python-serialize" => {
+            Ok(serde_pickle::to_vec(body, serde_pickle::SerOptions::new())?)
+        }
+        #[cfg(any(test, feature = "extra_content_types"))]
+        "application/x-msgpack" => Ok(rmp_serde::to_vec_named(body)?),
+        _ => Err(ProtocolError::BodySerializationError(
+            ContentTypeError::Unknown,
+        )),
+    }
+}
+
+/// Whether the broker stores messages so they survive its restart.
+#[derive(Default, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeliveryMode {
+    /// Messages are only kept in memory, which is faster but loses them when the broker
+    /// restarts.
+    Transient,
+    /// Messages are written to disk.
+    #[default]
+    Persistent,
+}
+
+impl DeliveryMode {
+    /// The value of the `delivery_mode` message property for this mode.
+    pub fn as_u8(self) -> u8 {
+        match self {
+            DeliveryMode::Transient => 1,
+            DeliveryMode::Persistent => 2,
+        }
+    }
+
+    /// Get the mode from the value of a `delivery_mode` message property.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(DeliveryMode::Transient),
+            2 => Some(DeliveryMode::Persistent),
+            _ => None,
+        }
+    }
+}
+
 /// Create a message with a custom configuration.
 pub struct MessageBuilder<T>
 where
@@ -49,6 +186,12 @@ where
 {
     message: Message,
     params: Option<T::Params>,
+    chain: Vec<SerializedSignature>,
+    chord: Option<SerializedSignature>,
+    callbacks: Vec<SerializedSignature>,
+    errbacks: Vec<SerializedSignature>,
+    compression: Option<Compression>,
+    compression_threshold: usize,
 }
 
 impl<T> MessageBuilder<T>
@@ -64,16 +207,25 @@ where
                     content_type: "application/json".into(),
                     content_encoding: "utf-8".into(),
                     reply_to: None,
+                    priority: None,
+                    delivery_mode: None,
                 },
                 headers: MessageHeaders {
                     id,
                     task: T::NAME.into(),
                     origin: ORIGIN.to_owned(),
+                    sent_at: Some(Utc::now()),
                     ..Default::default()
                 },
                 raw_body: Vec::new(),
             },
             params: None,
+            chain: Vec::new(),
+            chord: None,
+            callbacks: Vec::new(),
+            errbacks: Vec::new(),
+            compression: None,
+            compression_threshold: 0,
         }
     }
     /// Set which serialization method is used in the body.
@@ -81,14 +233,9 @@ where
     /// JSON is the default, and is also the only option unless the feature "extra_content_types" is enabled.
     #[cfg(any(test, feature = "extra_content_types"))]
     pub fn content_type(mut self, content_type: MessageContentType) -> Self {
-        use MessageContentType::*;
-        let content_type_name = match content_type {
-            Json => "application/json",
-            Yaml => "application/x-yaml",
-            Pickle => "application/x-python-serialize",
-            MsgPack => "application/x-msgpack",
-        };
+        let (content_type_name, content_encoding) = content_type.properties();
         self.message.properties.content_type = content_type_name.into();
+        self.message.properties.content_encoding = content_encoding.into();
         self
     }
 
@@ -107,6 +254,16 @@ where
         self
     }
 
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.message.properties.priority = Some(priority);
+        self
+    }
+
+    pub fn delivery_mode(mut self, delivery_mode: DeliveryMode) -> Self {
+        self.message.properties.delivery_mode = Some(delivery_mode);
+        self
+    }
+
     pub fn id(mut self, id: String) -> Self {
         self.message.headers.id = id;
         self
@@ -167,11 +324,25 @@ where
         self
     }
 
+    pub fn acks_late(mut self, acks_late: bool) -> Self {
+        self.message.headers.acks_late = Some(acks_late);
+        self
+    }
+
+    pub fn ignore_result(mut self, ignore_result: bool) -> Self {
+        self.message.headers.ignore_result = Some(ignore_result);
+        self
+    }
+
     pub fn time_limit(mut self, time_limit: u32) -> Self {
         self.message.headers.timelimit.1 = Some(time_limit);
         self
     }
 
+    pub fn soft_time_limit(self, soft_time_limit: u32) -> Self {
+        self.time_limit(soft_time_limit)
+    }
+
     pub fn hard_time_limit(mut self, time_limit: u32) -> Self {
         self.message.headers.timelimit.0 = Some(time_limit);
         self
@@ -182,9 +353,9 @@ where
         self
     }
 
-    pub fn countdown(self, countdown: u32) -> Self {
+    pub fn countdown(self, countdown: Duration) -> Self {
         let now = DateTime::<Utc>::from(SystemTime::now());
-        let eta = now + Duration::from_secs(countdown as u64);
+        let eta = now + countdown;
         self.eta(eta)
     }
 
@@ -193,9 +364,9 @@ where
         self
     }
 
-    pub fn expires_in(self, expires_in: u32) -> Self {
+    pub fn expires_in(self, expires_in: Duration) -> Self {
         let now = DateTime::<Utc>::from(SystemTime::now());
-        let expires = now + Duration::from_secs(expires_in as u64);
+        let expires = now + expires_in;
         self.expires(expires)
     }
 
@@ -204,31 +375,80 @@ where
         self
     }
 
+    /// Compress the body with the given algorithm.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Set the size (in bytes) below which the body isn't compressed.
+    pub fn compression_threshold(mut self, compression_threshold: usize) -> Self {
+        self.compression_threshold = compression_threshold;
+        self
+    }
+
+    /// Add custom headers to the message. Headers named like one of the
+    /// [`RESERVED_HEADERS`] are ignored.
+    pub fn headers(mut self, headers: HashMap<String, Value>) -> Self {
+        for (key, value) in headers {
+            if is_reserved_header(&key) {
+                warn!("Ignoring custom header {} which is reserved", key);
+            } else {
+                self.message.headers.extra.insert(key, value);
+            }
+        }
+        self
+    }
+
+    /// Set the tasks to execute after this one, in order.
+    pub fn chain(mut self, chain: Vec<SerializedSignature>) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// Set the callback of the chord the task is part of.
+    pub fn chord(mut self, callback: SerializedSignature) -> Self {
+        self.chord = Some(callback);
+        self
+    }
+
+    /// Set the tasks to send with the result of the task when it succeeds.
+    pub fn callbacks(mut self, callbacks: Vec<SerializedSignature>) -> Self {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Set the tasks to send with the ID and the error of the task when it fails.
+    pub fn errbacks(mut self, errbacks: Vec<SerializedSignature>) -> Self {
+        self.errbacks = errbacks;
+        self
+    }
+
     /// Get the `Message` with the custom configuration.
     pub fn build(mut self) -> Result<Message, ProtocolError> {
         if let Some(params) = self.params.take() {
-            let body = MessageBody::<T>::new(params);
-
-            let raw_body = match self.message.properties.content_type.as_str() {
-                "application/json" => serde_json::to_vec(&body)?,
-                #[cfg(any(test, feature = "extra_content_types"))]
-                "application/x-yaml" => {
-                    let mut vec = Vec::with_capacity(128);
-                    serde_yaml::to_writer(&mut vec, &body)?;
-                    vec
-                }
-                #[cfg(any(test, feature = "extra_content_types"))]
-                "application/x-python-serialize" => {
-                    serde_pickle::to_vec(&body, serde_pickle::SerOptions::new())?
-                }
-                #[cfg(any(test, feature = "extra_content_types"))]
-                "application/x-msgpack" => rmp_serde::to_vec(&body)?,
-                _ => {
-                    return Err(ProtocolError::BodySerializationError(
-                        ContentTypeError::Unknown,
-                    ));
+            let mut body = MessageBody::<T>::new(params);
+            if !self.chain.is_empty() {
+                // Like Python Celery, the remaining chain is reversed so that the next
+                // task can be popped from its end.
+                self.chain.reverse();
+                body.2.chain = Some(self.chain);
+            }
+            body.2.chord = self.chord.take();
+            if !self.callbacks.is_empty() {
+                body.2.callbacks = Some(std::mem::take(&mut self.callbacks));
+            }
+            if !self.errbacks.is_empty() {
+                body.2.errbacks = Some(std::mem::take(&mut self.errbacks));
+            }
+
+            let mut raw_body = serialize_body(&self.message.properties.content_type, &body)?;
+            if let Some(compression) = self.compression {
+                if raw_body.len() >= self.compression_threshold {
+                    raw_body = compression.compress(&raw_body)?;
+                    self.message.headers.compression = Some(compression.mime_type().into());
                 }
-            };
+            }
             self.message.raw_body = raw_body;
         };
         Ok(self.message)
@@ -256,9 +476,10 @@ pub struct Message {
 impl Message {
     /// Try deserializing the body.
     pub fn body<T: Task>(&self) -> Result<MessageBody<T>, ProtocolError> {
+        let raw_body = self.decompressed_body()?;
         match self.properties.content_type.as_str() {
             "application/json" => {
-                let value: Value = from_slice(&self.raw_body)?;
+                let value: Value = from_slice(&raw_body)?;
                 debug!("Deserialized message body: {:?}", value);
                 if let Value::Array(ref vec) = value {
                     if let [Value::Array(ref args), Value::Object(ref kwargs), Value::Object(ref embed)] =
@@ -289,7 +510,7 @@ impl Message {
             #[cfg(any(test, feature = "extra_content_types"))]
             "application/x-yaml" => {
                 use serde_yaml::{from_slice, from_value, Value};
-                let value: Value = from_slice(&self.raw_body)?;
+                let value: Value = from_slice(&raw_body)?;
                 debug!("Deserialized message body: {:?}", value);
                 if let Value::Sequence(ref vec) = value {
                     if let [Value::Sequence(ref args), Value::Mapping(ref kwargs), Value::Mapping(ref embed)] =
@@ -320,7 +541,7 @@ impl Message {
             #[cfg(any(test, feature = "extra_content_types"))]
             "application/x-python-serialize" => {
                 use serde_pickle::{from_slice, from_value, DeOptions, HashableValue, Value};
-                let value: Value = from_slice(&self.raw_body, DeOptions::new())?;
+                let value: Value = from_slice(&raw_body, DeOptions::new())?;
                 // debug!("Deserialized message body: {:?}", value);
                 if let Value::List(ref vec) = value {
                     if let [Value::List(ref args), Value::Dict(ref kwargs), Value::Dict(ref embed)] =
@@ -353,7 +574,7 @@ impl Message {
             "application/x-msgpack" => {
                 use rmp_serde::from_slice;
                 use rmpv::{ext::from_value, Value};
-                let value: Value = from_slice(&self.raw_body)?;
+                let value: Value = from_slice(&raw_body)?;
                 debug!("Deserialized message body: {:?}", value);
                 if let Value::Array(ref vec) = value {
                     if let [Value::Array(ref args), Value::Map(ref kwargs), Value::Map(ref embed)] =
@@ -411,6 +632,71 @@ impl Message {
         }
     }
 
+    /// Try deserializing the callbacks and work-flow primitives of the body, whatever the
+    /// task.
+    pub fn body_embed(&self) -> Result<MessageBodyEmbed, ProtocolError> {
+        use serde::de::IgnoredAny;
+        let (_, _, embed) = self.body_parts::<(IgnoredAny, IgnoredAny, MessageBodyEmbed)>()?;
+        Ok(embed)
+    }
+
+    /// Try deserializing the positional and keyword arguments of the body, whatever the
+    /// task.
+    pub(crate) fn body_args(&self) -> Result<(Value, Value), ProtocolError> {
+        use serde::de::IgnoredAny;
+        let (args, kwargs, _) = self.body_parts::<(Value, Value, IgnoredAny)>()?;
+        Ok((args, kwargs))
+    }
+
+    fn body_parts<B: serde::de::DeserializeOwned>(&self) -> Result<B, ProtocolError> {
+        let raw_body = self.decompressed_body()?;
+        match self.properties.content_type.as_str() {
+            "application/json" => Ok(from_slice::<B>(&raw_body)?),
+            #[cfg(any(test, feature = "extra_content_types"))]
+            "application/x-yaml" => Ok(serde_yaml::from_slice::<B>(&raw_body)?),
+            #[cfg(any(test, feature = "extra_content_types"))]
+            "application/x-python-serialize" => Ok(serde_pickle::from_slice::<B>(
+                &raw_body,
+                serde_pickle::DeOptions::new(),
+            )?),
+            #[cfg(any(test, feature = "extra_content_types"))]
+            "application/x-msgpack" => Ok(rmp_serde::from_slice::<B>(&raw_body)?),
+            _ => Err(ProtocolError::BodySerializationError(
+                ContentTypeError::Unknown,
+            )),
+        }
+    }
+
+    /// Replace the parameters of the task, keeping the callbacks and work-flow primitives
+    /// of the body, e.g. when it retries itself with new parameters.
+    pub(crate) fn set_params(&mut self, params: Value) -> Result<(), ProtocolError> {
+        let embed = self.body_embed()?;
+        let kwargs = match params {
+            Value::Object(kwargs) => kwargs,
+            Value::Null => Map::new(),
+            _ => return Err(ProtocolError::InvalidProperty("params".into())),
+        };
+        self.raw_body = serialize_body(
+            &self.properties.content_type,
+            &(Vec::<Value>::new(), kwargs, embed),
+        )?;
+        self.headers.compression = None;
+        self.headers.argsrepr = None;
+        self.headers.kwargsrepr = None;
+        Ok(())
+    }
+
+    /// Get the body, decompressed according to the `compression` header if it is set.
+    pub fn decompressed_body(&self) -> Result<Cow<'_, [u8]>, ProtocolError> {
+        match &self.headers.compression {
+            Some(mime_type) => Compression::from_mime_type(mime_type)
+                .ok_or_else(|| ProtocolError::UnknownCompression(mime_type.clone()))?
+                .decompress(&self.raw_body)
+                .map(Cow::Owned),
+            None => Ok(Cow::Borrowed(&self.raw_body)),
+        }
+    }
+
     /// Get the task ID.
     pub fn task_id(&self) -> &str {
         &self.headers.id
@@ -433,10 +719,14 @@ impl Message {
             Some(time) => json!(time.to_rfc3339()),
             None => Value::Null,
         };
+        let sent_at = match self.headers.sent_at {
+            Some(time) => json!(time.to_rfc3339()),
+            None => Value::Null,
+        };
         let mut buffer = Uuid::encode_buffer();
         let uuid = Uuid::new_v4().hyphenated().encode_lower(&mut buffer);
         let delivery_tag = uuid.to_owned();
-        let msg_json_value = json!({
+        let mut msg_json_value = json!({
             "body": ENGINE.encode(self.raw_body.clone()),
             "content-encoding": self.properties.content_encoding.clone(),
             "content-type": self.properties.content_type.clone(),
@@ -455,15 +745,28 @@ impl Message {
                 "timelimit": self.headers.timelimit.clone(),
                 "argsrepr": self.headers.argsrepr.clone(),
                 "kwargsrepr": self.headers.kwargsrepr.clone(),
-                "origin": self.headers.origin.clone()
+                "origin": self.headers.origin.clone(),
+                "acks_late": self.headers.acks_late,
+                "ignore_result": self.headers.ignore_result,
+                "redeliveries": self.headers.redeliveries,
+                "compression": self.headers.compression.clone(),
+                "signature": self.headers.signature.clone(),
+                "sent_at": sent_at,
             },
             "properties": json!({
                 "correlation_id": self.properties.correlation_id.clone(),
                 "reply_to": reply_to,
                 "delivery_tag": delivery_tag,
+                "priority": self.properties.priority,
+                "delivery_mode": self.properties.delivery_mode.unwrap_or_default().as_u8(),
                 "body_encoding": "base64",
             })
         });
+        if let Some(headers) = msg_json_value["headers"].as_object_mut() {
+            for (key, value) in self.headers.custom() {
+                headers.insert(key.clone(), value.clone());
+            }
+        }
         let res = serde_json::to_string(&msg_json_value)?;
         Ok(res.into_bytes())
     }
@@ -477,37 +780,55 @@ where
 
     /// Get a new [`MessageBuilder`] from a task signature.
     fn try_from(mut task_sig: Signature<T>) -> Result<Self, Self::Error> {
-        // Create random correlation id.
-        let mut buffer = Uuid::encode_buffer();
-        let uuid = Uuid::new_v4().hyphenated().encode_lower(&mut buffer);
-        let id = uuid.to_owned();
-
-        let mut builder = MessageBuilder::<T>::new(id);
-
-        // 'countdown' arbitrarily takes priority over 'eta'.
-        if let Some(countdown) = task_sig.countdown.take() {
-            builder = builder.countdown(countdown);
-            if task_sig.eta.is_some() {
-                warn!(
-                    "Task {} specified both a 'countdown' and an 'eta'. Ignoring 'eta'.",
-                    T::NAME
-                )
+        let id = match task_sig.task_id.take() {
+            Some(id) => {
+                check_task_id(&id)?;
+                id
             }
-        } else if let Some(eta) = task_sig.eta.take() {
-            builder = builder.eta(eta);
+            // Create random correlation id.
+            None => {
+                let mut buffer = Uuid::encode_buffer();
+                let uuid = Uuid::new_v4().hyphenated().encode_lower(&mut buffer);
+                uuid.to_owned()
+            }
+        };
+
+        let serialize = |links: &[ChainLink]| -> Result<Vec<_>, ProtocolError> {
+            links.iter().map(|link| link()).collect()
+        };
+        let mut builder = MessageBuilder::<T>::new(id)
+            .chain(serialize(&task_sig.chain)?)
+            .callbacks(serialize(&task_sig.callbacks)?)
+            .errbacks(serialize(&task_sig.errbacks)?);
+        if let Some(callback) = task_sig.chord.take() {
+            builder = builder.chord(callback);
+        }
+        if let Some(shadow) = task_sig.shadow.take() {
+            builder = builder.shadow(shadow);
+        }
+
+        match (task_sig.countdown.take(), task_sig.eta.take()) {
+            (Some(_), Some(_)) => {
+                return Err(ProtocolError::ConflictingOptions(
+                    "countdown".into(),
+                    "eta".into(),
+                ))
+            }
+            (Some(countdown), None) => builder = builder.countdown(countdown),
+            (None, Some(eta)) => builder = builder.eta(eta),
+            (None, None) => {}
         }
 
-        // 'expires_in' arbitrarily takes priority over 'expires'.
-        if let Some(expires_in) = task_sig.expires_in.take() {
-            builder = builder.expires_in(expires_in);
-            if task_sig.expires.is_some() {
-                warn!(
-                    "Task {} specified both 'expires_in' and 'expires'. Ignoring 'expires'.",
-                    T::NAME
-                )
+        match (task_sig.expires_in.take(), task_sig.expires.take()) {
+            (Some(_), Some(_)) => {
+                return Err(ProtocolError::ConflictingOptions(
+                    "expires_in".into(),
+                    "expires".into(),
+                ))
             }
-        } else if let Some(expires) = task_sig.expires.take() {
-            builder = builder.expires(expires);
+            (Some(expires_in), None) => builder = builder.expires_in(expires_in),
+            (None, Some(expires)) => builder = builder.expires(expires),
+            (None, None) => {}
         }
 
         #[cfg(any(test, feature = "extra_content_types"))]
@@ -515,14 +836,41 @@ where
             builder = builder.content_type(content_type);
         }
 
-        if let Some(time_limit) = task_sig.options.time_limit.take() {
-            builder = builder.time_limit(time_limit);
+        let (hard_time_limit, soft_time_limit) = task_sig.options.timelimit();
+        if let Some(time_limit) = soft_time_limit {
+            builder = builder.soft_time_limit(time_limit);
         }
 
-        if let Some(time_limit) = task_sig.options.hard_time_limit.take() {
+        if let Some(time_limit) = hard_time_limit {
             builder = builder.hard_time_limit(time_limit);
         }
 
+        if let Some(priority) = task_sig.options.priority.take() {
+            builder = builder.priority(priority);
+        }
+
+        if let Some(delivery_mode) = task_sig.options.delivery_mode.take() {
+            builder = builder.delivery_mode(delivery_mode);
+        }
+
+        if let Some(acks_late) = task_sig.options.acks_late.take() {
+            builder = builder.acks_late(acks_late);
+        }
+
+        if let Some(ignore_result) = task_sig.options.ignore_result {
+            builder = builder.ignore_result(ignore_result);
+        }
+
+        if let Some(compression) = task_sig.options.compression {
+            builder = builder.compression(compression);
+        }
+
+        if let Some(compression_threshold) = task_sig.options.compression_threshold {
+            builder = builder.compression_threshold(compression_threshold);
+        }
+
+        builder = builder.headers(std::mem::take(&mut task_sig.headers));
+
         builder.params(task_sig.params).build()
     }
 }
@@ -565,15 +913,24 @@ pub struct MessageProperties {
 
     /// Used by the RPC backend when failures are reported by the parent process.
     pub reply_to: Option<String>,
+
+    /// The priority of the message (see [`TaskOptions::priority`](crate::task::TaskOptions::priority)).
+    pub priority: Option<u8>,
+
+    /// Whether the message is persisted by the broker (see
+    /// [`TaskOptions::delivery_mode`](crate::task::TaskOptions::delivery_mode)).
+    pub delivery_mode: Option<DeliveryMode>,
 }
 
 /// Additional meta data pertaining to the Celery protocol.
 #[derive(Eq, PartialEq, Debug, Default, Deserialize, Clone)]
 pub struct MessageHeaders {
     /// A unique ID of the task.
+    #[serde(default)]
     pub id: String,
 
-    /// The name of the task.
+    /// The name of the task, which is missing from messages of version 1 of the protocol.
+    #[serde(default)]
     pub task: String,
 
     /// The programming language associated with the task.
@@ -608,6 +965,7 @@ pub struct MessageHeaders {
     ///
     /// *Note that as of writing this, the Python celery docs actually have a typo where it says
     /// these are reversed.*
+    #[serde(default)]
     pub timelimit: (Option<u32>, Option<u32>),
 
     /// A string representation of the positional arguments of the task.
@@ -618,6 +976,51 @@ pub struct MessageHeaders {
 
     /// A string representing the nodename of the process that produced the task.
     pub origin: Option<String>,
+
+    /// Whether the message is acknowledged after the task has been executed, overriding
+    /// the [`acks_late`](crate::task::TaskOptions::acks_late) option of the worker.
+    ///
+    /// This header is specific to Rust workers.
+    pub acks_late: Option<bool>,
+
+    /// Whether the result of the task isn't stored (see
+    /// [`TaskOptions::ignore_result`](crate::task::TaskOptions::ignore_result)).
+    pub ignore_result: Option<bool>,
+
+    /// The number of times the message was requeued because the worker executing the task
+    /// was lost (see
+    /// [`TaskOptions::reject_on_worker_lost`](crate::task::TaskOptions::reject_on_worker_lost)).
+    ///
+    /// This header is specific to Rust workers.
+    pub redeliveries: Option<u32>,
+
+    /// The MIME type of the algorithm the body is compressed with (see [`Compression`]).
+    pub compression: Option<String>,
+
+    /// The signature of the message, when it is signed (see [`MessageSigner`]).
+    ///
+    /// This header is specific to Rust workers.
+    pub signature: Option<String>,
+
+    /// When the message was created to be sent, from which the workers measure how long
+    /// the task waited in its queue.
+    ///
+    /// This header is specific to Rust workers.
+    pub sent_at: Option<DateTime<Utc>>,
+
+    /// Custom headers, like a tenant ID or a trace context, sent along with the headers of
+    /// the protocol. The ones named like one of the [`RESERVED_HEADERS`] are not sent.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl MessageHeaders {
+    /// Get the custom headers which don't override the headers of the protocol.
+    pub fn custom(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.extra
+            .iter()
+            .filter(|(key, _)| !is_reserved_header(key))
+    }
 }
 
 /// The body of a message. Contains the task itself as well as callback / errback
@@ -643,23 +1046,262 @@ where
 pub struct MessageBodyEmbed {
     /// An array of serialized signatures of tasks to call with the result of this task.
     #[serde(default)]
-    pub callbacks: Option<Vec<String>>,
+    pub callbacks: Option<Vec<SerializedSignature>>,
 
     /// An array of serialized signatures of tasks to call if this task results in an error.
     ///
-    /// Note that `errbacks` work differently from `callbacks` because the error returned by
-    /// a task may not be serializable. Therefore the `errbacks` tasks are passed the task ID
-    /// instead of the error itself.
+    /// Note that `errbacks` work differently from `callbacks`: they are passed the task ID
+    /// along with the error, rather than the error alone.
+    #[serde(default)]
+    pub errbacks: Option<Vec<SerializedSignature>>,
+
+    /// An array of serialized signatures of the remaining tasks in the chain, in reverse
+    /// order: the next task is the last one.
+    #[serde(default)]
+    pub chain: Option<Vec<SerializedSignature>>,
+
+    /// The serialized signature of the callback of the chord the task is part of.
+    #[serde(default)]
+    pub chord: Option<SerializedSignature>,
+}
+
+/// A signature serialized in the body of a message, like the remaining tasks of a chain.
+///
+/// Signatures follow the format of Python Celery's, so that a chain can be continued by
+/// Python workers as well as Rust ones.
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct SerializedSignature {
+    /// The name of the task.
+    pub task: String,
+
+    /// The positional arguments of the task.
+    #[serde(default)]
+    pub args: Vec<Value>,
+
+    /// The keyword arguments of the task.
+    #[serde(default)]
+    pub kwargs: Map<String, Value>,
+
+    /// The options the task is sent with, e.g. its `task_id` or `queue`.
     #[serde(default)]
-    pub errbacks: Option<Vec<String>>,
+    pub options: Map<String, Value>,
 
-    /// An array of serialized signatures of the remaining tasks in the chain.
+    /// The type of work-flow the signature stands for, if it isn't a single task.
     #[serde(default)]
-    pub chain: Option<Vec<String>>,
+    pub subtask_type: Option<String>,
 
-    /// The serialized signature of the chord callback.
+    /// Whether the task ignores the result of the previous task of the chain, instead of
+    /// receiving it as its first argument.
     #[serde(default)]
-    pub chord: Option<String>,
+    pub immutable: bool,
+
+    /// The number of tasks of the chord, if this is the signature of its callback.
+    #[serde(default)]
+    pub chord_size: Option<usize>,
+}
+
+impl SerializedSignature {
+    /// The ID the task is sent with, if it was chosen beforehand.
+    pub fn task_id(&self) -> Option<&str> {
+        self.options.get("task_id").and_then(Value::as_str)
+    }
+
+    /// The queue the task is sent to, if it isn't routed by its name.
+    pub fn queue(&self) -> Option<&str> {
+        self.options.get("queue").and_then(Value::as_str)
+    }
+
+    /// Whether the result of the task isn't stored (see
+    /// [`TaskOptions::ignore_result`](crate::task::TaskOptions::ignore_result)).
+    pub fn ignore_result(&self) -> bool {
+        self.options
+            .get("ignore_result")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Create the message of the task once it is triggered by the task `parent_id`, if any,
+    /// e.g. the previous task of its chain, with `args` prepended to its arguments. The
+    /// rest of the chain is sent along with it.
+    pub(crate) fn into_message(
+        mut self,
+        parent_id: Option<&str>,
+        args: Vec<Value>,
+        chain: Vec<SerializedSignature>,
+    ) -> Result<Message, ProtocolError> {
+        let id = match self.task_id() {
+            Some(id) => id.to_owned(),
+            None => Uuid::new_v4().to_string(),
+        };
+        let eta = match self.time_option("eta")? {
+            Some(eta) => Some(eta),
+            None => self.time_option("countdown")?,
+        };
+        let expires = self.time_option("expires")?;
+        let time_limit = self.u32_option("time_limit");
+        let soft_time_limit = self.u32_option("soft_time_limit");
+        let priority = self.u32_option("priority").map(|priority| priority as u8);
+        let extra = match self.options.get("headers") {
+            Some(Value::Object(headers)) => headers
+                .iter()
+                .filter(|(key, _)| !is_reserved_header(key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            _ => HashMap::new(),
+        };
+
+        if !self.immutable {
+            self.args.splice(0..0, args);
+        }
+        let embed = MessageBodyEmbed {
+            chain: if chain.is_empty() { None } else { Some(chain) },
+            ..Default::default()
+        };
+        // Like Python signatures, the serializer is an option, so that links and chains
+        // keep the format their tasks were declared with.
+        let content_type = match self.options.get("serializer") {
+            None | Some(Value::Null) => MessageContentType::Json,
+            Some(Value::String(name)) => MessageContentType::from_serializer_name(name)
+                .ok_or_else(|| ProtocolError::InvalidProperty("serializer".into()))?,
+            Some(_) => return Err(ProtocolError::InvalidProperty("serializer".into())),
+        };
+        let (content_type, content_encoding) = content_type.properties();
+        let raw_body = serialize_body(content_type, &(&self.args, &self.kwargs, &embed))?;
+        Ok(Message {
+            properties: MessageProperties {
+                correlation_id: id.clone(),
+                content_type: content_type.into(),
+                content_encoding: content_encoding.into(),
+                reply_to: None,
+                priority,
+                delivery_mode: None,
+            },
+            headers: MessageHeaders {
+                id,
+                task: self.task,
+                parent_id: parent_id.map(String::from),
+                eta,
+                expires,
+                timelimit: (time_limit, soft_time_limit),
+                origin: ORIGIN.to_owned(),
+                sent_at: Some(Utc::now()),
+                shadow: self
+                    .options
+                    .get("shadow")
+                    .and_then(Value::as_str)
+                    .map(String::from),
+                ignore_result: self.options.get("ignore_result").and_then(Value::as_bool),
+                extra,
+                ..Default::default()
+            },
+            raw_body,
+        })
+    }
+
+    /// Get a time option, either a date or, like Python Celery, a number of seconds from now.
+    fn time_option(&self, name: &str) -> Result<Option<DateTime<Utc>>, ProtocolError> {
+        match self.options.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(time)) => DateTime::parse_from_rfc3339(time)
+                .map(|time| Some(time.with_timezone(&Utc)))
+                .map_err(|_| ProtocolError::InvalidProperty(name.into())),
+            Some(Value::Number(secs)) => {
+                let secs = secs.as_f64().unwrap_or_default().max(0.0);
+                let now = DateTime::<Utc>::from(SystemTime::now());
+                Ok(Some(now + Duration::from_secs_f64(secs)))
+            }
+            Some(_) => Err(ProtocolError::InvalidProperty(name.into())),
+        }
+    }
+
+    fn u32_option(&self, name: &str) -> Option<u32> {
+        self.options
+            .get(name)
+            .and_then(Value::as_f64)
+            .map(|value| value as u32)
+    }
+}
+
+impl<T> TryFrom<&Signature<T>> for SerializedSignature
+where
+    T: Task,
+{
+    type Error = ProtocolError;
+
+    /// Serialize the signature of a task executed after another task, whose result is
+    /// passed as its first parameter.
+    fn try_from(task_sig: &Signature<T>) -> Result<Self, Self::Error> {
+        Self::partial(task_sig, 1)
+    }
+}
+
+impl SerializedSignature {
+    /// Serialize the signature of a task triggered by another task, which passes its first
+    /// `given` parameters.
+    pub(crate) fn partial<T: Task>(
+        task_sig: &Signature<T>,
+        given: usize,
+    ) -> Result<Self, ProtocolError> {
+        let mut kwargs = match serde_json::to_value(&task_sig.params)? {
+            Value::Object(kwargs) => kwargs,
+            Value::Null => Map::new(),
+            _ => return Err(ProtocolError::InvalidProperty("params".into())),
+        };
+        for arg in T::ARGS.iter().take(given) {
+            kwargs.remove(*arg);
+        }
+
+        let mut options = task_sig.send_options().serialize()?;
+        options
+            .entry("task_id")
+            .or_insert_with(|| json!(Uuid::new_v4().to_string()));
+
+        Ok(Self {
+            task: T::NAME.into(),
+            args: vec![],
+            kwargs,
+            options,
+            subtask_type: None,
+            immutable: false,
+            chord_size: None,
+        })
+    }
+
+    /// Create the signature of a task from its name and arguments, when its type isn't
+    /// known. `args` must be an array, and `kwargs` an object, or null when empty.
+    pub(crate) fn by_name(
+        name: &str,
+        args: Value,
+        kwargs: Value,
+        options: &SendOptions,
+    ) -> Result<Self, ProtocolError> {
+        let args = match args {
+            Value::Array(args) => args,
+            Value::Null => vec![],
+            _ => return Err(ProtocolError::InvalidProperty("args".into())),
+        };
+        let kwargs = match kwargs {
+            Value::Object(kwargs) => kwargs,
+            Value::Null => Map::new(),
+            _ => return Err(ProtocolError::InvalidProperty("kwargs".into())),
+        };
+        Ok(Self {
+            task: name.into(),
+            args,
+            kwargs,
+            options: options.serialize()?,
+            subtask_type: None,
+            immutable: false,
+            chord_size: None,
+        })
+    }
+}
+
+impl TryCreateMessage for SerializedSignature {
+    /// Create the message of the task, with a new ID unless the signature has one.
+    fn try_create_message(&self) -> Result<Message, ProtocolError> {
+        self.clone().into_message(None, vec![], vec![])
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -673,6 +1315,10 @@ pub struct DeliveryProperties {
     pub reply_to: Option<String>,
     pub delivery_tag: String,
     pub body_encoding: BodyEncoding,
+    #[serde(default)]
+    pub priority: Option<u8>,
+    #[serde(default)]
+    pub delivery_mode: Option<u8>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -693,12 +1339,17 @@ impl TryDeserializeMessage for Delivery {
                 .decode(self.body.clone())
                 .map_err(|e| ProtocolError::InvalidProperty(format!("body error: {e}")))?,
         };
-        Ok(Message {
+        Message {
             properties: MessageProperties {
                 correlation_id: self.properties.correlation_id.clone(),
                 content_type: self.content_type.clone(),
                 content_encoding: self.content_encoding.clone(),
                 reply_to: self.properties.reply_to.clone(),
+                priority: self.properties.priority,
+                delivery_mode: self
+                    .properties
+                    .delivery_mode
+                    .and_then(DeliveryMode::from_u8),
             },
             headers: MessageHeaders {
                 id: self.headers.id.clone(),
@@ -716,9 +1367,17 @@ impl TryDeserializeMessage for Delivery {
                 argsrepr: self.headers.argsrepr.clone(),
                 kwargsrepr: self.headers.kwargsrepr.clone(),
                 origin: self.headers.origin.clone(),
+                acks_late: self.headers.acks_late,
+                ignore_result: self.headers.ignore_result,
+                redeliveries: self.headers.redeliveries,
+                compression: self.headers.compression.clone(),
+                signature: self.headers.signature.clone(),
+                sent_at: self.headers.sent_at,
+                extra: self.headers.extra.clone(),
             },
             raw_body,
-        })
+        }
+        .upgrade_v1()
     }
 }
 
@@ -3,7 +3,7 @@ use async_trait::async_trait;
 use super::*;
 use crate::error::TaskError;
 use crate::task::{Request, Task, TaskOptions};
-use chrono::{DateTime, SecondsFormat, Utc};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use std::time::SystemTime;
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -58,6 +58,8 @@ fn test_deserialize_body_with_args() {
             content_type: "application/json".into(),
             content_encoding: "utf-8".into(),
             reply_to: None,
+            priority: None,
+            delivery_mode: None,
         },
         headers: MessageHeaders {
             id: "aaa".into(),
@@ -88,6 +90,8 @@ fn test_yaml_deserialize_body_with_args() {
             content_type: "application/x-yaml".into(),
             content_encoding: "utf-8".into(),
             reply_to: None,
+            priority: None,
+            delivery_mode: None,
         },
         headers: MessageHeaders {
             id: "aaa".into(),
@@ -100,6 +104,56 @@ fn test_yaml_deserialize_body_with_args() {
     assert_eq!(body.1.a, 4);
 }
 
+/// The body of `test.apply_async(args=(4,), serializer="yaml")` sent by Python.
+const PYTHON_YAML: &str =
+    "- - 4\n- {}\n- callbacks: null\n  chain: null\n  chord: null\n  errbacks: null\n";
+
+/// The body of the first task of `(test.s(a=4) | test.s().set(queue="second"))
+/// .on_error(log_error.s())` sent by Python with the yaml serializer.
+const PYTHON_YAML_CHAIN: &str = "- []\n- a: 4\n- callbacks: null\n  chain:\n  - args: []\n    chord_size: null\n    immutable: false\n    kwargs: {}\n    options:\n      queue: second\n      task_id: bbb\n    subtask_type: null\n    task: test\n  chord: null\n  errbacks:\n  - args: []\n    chord_size: null\n    immutable: false\n    kwargs: {}\n    options:\n      task_id: eee\n    subtask_type: null\n    task: log_error\n";
+
+#[test]
+fn test_yaml_parse_python_bodies() {
+    let yaml_message = |raw_body: &str| Message {
+        properties: MessageProperties {
+            correlation_id: "aaa".into(),
+            content_type: "application/x-yaml".into(),
+            content_encoding: "utf-8".into(),
+            reply_to: None,
+            priority: None,
+            delivery_mode: None,
+        },
+        headers: MessageHeaders {
+            id: "aaa".into(),
+            task: "test".into(),
+            ..Default::default()
+        },
+        raw_body: Vec::from(raw_body),
+    };
+
+    let message = yaml_message(PYTHON_YAML);
+    let (params, embed) = message.body::<TestTask>().unwrap().parts();
+    assert_eq!(4, params.a);
+    assert_eq!(MessageBodyEmbed::default(), embed);
+    assert_eq!(MessageBodyEmbed::default(), message.body_embed().unwrap());
+
+    let message = yaml_message(PYTHON_YAML_CHAIN);
+    let (params, embed) = message.body::<TestTask>().unwrap().parts();
+    assert_eq!(4, params.a);
+    assert_eq!(embed, message.body_embed().unwrap());
+    let errback = embed.errbacks.unwrap().pop().unwrap();
+    assert_eq!("log_error", errback.task);
+    assert_eq!(Some("eee"), errback.task_id());
+    let mut chain = embed.chain.unwrap();
+    let next = chain.pop().unwrap();
+    assert_eq!(Some("second"), next.queue());
+    let message = next
+        .into_message(Some("aaa"), vec![json!(4)], chain)
+        .unwrap();
+    assert_eq!("bbb", message.task_id());
+    assert_eq!(4, message.body::<TestTask>().unwrap().1.a);
+}
+
 const PICKLE: &[u8] = b"\x80\x03(]}(X\x01\x00\x00\x00aJ\x04\x00\x00\x00u}(X\x09\x00\x00\x00callbacksNX\x08\x00\x00\x00errbacksNX\x05\x00\x00\x00chainNX\x05\x00\x00\x00chordNut.";
 #[test]
 fn test_pickle_serialize_body() {
@@ -117,6 +171,8 @@ fn test_pickle_deserialize_body_with_args() {
             content_type: "application/x-python-serialize".into(),
             content_encoding: "utf-8".into(),
             reply_to: None,
+            priority: None,
+            delivery_mode: None,
         },
         headers: MessageHeaders {
             id: "aaa".into(),
@@ -129,33 +185,155 @@ fn test_pickle_deserialize_body_with_args() {
     assert_eq!(body.1.a, 4);
 }
 
-const MSGPACK: &[u8] = &[147, 144, 145, 4, 148, 192, 192, 192, 192];
+/// Structs are encoded as maps, like Python Celery encodes the keyword arguments and the
+/// embedded work-flow primitives.
+const MSGPACK: &[u8] =
+    b"\x93\x90\x81\xa1a\x04\x84\xa9callbacks\xc0\xa8errbacks\xc0\xa5chain\xc0\xa5chord\xc0";
 #[test]
 fn test_msgpack_serialize_body() {
-    let body = MessageBody::<TestTask>::new(TestTaskParams { a: 4 });
-    let serialized = rmp_serde::to_vec(&body).unwrap();
-    // println!("{:?}", serialized);
-    assert_eq!(serialized, MSGPACK);
+    let message = MessageBuilder::<TestTask>::new("aaa".into())
+        .content_type(MessageContentType::MsgPack)
+        .params(TestTaskParams { a: 4 })
+        .build()
+        .unwrap();
+    assert_eq!("application/x-msgpack", message.properties.content_type);
+    assert_eq!("binary", message.properties.content_encoding);
+    assert_eq!(message.raw_body, MSGPACK);
 }
 
+/// The body sent by previous versions, which encoded structs as arrays.
+const MSGPACK_ARRAYS: &[u8] = &[147, 144, 145, 4, 148, 192, 192, 192, 192];
+
 #[test]
 fn test_msgpack_deserialize_body_with_args() {
+    for raw_body in [MSGPACK, MSGPACK_ARRAYS, PYTHON_MSGPACK] {
+        let message = Message {
+            properties: MessageProperties {
+                correlation_id: "aaa".into(),
+                content_type: "application/x-msgpack".into(),
+                content_encoding: "binary".into(),
+                reply_to: None,
+                priority: None,
+                delivery_mode: None,
+            },
+            headers: MessageHeaders {
+                id: "aaa".into(),
+                task: "TestTask".into(),
+                ..Default::default()
+            },
+            raw_body: raw_body.to_vec(),
+        };
+        let body = message.body::<TestTask>().unwrap();
+        assert_eq!(body.1.a, 4);
+        assert_eq!(MessageBodyEmbed::default(), message.body_embed().unwrap());
+    }
+}
+
+/// The body of `test.apply_async(args=(4,), serializer="msgpack")` sent by Python.
+const PYTHON_MSGPACK: &[u8] =
+    b"\x93\x91\x04\x80\x84\xa9callbacks\xc0\xa8errbacks\xc0\xa5chain\xc0\xa5chord\xc0";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct BlobTaskParams {
+    blob: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    bytes: Vec<u8>,
+    names: HashMap<u32, String>,
+}
+
+struct BlobTask {
+    request: Request<Self>,
+    options: TaskOptions,
+}
+
+#[async_trait]
+impl Task for BlobTask {
+    const NAME: &'static str = "blob";
+    const ARGS: &'static [&'static str] = &["blob", "bytes", "names"];
+
+    type Params = BlobTaskParams;
+    type Returns = ();
+
+    fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+        Self { request, options }
+    }
+
+    fn request(&self) -> &Request<Self> {
+        &self.request
+    }
+
+    fn options(&self) -> &TaskOptions {
+        &self.options
+    }
+
+    async fn run(&self, _params: Self::Params) -> Result<(), TaskError> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_msgpack_round_trip() {
+    let params = BlobTaskParams {
+        blob: vec![0, 1, 255],
+        bytes: vec![0, 1, 255],
+        names: HashMap::from([(1, "one".into()), (2, "two".into())]),
+    };
+    let message = Message::try_from(
+        Signature::<BlobTask>::new(params.clone())
+            .with_content_type(MessageContentType::MsgPack)
+            .link(Signature::<TestTask>::new(TestTaskParams { a: 0 })),
+    )
+    .unwrap();
+    // The bytes are encoded as a MessagePack binary.
+    let body: rmpv::Value = rmp_serde::from_slice(&message.raw_body).unwrap();
+    let bytes = body.as_array().unwrap()[1]
+        .as_map()
+        .unwrap()
+        .iter()
+        .find(|(key, _)| key.as_str() == Some("bytes"))
+        .map(|(_, value)| value.clone());
+    assert_eq!(Some(rmpv::Value::Binary(vec![0, 1, 255])), bytes);
+
+    let ser_msg = message.json_serialized().unwrap();
+    let delivery: Delivery = serde_json::from_slice(&ser_msg[..]).unwrap();
+    let message2 = delivery.try_deserialize_message().unwrap();
+    assert_eq!("application/x-msgpack", message2.properties.content_type);
+    let (params2, embed) = message2.body::<BlobTask>().unwrap().parts();
+    assert_eq!(params, params2);
+    assert_eq!("test", embed.callbacks.unwrap()[0].task);
+}
+
+/// The body of `blob.apply_async(args=([0, 1], b"\x00\xff"), kwargs={"names": {1: "one"}},
+/// serializer="msgpack")` sent by Python.
+const PYTHON_BLOB_MSGPACK: &[u8] = b"\x93\x92\x92\x00\x01\xc4\x02\x00\xff\x81\xa5names\x81\x01\xa3one\x84\xa9callbacks\xc0\xa8errbacks\xc0\xa5chain\xc0\xa5chord\xc0";
+
+#[test]
+fn test_msgpack_parse_python_blob() {
     let message = Message {
         properties: MessageProperties {
             correlation_id: "aaa".into(),
             content_type: "application/x-msgpack".into(),
-            content_encoding: "utf-8".into(),
+            content_encoding: "binary".into(),
             reply_to: None,
+            priority: None,
+            delivery_mode: None,
         },
         headers: MessageHeaders {
             id: "aaa".into(),
-            task: "TestTask".into(),
+            task: "blob".into(),
             ..Default::default()
         },
-        raw_body: MSGPACK.to_vec(),
+        raw_body: PYTHON_BLOB_MSGPACK.to_vec(),
     };
-    let body = message.body::<TestTask>().unwrap();
-    assert_eq!(body.1.a, 4);
+    let params = message.body::<BlobTask>().unwrap().1;
+    assert_eq!(
+        BlobTaskParams {
+            blob: vec![0, 1],
+            bytes: vec![0, 255],
+            names: HashMap::from([(1, "one".into())]),
+        },
+        params
+    );
 }
 
 #[test]
@@ -173,6 +351,8 @@ fn test_serialization() {
             content_type: "application/json".into(),
             content_encoding: "utf-8".into(),
             reply_to: Some("bbb".into()),
+            priority: None,
+            delivery_mode: None,
         },
         headers: MessageHeaders {
             id: "aaa".into(),
@@ -190,6 +370,16 @@ fn test_serialization() {
             argsrepr: Some("(1)".into()),
             kwargsrepr: Some("{'y': 2}".into()),
             origin: Some("gen123@piper".into()),
+            acks_late: Some(true),
+            ignore_result: Some(true),
+            redeliveries: Some(2),
+            compression: None,
+            signature: None,
+            sent_at: Some(now),
+            extra: HashMap::from([
+                ("tenant".to_string(), json!("a")),
+                ("id".to_string(), json!("zzz")),
+            ]),
         },
         raw_body: Vec::from(JSON),
     };
@@ -207,6 +397,7 @@ fn test_serialization() {
         String::from("aaa")
     );
     assert_eq!(ser_msg_json["properties"]["reply_to"], String::from("bbb"));
+    assert_eq!(ser_msg_json["properties"]["delivery_mode"], 2);
     assert_ne!(ser_msg_json["properties"]["delivery_tag"], "");
     assert_eq!(
         ser_msg_json["properties"]["body_encoding"],
@@ -228,9 +419,501 @@ fn test_serialization() {
     assert_eq!(ser_msg_json["headers"]["argsrepr"], "(1)");
     assert_eq!(ser_msg_json["headers"]["kwargsrepr"], "{'y': 2}");
     assert_eq!(ser_msg_json["headers"]["origin"], "gen123@piper");
+    assert_eq!(ser_msg_json["headers"]["acks_late"], true);
+    assert_eq!(ser_msg_json["headers"]["ignore_result"], true);
+    assert_eq!(ser_msg_json["headers"]["redeliveries"], 2);
+    assert_eq!(ser_msg_json["headers"]["sent_at"], now_str);
+    assert_eq!(ser_msg_json["headers"]["tenant"], "a");
     let body = ENGINE
         .decode(ser_msg_json["body"].as_str().unwrap())
         .unwrap();
     assert_eq!(body.len(), 73);
     assert_eq!(&body, JSON.as_bytes());
 }
+
+#[test]
+fn test_delivery_mode() {
+    let message = Message::try_from(
+        Signature::<TestTask>::new(TestTaskParams { a: 4 })
+            .with_delivery_mode(DeliveryMode::Transient),
+    )
+    .unwrap();
+    assert_eq!(
+        message.properties.delivery_mode,
+        Some(DeliveryMode::Transient)
+    );
+
+    let ser_msg = message.json_serialized().unwrap();
+    let delivery: Delivery = serde_json::from_slice(&ser_msg[..]).unwrap();
+    assert_eq!(delivery.properties.delivery_mode, Some(1));
+    let message2 = delivery.try_deserialize_message().unwrap();
+    assert_eq!(
+        message2.properties.delivery_mode,
+        Some(DeliveryMode::Transient)
+    );
+}
+
+#[test]
+fn test_custom_headers() {
+    let message = Message::try_from(
+        Signature::<TestTask>::new(TestTaskParams { a: 4 }).with_headers(HashMap::from([
+            ("tenant".to_string(), json!("a")),
+            ("retries".to_string(), json!(10)),
+        ])),
+    )
+    .unwrap();
+    // Custom headers can't override the headers of the protocol.
+    assert_eq!(None, message.headers.retries);
+    assert_eq!(
+        HashMap::from([("tenant".to_string(), json!("a"))]),
+        message.headers.extra
+    );
+
+    let ser_msg = message.json_serialized().unwrap();
+    let delivery: Delivery = serde_json::from_slice(&ser_msg[..]).unwrap();
+    let message2 = delivery.try_deserialize_message().unwrap();
+    assert_eq!(message.headers, message2.headers);
+    let request = Request::<TestTask>::try_from(message2).unwrap();
+    assert_eq!(Some(&json!("a")), request.headers.get("tenant"));
+
+    // Triggered tasks are sent with their own headers.
+    let message = Message::try_from(
+        Signature::<TestTask>::new(TestTaskParams { a: 1 }).then(
+            Signature::<TestTask>::new(TestTaskParams { a: 0 })
+                .with_headers(HashMap::from([("tenant".to_string(), json!("b"))])),
+        ),
+    )
+    .unwrap();
+    let next = message.body_embed().unwrap().chain.unwrap().pop().unwrap();
+    let message = next.into_message(None, vec![json!(4)], vec![]).unwrap();
+    assert_eq!(Some(&json!("b")), message.headers.extra.get("tenant"));
+}
+
+#[test]
+fn test_shadow() {
+    let message = Message::try_from(
+        Signature::<TestTask>::new(TestTaskParams { a: 4 }).with_shadow("test:sales_daily"),
+    )
+    .unwrap();
+    assert_eq!("test", message.headers.task);
+    let ser_msg = message.json_serialized().unwrap();
+    let ser_msg_json: serde_json::Value = serde_json::from_slice(&ser_msg[..]).unwrap();
+    assert_eq!(ser_msg_json["headers"]["shadow"], json!("test:sales_daily"));
+
+    // The worker reports the task under its shadow name.
+    let delivery: Delivery = serde_json::from_slice(&ser_msg[..]).unwrap();
+    let request =
+        Request::<TestTask>::try_from(delivery.try_deserialize_message().unwrap()).unwrap();
+    assert_eq!("test:sales_daily", request.display_name());
+    let request = Request::<TestTask>::try_from(
+        Message::try_from(Signature::<TestTask>::new(TestTaskParams { a: 4 })).unwrap(),
+    )
+    .unwrap();
+    assert_eq!("test", request.display_name());
+
+    // Tasks sent by name and triggered tasks keep their shadow name.
+    let options = SendOptions::new().with_shadow("tasks.add:fast");
+    let message = SerializedSignature::by_name("tasks.add", json!([1, 2]), Value::Null, &options)
+        .unwrap()
+        .try_create_message()
+        .unwrap();
+    assert_eq!("tasks.add", message.headers.task);
+    assert_eq!(Some("tasks.add:fast".into()), message.headers.shadow);
+}
+
+/// The JSON body of `test.apply_async(args=(4,), compression="zlib")` sent by Python.
+const PYTHON_ZLIB: &[u8] = b"\x78\x9c\x8b\x8e\x36\x89\xd5\x51\xa8\xae\x05\x62\xa5\xe4\xc4\x9c\x9c\xa4\xc4\xe4\xec\x62\x25\x2b\x85\xbc\xd2\x9c\x1c\x1d\x05\xa5\xd4\xa2\x22\x34\x91\xe4\x8c\xc4\xcc\x3c\x64\x6e\x7e\x51\x0a\x94\x5b\x1b\x0b\x00\xe2\x0e\x19\x75";
+
+#[test]
+fn test_parse_python_compressed_body() {
+    let message = Message {
+        properties: MessageProperties {
+            correlation_id: "aaa".into(),
+            content_type: "application/json".into(),
+            content_encoding: "utf-8".into(),
+            reply_to: None,
+            priority: None,
+            delivery_mode: None,
+        },
+        headers: MessageHeaders {
+            id: "aaa".into(),
+            task: "test".into(),
+            compression: Some("application/x-gzip".into()),
+            ..Default::default()
+        },
+        raw_body: PYTHON_ZLIB.to_vec(),
+    };
+    assert_eq!(4, message.body::<TestTask>().unwrap().1.a);
+    assert_eq!(MessageBodyEmbed::default(), message.body_embed().unwrap());
+
+    let message = Message {
+        headers: MessageHeaders {
+            compression: Some("application/x-bz2".into()),
+            ..message.headers
+        },
+        ..message
+    };
+    assert!(matches!(
+        message.body::<TestTask>(),
+        Err(ProtocolError::UnknownCompression(_))
+    ));
+}
+
+#[test]
+fn test_compression() {
+    for compression in [Compression::Zlib, Compression::Gzip, Compression::Zstd] {
+        let message = Message::try_from(
+            Signature::<TestTask>::new(TestTaskParams { a: 4 }).with_compression(compression),
+        )
+        .unwrap();
+        assert_eq!(
+            Some(compression.mime_type()),
+            message.headers.compression.as_deref()
+        );
+        assert_ne!(JSON.as_bytes(), message.raw_body);
+        assert_eq!(JSON.as_bytes(), &message.decompressed_body().unwrap()[..]);
+
+        let ser_msg = message.json_serialized().unwrap();
+        let delivery: Delivery = serde_json::from_slice(&ser_msg[..]).unwrap();
+        let message2 = delivery.try_deserialize_message().unwrap();
+        assert_eq!(message.headers, message2.headers);
+        assert_eq!(message.raw_body, message2.raw_body);
+        assert_eq!(4, message2.body::<TestTask>().unwrap().1.a);
+    }
+
+    // Bodies smaller than the threshold aren't compressed.
+    let message_with_threshold = |compression_threshold| {
+        let mut sig =
+            Signature::<TestTask>::new(TestTaskParams { a: 4 }).with_compression(Compression::Zlib);
+        sig.options.compression_threshold = Some(compression_threshold);
+        Message::try_from(sig).unwrap()
+    };
+    let message = message_with_threshold(JSON.len() + 1);
+    assert_eq!(None, message.headers.compression);
+    assert_eq!(JSON.as_bytes(), message.raw_body);
+    let message = message_with_threshold(JSON.len());
+    assert!(message.headers.compression.is_some());
+}
+
+#[test]
+fn test_message_signing() {
+    let signer = MessageSigner::new(b"new key").accept_key(b"old key");
+    let message = |a| Message::try_from(Signature::<TestTask>::new(TestTaskParams { a })).unwrap();
+
+    let mut signed = message(4);
+    assert!(matches!(
+        signer.verify(&signed),
+        Err(ProtocolError::MissingSignature)
+    ));
+    signer.sign(&mut signed);
+    signer.verify(&signed).unwrap();
+
+    // The signature survives the serialization of the message, and retries.
+    let ser_msg = signed.json_serialized().unwrap();
+    let delivery: Delivery = serde_json::from_slice(&ser_msg[..]).unwrap();
+    let mut message2 = delivery.try_deserialize_message().unwrap();
+    message2.headers.retries = Some(1);
+    signer.verify(&message2).unwrap();
+
+    // Messages signed with the old key are still accepted while rotating keys.
+    let mut signed_with_old_key = message(4);
+    MessageSigner::new(b"old key").sign(&mut signed_with_old_key);
+    signer.verify(&signed_with_old_key).unwrap();
+    let mut signed_with_other_key = message(4);
+    MessageSigner::new(b"other key").sign(&mut signed_with_other_key);
+    assert!(matches!(
+        signer.verify(&signed_with_other_key),
+        Err(ProtocolError::InvalidSignature)
+    ));
+
+    // Workers which only verify signatures don't sign messages.
+    let mut unsigned = message(4);
+    let verifier = MessageSigner::verify_only().accept_key(b"new key");
+    verifier.sign(&mut unsigned);
+    assert_eq!(None, unsigned.headers.signature);
+    verifier.verify(&signed).unwrap();
+
+    let mut tampered = signed.clone();
+    tampered.raw_body = message(9).raw_body;
+    assert!(matches!(
+        signer.verify(&tampered),
+        Err(ProtocolError::InvalidSignature)
+    ));
+    let mut tampered = signed.clone();
+    tampered.headers.task = "other".into();
+    assert!(matches!(
+        signer.verify(&tampered),
+        Err(ProtocolError::InvalidSignature)
+    ));
+    let mut tampered = signed;
+    tampered.headers.signature = Some("not base64!".into());
+    assert!(matches!(
+        signer.verify(&tampered),
+        Err(ProtocolError::InvalidSignature)
+    ));
+}
+
+#[test]
+fn test_serialize_chain() {
+    let message = Message::try_from(
+        Signature::<TestTask>::new(TestTaskParams { a: 1 }).then(
+            Signature::<TestTask>::new(TestTaskParams { a: 0 })
+                .with_queue("second")
+                .then(
+                    Signature::<TestTask>::new(TestTaskParams { a: 0 })
+                        .with_countdown(Duration::from_secs(2)),
+                ),
+        ),
+    )
+    .unwrap();
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    let chain = body[2]["chain"].as_array().unwrap();
+    assert_eq!(2, chain.len());
+    // Like in Python Celery, the remaining chain is reversed.
+    assert_eq!(chain[0]["options"]["countdown"], 2.0);
+    assert_eq!(chain[1]["options"]["queue"], "second");
+    for sig in chain {
+        assert_eq!(sig["task"], "test");
+        assert_eq!(sig["args"], json!([]));
+        // The first parameter is given by the previous task.
+        assert_eq!(sig["kwargs"], json!({}));
+        assert_eq!(sig["immutable"], false);
+        assert!(sig["options"]["task_id"].is_string());
+    }
+
+    let chain = message.body_embed().unwrap().chain.unwrap();
+    assert_eq!(Some("second"), chain[1].queue());
+}
+
+#[test]
+fn test_serialize_links() {
+    let message = Message::try_from(
+        Signature::<TestTask>::new(TestTaskParams { a: 1 })
+            .link(Signature::<TestTask>::new(TestTaskParams { a: 0 }))
+            .link(Signature::<TestTask>::new(TestTaskParams { a: 0 }).with_queue("second"))
+            .link_error(Signature::<TestTask>::new(TestTaskParams { a: 0 })),
+    )
+    .unwrap();
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[2]["chain"], Value::Null);
+    let callbacks = body[2]["callbacks"].as_array().unwrap();
+    assert_eq!(2, callbacks.len());
+    assert_eq!(callbacks[1]["options"]["queue"], "second");
+    let errbacks = body[2]["errbacks"].as_array().unwrap();
+    assert_eq!(1, errbacks.len());
+    for sig in callbacks.iter().chain(errbacks) {
+        assert_eq!(sig["task"], "test");
+        // The first parameters are given by the linked task.
+        assert_eq!(sig["kwargs"], json!({}));
+        assert!(sig["options"]["task_id"].is_string());
+    }
+
+    let message = Message::try_from(Signature::<TestTask>::new(TestTaskParams { a: 1 })).unwrap();
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[2]["callbacks"], Value::Null);
+    assert_eq!(body[2]["errbacks"], Value::Null);
+}
+
+#[test]
+fn test_signature_by_name() {
+    let options = SendOptions::new()
+        .with_expires_in(Duration::from_secs(60))
+        .with_priority(3)
+        .with_time_limit(10);
+    let sig =
+        SerializedSignature::by_name("tasks.add", json!([1, 2]), Value::Null, &options).unwrap();
+    assert_eq!(None, sig.task_id());
+
+    // Each message gets its own ID, and its expiration time is relative to its creation.
+    let message = sig.try_create_message().unwrap();
+    let other = sig.try_create_message().unwrap();
+    assert_ne!(message.task_id(), other.task_id());
+    assert_eq!("tasks.add", message.headers.task);
+    assert_eq!(None, message.headers.parent_id);
+    assert_eq!((None, Some(10)), message.headers.timelimit);
+    assert_eq!(Some(3), message.properties.priority);
+    let expires_in = message.headers.expires.unwrap() - Utc::now();
+    assert!(expires_in > chrono::Duration::seconds(59));
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[0], json!([1, 2]));
+    assert_eq!(body[1], json!({}));
+
+    let conflicting = SendOptions::new()
+        .with_countdown(Duration::from_secs(1))
+        .with_eta(Utc::now());
+    assert!(matches!(
+        SerializedSignature::by_name("tasks.add", json!([]), json!({}), &conflicting),
+        Err(ProtocolError::ConflictingOptions(_, _))
+    ));
+}
+
+/// The body of `add.s(1, 2).on_error(log_error.s())` linked to `mul.s(2)` sent by Python.
+const PYTHON_LINKS_JSON: &str = r#"[[1, 2], {}, {"callbacks": [{"task": "mul", "args": [2], "kwargs": {}, "options": {}, "subtask_type": null, "immutable": false, "chord_size": null}], "errbacks": [{"task": "log_error", "args": [], "kwargs": {}, "options": {"task_id": "eee"}, "subtask_type": null, "immutable": false, "chord_size": null}], "chain": null, "chord": null}]"#;
+
+#[test]
+fn test_parse_python_links() {
+    let message = Message {
+        properties: MessageProperties {
+            correlation_id: "aaa".into(),
+            content_type: "application/json".into(),
+            content_encoding: "utf-8".into(),
+            reply_to: None,
+            priority: None,
+            delivery_mode: None,
+        },
+        headers: MessageHeaders {
+            id: "aaa".into(),
+            task: "add".into(),
+            ..Default::default()
+        },
+        raw_body: Vec::from(PYTHON_LINKS_JSON),
+    };
+    let embed = message.body_embed().unwrap();
+    let callback = embed.callbacks.unwrap().pop().unwrap();
+    assert_eq!("mul", callback.task);
+    let message = callback
+        .into_message(Some("aaa"), vec![json!(3)], vec![])
+        .unwrap();
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[0], json!([3, 2]));
+
+    let errback = embed.errbacks.unwrap().pop().unwrap();
+    let message = errback
+        .into_message(Some("aaa"), vec![json!("aaa"), json!("error")], vec![])
+        .unwrap();
+    assert_eq!("eee", message.task_id());
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[0], json!(["aaa", "error"]));
+}
+
+/// The body of the first task of `add.s(1, 2) | add.s(3) | mul.si(2, 5)` sent by Python.
+const PYTHON_CHAIN_JSON: &str = r#"[[1, 2], {}, {"callbacks": null, "errbacks": null, "chain": [{"task": "mul", "args": [2, 5], "kwargs": {}, "options": {"task_id": "ccc", "reply_to": "ddd"}, "subtask_type": null, "immutable": true, "chord_size": null}, {"task": "add", "args": [3], "kwargs": {}, "options": {"task_id": "bbb", "reply_to": "ddd"}, "subtask_type": null, "immutable": false, "chord_size": null}], "chord": null}]"#;
+
+#[test]
+fn test_continue_python_chain() {
+    let message = Message {
+        properties: MessageProperties {
+            correlation_id: "aaa".into(),
+            content_type: "application/json".into(),
+            content_encoding: "utf-8".into(),
+            reply_to: None,
+            priority: None,
+            delivery_mode: None,
+        },
+        headers: MessageHeaders {
+            id: "aaa".into(),
+            task: "add".into(),
+            ..Default::default()
+        },
+        raw_body: Vec::from(PYTHON_CHAIN_JSON),
+    };
+    let mut chain = message.body_embed().unwrap().chain.unwrap();
+
+    // The result is prepended to the arguments of the next task.
+    let next = chain.pop().unwrap();
+    let message = next
+        .into_message(Some("aaa"), vec![json!(3)], chain)
+        .unwrap();
+    assert_eq!("bbb", message.task_id());
+    assert_eq!("add", message.headers.task);
+    assert_eq!(Some("aaa".into()), message.headers.parent_id);
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[0], json!([3, 3]));
+    assert_eq!(body[2]["chain"][0]["task"], "mul");
+
+    // Immutable signatures ignore the result.
+    let mut chain = message.body_embed().unwrap().chain.unwrap();
+    let next = chain.pop().unwrap();
+    let message = next
+        .into_message(Some("bbb"), vec![json!(6)], chain)
+        .unwrap();
+    assert_eq!("ccc", message.task_id());
+    let body: Value = serde_json::from_slice(&message.raw_body).unwrap();
+    assert_eq!(body[0], json!([2, 5]));
+    assert_eq!(body[2]["chain"], Value::Null);
+}
+
+/// A message of version 1 of the protocol sent to Redis by Celery 3.1 with
+/// `test.apply_async(args=(4,), eta=..., time_limit=30)`, retried twice.
+const PYTHON_V1_DELIVERY: &str = r#"{"body": "eyJleHBpcmVzIjogbnVsbCwgInV0YyI6IHRydWUsICJhcmdzIjogWzRdLCAiY2hvcmQiOiBudWxsLCAiY2FsbGJhY2tzIjogbnVsbCwgImVycmJhY2tzIjogbnVsbCwgInRhc2tzZXQiOiBudWxsLCAiaWQiOiAiOWIxYjNiOWUtNWE4Yy00YjUzLTliMWEtN2ExYzJmM2U0ZDVmIiwgInJldHJpZXMiOiAyLCAidGFzayI6ICJ0ZXN0IiwgInRpbWVsaW1pdCI6IFszMCwgbnVsbF0sICJldGEiOiAiMjAxNS0wNi0wNFQxNTo0OTo0Mi4xMjczODYrMDA6MDAiLCAia3dhcmdzIjoge319", "headers": {}, "content-type": "application/json", "properties": {"body_encoding": "base64", "correlation_id": "9b1b3b9e-5a8c-4b53-9b1a-7a1c2f3e4d5f", "reply_to": "3b2e5c1a-0f6e-3a51-9d40-2c8e0b2d1c4a", "delivery_info": {"priority": 0, "routing_key": "celery", "exchange": "celery"}, "delivery_mode": 2, "delivery_tag": "4f0e8d39-8c3b-4f65-a2b0-16a0c5b9a9c1"}, "content-encoding": "utf-8"}"#;
+
+/// The pickled body of a message of version 1 of the protocol sent by Celery 3.1 on Python
+/// 2 with `CELERY_ENABLE_UTC = False`, for a task of a group.
+const PYTHON_V1_PICKLE: &[u8] = b"\x80\x02}q\x00(U\x07expiresq\x01NU\x03utcq\x02\x89U\x04argsq\x03K\x04\x85q\x04U\x05chordq\x05NU\tcallbacksq\x06NU\x08errbacksq\x07NU\x07tasksetq\x08U$7d4f0ab1-3c1e-4a8e-8f1b-2a6f0c9d8e7bq\tU\x02idq\nU\x03aaaq\x0bU\x07retriesq\x0cK\x00U\x04taskq\rU\x04testq\x0eU\ttimelimitq\x0fNN\x86q\x10U\x03etaq\x11U\x1a2015-06-04T17:49:42.127386q\x12U\x06kwargsq\x13}q\x14u.";
+
+#[test]
+fn test_parse_python_v1_delivery() {
+    let delivery: Delivery = serde_json::from_str(PYTHON_V1_DELIVERY).unwrap();
+    let message = delivery.try_deserialize_message().unwrap();
+    assert_eq!("9b1b3b9e-5a8c-4b53-9b1a-7a1c2f3e4d5f", message.task_id());
+    assert_eq!("test", message.headers.task);
+    assert_eq!(Some(2), message.headers.retries);
+    assert_eq!((Some(30), None), message.headers.timelimit);
+    assert_eq!(None, message.headers.expires);
+    assert_eq!(
+        Some("2015-06-04T15:49:42.127386Z"),
+        message
+            .headers
+            .eta
+            .map(|eta| eta.to_rfc3339_opts(SecondsFormat::Micros, true))
+            .as_deref()
+    );
+
+    let request = Request::<TestTask>::try_from(message).unwrap();
+    assert_eq!(4, request.params.a);
+    assert_eq!(2, request.retries);
+}
+
+#[test]
+fn test_parse_python_v1_pickle() {
+    let message = Message {
+        properties: MessageProperties {
+            correlation_id: "aaa".into(),
+            content_type: "application/x-python-serialize".into(),
+            content_encoding: "binary".into(),
+            reply_to: None,
+            priority: None,
+            delivery_mode: None,
+        },
+        headers: MessageHeaders::default(),
+        raw_body: PYTHON_V1_PICKLE.to_vec(),
+    }
+    .upgrade_v1()
+    .unwrap();
+    assert_eq!("aaa", message.task_id());
+    assert_eq!("test", message.headers.task);
+    assert_eq!(
+        Some("7d4f0ab1-3c1e-4a8e-8f1b-2a6f0c9d8e7b".into()),
+        message.headers.group
+    );
+    // Naive times are in the local time of the producer.
+    let eta = chrono::NaiveDate::from_ymd_opt(2015, 6, 4)
+        .and_then(|date| date.and_hms_micro_opt(17, 49, 42, 127386))
+        .and_then(|eta| chrono::Local.from_local_datetime(&eta).earliest())
+        .unwrap();
+    assert_eq!(Some(eta.with_timezone(&Utc)), message.headers.eta);
+    assert_eq!(4, message.body::<TestTask>().unwrap().1.a);
+}
+
+#[test]
+fn test_message_without_task_rejected() {
+    let message = Message {
+        properties: MessageProperties {
+            correlation_id: "aaa".into(),
+            content_type: "application/json".into(),
+            content_encoding: "utf-8".into(),
+            reply_to: None,
+            priority: None,
+            delivery_mode: None,
+        },
+        headers: MessageHeaders {
+            id: "aaa".into(),
+            ..Default::default()
+        },
+        raw_body: Vec::from(&b"[[4], {}, {}]"[..]),
+    };
+    assert!(matches!(
+        message.upgrade_v1(),
+        Err(ProtocolError::MissingRequiredHeader(header)) if header == "task"
+    ));
+}
@@ -0,0 +1,136 @@
+//! Decoding of messages of version 1 of the Celery protocol, still sent by old Python
+//! producers (before Celery 4).
+//!
+//! Their task name, ID and options are in the body rather than in the headers. Workers only
+//! decode them: they are converted to the version 2 used by this crate, so that the rest of
+//! the worker doesn't know the difference.
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{serialize_body, Message, MessageBodyEmbed, SerializedSignature};
+use crate::error::{ContentTypeError, ProtocolError};
+
+/// The body of a message of version 1 of the protocol.
+#[derive(Deserialize)]
+struct BodyV1 {
+    task: String,
+    id: String,
+    #[serde(default)]
+    args: Vec<Value>,
+    #[serde(default)]
+    kwargs: Map<String, Value>,
+    #[serde(default)]
+    retries: Option<u32>,
+    #[serde(default)]
+    eta: Option<String>,
+    #[serde(default)]
+    expires: Option<String>,
+    /// Whether naive times are in UTC rather than in the local time of the producer, which
+    /// is assumed to be the one of the worker.
+    #[serde(default = "default_utc")]
+    utc: bool,
+    /// The hard and soft time limits.
+    #[serde(default)]
+    timelimit: Option<(Option<f64>, Option<f64>)>,
+    /// The ID of the group of the task.
+    #[serde(default)]
+    taskset: Option<String>,
+    #[serde(default)]
+    callbacks: Option<Vec<SerializedSignature>>,
+    #[serde(default)]
+    errbacks: Option<Vec<SerializedSignature>>,
+    #[serde(default)]
+    chord: Option<SerializedSignature>,
+}
+
+fn default_utc() -> bool {
+    true
+}
+
+impl Message {
+    /// Convert a message of version 1 of the protocol, told apart by the lack of a `task`
+    /// header, to version 2. Other messages are returned as they are.
+    pub(crate) fn upgrade_v1(mut self) -> Result<Message, ProtocolError> {
+        if !self.headers.task.is_empty() {
+            return Ok(self);
+        }
+        let body = self
+            .body_v1()
+            .map_err(|_| ProtocolError::MissingRequiredHeader("task".into()))?;
+
+        self.headers.id = body.id;
+        self.headers.task = body.task;
+        self.headers.retries = body.retries;
+        self.headers.group = body.taskset;
+        self.headers.eta = parse_time(body.eta, body.utc, "eta")?;
+        self.headers.expires = parse_time(body.expires, body.utc, "expires")?;
+        if let Some((time_limit, soft_time_limit)) = body.timelimit {
+            self.headers.timelimit = (
+                time_limit.map(|secs| secs as u32),
+                soft_time_limit.map(|secs| secs as u32),
+            );
+        }
+        let embed = MessageBodyEmbed {
+            callbacks: body.callbacks,
+            errbacks: body.errbacks,
+            chord: body.chord,
+            ..Default::default()
+        };
+        // The body is stored uncompressed, since it is only read by this worker.
+        self.raw_body = serialize_body(
+            &self.properties.content_type,
+            &(body.args, body.kwargs, embed),
+        )?;
+        self.headers.compression = None;
+        Ok(self)
+    }
+
+    fn body_v1(&self) -> Result<BodyV1, ProtocolError> {
+        let raw_body = self.decompressed_body()?;
+        match self.properties.content_type.as_str() {
+            "application/json" => Ok(serde_json::from_slice(&raw_body)?),
+            #[cfg(any(test, feature = "extra_content_types"))]
+            "application/x-yaml" => Ok(serde_yaml::from_slice(&raw_body)?),
+            // Python 2 producers pickle strings as bytes.
+            #[cfg(any(test, feature = "extra_content_types"))]
+            "application/x-python-serialize" => Ok(serde_pickle::from_slice(
+                &raw_body,
+                serde_pickle::DeOptions::new().decode_strings(),
+            )?),
+            #[cfg(any(test, feature = "extra_content_types"))]
+            "application/x-msgpack" => Ok(rmp_serde::from_slice(&raw_body)?),
+            _ => Err(ProtocolError::BodySerializationError(
+                ContentTypeError::Unknown,
+            )),
+        }
+    }
+}
+
+/// Parse a time of a version 1 message, which is naive unless the producer is configured
+/// with a time zone.
+fn parse_time(
+    time: Option<String>,
+    utc: bool,
+    name: &str,
+) -> Result<Option<DateTime<Utc>>, ProtocolError> {
+    let time = match time {
+        Some(time) => time,
+        None => return Ok(None),
+    };
+    if let Ok(time) = DateTime::parse_from_rfc3339(&time) {
+        return Ok(Some(time.with_timezone(&Utc)));
+    }
+    let naive = NaiveDateTime::parse_from_str(&time, "%Y-%m-%dT%H:%M:%S%.f")
+        .map_err(|_| ProtocolError::InvalidProperty(name.into()))?;
+    if utc {
+        Ok(Some(Utc.from_utc_datetime(&naive)))
+    } else {
+        Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|time| Some(time.with_timezone(&Utc)))
+            .ok_or_else(|| ProtocolError::InvalidProperty(name.into()))
+    }
+}
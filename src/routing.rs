@@ -1,18 +1,52 @@
 use globset::{Glob, GlobMatcher};
+use std::fmt;
 
 use crate::error::BadRoutingPattern;
+use crate::task::Task;
 
-/// A rule for routing tasks to a queue based on a glob pattern.
+/// Where the tasks matched by a [`Rule`] are sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Destination {
+    /// A queue, through the default exchange.
+    Queue(String),
+
+    /// An exchange, which routes the tasks to the queues bound to it based on the routing key.
+    Exchange {
+        exchange: String,
+        routing_key: String,
+    },
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Queue(queue) => write!(f, "{}", queue),
+            Destination::Exchange {
+                exchange,
+                routing_key,
+            } => write!(f, "exchange {} with routing key {}", exchange, routing_key),
+        }
+    }
+}
+
+/// A rule for routing tasks to a queue or an exchange based on a glob pattern.
 pub(crate) struct Rule {
     pub(crate) pattern: GlobMatcher,
-    pub(crate) queue: String,
+    pub(crate) destination: Destination,
 }
 
 impl Rule {
     pub(crate) fn new(pattern: &str, queue: &str) -> Result<Self, BadRoutingPattern> {
+        Self::with_destination(pattern, Destination::Queue(queue.into()))
+    }
+
+    pub(crate) fn with_destination(
+        pattern: &str,
+        destination: Destination,
+    ) -> Result<Self, BadRoutingPattern> {
         Ok(Self {
             pattern: Glob::new(pattern)?.compile_matcher(),
-            queue: queue.into(),
+            destination,
         })
     }
 
@@ -21,10 +55,19 @@ impl Rule {
     }
 }
 
-pub(crate) fn route<'a>(task_name: &'a str, rules: &'a [Rule]) -> Option<&'a str> {
+/// Get the exchange a task is published to according to its definition, which takes
+/// precedence over the routing rules. The queue of its definition is set on its signature.
+pub(crate) fn task_destination<T: Task>() -> Option<Destination> {
+    T::EXCHANGE.map(|(exchange, routing_key)| Destination::Exchange {
+        exchange: exchange.into(),
+        routing_key: routing_key.into(),
+    })
+}
+
+pub(crate) fn route<'a>(task_name: &'a str, rules: &'a [Rule]) -> Option<&'a Destination> {
     for rule in rules {
         if rule.is_match(task_name) {
-            return Some(&rule.queue);
+            return Some(&rule.destination);
         }
     }
     None
@@ -39,9 +82,28 @@ mod tests {
         let rules = vec![
             Rule::new("tasks.backend.*", "backend").unwrap(),
             Rule::new("tasks.ml.*", "ml").unwrap(),
+            Rule::with_destination(
+                "tasks.events.*",
+                Destination::Exchange {
+                    exchange: "events".into(),
+                    routing_key: "events.task".into(),
+                },
+            )
+            .unwrap(),
             Rule::new("tasks.*", "celery").unwrap(),
         ];
 
-        assert_eq!(route("tasks.ml.predict", &rules[..]), Some("ml"));
+        assert_eq!(
+            route("tasks.ml.predict", &rules[..]),
+            Some(&Destination::Queue("ml".into()))
+        );
+        assert_eq!(
+            route("tasks.events.publish", &rules[..]),
+            Some(&Destination::Exchange {
+                exchange: "events".into(),
+                routing_key: "events.task".into(),
+            })
+        );
+        assert_eq!(route("other", &rules[..]), None);
     }
 }
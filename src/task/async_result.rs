@@ -13,6 +13,7 @@ use super::TaskState;
 pub struct AsyncResult {
     task_id: String,
     backend: Option<Arc<dyn Backend>>,
+    ignored: bool,
 }
 
 impl AsyncResult {
@@ -20,6 +21,17 @@ impl AsyncResult {
         Self {
             task_id: task_id.into(),
             backend,
+            ignored: false,
+        }
+    }
+
+    /// The result of a task whose result isn't stored, whose methods fail with
+    /// [`BackendError::ResultIgnored`].
+    pub(crate) fn ignored(task_id: &str) -> Self {
+        Self {
+            task_id: task_id.into(),
+            backend: None,
+            ignored: true,
         }
     }
 
@@ -42,7 +54,10 @@ impl AsyncResult {
         self.throw_if_backend_not_set()?;
         let backend = self.backend.clone().unwrap();
         let state = backend.get_state(&self.task_id).await?;
-        Ok(state == TaskState::Success || state == TaskState::Failure)
+        Ok(state == TaskState::Success
+            || state == TaskState::Failure
+            || state == TaskState::Revoked
+            || state == TaskState::Ignored)
     }
 
     /// Get result of task
@@ -68,7 +83,14 @@ impl AsyncResult {
         Ok(backend.get_traceback(&self.task_id).await?)
     }
 
-    /// Task's state
+    /// Node name of the worker which executed the task, if it was executed
+    pub async fn worker(&self) -> Result<Option<String>, BackendError> {
+        self.throw_if_backend_not_set()?;
+        let backend = self.backend.clone().unwrap();
+        Ok(backend.get_worker(&self.task_id).await?)
+    }
+
+    /// Task's state, which is `Pending` until the backend knows about the task
     pub async fn state(&self) -> Result<TaskState, BackendError> {
         self.throw_if_backend_not_set()?;
         let backend = self.backend.clone().unwrap();
@@ -91,10 +113,11 @@ impl AsyncResult {
     fn throw_if_backend_not_set(&self) -> Result<(), BackendError> {
         match &self.backend {
             Some(_) => Ok(()),
+            None if self.ignored => Err(BackendError::ResultIgnored),
             None => Err(BackendError::NotSet),
         }
     }
-    
+
     /// Watches the backend and blocks until the state of the task changes to a `Success` or `Failure`
     pub async fn wait_for_completion(&self) -> Result<bool, BackendError> {
         self.throw_if_backend_not_set()?;
@@ -0,0 +1,105 @@
+use async_trait::async_trait;
+use std::time::Duration;
+
+use super::{Request, Task, TaskResult};
+
+/// How the results of a batch are stored when some of its tasks failed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BatchFailurePolicy {
+    /// All the tasks of the batch fail with the first error of the batch, even those which
+    /// succeeded.
+    #[default]
+    FailAll,
+
+    /// Each task of the batch gets its own result, so that only the tasks which failed
+    /// fail.
+    PerItem,
+}
+
+/// A [`Task`] whose messages are processed many at a time, with a single invocation of
+/// [`run_batch`](BatchTask::run_batch) for a whole batch of requests, like the batches of
+/// [celery-batches](https://celery-batches.readthedocs.io) in Python.
+///
+/// A batch task is registered with
+/// [`Celery::register_batch_task`](crate::Celery::register_batch_task). The worker then
+/// accumulates the requests of the task until it has [`MAX_SIZE`](BatchTask::MAX_SIZE)
+/// of them, or until [`FLUSH_INTERVAL`](BatchTask::FLUSH_INTERVAL) elapsed since the
+/// first one, and runs them as a batch. The batches which are still filling up when the
+/// worker shuts down are run before it stops.
+///
+/// The messages of a batch are acknowledged once the batch ran, and each task of the batch
+/// gets its result in the backend according to the
+/// [`FAILURE_POLICY`](BatchTask::FAILURE_POLICY). Since the messages of a batch are held
+/// until then, the [`prefetch_count`](crate::CeleryBuilder::prefetch_count) of the worker
+/// should be at least `MAX_SIZE`, lest the batches are always flushed by the interval.
+///
+/// The batches are only formed by the worker: the tasks are still sent one by one, and
+/// [`run`](Task::run) is what executes a task which isn't part of a batch, e.g. with
+/// [`task_always_eager`](crate::CeleryBuilder::task_always_eager). The countdowns, retries
+/// and time limits of the tasks don't apply to batches.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_trait::async_trait;
+/// # use celery::prelude::*;
+/// # use celery::task::{BatchTask, Request, TaskOptions};
+/// # use std::time::Duration;
+/// struct CountClicks {
+///     request: Request<Self>,
+///     options: TaskOptions,
+/// }
+///
+/// #[async_trait]
+/// impl Task for CountClicks {
+///     const NAME: &'static str = "count_clicks";
+///     const ARGS: &'static [&'static str] = &["url"];
+///
+///     type Params = String;
+///     type Returns = usize;
+///
+///     fn from_request(request: Request<Self>, options: TaskOptions) -> Self {
+///         Self { request, options }
+///     }
+///
+///     fn request(&self) -> &Request<Self> {
+///         &self.request
+///     }
+///
+///     fn options(&self) -> &TaskOptions {
+///         &self.options
+///     }
+///
+///     async fn run(&self, _url: String) -> TaskResult<usize> {
+///         Ok(1)
+///     }
+/// }
+///
+/// #[async_trait]
+/// impl BatchTask for CountClicks {
+///     const MAX_SIZE: usize = 500;
+///     const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+///
+///     async fn run_batch(requests: Vec<Request<Self>>) -> TaskResult<Vec<TaskResult<usize>>> {
+///         // Save the clicks in bulk here.
+///         Ok(requests.iter().map(|_| Ok(1)).collect())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait BatchTask: Task {
+    /// The number of requests at which a batch is run.
+    const MAX_SIZE: usize;
+
+    /// The longest time the first request of a batch waits for the batch to fill up
+    /// before the batch is run anyway.
+    const FLUSH_INTERVAL: Duration;
+
+    /// How the results of the batch are stored when some of its tasks failed.
+    const FAILURE_POLICY: BatchFailurePolicy = BatchFailurePolicy::FailAll;
+
+    /// Run a batch of requests, returning the result of each request, in order. An error
+    /// fails all the tasks of the batch, and the requests without a result fail with an
+    /// [`UnexpectedError`](crate::error::TaskError::UnexpectedError).
+    async fn run_batch(requests: Vec<Request<Self>>) -> TaskResult<Vec<TaskResult<Self::Returns>>>;
+}
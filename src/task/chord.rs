@@ -0,0 +1,93 @@
+use std::convert::TryFrom;
+
+use super::{AsyncResult, Group, GroupResult, Signature, Task};
+use crate::error::ProtocolError;
+use crate::protocol::SerializedSignature;
+
+/// How the callback of a [`Chord`] handles the tasks of the chord which failed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChordErrorPolicy {
+    /// The callback fails as soon as a task of the chord fails, without being executed.
+    #[default]
+    Propagate,
+
+    /// The callback is executed once all the tasks of the chord finished, with the
+    /// serialized [`TaskError`](crate::error::TaskError)s of the tasks which failed in
+    /// place of their results.
+    PassThrough,
+}
+
+/// A group of tasks whose callback is executed with the results of all of them once they
+/// finished, like a chord in Python Celery.
+///
+/// A chord is sent with [`Celery::send_chord`](crate::Celery::send_chord).
+///
+/// # Examples
+///
+/// ```rust
+/// use celery::prelude::*;
+/// use celery::task::{chord, group};
+///
+/// #[celery::task]
+/// fn add(x: i32, y: i32) -> TaskResult<i32> {
+///     Ok(x + y)
+/// }
+///
+/// #[celery::task]
+/// fn sum(values: Vec<i32>) -> TaskResult<i32> {
+///     Ok(values.into_iter().sum())
+/// }
+///
+/// let chord = chord(group(vec![add::new(1, 2), add::new(3, 4)]), sum::new(vec![]));
+/// ```
+pub struct Chord {
+    pub(crate) header: Group,
+    pub(crate) callback:
+        Box<dyn FnOnce() -> Result<SerializedSignature, ProtocolError> + Send + Sync>,
+    pub(crate) error_policy: ChordErrorPolicy,
+}
+
+impl Chord {
+    /// Set how the callback handles the tasks of the chord which failed.
+    pub fn with_error_policy(mut self, error_policy: ChordErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+}
+
+/// Create a chord from a group of tasks and a callback, which receives the list of the
+/// results of the tasks, in order, as its first parameter. The value of the first
+/// parameter in `callback` is ignored.
+pub fn chord<T>(header: Group, callback: Signature<T>) -> Chord
+where
+    T: Task + 'static,
+{
+    Chord {
+        header,
+        callback: Box::new(move || SerializedSignature::try_from(&callback)),
+        error_policy: ChordErrorPolicy::default(),
+    }
+}
+
+/// A [`ChordResult`] is a handle for the results of the tasks of a [`Chord`] and of its
+/// callback.
+pub struct ChordResult {
+    header: GroupResult,
+    callback: AsyncResult,
+}
+
+impl ChordResult {
+    pub(crate) fn new(header: GroupResult, callback: AsyncResult) -> Self {
+        Self { header, callback }
+    }
+
+    /// Results of the tasks of the chord
+    pub fn header(&self) -> &GroupResult {
+        &self.header
+    }
+
+    /// Result of the callback of the chord
+    pub fn callback(&self) -> &AsyncResult {
+        &self.callback
+    }
+}
@@ -0,0 +1,50 @@
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+use super::{Request, Task};
+
+/// Information about an execution of a task, passed to its
+/// [`on_success`](Task::on_success), [`on_failure`](Task::on_failure) and
+/// [`on_retry`](Task::on_retry) callbacks.
+pub struct TaskContext<'a, T>
+where
+    T: Task,
+{
+    /// The unique ID of the task.
+    pub task_id: &'a str,
+
+    /// The parameters the task was executed with.
+    pub params: &'a T::Params,
+
+    /// How many times the task had been retried before this execution.
+    pub retries: u32,
+
+    /// Whether this execution is a retry, i.e. whether `retries` is above 0.
+    pub is_retry: bool,
+
+    /// The ETA the task was executed at, if it had one.
+    pub eta: Option<DateTime<Utc>>,
+
+    /// The queue the task was consumed from, if known.
+    pub queue: Option<&'a str>,
+
+    /// How long the task was executed for.
+    pub elapsed: Duration,
+}
+
+impl<'a, T> TaskContext<'a, T>
+where
+    T: Task,
+{
+    pub(crate) fn new(request: &'a Request<T>, elapsed: Duration) -> Self {
+        Self {
+            task_id: &request.id,
+            params: &request.params,
+            retries: request.retries,
+            is_retry: request.is_retry(),
+            eta: request.eta,
+            queue: request.queue.as_deref(),
+            elapsed,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use super::{Signature, Task};
+use crate::error::CeleryError;
+use crate::protocol::{Message, SerializedSignature};
+use crate::routing::Destination;
+use crate::Celery;
+
+/// A task of a group, prepared to be sent by an app with the callback of the chord of the
+/// group, if any.
+pub(crate) type GroupMember = Box<
+    dyn FnOnce(&Celery, Option<SerializedSignature>) -> Result<(Message, Destination), CeleryError>
+        + Send
+        + Sync,
+>;
+
+/// A group of tasks executed in parallel, like a group in Python Celery.
+///
+/// A group is sent with [`Celery::send_group`], which returns a
+/// [`GroupResult`](super::GroupResult) to collect the results of all of its tasks.
+///
+/// # Examples
+///
+/// ```rust
+/// use celery::prelude::*;
+/// use celery::task::group;
+///
+/// #[celery::task]
+/// fn add(x: i32, y: i32) -> TaskResult<i32> {
+///     Ok(x + y)
+/// }
+///
+/// #[celery::task]
+/// fn multiply(x: i32, y: i32) -> TaskResult<i32> {
+///     Ok(x * y)
+/// }
+///
+/// let group = group(vec![add::new(1, 2), add::new(3, 4)]).with_task(multiply::new(5, 6));
+/// assert_eq!(3, group.len());
+/// ```
+#[derive(Default)]
+pub struct Group {
+    pub(crate) members: Vec<GroupMember>,
+}
+
+impl Group {
+    /// Create an empty group.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a task to the group, which can be of any type.
+    pub fn with_task<T>(mut self, mut task_sig: Signature<T>) -> Self
+    where
+        T: Task + 'static,
+    {
+        self.members.push(Box::new(move |app: &Celery, chord| {
+            task_sig.chord = chord;
+            app.prepare_task(task_sig)
+        }));
+        self
+    }
+
+    /// Get the number of tasks in the group.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Check if the group has no tasks.
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+/// Create a group of tasks of the same type. Tasks of other types can be added with
+/// [`Group::with_task`].
+pub fn group<T>(task_sigs: Vec<Signature<T>>) -> Group
+where
+    T: Task + 'static,
+{
+    task_sigs.into_iter().fold(Group::new(), Group::with_task)
+}
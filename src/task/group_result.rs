@@ -0,0 +1,79 @@
+use serde::de::DeserializeOwned;
+
+use crate::error::{BackendError, TaskError};
+
+use super::{AsyncResult, TaskState};
+
+/// A [`GroupResult`] is a handle for the results of the tasks of a
+/// [`Group`](super::Group).
+pub struct GroupResult {
+    group_id: String,
+    results: Vec<AsyncResult>,
+}
+
+impl GroupResult {
+    pub(crate) fn new(group_id: &str, results: Vec<AsyncResult>) -> Self {
+        Self {
+            group_id: group_id.into(),
+            results,
+        }
+    }
+
+    /// Group's ID
+    pub fn group_id(&self) -> String {
+        self.group_id.clone()
+    }
+
+    /// Results of the tasks of the group, in order
+    pub fn results(&self) -> &[AsyncResult] {
+        &self.results
+    }
+
+    /// Returns true if every task of the group is finished
+    pub async fn ready(&self) -> Result<bool, BackendError> {
+        for result in &self.results {
+            if !result.ready().await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Number of tasks of the group which succeeded
+    pub async fn completed_count(&self) -> Result<usize, BackendError> {
+        let mut count = 0;
+        for result in &self.results {
+            if result.successful().await? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Waits for every task of the group to finish and gets their results, in order.
+    ///
+    /// The tasks which failed or were revoked give their error instead of a result, without
+    /// affecting the results of the others.
+    pub async fn join<T: Send + Sync + Unpin + DeserializeOwned>(
+        &self,
+    ) -> Result<Vec<Result<T, TaskError>>, BackendError> {
+        let mut joined = Vec::with_capacity(self.results.len());
+        for result in &self.results {
+            result.wait_for_completion().await?;
+            let task_result = match result.state().await? {
+                TaskState::Success => Ok(result
+                    .result()
+                    .await?
+                    .ok_or_else(|| BackendError::DocumentNotFound(result.task_id()))?),
+                TaskState::Revoked => Err(TaskError::UnexpectedError("task revoked".into())),
+                TaskState::Ignored => Err(TaskError::UnexpectedError("task ignored".into())),
+                _ => Err(result
+                    .traceback()
+                    .await?
+                    .unwrap_or_else(|| TaskError::UnexpectedError("unknown error".into()))),
+            };
+            joined.push(task_result);
+        }
+        Ok(joined)
+    }
+}
@@ -4,19 +4,39 @@ use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use rand::distributions::{Distribution, Uniform};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::error::TaskError;
 
 mod async_result;
+mod batch;
+mod chord;
+mod context;
+mod group;
+mod group_result;
 mod options;
+mod rate_limit;
 mod request;
+mod retry_options;
+mod send_options;
 mod signature;
 
 pub use async_result::AsyncResult;
+pub use batch::{BatchFailurePolicy, BatchTask};
+pub use chord::{chord, Chord, ChordErrorPolicy, ChordResult};
+pub use context::TaskContext;
+pub use group::{group, Group};
+pub use group_result::GroupResult;
 pub use options::TaskOptions;
+pub use rate_limit::RateLimit;
+pub(crate) use rate_limit::RateLimiter;
 pub use request::Request;
+pub use retry_options::RetryOptions;
+pub(crate) use send_options::check_task_id;
+pub use send_options::SendOptions;
+pub(crate) use signature::ChainLink;
 pub use signature::Signature;
+pub use tokio_util::sync::CancellationToken;
 
 /// The return type for a task.
 pub type TaskResult<R> = Result<R, TaskError>;
@@ -52,14 +72,42 @@ pub trait Task: Send + Sync + std::marker::Sized {
     const DEFAULTS: TaskOptions = TaskOptions {
         time_limit: None,
         hard_time_limit: None,
+        soft_time_limit: None,
         max_retries: None,
         min_retry_delay: None,
         max_retry_delay: None,
+        retry_backoff: None,
+        retry_backoff_max: None,
+        retry_jitter: None,
         retry_for_unexpected: None,
         acks_late: None,
+        reject_on_worker_lost: None,
         content_type: None,
+        compression: None,
+        compression_threshold: None,
+        priority: None,
+        delivery_mode: None,
+        rate_limit: None,
+        dedup: None,
+        dedup_ttl: None,
+        ignore_result: None,
     };
 
+    /// The queue the task is sent to unless its signature sets another one, which takes
+    /// precedence over the task routes of the app. See the `queue` attribute of the
+    /// [`task`](../attr.task.html) macro.
+    const QUEUE: Option<&'static str> = None;
+
+    /// The exchange the task is published to and its routing key, unless its signature
+    /// sets a queue, which take precedence over the task routes of the app. See the
+    /// `exchange` and `routing_key` attributes of the [`task`](../attr.task.html) macro.
+    const EXCHANGE: Option<(&'static str, &'static str)> = None;
+
+    /// Whether the task runs on the blocking threads of the runtime, so that a synchronous
+    /// or CPU-bound body doesn't hold up the other tasks of the worker. See the `blocking`
+    /// attribute of the [`task`](../attr.task.html) macro.
+    const BLOCKING: bool = false;
+
     /// The parameters of the task.
     type Params: Clone + Send + Sync + Serialize + for<'de> Deserialize<'de>;
     type Returns: Send + Sync + Unpin + std::fmt::Debug + Serialize;
@@ -91,13 +139,23 @@ pub trait Task: Send + Sync + std::marker::Sized {
     /// This function defines how a task executes.
     async fn run(&self, params: Self::Params) -> TaskResult<Self::Returns>;
 
-    /// Callback that will run after a task fails.
+    /// Callback that will run alongside the task once it exceeds its
+    /// [soft time limit](TaskOptions::soft_time_limit), to let it clean up before it is
+    /// interrupted at its hard time limit.
+    async fn on_soft_timeout(&self) {}
+
+    /// Callback that will run after a task fails for good, i.e. when the error isn't
+    /// retried or the task ran out of retries.
+    #[allow(unused_variables)]
+    async fn on_failure(&self, ctx: &TaskContext<'_, Self>, err: &TaskError) {}
+
+    /// Callback that will run after a task fails with an error which is retried.
     #[allow(unused_variables)]
-    async fn on_failure(&self, err: &TaskError) {}
+    async fn on_retry(&self, ctx: &TaskContext<'_, Self>, err: &TaskError) {}
 
     /// Callback that will run after a task completes successfully.
     #[allow(unused_variables)]
-    async fn on_success(&self, returned: &Self::Returns) {}
+    async fn on_success(&self, ctx: &TaskContext<'_, Self>, returned: &Self::Returns) {}
 
     /// Returns the registered name of the task.
     fn name(&self) -> &'static str {
@@ -123,29 +181,53 @@ pub trait Task: Send + Sync + std::marker::Sized {
         Err(TaskError::Retry(Some(eta)))
     }
 
+    /// This can be called from within a task function to trigger a retry with
+    /// [`RetryOptions`], e.g. with new parameters or after a delay decided by the task.
+    ///
+    /// Like other retries, it fails for good once the task ran out of retries, which can
+    /// be overridden for this retry with [`RetryOptions::max_retries`].
+    fn retry(&self, options: RetryOptions<Self::Params>) -> TaskResult<Self::Returns> {
+        let eta = options.countdown.and_then(|countdown| {
+            Utc::now().checked_add_signed(chrono::Duration::from_std(countdown).ok()?)
+        });
+        self.request().set_retry_options(options);
+        Err(TaskError::Retry(eta))
+    }
+
+    /// This can be called from within a task function to give its message back, sending it
+    /// back to its queue with `requeue` (see [`TaskError::Reject`]).
+    fn reject(&self, requeue: bool) -> TaskResult<Self::Returns> {
+        Err(TaskError::Reject { requeue })
+    }
+
     /// Get a future ETA at which time the task should be retried. By default this
-    /// uses a capped exponential backoff strategy.
+    /// uses a capped exponential backoff strategy (see [`TaskOptions::retry_backoff`]).
     fn retry_eta(&self) -> Option<DateTime<Utc>> {
-        let retries = self.request().retries;
-        let delay_secs = std::cmp::min(
-            2u32.checked_pow(retries)
-                .unwrap_or_else(|| self.max_retry_delay()),
-            self.max_retry_delay(),
-        );
-        let delay_secs = std::cmp::max(delay_secs, self.min_retry_delay());
-        let between = Uniform::from(0..1000);
+        let mut options = Self::DEFAULTS;
+        options.update(self.options());
+        let delay = options.retry_delay(self.request().retries).as_millis() as u64;
         let mut rng = rand::thread_rng();
-        let delay_millis = between.sample(&mut rng);
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .ok()
-            .and_then(|now| {
-                let now_secs = now.as_secs() as u32;
-                let now_millis = now.subsec_millis();
-                let eta_secs = now_secs + delay_secs;
-                let eta_millis = now_millis + delay_millis;
-                DateTime::from_timestamp(eta_secs as i64, eta_millis * 1000)
-            })
+        let delay_millis = if options.retry_jitter.unwrap_or(false) {
+            let min_millis = u64::from(options.min_retry_delay.unwrap_or(0)) * 1000;
+            Uniform::new_inclusive(min_millis.min(delay), delay).sample(&mut rng)
+        } else {
+            delay + Uniform::from(0..1000).sample(&mut rng)
+        };
+        Some(Utc::now() + chrono::Duration::milliseconds(delay_millis as i64))
+    }
+
+    /// Whether the task should be retried after failing with `err`, as long as it has
+    /// retries left. By default every error is retried, except
+    /// [`UnexpectedError`](TaskError::UnexpectedError)s when
+    /// [`retry_for_unexpected`](TaskOptions::retry_for_unexpected) is `false`.
+    ///
+    /// Retries requested by the task itself, e.g. with
+    /// [`retry_with_countdown`](Task::retry_with_countdown), always happen.
+    fn should_retry(&self, err: &TaskError) -> bool {
+        match err {
+            TaskError::UnexpectedError(_) => self.retry_for_unexpected(),
+            _ => true,
+        }
     }
 
     fn retry_for_unexpected(&self) -> bool {
@@ -155,20 +237,23 @@ pub trait Task: Send + Sync + std::marker::Sized {
             .unwrap_or(true)
     }
 
+    /// The hard time limit of the task, after which it is interrupted.
     fn time_limit(&self) -> Option<u32> {
-        self.request().time_limit.or_else(|| {
-            // Take min or `time_limit` and `hard_time_limit`.
-            let time_limit = Self::DEFAULTS.time_limit.or(self.options().time_limit);
-            let hard_time_limit = Self::DEFAULTS
-                .hard_time_limit
-                .or(self.options().hard_time_limit);
-            match (time_limit, hard_time_limit) {
-                (Some(t1), Some(t2)) => Some(std::cmp::min(t1, t2)),
-                (Some(t1), None) => Some(t1),
-                (None, Some(t2)) => Some(t2),
-                _ => None,
-            }
-        })
+        self.request()
+            .time_limit
+            .or_else(|| request::split_time_limits(option_time_limits(self)).0)
+    }
+
+    /// The soft time limit of the task, after which it is signalled.
+    ///
+    /// The time limits of the request take precedence over the task options as a whole,
+    /// so that a soft time limit is always lower than the hard time limit it comes with.
+    fn soft_time_limit(&self) -> Option<u32> {
+        if self.request().time_limit.is_some() {
+            self.request().soft_time_limit
+        } else {
+            request::split_time_limits(option_time_limits(self)).1
+        }
     }
 
     fn max_retries(&self) -> Option<u32> {
@@ -190,11 +275,71 @@ pub trait Task: Send + Sync + std::marker::Sized {
     }
 
     fn acks_late(&self) -> bool {
-        Self::DEFAULTS
+        self.request()
             .acks_late
+            .or(Self::DEFAULTS.acks_late)
             .or(self.options().acks_late)
             .unwrap_or(false)
     }
+
+    fn reject_on_worker_lost(&self) -> bool {
+        Self::DEFAULTS
+            .reject_on_worker_lost
+            .or(self.options().reject_on_worker_lost)
+            .unwrap_or(false)
+    }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        Self::DEFAULTS.rate_limit.or(self.options().rate_limit)
+    }
+
+    /// How long the task is claimed for before it is executed, if it is
+    /// [deduplicated](TaskOptions::dedup).
+    fn dedup_ttl(&self) -> Option<Duration> {
+        let dedup = Self::DEFAULTS
+            .dedup
+            .or(self.options().dedup)
+            .unwrap_or(false);
+        dedup.then(|| {
+            Self::DEFAULTS
+                .dedup_ttl
+                .or(self.options().dedup_ttl)
+                .unwrap_or(Duration::from_secs(3600))
+        })
+    }
+
+    fn ignore_result(&self) -> bool {
+        self.request()
+            .ignore_result
+            .or(Self::DEFAULTS.ignore_result)
+            .or(self.options().ignore_result)
+            .unwrap_or(false)
+    }
+}
+
+/// Run the synchronous body of a task on the blocking threads of the runtime, like the
+/// tasks with the `blocking` attribute do.
+///
+/// A panic of the body is resumed in the calling task, so that the worker handles it like
+/// the panic of an async body. The time limit of the task still applies, but the body
+/// can't be interrupted: it runs to completion on its thread while the task times out.
+pub async fn run_blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(returned) => returned,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(err) => panic!("blocking task didn't run: {}", err),
+    }
+}
+
+/// The hard and soft time limits of a task from its options, respectively.
+fn option_time_limits<T: Task>(task: &T) -> (Option<u32>, Option<u32>) {
+    let mut options = T::DEFAULTS;
+    options.update(task.options());
+    options.timelimit()
 }
 
 #[derive(Clone, Debug)]
@@ -214,6 +359,11 @@ pub enum TaskState {
     Failure,
     /// The task executed successfully.
     Success,
+    /// The task was discarded without being executed, because it expired.
+    Revoked,
+    /// A duplicate delivery of the task was dropped without being executed, while the
+    /// task had no other state (see [`TaskOptions::dedup`]).
+    Ignored,
 }
 
 /// Extension methods for `Result` types within a task body.
@@ -1,4 +1,6 @@
-use crate::protocol::MessageContentType;
+use super::RateLimit;
+use crate::protocol::{Compression, DeliveryMode, MessageContentType};
+use std::time::Duration;
 
 /// Configuration options pertaining to a task.
 ///
@@ -30,6 +32,12 @@ pub struct TaskOptions {
     ///
     /// If this option is left unspecified, the default behavior will be to enforce no time limit.
     ///
+    /// A task which times out is handled like a task which failed with any other error: it
+    /// is retried if it [should be](crate::task::Task::should_retry) and has retries left,
+    /// otherwise the `TimeoutError` is stored in the result backend, passed to
+    /// [`Task::on_failure`](crate::task::Task::on_failure), and the message is acknowledged
+    /// or, with [`acks_late`](TaskOptions::acks_late), rejected.
+    ///
     /// *Note, however, that only non-blocking tasks can be interrupted, so it's important
     /// to use async functions within task implementations whenever they are available.*
     pub time_limit: Option<u32>,
@@ -39,9 +47,9 @@ pub struct TaskOptions {
     /// option when sending tasks to a Python consumer.
     /// If you desire to set a "hard time limit", use this option.
     ///
-    /// *Note that this is really only for compatability with Python workers*.
-    /// `time_limit` and `hard_time_limit` are treated the same by Rust workers, and if both
-    /// are set, the minimum of the two will be used.
+    /// Like Python workers, Rust workers treat a `time_limit` lower than the
+    /// `hard_time_limit` as a [soft time limit](TaskOptions::soft_time_limit). Otherwise the
+    /// lowest of the two is enforced as the hard time limit.
     ///
     /// This can be set with
     /// - [`task_hard_time_limit`](crate::CeleryBuilder::task_hard_time_limit) at the app level,
@@ -49,6 +57,26 @@ pub struct TaskOptions {
     /// - [`with_hard_time_limit`](crate::task::Signature::method.with_hard_time_limit) at the request / signature level.
     pub hard_time_limit: Option<u32>,
 
+    /// Soft time limit for a task.
+    ///
+    /// If set to `Some(n)`, the task is signalled once it has run for `n` seconds, but it is
+    /// only interrupted at its hard time limit, i.e. the lowest of
+    /// [`time_limit`](TaskOptions::time_limit) and
+    /// [`hard_time_limit`](TaskOptions::hard_time_limit). Until then the task can
+    /// clean up: it can poll
+    /// [`Request::is_soft_time_limit_exceeded`](crate::task::Request::is_soft_time_limit_exceeded),
+    /// and [`Task::on_soft_timeout`](crate::task::Task::on_soft_timeout) runs alongside it.
+    ///
+    /// This can be set with
+    /// - [`task_soft_time_limit`](crate::CeleryBuilder::task_soft_time_limit) at the app level,
+    /// - [`soft_time_limit`](../attr.task.html#parameters) at the task level, and
+    /// - [`with_soft_time_limit`](crate::task::Signature::with_soft_time_limit) at the request / signature level.
+    ///
+    /// Both limits are sent in the task messages, like Python Celery does. A soft time limit
+    /// which isn't lower than the hard time limit is ignored, and a soft time limit set
+    /// without any hard time limit is enforced as a hard time limit by Rust workers.
+    pub soft_time_limit: Option<u32>,
+
     /// Maximum number of retries for this task.
     ///
     /// This can be set with
@@ -80,6 +108,37 @@ pub struct TaskOptions {
     /// (3600 seconds) with some random jitter.
     pub max_retry_delay: Option<u32>,
 
+    /// Whether the delay between retries doubles with each retry, starting from 1 second.
+    /// Otherwise tasks are retried every [`min_retry_delay`](TaskOptions::min_retry_delay).
+    ///
+    /// This can be set with
+    /// - [`task_retry_backoff`](crate::CeleryBuilder::task_retry_backoff) at the app level, and
+    /// - [`retry_backoff`](../attr.task.html#parameters) at the task level.
+    ///
+    /// If this option is left unspecified, the default behavior will be to back off.
+    pub retry_backoff: Option<bool>,
+
+    /// The maximum delay between retries when backing off.
+    ///
+    /// This can be set with
+    /// - [`task_retry_backoff_max`](crate::CeleryBuilder::task_retry_backoff_max) at the app level, and
+    /// - [`retry_backoff_max`](../attr.task.html#parameters) at the task level, in seconds.
+    ///
+    /// If this option is left unspecified, the delays are capped at
+    /// [`max_retry_delay`](TaskOptions::max_retry_delay).
+    pub retry_backoff_max: Option<Duration>,
+
+    /// Whether the delay before a retry is picked at random between
+    /// [`min_retry_delay`](TaskOptions::min_retry_delay) and the backoff delay ("full jitter"),
+    /// so that tasks which failed together don't all retry together.
+    ///
+    /// This can be set with
+    /// - [`task_retry_jitter`](crate::CeleryBuilder::task_retry_jitter) at the app level, and
+    /// - [`retry_jitter`](../attr.task.html#parameters) at the task level.
+    ///
+    /// If this option is left unspecified, the delays only vary by less than a second.
+    pub retry_jitter: Option<bool>,
+
     /// Whether or not to retry the task when an [`UnexpectedError`](crate::error::TaskError::UnexpectedError)
     /// is returned.
     ///
@@ -98,12 +157,39 @@ pub struct TaskOptions {
     /// then it is recommended that you set this to `true`.
     ///
     /// This can be set with
-    /// - [`acks_late`](crate::CeleryBuilder::acks_late) at the app level, and
-    /// - [`acks_late`](../attr.task.html#parameters) at the task level.
+    /// - [`acks_late`](crate::CeleryBuilder::acks_late) at the app level,
+    /// - [`acks_late`](../attr.task.html#parameters) at the task level, and
+    /// - [`with_acks_late`](crate::task::Signature::with_acks_late) at the request / signature level.
     ///
     /// If this option is left unspecified, the default behavior will be to ack early.
+    ///
+    /// Messages of tasks acknowledged late are rejected instead once the task has exhausted
+    /// its retries, so they can be parked in a
+    /// [dead-letter queue](crate::broker::QueueOptions::with_dead_letter_queue).
+    /// Messages acknowledged early have already been removed from their queue by then, so
+    /// the worker sends them to the dead-letter queue itself.
     pub acks_late: Option<bool>,
 
+    /// Whether the messages of tasks [acknowledged late](TaskOptions::acks_late) are
+    /// requeued when the worker is lost while executing them, i.e. when the task panics.
+    ///
+    /// The requeued messages can then be executed again by another worker. To avoid
+    /// redelivering a poison message forever, a message which was already requeued
+    /// [`worker_max_redeliveries`](crate::CeleryBuilder::worker_max_redeliveries) times is
+    /// rejected instead, so that it can be parked in a
+    /// [dead-letter queue](crate::broker::QueueOptions::with_dead_letter_queue).
+    ///
+    /// Otherwise, like messages acknowledged early, the message is acknowledged and the task
+    /// is marked as failed.
+    ///
+    /// This can be set with
+    /// - [`task_reject_on_worker_lost`](crate::CeleryBuilder::task_reject_on_worker_lost) at the app level, and
+    /// - [`reject_on_worker_lost`](../attr.task.html#parameters) at the task level.
+    ///
+    /// If this option is left unspecified, the default behavior will be to not requeue the
+    /// messages.
+    pub reject_on_worker_lost: Option<bool>,
+
     /// Which serialization format to use for task messages.
     ///
     /// This can be set with
@@ -111,6 +197,125 @@ pub struct TaskOptions {
     /// - [`content_type`](../attr.task.html#parameters) at the task level.
     /// - [`with_content_type`](crate::task::Signature::with_content_type) at the request / signature level.
     pub content_type: Option<MessageContentType>,
+
+    /// The algorithm to compress the bodies of the task messages with.
+    ///
+    /// This can be set with
+    /// - [`task_compression`](crate::CeleryBuilder::task_compression) at the app level,
+    /// - [`compression`](../attr.task.html#parameters) at the task level, and
+    /// - [`with_compression`](crate::task::Signature::with_compression) at the request / signature level.
+    ///
+    /// If this option is left unspecified, the bodies aren't compressed.
+    pub compression: Option<Compression>,
+
+    /// The size (in bytes) below which message bodies aren't compressed, since compressing
+    /// small bodies costs more than it saves.
+    ///
+    /// This can be set with
+    /// - [`task_compression_threshold`](crate::CeleryBuilder::task_compression_threshold) at the app level.
+    ///
+    /// If this option is left unspecified, every body is compressed.
+    pub compression_threshold: Option<usize>,
+
+    /// The priority of the task messages, from 0 to 255.
+    ///
+    /// With the AMQP broker, messages with a higher priority are delivered first, but only
+    /// from queues declared with a maximum priority through
+    /// [`queue_max_priority`](crate::CeleryBuilder::queue_max_priority). Sending a task
+    /// to such a queue with a priority above its maximum fails with
+    /// [`InvalidPriority`](crate::error::CeleryError::InvalidPriority), instead of the
+    /// broker treating it as the maximum.
+    ///
+    /// The Redis broker emulates priorities the same way as Python Celery: messages are
+    /// split into one list per priority step (0, 3, 6 and 9), and *lower* values are
+    /// delivered first, so 0 is the highest priority there.
+    ///
+    /// This can be set with
+    /// - [`task_default_priority`](crate::CeleryBuilder::task_default_priority) at the app level,
+    /// - [`priority`](../attr.task.html#parameters) at the task level, and
+    /// - [`with_priority`](crate::task::Signature::with_priority) at the request / signature level.
+    ///
+    /// If this option is left unspecified, messages are sent without a priority.
+    pub priority: Option<u8>,
+
+    /// Whether the task messages are persisted by the broker.
+    ///
+    /// [`Transient`](DeliveryMode::Transient) messages are faster to publish, but they are
+    /// lost if the broker restarts, so they suit tasks which are cheap to lose, like
+    /// telemetry. This maps to the `delivery_mode` property of AMQP messages, and has no
+    /// effect with the other brokers.
+    ///
+    /// This can be set with
+    /// - [`task_default_delivery_mode`](crate::CeleryBuilder::task_default_delivery_mode) at the app level,
+    /// - [`delivery_mode`](../attr.task.html#parameters) at the task level, and
+    /// - [`with_delivery_mode`](crate::task::Signature::with_delivery_mode) at the request / signature level.
+    ///
+    /// If this option is left unspecified, messages are persistent.
+    pub delivery_mode: Option<DeliveryMode>,
+
+    /// The maximum rate at which each worker starts executing the task.
+    ///
+    /// Executions beyond the rate are delayed by the worker, and their messages are only
+    /// acknowledged once they start, so they keep counting towards the
+    /// [`prefetch_count`](crate::CeleryBuilder::prefetch_count). A worker consuming a backlog
+    /// of rate limited tasks therefore fetches other tasks more slowly as well. The rate
+    /// limit of a task can also be changed at runtime with
+    /// [`control_rate_limit`](crate::Celery::control_rate_limit).
+    ///
+    /// This can be set with
+    /// - [`task_rate_limit`](crate::CeleryBuilder::task_rate_limit) at the app level, and
+    /// - [`rate_limit`](../attr.task.html#parameters) at the task level.
+    ///
+    /// If this option is left unspecified, executions aren't rate limited.
+    pub rate_limit: Option<RateLimit>,
+
+    /// Whether workers drop the duplicate deliveries of the task, for tasks which aren't
+    /// idempotent. Brokers deliver messages at least once, and publishes which are retried
+    /// can send the same message twice.
+    ///
+    /// Before executing the task, the worker atomically claims it in the result backend for
+    /// [`dedup_ttl`](TaskOptions::dedup_ttl). When the task was already claimed, the message
+    /// is acknowledged and dropped, and the task is marked as
+    /// [`Ignored`](crate::task::TaskState::Ignored) unless it already has a state. Each
+    /// retry of the task is claimed on its own, and the claim of a task which the worker
+    /// requeues is released. If the worker dies while executing the task, the claim
+    /// expires after its TTL so that the task runs when it is delivered again, while the
+    /// deliveries before that are still dropped.
+    ///
+    /// This requires a result backend, without which tasks aren't deduplicated. This can
+    /// be set with
+    /// - [`task_dedup`](crate::CeleryBuilder::task_dedup) at the app level, and
+    /// - [`dedup`](../attr.task.html#parameters) at the task level.
+    ///
+    /// If this option is left unspecified, tasks aren't deduplicated.
+    pub dedup: Option<bool>,
+
+    /// How long the claim of a [deduplicated](TaskOptions::dedup) task lasts. It should be
+    /// longer than the task runs, and than the time it may take for a duplicate to be
+    /// delivered.
+    ///
+    /// This can be set with
+    /// - [`task_dedup_ttl`](crate::CeleryBuilder::task_dedup_ttl) at the app level, and
+    /// - [`dedup_ttl`](../attr.task.html#parameters) at the task level, in seconds.
+    ///
+    /// If this option is left unspecified, claims last for 1 hour.
+    pub dedup_ttl: Option<Duration>,
+
+    /// Whether the result of the task isn't stored, for tasks whose results aren't used.
+    /// Workers then don't write the states of the task to the result backend, and the
+    /// [`AsyncResult`](crate::task::AsyncResult) of the task returns
+    /// [`BackendError::ResultIgnored`](crate::error::BackendError::ResultIgnored) instead of
+    /// querying the backend. The option is sent in the `ignore_result` header of the
+    /// message, like Python Celery does.
+    ///
+    /// The tasks of a chord need their results to be stored, since they are passed to its
+    /// callback. This can be set with
+    /// - [`task_ignore_result`](crate::CeleryBuilder::task_ignore_result) at the app level,
+    /// - [`ignore_result`](../attr.task.html#parameters) at the task level, and
+    /// - [`with_ignore_result`](crate::task::Signature::with_ignore_result) at the request / signature level.
+    ///
+    /// If this option is left unspecified, results are stored.
+    pub ignore_result: Option<bool>,
 }
 
 impl TaskOptions {
@@ -118,12 +323,60 @@ impl TaskOptions {
     pub(crate) fn update(&mut self, other: &TaskOptions) {
         self.time_limit = self.time_limit.or(other.time_limit);
         self.hard_time_limit = self.hard_time_limit.or(other.hard_time_limit);
+        self.soft_time_limit = self.soft_time_limit.or(other.soft_time_limit);
         self.max_retries = self.max_retries.or(other.max_retries);
         self.min_retry_delay = self.min_retry_delay.or(other.min_retry_delay);
         self.max_retry_delay = self.max_retry_delay.or(other.max_retry_delay);
+        self.retry_backoff = self.retry_backoff.or(other.retry_backoff);
+        self.retry_backoff_max = self.retry_backoff_max.or(other.retry_backoff_max);
+        self.retry_jitter = self.retry_jitter.or(other.retry_jitter);
         self.retry_for_unexpected = self.retry_for_unexpected.or(other.retry_for_unexpected);
         self.acks_late = self.acks_late.or(other.acks_late);
+        self.reject_on_worker_lost = self.reject_on_worker_lost.or(other.reject_on_worker_lost);
         self.content_type = self.content_type.or(other.content_type);
+        self.compression = self.compression.or(other.compression);
+        self.compression_threshold = self.compression_threshold.or(other.compression_threshold);
+        self.priority = self.priority.or(other.priority);
+        self.delivery_mode = self.delivery_mode.or(other.delivery_mode);
+        self.rate_limit = self.rate_limit.or(other.rate_limit);
+        self.dedup = self.dedup.or(other.dedup);
+        self.dedup_ttl = self.dedup_ttl.or(other.dedup_ttl);
+        self.ignore_result = self.ignore_result.or(other.ignore_result);
+    }
+
+    /// Get the hard and soft time limits, respectively, to send in a task message.
+    ///
+    /// Without a soft time limit, `time_limit` is sent as the soft time limit for
+    /// compatibility, which Rust workers still enforce as a hard time limit when it is
+    /// the only one.
+    pub(crate) fn timelimit(&self) -> (Option<u32>, Option<u32>) {
+        match self.soft_time_limit {
+            Some(soft_time_limit) => {
+                let hard_time_limit = self
+                    .time_limit
+                    .into_iter()
+                    .chain(self.hard_time_limit)
+                    .min();
+                (hard_time_limit, Some(soft_time_limit))
+            }
+            None => (self.hard_time_limit, self.time_limit),
+        }
+    }
+
+    /// Get the delay before retrying a task which was already retried `retries` times,
+    /// before any jitter.
+    pub(crate) fn retry_delay(&self, retries: u32) -> Duration {
+        let min = Duration::from_secs(self.min_retry_delay.unwrap_or(0).into());
+        if !self.retry_backoff.unwrap_or(true) {
+            return min;
+        }
+        let max = self
+            .retry_backoff_max
+            .unwrap_or_else(|| Duration::from_secs(self.max_retry_delay.unwrap_or(3600).into()));
+        let delay = 2u64
+            .checked_pow(retries)
+            .map_or(max, |secs| Duration::from_secs(secs).min(max));
+        delay.max(min)
     }
 
     /// Override the fields in `other` with the fields in `self`.
@@ -155,4 +408,52 @@ mod tests {
         assert_eq!(options.max_retries, Some(3));
         assert_eq!(options.acks_late, Some(true));
     }
+
+    #[test]
+    fn test_timelimit() {
+        let options = TaskOptions {
+            time_limit: Some(10),
+            ..Default::default()
+        };
+        assert_eq!((None, Some(10)), options.timelimit());
+
+        let options = TaskOptions {
+            time_limit: Some(10),
+            hard_time_limit: Some(20),
+            soft_time_limit: Some(5),
+            ..Default::default()
+        };
+        assert_eq!((Some(10), Some(5)), options.timelimit());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let options = TaskOptions {
+            retry_backoff_max: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let delays: Vec<_> = [0, 1, 2, 3, 4, 100]
+            .iter()
+            .map(|&retries| options.retry_delay(retries).as_secs())
+            .collect();
+        assert_eq!(vec![1, 2, 4, 8, 10, 10], delays);
+
+        // The cap falls back to the maximum retry delay, and the minimum still applies.
+        let options = TaskOptions {
+            min_retry_delay: Some(3),
+            max_retry_delay: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(Duration::from_secs(3), options.retry_delay(0));
+        assert_eq!(Duration::from_secs(16), options.retry_delay(4));
+        assert_eq!(Duration::from_secs(20), options.retry_delay(5));
+
+        let options = TaskOptions {
+            min_retry_delay: Some(5),
+            retry_backoff: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(Duration::from_secs(5), options.retry_delay(0));
+        assert_eq!(Duration::from_secs(5), options.retry_delay(6));
+    }
 }
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::ParseRateLimitError;
+
+/// The maximum rate at which a worker starts executing a task, e.g. 10 per second.
+///
+/// Rate limits can be parsed from the same strings as in Python Celery, i.e. `"10/s"`,
+/// `"100/m"` or `"1000/h"`, where a number alone is per second.
+///
+/// ```rust
+/// # use celery::task::RateLimit;
+/// let rate_limit: RateLimit = "100/m".parse().unwrap();
+/// assert_eq!(RateLimit::per_minute(100), rate_limit);
+/// assert_eq!("100/m", rate_limit.to_string());
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    tasks: u32,
+    per_secs: u32,
+}
+
+impl RateLimit {
+    /// At most `tasks` executions per second.
+    pub const fn per_second(tasks: u32) -> Self {
+        Self { tasks, per_secs: 1 }
+    }
+
+    /// At most `tasks` executions per minute.
+    pub const fn per_minute(tasks: u32) -> Self {
+        Self {
+            tasks,
+            per_secs: 60,
+        }
+    }
+
+    /// At most `tasks` executions per hour.
+    pub const fn per_hour(tasks: u32) -> Self {
+        Self {
+            tasks,
+            per_secs: 3600,
+        }
+    }
+
+    /// The number of executions per second.
+    fn rate(&self) -> f64 {
+        self.tasks as f64 / self.per_secs as f64
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = ParseRateLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseRateLimitError(s.into());
+        let (tasks, unit) = match s.trim().split_once('/') {
+            Some((tasks, unit)) => (tasks, unit),
+            None => (s.trim(), "s"),
+        };
+        let tasks: u32 = tasks.parse().map_err(|_| error())?;
+        if tasks == 0 {
+            return Err(error());
+        }
+        match unit {
+            "s" => Ok(Self::per_second(tasks)),
+            "m" => Ok(Self::per_minute(tasks)),
+            "h" => Ok(Self::per_hour(tasks)),
+            _ => Err(error()),
+        }
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.per_secs {
+            60 => "m",
+            3600 => "h",
+            _ => "s",
+        };
+        write!(f, "{}/{}", self.tasks, unit)
+    }
+}
+
+/// A token bucket holding a single token, like Python Celery's, so that the executions
+/// are spread evenly instead of in bursts.
+///
+/// Tokens are reserved ahead of time, so that concurrent executions wait in turn.
+struct TokenBucket {
+    rate_limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate_limit: RateLimit) -> Self {
+        Self {
+            rate_limit,
+            tokens: 1.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token, returning how long to wait until it is available.
+    fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_limit.rate()).min(1.0);
+        self.last_refill = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate_limit.rate())
+        }
+    }
+}
+
+/// The token buckets of the rate limited tasks by name, along with the rate limits set
+/// at runtime.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    overrides: Mutex<HashMap<String, Option<RateLimit>>>,
+}
+
+impl RateLimiter {
+    /// Override the rate limit of a task, `None` disabling it.
+    pub(crate) fn set_rate_limit(&self, task_name: &str, rate_limit: Option<RateLimit>) {
+        self.overrides
+            .lock()
+            .unwrap()
+            .insert(task_name.into(), rate_limit);
+    }
+
+    /// Take a token for an execution of a task, returning how long to wait before
+    /// starting it. `rate_limit` is the one of the task, unless it was overridden.
+    pub(crate) fn reserve(&self, task_name: &str, rate_limit: Option<RateLimit>) -> Duration {
+        let rate_limit = match self.overrides.lock().unwrap().get(task_name) {
+            Some(rate_limit) => *rate_limit,
+            None => rate_limit,
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let rate_limit = match rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => {
+                buckets.remove(task_name);
+                return Duration::from_secs(0);
+            }
+        };
+        let bucket = buckets
+            .entry(task_name.into())
+            .or_insert_with(|| TokenBucket::new(rate_limit));
+        if bucket.rate_limit != rate_limit {
+            *bucket = TokenBucket::new(rate_limit);
+        }
+        bucket.reserve()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!(Ok(RateLimit::per_second(10)), "10/s".parse());
+        assert_eq!(Ok(RateLimit::per_second(10)), "10".parse());
+        assert_eq!(Ok(RateLimit::per_minute(100)), "100/m".parse());
+        assert_eq!(Ok(RateLimit::per_hour(1000)), "1000/h".parse());
+        assert!("0/s".parse::<RateLimit>().is_err());
+        assert!("10/d".parse::<RateLimit>().is_err());
+        assert!("ten/s".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::default();
+        let rate_limit = Some(RateLimit::per_second(10));
+        assert_eq!(Duration::from_secs(0), limiter.reserve("task", rate_limit));
+        let delay = limiter.reserve("task", rate_limit);
+        assert!(delay > Duration::from_millis(90) && delay <= Duration::from_millis(100));
+        // The reservations queue up.
+        assert!(limiter.reserve("task", rate_limit) > Duration::from_millis(190));
+        // The buckets are per task.
+        assert_eq!(Duration::from_secs(0), limiter.reserve("other", rate_limit));
+
+        limiter.set_rate_limit("task", None);
+        assert_eq!(Duration::from_secs(0), limiter.reserve("task", rate_limit));
+        assert_eq!(Duration::from_secs(0), limiter.reserve("task", rate_limit));
+    }
+}
@@ -1,10 +1,18 @@
-use super::Task;
+use super::{RetryOptions, Task};
 use crate::error::ProtocolError;
-use crate::protocol::Message;
+use crate::protocol::{Message, SerializedSignature};
 use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// The number of milliseconds a request is still executed after its expiration time.
+pub(crate) const EXPIRES_GRACE_PERIOD: i64 = 1000;
 
 /// A [`Request`] contains information and state related to the currently executing task.
 #[derive(Clone)]
@@ -18,8 +26,8 @@ where
     /// The unique ID of the task's group, if this task is a member.
     pub group: Option<String>,
 
-    /// The unique ID of the chord this task belongs to (if the task is part of the header).
-    pub chord: Option<String>,
+    /// The callback of the chord this task belongs to (if the task is part of the header).
+    pub chord: Option<SerializedSignature>,
 
     /// Custom ID used for things like de-duplication. Usually the same as `id`.
     pub correlation_id: String,
@@ -30,10 +38,16 @@ where
     /// Name of the host that sent this task.
     pub origin: Option<String>,
 
-    /// How many times the current task has been retried.
+    /// The name the task is logged and reported under instead of its own, if it was sent
+    /// with one (see [`Signature::with_shadow`](crate::task::Signature::with_shadow)).
+    pub shadow: Option<String>,
+
+    /// How many times the current task has been retried, so 0 for its first execution.
+    /// Workers increment it when they send the task back to be retried.
     pub retries: u32,
 
-    /// The original ETA of the task.
+    /// The ETA of the task, which is the time it was retried at when it was retried with a
+    /// delay.
     pub eta: Option<DateTime<Utc>>,
 
     /// The original expiration time of the task.
@@ -42,11 +56,77 @@ where
     /// Node name of the worker instance executing the task.
     pub hostname: Option<String>,
 
+    /// The queue the task was consumed from.
+    pub queue: Option<String>,
+
     /// Where to send reply to (queue name).
     pub reply_to: Option<String>,
 
     /// The time limit (in seconds) allocated for this task to execute.
     pub time_limit: Option<u32>,
+
+    /// The time (in seconds) after which the task is signalled to wrap up, lower than
+    /// [`time_limit`](Request::time_limit).
+    pub soft_time_limit: Option<u32>,
+
+    soft_time_limit_exceeded: Arc<AtomicBool>,
+
+    /// Cancelled when the task is aborted.
+    abort_token: CancellationToken,
+
+    /// The options the task retried itself with, if it did (see
+    /// [`Task::retry`](super::Task::retry)).
+    retry_options: Arc<Mutex<Option<RetryOptions<T::Params>>>>,
+
+    /// Whether the message is acknowledged after the task has been executed, if it was
+    /// set when sending the task.
+    pub acks_late: Option<bool>,
+
+    /// Whether the result of the task isn't stored, if it was set when sending the task.
+    pub ignore_result: Option<bool>,
+
+    /// How many times the message was requeued because the worker executing the task was
+    /// lost.
+    pub redeliveries: u32,
+
+    /// The remaining tasks of the chain this task belongs to, in reverse order: the next
+    /// task is the last one.
+    pub chain: Vec<SerializedSignature>,
+
+    /// The tasks to send with the result of the task when it succeeds.
+    pub callbacks: Vec<SerializedSignature>,
+
+    /// The tasks to send with the ID and the error of the task when it fails.
+    pub errbacks: Vec<SerializedSignature>,
+
+    /// The custom headers the task was sent with (see
+    /// [`Signature::with_headers`](super::Signature::with_headers)).
+    pub headers: HashMap<String, Value>,
+
+    /// Whether the task is executed eagerly, i.e. in the process which sent it, when
+    /// [`task_always_eager`](crate::CeleryBuilder::task_always_eager) is set. Eagerly
+    /// executed tasks are not retried.
+    pub is_eager: bool,
+}
+
+/// Split the time limits of a task message, i.e. the hard and soft time limits
+/// respectively, into the hard and soft time limits enforced by the worker.
+///
+/// Like Python workers, a soft time limit lower than the hard time limit only signals the
+/// task. Otherwise the lowest time limit is the hard time limit, since a single time limit
+/// is sent as the soft time limit for compatibility.
+pub(crate) fn split_time_limits(
+    timelimit: (Option<u32>, Option<u32>),
+) -> (Option<u32>, Option<u32>) {
+    match timelimit {
+        (Some(hard_timelimit), Some(soft_timelimit)) if soft_timelimit < hard_timelimit => {
+            (Some(hard_timelimit), Some(soft_timelimit))
+        }
+        (Some(hard_timelimit), Some(soft_timelimit)) => {
+            (Some(std::cmp::min(soft_timelimit, hard_timelimit)), None)
+        }
+        (hard_timelimit, soft_timelimit) => (hard_timelimit.or(soft_timelimit), None),
+    }
 }
 
 impl<T> Request<T>
@@ -54,14 +134,7 @@ where
     T: Task,
 {
     pub fn new(m: Message, p: T::Params) -> Self {
-        let time_limit = match m.headers.timelimit {
-            (Some(soft_timelimit), Some(hard_timelimit)) => {
-                Some(std::cmp::min(soft_timelimit, hard_timelimit))
-            }
-            (Some(soft_timelimit), None) => Some(soft_timelimit),
-            (None, Some(hard_timelimit)) => Some(hard_timelimit),
-            _ => None,
-        };
+        let (time_limit, soft_time_limit) = split_time_limits(m.headers.timelimit);
         Self {
             id: m.headers.id,
             group: m.headers.group,
@@ -69,15 +142,93 @@ where
             correlation_id: m.properties.correlation_id,
             params: p,
             origin: m.headers.origin,
+            shadow: m.headers.shadow,
             retries: m.headers.retries.unwrap_or(0),
             eta: m.headers.eta,
             expires: m.headers.expires,
             hostname: None,
+            queue: None,
             reply_to: m.properties.reply_to,
             time_limit,
+            soft_time_limit,
+            soft_time_limit_exceeded: Arc::new(AtomicBool::new(false)),
+            abort_token: CancellationToken::new(),
+            retry_options: Arc::new(Mutex::new(None)),
+            acks_late: m.headers.acks_late,
+            ignore_result: m.headers.ignore_result,
+            redeliveries: m.headers.redeliveries.unwrap_or(0),
+            chain: vec![],
+            callbacks: vec![],
+            errbacks: vec![],
+            headers: m.headers.extra,
+            is_eager: false,
         }
     }
 
+    /// Check if the task has exceeded its [soft time
+    /// limit](crate::task::TaskOptions::soft_time_limit), in which case it should wrap up
+    /// before it is interrupted.
+    pub fn is_soft_time_limit_exceeded(&self) -> bool {
+        self.soft_time_limit_exceeded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_soft_time_limit_exceeded(&self) {
+        self.soft_time_limit_exceeded.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_retry_options(&self, options: RetryOptions<T::Params>) {
+        *self.retry_options.lock().unwrap() = Some(options);
+    }
+
+    pub(crate) fn take_retry_options(&self) -> Option<RetryOptions<T::Params>> {
+        self.retry_options.lock().unwrap().take()
+    }
+
+    /// Get the token cancelled when the task is aborted while it runs, i.e. when it is
+    /// revoked with [`Celery::control_terminate`](crate::Celery::control_terminate). A
+    /// long-running task can stop early by awaiting
+    /// [`cancelled`](CancellationToken::cancelled) alongside its work, in which case it
+    /// is recorded as revoked whatever it returns. Tasks which don't stop are terminated
+    /// after the [grace period](crate::CeleryBuilder::worker_abort_grace_period) of the
+    /// worker.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use celery::prelude::*;
+    /// use tokio::time::{self, Duration};
+    ///
+    /// #[celery::task(bind = true)]
+    /// async fn export(task: &Self, pages: u32) -> TaskResult<u32> {
+    ///     for page in 0..pages {
+    ///         tokio::select! {
+    ///             _ = task.request().abort_token().cancelled() => return Ok(page),
+    ///             _ = time::sleep(Duration::from_secs(1)) => (),
+    ///         }
+    ///     }
+    ///     Ok(pages)
+    /// }
+    /// ```
+    pub fn abort_token(&self) -> &CancellationToken {
+        &self.abort_token
+    }
+
+    /// Check if the task was aborted while it runs (see [`abort_token`](Request::abort_token)).
+    pub fn is_aborted(&self) -> bool {
+        self.abort_token.is_cancelled()
+    }
+
+    /// Get the name the task is logged and reported under, which is its shadow name if it
+    /// has one.
+    pub fn display_name(&self) -> &str {
+        self.shadow.as_deref().unwrap_or(T::NAME)
+    }
+
+    /// Check if this execution of the task is a retry, as opposed to its first execution.
+    pub fn is_retry(&self) -> bool {
+        self.retries > 0
+    }
+
     /// Check if the request has a future ETA.
     pub fn is_delayed(&self) -> bool {
         self.eta.is_some()
@@ -98,11 +249,13 @@ where
         }
     }
 
-    /// Check if the request is expired.
+    /// Check if the request is expired. Requests are only considered expired a second
+    /// after their expiration time, to tolerate a small clock skew between the producer
+    /// and the worker.
     pub fn is_expired(&self) -> bool {
         if let Some(expires) = self.expires {
             let now = DateTime::<Utc>::from(SystemTime::now());
-            (now - expires).num_milliseconds() >= 0
+            (now - expires).num_milliseconds() >= EXPIRES_GRACE_PERIOD
         } else {
             false
         }
@@ -117,7 +270,12 @@ where
 
     fn try_from(m: Message) -> Result<Self, Self::Error> {
         let body = m.body::<T>()?;
-        let (task_params, _) = body.parts();
-        Ok(Self::new(m, task_params))
+        let (task_params, embed) = body.parts();
+        let mut request = Self::new(m, task_params);
+        request.chain = embed.chain.unwrap_or_default();
+        request.chord = embed.chord;
+        request.callbacks = embed.callbacks.unwrap_or_default();
+        request.errbacks = embed.errbacks.unwrap_or_default();
+        Ok(request)
     }
 }
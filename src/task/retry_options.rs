@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+/// How a task retries itself with [`Task::retry`](super::Task::retry), e.g. with a
+/// refreshed token or after the delay an upstream service asked for.
+///
+/// # Examples
+///
+/// ```rust
+/// use celery::prelude::*;
+/// use celery::task::RetryOptions;
+/// use std::time::Duration;
+///
+/// #[celery::task(bind = true)]
+/// fn fetch(task: &Self, token: String) -> TaskResult<()> {
+///     if token.is_empty() {
+///         let mut params = task.request().params.clone();
+///         params.token = "refreshed".into();
+///         return task.retry(
+///             RetryOptions::new()
+///                 .with_countdown(Duration::from_secs(120))
+///                 .with_params(params),
+///         );
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct RetryOptions<P> {
+    /// How long to wait before the retry, instead of the retry delay of the task.
+    pub countdown: Option<Duration>,
+
+    /// The parameters to retry the task with, instead of the ones of this execution.
+    pub params: Option<P>,
+
+    /// The maximum number of retries checked for this retry, instead of
+    /// [`TaskOptions::max_retries`](super::TaskOptions::max_retries).
+    pub max_retries: Option<u32>,
+}
+
+impl<P> RetryOptions<P> {
+    /// Create new `RetryOptions`, with which the task is retried with the same parameters
+    /// after its usual retry delay.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how long to wait before the retry.
+    pub fn with_countdown(mut self, countdown: Duration) -> Self {
+        self.countdown = Some(countdown);
+        self
+    }
+
+    /// Set the parameters to retry the task with.
+    pub fn with_params(mut self, params: P) -> Self {
+        self.params = Some(params);
+        self
+    }
+
+    /// Set the maximum number of retries checked for this retry.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+}
+
+impl<P> Default for RetryOptions<P> {
+    fn default() -> Self {
+        Self {
+            countdown: None,
+            params: None,
+            max_retries: None,
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use super::TaskOptions;
+use crate::error::ProtocolError;
+use crate::protocol::MessageContentType;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The execution options of a task sent by name, with
+/// [`Celery::send_task_by_name`](crate::Celery::send_task_by_name), when its type isn't
+/// known. These are the options of a [`Signature`](super::Signature), which they follow.
+///
+/// # Examples
+///
+/// ```rust
+/// use celery::task::SendOptions;
+/// use std::time::Duration;
+///
+/// let options = SendOptions::new()
+///     .with_queue("reports")
+///     .with_countdown(Duration::from_secs(10));
+/// ```
+#[derive(Clone, Default)]
+pub struct SendOptions {
+    pub(crate) queue: Option<String>,
+    pub(crate) countdown: Option<Duration>,
+    pub(crate) eta: Option<DateTime<Utc>>,
+    pub(crate) expires_in: Option<Duration>,
+    pub(crate) expires: Option<DateTime<Utc>>,
+    pub(crate) task_id: Option<String>,
+    pub(crate) shadow: Option<String>,
+    pub(crate) options: TaskOptions,
+    pub(crate) headers: HashMap<String, Value>,
+}
+
+impl SendOptions {
+    /// Create new `SendOptions`, with which the task is routed by its name and executed
+    /// right away.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the queue.
+    pub fn with_queue(mut self, queue: &str) -> Self {
+        self.queue = Some(queue.into());
+        self
+    }
+
+    /// Set how long to wait before executing the task. It can't be combined with an
+    /// [ETA](SendOptions::with_eta).
+    pub fn with_countdown(mut self, countdown: Duration) -> Self {
+        self.countdown = Some(countdown);
+        self
+    }
+
+    /// Set the time at which to execute the task.
+    pub fn with_eta(mut self, eta: DateTime<Utc>) -> Self {
+        self.eta = Some(eta);
+        self
+    }
+
+    /// Set how long until the task expires. It can't be combined with an
+    /// [expiration time](SendOptions::with_expires).
+    pub fn with_expires_in(mut self, expires_in: Duration) -> Self {
+        self.expires_in = Some(expires_in);
+        self
+    }
+
+    /// Set the time after which the task should no longer be executed.
+    pub fn with_expires(mut self, expires: DateTime<Utc>) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Set the ID of the task instead of a random UUID (see
+    /// [`Signature::with_task_id`](super::Signature::with_task_id)).
+    pub fn with_task_id(mut self, task_id: &str) -> Self {
+        self.task_id = Some(task_id.into());
+        self
+    }
+
+    /// Set the name the task is logged and reported under (see
+    /// [`Signature::with_shadow`](super::Signature::with_shadow)).
+    pub fn with_shadow(mut self, shadow: &str) -> Self {
+        self.shadow = Some(shadow.into());
+        self
+    }
+
+    /// Add custom headers to the task message (see
+    /// [`Signature::with_headers`](super::Signature::with_headers)).
+    pub fn with_headers(mut self, headers: HashMap<String, Value>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Set the priority of the task message (see [`TaskOptions::priority`]).
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.options.priority = Some(priority);
+        self
+    }
+
+    /// Set a time limit (in seconds) for the task.
+    pub fn with_time_limit(mut self, time_limit: u32) -> Self {
+        self.options.time_limit = Some(time_limit);
+        self
+    }
+
+    /// Set a hard time limit (in seconds) for the task (see
+    /// [`TaskOptions::hard_time_limit`]).
+    pub fn with_hard_time_limit(mut self, time_limit: u32) -> Self {
+        self.options.hard_time_limit = Some(time_limit);
+        self
+    }
+
+    /// Set a soft time limit (in seconds) for the task (see
+    /// [`TaskOptions::soft_time_limit`]).
+    pub fn with_soft_time_limit(mut self, soft_time_limit: u32) -> Self {
+        self.options.soft_time_limit = Some(soft_time_limit);
+        self
+    }
+
+    /// Set whether the result of the task isn't stored (see
+    /// [`TaskOptions::ignore_result`]).
+    pub fn with_ignore_result(mut self, ignore_result: bool) -> Self {
+        self.options.ignore_result = Some(ignore_result);
+        self
+    }
+
+    /// Set the format the task message body is serialized with (see
+    /// [`TaskOptions::content_type`]).
+    pub fn with_content_type(mut self, content_type: MessageContentType) -> Self {
+        self.options.content_type = Some(content_type);
+        self
+    }
+
+    /// Serialize the options like the options of a Python signature. Relative times are
+    /// kept as numbers of seconds, so that they are relative to when the message is
+    /// created.
+    pub(crate) fn serialize(&self) -> Result<Map<String, Value>, ProtocolError> {
+        let mut options = Map::new();
+        if let Some(task_id) = &self.task_id {
+            check_task_id(task_id)?;
+            options.insert("task_id".into(), json!(task_id));
+        }
+        if let Some(queue) = &self.queue {
+            options.insert("queue".into(), json!(queue));
+        }
+        if let Some(shadow) = &self.shadow {
+            options.insert("shadow".into(), json!(shadow));
+        }
+        match (self.countdown, self.eta) {
+            (Some(_), Some(_)) => {
+                return Err(ProtocolError::ConflictingOptions(
+                    "countdown".into(),
+                    "eta".into(),
+                ))
+            }
+            (Some(countdown), None) => {
+                options.insert("countdown".into(), json!(countdown.as_secs_f64()));
+            }
+            (None, Some(eta)) => {
+                options.insert("eta".into(), json!(eta.to_rfc3339()));
+            }
+            (None, None) => {}
+        }
+        match (self.expires_in, self.expires) {
+            (Some(_), Some(_)) => {
+                return Err(ProtocolError::ConflictingOptions(
+                    "expires_in".into(),
+                    "expires".into(),
+                ))
+            }
+            (Some(expires_in), None) => {
+                options.insert("expires".into(), json!(expires_in.as_secs_f64()));
+            }
+            (None, Some(expires)) => {
+                options.insert("expires".into(), json!(expires.to_rfc3339()));
+            }
+            (None, None) => {}
+        }
+        let (hard_time_limit, soft_time_limit) = self.options.timelimit();
+        if let Some(time_limit) = hard_time_limit {
+            options.insert("time_limit".into(), json!(time_limit));
+        }
+        if let Some(time_limit) = soft_time_limit {
+            options.insert("soft_time_limit".into(), json!(time_limit));
+        }
+        if let Some(priority) = self.options.priority {
+            options.insert("priority".into(), json!(priority));
+        }
+        if let Some(ignore_result) = self.options.ignore_result {
+            options.insert("ignore_result".into(), json!(ignore_result));
+        }
+        if let Some(content_type) = self.options.content_type {
+            options.insert("serializer".into(), json!(content_type.serializer_name()));
+        }
+        if !self.headers.is_empty() {
+            options.insert("headers".into(), json!(self.headers));
+        }
+        Ok(options)
+    }
+}
+
+/// Check that a task ID chosen when sending a task is valid.
+pub(crate) fn check_task_id(task_id: &str) -> Result<(), ProtocolError> {
+    if task_id.is_empty() {
+        return Err(ProtocolError::InvalidProperty("task_id".into()));
+    }
+    Ok(())
+}
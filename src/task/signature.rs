@@ -1,6 +1,17 @@
-use super::{Task, TaskOptions};
-use crate::protocol::MessageContentType;
+use super::{SendOptions, Task, TaskOptions};
+use crate::error::ProtocolError;
+use crate::protocol::{Compression, DeliveryMode, MessageContentType, SerializedSignature};
 use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A task triggered by another one, like the next task of a chain or a callback, serialized
+/// when the task is sent.
+pub(crate) type ChainLink =
+    Arc<dyn Fn() -> Result<SerializedSignature, ProtocolError> + Send + Sync>;
 
 /// Wraps the parameters and execution options for a single task invocation.
 ///
@@ -31,25 +42,46 @@ where
     /// A queue to send the task to.
     pub(crate) queue: Option<String>,
 
-    /// The number of seconds to wait before executing the task. This is equivalent to setting
+    /// How long to wait before executing the task. This is equivalent to setting
     /// [`eta`](struct.Signature.html#structfield.eta)
     /// to `current_time + countdown`.
-    pub(crate) countdown: Option<u32>,
+    pub(crate) countdown: Option<Duration>,
 
     /// A future ETA at which to execute the task.
     pub(crate) eta: Option<DateTime<Utc>>,
 
-    /// A number of seconds until the task expires, at which point it should no longer
-    /// be executed. This is equivalent to setting
+    /// How long until the task expires, at which point it should no longer be executed.
+    /// This is equivalent to setting
     /// [`expires`](struct.Signature.html#structfield.expires)
     /// to `current_time + expires_in`.
-    pub(crate) expires_in: Option<u32>,
+    pub(crate) expires_in: Option<Duration>,
 
     /// A future time at which the task will expire.
     pub(crate) expires: Option<DateTime<Utc>>,
 
+    /// The ID to send the task with instead of a random one.
+    pub(crate) task_id: Option<String>,
+
+    /// The name the task is logged and reported under by the worker.
+    pub(crate) shadow: Option<String>,
+
     /// Additional options.
     pub(crate) options: TaskOptions,
+
+    /// Custom headers to send along with the headers of the protocol.
+    pub(crate) headers: HashMap<String, Value>,
+
+    /// The tasks to execute after this one, in order.
+    pub(crate) chain: Vec<ChainLink>,
+
+    /// The callback of the chord the task is part of.
+    pub(crate) chord: Option<SerializedSignature>,
+
+    /// The tasks to send with the result of the task when it succeeds.
+    pub(crate) callbacks: Vec<ChainLink>,
+
+    /// The tasks to send with the ID and the error of the task when it fails.
+    pub(crate) errbacks: Vec<ChainLink>,
 }
 
 impl<T> Signature<T>
@@ -60,12 +92,19 @@ where
     pub fn new(params: T::Params) -> Self {
         Self {
             params,
-            queue: None,
+            queue: T::QUEUE.map(Into::into),
             countdown: None,
             eta: None,
             expires_in: None,
             expires: None,
+            task_id: None,
+            shadow: None,
             options: T::DEFAULTS,
+            headers: HashMap::new(),
+            chain: vec![],
+            chord: None,
+            callbacks: vec![],
+            errbacks: vec![],
         }
     }
 
@@ -74,55 +113,201 @@ where
         T::NAME
     }
 
-    /// Set the queue.
+    /// Get the options the task is sent with.
+    pub(crate) fn send_options(&self) -> SendOptions {
+        SendOptions {
+            queue: self.queue.clone(),
+            countdown: self.countdown,
+            eta: self.eta,
+            expires_in: self.expires_in,
+            expires: self.expires,
+            task_id: self.task_id.clone(),
+            shadow: self.shadow.clone(),
+            options: self.options,
+            headers: self.headers.clone(),
+        }
+    }
+
+    /// Set the queue, overriding the one of the task definition and the task routes of the
+    /// app.
     pub fn with_queue(mut self, queue: &str) -> Self {
         self.queue = Some(queue.into());
         self
     }
 
-    /// Set the countdown.
-    pub fn with_countdown(mut self, countdown: u32) -> Self {
+    /// Set how long to wait before executing the task.
+    ///
+    /// It can't be combined with an [ETA](Signature::with_eta): sending a task with both
+    /// fails.
+    pub fn with_countdown(mut self, countdown: Duration) -> Self {
         self.countdown = Some(countdown);
         self
     }
 
-    /// Set the ETA.
+    /// Set the time at which to execute the task.
+    ///
+    /// Workers hold the tasks which aren't due yet without counting them towards their
+    /// `prefetch_count`, and their results stay pending until they start.
     pub fn with_eta(mut self, eta: DateTime<Utc>) -> Self {
         self.eta = Some(eta);
         self
     }
 
-    /// Set the number of seconds until the task expires.
-    pub fn with_expires_in(mut self, expires_in: u32) -> Self {
+    /// Set how long until the task expires.
+    ///
+    /// It can't be combined with an [expiration time](Signature::with_expires): sending a
+    /// task with both fails.
+    pub fn with_expires_in(mut self, expires_in: Duration) -> Self {
         self.expires_in = Some(expires_in);
         self
     }
 
-    /// Set the expiration time.
+    /// Set the time after which the task should no longer be executed.
+    ///
+    /// Workers discard the tasks they receive after it and mark them as revoked, whether
+    /// or not the broker drops expired messages itself. A task which started before it
+    /// still runs to completion.
     pub fn with_expires(mut self, expires: DateTime<Utc>) -> Self {
         self.expires = Some(expires);
         self
     }
 
+    /// Set the ID of the task instead of a random UUID, e.g. an ID derived from a business
+    /// key like `invoice-12345-generate` so that sending the task again refers to the same
+    /// result. The ID must not be empty, otherwise sending the task fails.
+    ///
+    /// Sending the task again with the same ID sends another message: both are executed,
+    /// and the result stored last in the backend wins. The [`AsyncResult`](super::AsyncResult)
+    /// of either refers to that result.
+    pub fn with_task_id(mut self, task_id: &str) -> Self {
+        self.task_id = Some(task_id.into());
+        self
+    }
+
+    /// Set the name the task is logged and reported under by the worker, instead of its own,
+    /// e.g. `run_report:sales_daily` for a generic `run_report` task. It is sent in the
+    /// `shadow` header like Python Celery does, and is the `name` of the task in the
+    /// [events](crate::CeleryBuilder::worker_events) of the worker, which still executes the
+    /// task registered under the task's own name.
+    pub fn with_shadow(mut self, shadow: &str) -> Self {
+        self.shadow = Some(shadow.into());
+        self
+    }
+
+    /// Add custom headers to the task message, e.g. a tenant ID or a trace context. Workers
+    /// can read them from the [`Request`](super::Request) of the task and from the
+    /// `task_prerun` and `task_postrun` signals.
+    ///
+    /// Headers named like one of the headers of the protocol, e.g. `id` or `retries`
+    /// (see [`RESERVED_HEADERS`](crate::protocol::RESERVED_HEADERS)), are ignored.
+    pub fn with_headers(mut self, headers: HashMap<String, Value>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Execute another task after this one, with the result of this task as its first
+    /// parameter, like a chain in Python Celery. The value of the first parameter in
+    /// `next` is ignored.
+    ///
+    /// The chain stops at the first task which fails, and its error is also stored as the
+    /// result of the tasks which didn't run. Sending a chain with
+    /// [`send_task`](crate::Celery::send_task) returns the result of its last task.
+    pub fn then<U>(mut self, mut next: Signature<U>) -> Self
+    where
+        U: Task + 'static,
+    {
+        let rest = std::mem::take(&mut next.chain);
+        self.chain
+            .push(Arc::new(move || SerializedSignature::try_from(&next)));
+        self.chain.extend(rest);
+        self
+    }
+
+    /// Send another task with the result of this task as its first parameter when this task
+    /// succeeds. The value of the first parameter in `callback` is ignored.
+    ///
+    /// Unlike with [`then`](Signature::then), any number of tasks can be linked, and they
+    /// are all sent.
+    pub fn link<U>(mut self, callback: Signature<U>) -> Self
+    where
+        U: Task + 'static,
+    {
+        self.callbacks
+            .push(Arc::new(move || SerializedSignature::try_from(&callback)));
+        self
+    }
+
+    /// Send another task when this task fails, with the ID of this task and its
+    /// [`TaskError`](crate::error::TaskError) as its first two parameters. The values of these
+    /// parameters in `errback` are ignored.
+    ///
+    /// The errback isn't sent when the task is retried, only once it failed for good.
+    pub fn link_error<U>(mut self, errback: Signature<U>) -> Self
+    where
+        U: Task + 'static,
+    {
+        self.errbacks
+            .push(Arc::new(move || SerializedSignature::partial(&errback, 2)));
+        self
+    }
+
     /// Set the content type serialization format for the message body.
     pub fn with_content_type(mut self, content_type: MessageContentType) -> Self {
         self.options.content_type = Some(content_type);
         self
     }
 
+    /// Set the algorithm to compress the task message with (see
+    /// [`TaskOptions::compression`]).
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.options.compression = Some(compression);
+        self
+    }
+
+    /// Set the priority of the task message (see [`TaskOptions::priority`]).
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.options.priority = Some(priority);
+        self
+    }
+
+    /// Set whether the task message is persisted by the broker (see
+    /// [`TaskOptions::delivery_mode`]).
+    pub fn with_delivery_mode(mut self, delivery_mode: DeliveryMode) -> Self {
+        self.options.delivery_mode = Some(delivery_mode);
+        self
+    }
+
+    /// Set whether the task message is acknowledged after the task has been executed
+    /// (see [`TaskOptions::acks_late`]).
+    pub fn with_acks_late(mut self, acks_late: bool) -> Self {
+        self.options.acks_late = Some(acks_late);
+        self
+    }
+
+    /// Set whether the result of the task isn't stored (see
+    /// [`TaskOptions::ignore_result`]).
+    pub fn with_ignore_result(mut self, ignore_result: bool) -> Self {
+        self.options.ignore_result = Some(ignore_result);
+        self
+    }
+
     /// Set a time limit (in seconds) for the task.
     pub fn with_time_limit(mut self, time_limit: u32) -> Self {
         self.options.time_limit = Some(time_limit);
         self
     }
 
-    /// Set a hard time limit (in seconds) for the task.
-    ///
-    /// *Note that this is really only for compatability with Python workers*.
-    /// `time_limit` and `hard_time_limit` are treated the same by Rust workers, and if both
-    /// are set, the minimum of the two will be used.
+    /// Set a hard time limit (in seconds) for the task (see
+    /// [`TaskOptions::hard_time_limit`]).
     pub fn with_hard_time_limit(mut self, time_limit: u32) -> Self {
         self.options.hard_time_limit = Some(time_limit);
         self
     }
+
+    /// Set a soft time limit (in seconds) for the task (see
+    /// [`TaskOptions::soft_time_limit`]).
+    pub fn with_soft_time_limit(mut self, soft_time_limit: u32) -> Self {
+        self.options.soft_time_limit = Some(soft_time_limit);
+        self
+    }
 }
@@ -1,9 +1,9 @@
-//! These tests should be compiled but not run.
+use celery::backend::RedisBackend;
 
 #[tokio::test]
 async fn test_basic_use() {
     let _app = celery::app!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         backend = std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://localhost".into()),
         tasks = [],
         task_routes = []
@@ -14,10 +14,10 @@ async fn test_basic_use() {
 
 #[tokio::test]
 async fn test_basic_use_with_variable() {
-    let connection_string = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into());
+    let connection_string = String::from("memory://");
     let default_queue = "default";
     let _app = celery::app!(
-        broker = AMQPBroker { connection_string },
+        broker = InMemoryBroker { connection_string },
         tasks = [],
         task_routes = [],
         default_queue = default_queue,
@@ -29,7 +29,7 @@ async fn test_basic_use_with_variable() {
 #[tokio::test]
 async fn test_basic_use_with_trailing_comma() {
     let _app = celery::app!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = [],
     )
@@ -40,7 +40,7 @@ async fn test_basic_use_with_trailing_comma() {
 #[tokio::test]
 async fn test_with_options() {
     let _app = celery::app!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = [],
         task_time_limit = 2
@@ -52,7 +52,7 @@ async fn test_with_options() {
 #[tokio::test]
 async fn test_with_options_and_trailing_comma() {
     let _app = celery::app!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = [],
         task_time_limit = 2,
@@ -64,10 +64,62 @@ async fn test_with_options_and_trailing_comma() {
 #[tokio::test]
 async fn test_backend() {
     let _app = celery::app!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = []
     )
     .await
     .unwrap();
 }
+
+#[tokio::test]
+async fn test_backend_type() {
+    let _app = celery::app!(
+        broker = InMemoryBroker { "memory://" },
+        backend = RedisBackend { "redis://localhost" },
+        tasks = [],
+        task_routes = []
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_backend_type_with_variable_and_trailing_comma() {
+    let backend_url = std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://localhost".into());
+    let _app = celery::app!(
+        broker = InMemoryBroker { "memory://" },
+        backend = RedisBackend { backend_url },
+        tasks = [],
+        task_routes = [],
+        task_time_limit = 2,
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_backend_with_variable() {
+    let backend_url = String::from("redis://localhost");
+    let _app = celery::app!(
+        broker = InMemoryBroker { "memory://" },
+        backend = backend_url,
+        tasks = [],
+        task_routes = [],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_backend_url_with_variable() {
+    let backend_url = String::from("redis://localhost");
+    let _app = celery::app!(
+        broker = InMemoryBroker { "memory://" },
+        backend_url = backend_url,
+        tasks = [],
+        task_routes = [],
+    )
+    .await
+    .unwrap();
+}
@@ -1,7 +1,7 @@
 #[tokio::test]
 async fn test_basic_use() {
     let _beat = celery::beat!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = []
     )
@@ -11,9 +11,9 @@ async fn test_basic_use() {
 
 #[tokio::test]
 async fn test_basic_use_with_variable() {
-    let connection_string = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into());
+    let connection_string = String::from("memory://");
     let _app = celery::beat!(
-        broker = AMQPBroker { connection_string },
+        broker = InMemoryBroker { connection_string },
         tasks = [],
         task_routes = []
     )
@@ -24,7 +24,7 @@ async fn test_basic_use_with_variable() {
 #[tokio::test]
 async fn test_basic_use_with_trailing_comma() {
     let _beat = celery::beat!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = [],
     )
@@ -35,7 +35,7 @@ async fn test_basic_use_with_trailing_comma() {
 #[tokio::test]
 async fn test_with_options() {
     let _beat = celery::beat!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = [],
         default_queue = "celery"
@@ -47,7 +47,7 @@ async fn test_with_options() {
 #[tokio::test]
 async fn test_with_options_and_trailing_comma() {
     let _beat = celery::beat!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [],
         task_routes = [],
         default_queue = "celery",
@@ -59,7 +59,7 @@ async fn test_with_options_and_trailing_comma() {
 #[tokio::test]
 async fn test_tasks_and_task_routes_with_trailing_comma() {
     let _beat = celery::beat!(
-        broker = AMQPBroker { std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://localhost:5672//".into()) },
+        broker = InMemoryBroker { "memory://" },
         tasks = [,],
         task_routes = [,],
     )
@@ -1,15 +1,26 @@
 #![allow(non_upper_case_globals)]
 
-use celery::error::TaskError;
-use celery::task::{Request, Signature, Task, TaskOptions};
+use celery::broker::{
+    AMQPBrokerBuilder, Broker, BrokerBuilder, ConnectionState, ExchangeKind, QueueOptions,
+};
+use celery::error::{BrokerError, TaskError};
+use celery::protocol::Message;
+use celery::task::{Request, Signature, Task, TaskContext, TaskOptions};
 
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
+use futures::StreamExt;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tokio::time::{self, Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
 
 static SUCCESSES: Lazy<Mutex<HashMap<String, Result<i32, TaskError>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
@@ -56,11 +67,11 @@ impl Task for add {
         Ok(params.x + params.y)
     }
 
-    async fn on_success(&self, returned: &Self::Returns) {
+    async fn on_success(&self, ctx: &TaskContext<'_, Self>, returned: &Self::Returns) {
         SUCCESSES
             .lock()
             .unwrap()
-            .insert(self.request().id.clone(), Ok(*returned));
+            .insert(ctx.task_id.into(), Ok(*returned));
     }
 }
 
@@ -111,3 +122,459 @@ async fn test_amqp_broker() {
     assert!(successes[&task_id_2].is_ok());
     assert_eq!(successes[&task_id_2].as_ref().unwrap(), &4);
 }
+
+#[tokio::test]
+#[ignore = "requires the rabbitmq_delayed_message_exchange plugin"]
+async fn test_amqp_delayed_delivery() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    let broker = Box::new(AMQPBrokerBuilder::new(&broker_url))
+        .delayed_delivery(true)
+        .declare_queue("delayed")
+        .build(5)
+        .await
+        .unwrap();
+
+    let message = Message::try_from(add::new(1, 2).with_countdown(Duration::from_secs(2))).unwrap();
+    let sent_at = Instant::now();
+    broker.send(&message, "delayed").await.unwrap();
+
+    let (_, mut deliveries) = broker.consume("delayed", Box::new(|_| {})).await.unwrap();
+    let delivery = time::timeout(Duration::from_secs(10), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    // The message is only delivered once it is due.
+    assert!(sent_at.elapsed() >= Duration::from_secs(2));
+    assert_eq!(
+        message.task_id(),
+        delivery.try_deserialize_message().unwrap().task_id()
+    );
+    broker.ack(delivery.as_ref()).await.unwrap();
+}
+
+/// Publishes the same batch of messages with and without publisher confirms and reports
+/// the throughput of both, which is the figure quoted in the `publisher_confirms` docs.
+#[tokio::test]
+async fn test_amqp_publisher_confirms() {
+    const MESSAGES: u32 = 500;
+
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    for confirms in [false, true] {
+        let broker = Box::new(AMQPBrokerBuilder::new(&broker_url))
+            .publisher_confirms(confirms)
+            .declare_queue("confirms")
+            .build(5)
+            .await
+            .unwrap();
+
+        let started_at = Instant::now();
+        for i in 0..MESSAGES {
+            let message = Message::try_from(add::new(i as i32, 1)).unwrap();
+            broker.send(&message, "confirms").await.unwrap();
+        }
+        let elapsed = started_at.elapsed();
+        println!(
+            "publisher confirms {}: {:.0} messages/s",
+            if confirms { "on" } else { "off" },
+            MESSAGES as f64 / elapsed.as_secs_f64()
+        );
+
+        broker.close().await.unwrap();
+    }
+}
+
+/// Publishes the same batch of messages concurrently from a single channel and from a pool
+/// of channels, and reports the throughput of both.
+#[tokio::test]
+async fn test_amqp_producer_pool() {
+    const MESSAGES: u32 = 2000;
+
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    for pool_limit in [1, 8] {
+        let broker = Box::new(AMQPBrokerBuilder::new(&broker_url))
+            .pool_limit(pool_limit)
+            .declare_queue("pool")
+            .build(5)
+            .await
+            .unwrap();
+
+        let messages: Vec<Message> = (0..MESSAGES)
+            .map(|i| Message::try_from(add::new(i as i32, 1)).unwrap())
+            .collect();
+        let started_at = Instant::now();
+        let results =
+            futures::future::join_all(messages.iter().map(|message| broker.send(message, "pool")))
+                .await;
+        let elapsed = started_at.elapsed();
+        assert!(results.iter().all(Result::is_ok));
+        println!(
+            "producer pool of {}: {:.0} messages/s",
+            pool_limit,
+            MESSAGES as f64 / elapsed.as_secs_f64()
+        );
+
+        broker.close().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_amqp_producer_pool_replaces_closed_channels() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    let broker = Box::new(AMQPBrokerBuilder::new(&broker_url))
+        .pool_limit(2)
+        .declare_queue("pool_replace")
+        .build(5)
+        .await
+        .unwrap();
+
+    // Publishing to an exchange which doesn't exist makes the server close the channel.
+    let message = Message::try_from(add::new(1, 2)).unwrap();
+    let _ = broker
+        .send_to_exchange(&message, "celery.missing", "pool_replace")
+        .await;
+    time::sleep(Duration::from_millis(500)).await;
+
+    // Every channel of the pool still works, the closed one having been replaced.
+    for _ in 0..4 {
+        broker.send(&message, "pool_replace").await.unwrap();
+    }
+    broker.close().await.unwrap();
+}
+
+/// A backlog in one queue only takes the prefetch slots of its own prefetch count, so the
+/// other queues consumed by the worker are still serviced.
+#[tokio::test]
+async fn test_amqp_queue_prefetch_count() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    let broker = Box::new(AMQPBrokerBuilder::new(&broker_url))
+        .prefetch_count(10)
+        .declare_queue_with_options(
+            "fair_backlog",
+            QueueOptions {
+                prefetch_count: Some(5),
+                ..Default::default()
+            },
+        )
+        .declare_queue("fair_other")
+        .build(5)
+        .await
+        .unwrap();
+    for i in 0..1000 {
+        let message = Message::try_from(add::new(i, 1)).unwrap();
+        broker.send(&message, "fair_backlog").await.unwrap();
+    }
+    let message = Message::try_from(add::new(1, 2)).unwrap();
+    broker.send(&message, "fair_other").await.unwrap();
+
+    // Consume both queues at once like a worker does, without acknowledging anything.
+    let mut deliveries = tokio_stream::StreamMap::new();
+    for queue in &["fair_backlog", "fair_other"] {
+        let (_, consumer) = broker.consume(queue, Box::new(|_| {})).await.unwrap();
+        deliveries.insert(*queue, consumer);
+    }
+    let mut received: HashMap<&str, usize> = HashMap::new();
+    while let Ok(Some((queue, delivery))) =
+        time::timeout(Duration::from_secs(2), deliveries.next()).await
+    {
+        assert!(delivery.is_ok());
+        *received.entry(queue).or_default() += 1;
+    }
+    assert!(received.get("fair_backlog").copied().unwrap_or_default() <= 5);
+    assert!(received.get("fair_other").copied().unwrap_or_default() >= 1);
+}
+
+/// Messages sent with an expiration time are discarded by the server once it is over.
+#[tokio::test]
+async fn test_amqp_discards_expired_messages() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    let broker = Box::new(AMQPBrokerBuilder::new(&broker_url))
+        .declare_queue("expiring")
+        .build(5)
+        .await
+        .unwrap();
+    let signature =
+        add::new(1, 2).with_expires(chrono::Utc::now() + chrono::Duration::milliseconds(500));
+    let message = Message::try_from(signature).unwrap();
+    broker.send(&message, "expiring").await.unwrap();
+    time::sleep(Duration::from_secs(1)).await;
+
+    let (_, mut deliveries) = broker.consume("expiring", Box::new(|_| {})).await.unwrap();
+    assert!(time::timeout(Duration::from_secs(1), deliveries.next())
+        .await
+        .is_err());
+}
+
+/// When the queue being consumed is deleted, the server cancels its consumer and the
+/// worker declares the queue again and starts a new one.
+#[tokio::test]
+async fn test_amqp_recovers_from_consumer_cancellation() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    let my_app = celery::CeleryBuilder::new("celery", &broker_url, None)
+        .default_queue("recovering")
+        .broker_connection_retry_delay(1)
+        .build()
+        .await
+        .unwrap();
+    my_app.register_task::<add>().await.unwrap();
+    let my_app = Arc::new(my_app);
+
+    let recovery = async {
+        time::sleep(Duration::from_millis(500)).await;
+        let conn = lapin::Connection::connect(&broker_url, lapin::ConnectionProperties::default())
+            .await
+            .unwrap();
+        let channel = conn.create_channel().await.unwrap();
+        channel
+            .queue_delete("recovering", Default::default())
+            .await
+            .unwrap();
+        conn.close(0, "").await.unwrap();
+
+        // Give the worker the time to reconnect before sending the task, which would be
+        // dropped by the server if the queue didn't exist.
+        time::sleep(Duration::from_secs(2)).await;
+        let task_id = my_app.send_task(add::new(3, 4)).await.unwrap().task_id();
+        while !SUCCESSES.lock().unwrap().contains_key(&task_id) {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        task_id
+    };
+    let task_id = time::timeout(Duration::from_secs(10), async {
+        tokio::select! {
+            result = my_app.consume_from(&["recovering"]) => panic!("worker stopped: {:?}", result),
+            task_id = recovery => task_id,
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(SUCCESSES.lock().unwrap()[&task_id].as_ref().unwrap(), &7);
+}
+
+/// The broker URL can be read from environment variables.
+#[tokio::test]
+async fn test_amqp_broker_url_from_env() {
+    if std::env::var("AMQP_ADDR").is_err() {
+        std::env::set_var("AMQP_ADDR", "amqp://127.0.0.1:5672//");
+    }
+    let broker = AMQPBrokerBuilder::new("${AMQP_ADDR}")
+        .build(5)
+        .await
+        .unwrap();
+    assert!(!broker.safe_url().contains("${"));
+    broker.close().await.unwrap();
+}
+
+/// Start a TCP proxy to `target` which stops forwarding anything once frozen, without
+/// closing the connections, like a NAT gateway which dropped them.
+async fn freezable_proxy(target: String) -> (std::net::SocketAddr, Arc<AtomicBool>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let frozen = Arc::new(AtomicBool::new(false));
+    let proxy_frozen = frozen.clone();
+    tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let server = TcpStream::connect(&target).await.unwrap();
+            let (client_read, client_write) = client.into_split();
+            let (server_read, server_write) = server.into_split();
+            tokio::spawn(forward(client_read, server_write, proxy_frozen.clone()));
+            tokio::spawn(forward(server_read, client_write, proxy_frozen.clone()));
+        }
+    });
+    (addr, frozen)
+}
+
+async fn forward(
+    mut from: tokio::net::tcp::OwnedReadHalf,
+    mut to: tokio::net::tcp::OwnedWriteHalf,
+    frozen: Arc<AtomicBool>,
+) {
+    let mut buffer = [0; 4096];
+    while let Ok(n) = from.read(&mut buffer).await {
+        if n == 0 || frozen.load(Ordering::SeqCst) {
+            // Keep the connection open, forwarding nothing.
+            futures::future::pending::<()>().await;
+        }
+        if to.write_all(&buffer[..n]).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Start a TCP proxy to `target` whose connections can be dropped one by one, through
+/// their forwarding tasks in the order the connections were opened.
+async fn killable_proxy(target: String) -> (std::net::SocketAddr, Arc<Mutex<Vec<JoinHandle<()>>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(Mutex::new(vec![]));
+    let proxy_connections = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let mut server = TcpStream::connect(&target).await.unwrap();
+            let connection = tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            });
+            proxy_connections.lock().unwrap().push(connection);
+        }
+    });
+    (addr, connections)
+}
+
+/// The URL of the broker at `broker_url` through a proxy listening on `proxy_addr`.
+fn proxied_url(broker_url: &str, proxy_addr: std::net::SocketAddr) -> String {
+    let uri = lapin::uri::AMQPUri::from_str(broker_url).unwrap();
+    format!(
+        "amqp://{}:{}@{}/{}",
+        uri.authority.userinfo.username,
+        uri.authority.userinfo.password,
+        proxy_addr,
+        uri.vhost.replace('/', "%2f")
+    )
+}
+
+/// The address of the broker at `broker_url`, for a proxy to forward to.
+fn broker_addr(broker_url: &str) -> String {
+    let uri = lapin::uri::AMQPUri::from_str(broker_url).unwrap();
+    format!("{}:{}", uri.authority.host, uri.authority.port)
+}
+
+/// A connection which stops answering is noticed by the connection monitor, and
+/// re-established before the next message is sent.
+#[tokio::test]
+async fn test_amqp_connection_monitor() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    let (proxy_addr, frozen) = freezable_proxy(broker_addr(&broker_url)).await;
+
+    let broker = Box::new(AMQPBrokerBuilder::new(&proxied_url(
+        &broker_url,
+        proxy_addr,
+    )))
+    .heartbeat(Some(1))
+    .separate_producer_connection(false)
+    .declare_queue("monitored")
+    .build(5)
+    .await
+    .unwrap();
+    assert_eq!(broker.connection_state(), ConnectionState::Connected);
+
+    frozen.store(true, Ordering::SeqCst);
+    time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(broker.connection_state(), ConnectionState::NotConnected);
+
+    // New connections go through again, while the frozen one stays dead.
+    frozen.store(false, Ordering::SeqCst);
+    let message = Message::try_from(add::new(1, 2)).unwrap();
+    broker.send(&message, "monitored").await.unwrap();
+    assert_eq!(broker.connection_state(), ConnectionState::Connected);
+
+    broker.close().await.unwrap();
+    assert_eq!(broker.connection_state(), ConnectionState::Closed);
+}
+
+/// The producer and the consumer connections are re-established independently, so losing
+/// one of them doesn't interrupt the other.
+#[tokio::test]
+async fn test_amqp_separate_producer_connection() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    let (proxy_addr, connections) = killable_proxy(broker_addr(&broker_url)).await;
+
+    let broker = Box::new(AMQPBrokerBuilder::new(&proxied_url(
+        &broker_url,
+        proxy_addr,
+    )))
+    .declare_queue("separate_producer")
+    .build(5)
+    .await
+    .unwrap();
+    // The producer connection is only opened by the first send.
+    assert_eq!(connections.lock().unwrap().len(), 1);
+    let first = Message::try_from(add::new(1, 2)).unwrap();
+    broker.send(&first, "separate_producer").await.unwrap();
+    assert_eq!(connections.lock().unwrap().len(), 2);
+
+    // Messages are still sent while the consumer connection is lost.
+    connections.lock().unwrap()[0].abort();
+    time::sleep(Duration::from_millis(500)).await;
+    let second = Message::try_from(add::new(2, 3)).unwrap();
+    broker.send(&second, "separate_producer").await.unwrap();
+    assert_eq!(connections.lock().unwrap().len(), 2);
+
+    // Messages are still consumed while the producer connection is lost.
+    broker.reconnect(5).await.unwrap();
+    connections.lock().unwrap()[1].abort();
+    time::sleep(Duration::from_millis(500)).await;
+    let (_, mut deliveries) = broker
+        .consume("separate_producer", Box::new(|_| {}))
+        .await
+        .unwrap();
+    for message in [&first, &second].iter() {
+        let delivery = time::timeout(Duration::from_secs(2), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .ok()
+            .unwrap();
+        assert_eq!(
+            message.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+        broker.ack(delivery.as_ref()).await.unwrap();
+    }
+
+    // The producer connection is re-established by the next send.
+    let third = Message::try_from(add::new(3, 4)).unwrap();
+    broker.send(&third, "separate_producer").await.unwrap();
+    assert_eq!(connections.lock().unwrap().len(), 4);
+    let delivery = time::timeout(Duration::from_secs(2), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    assert_eq!(
+        third.task_id(),
+        delivery.try_deserialize_message().unwrap().task_id()
+    );
+    broker.ack(delivery.as_ref()).await.unwrap();
+    broker.close().await.unwrap();
+}
+
+/// Declaring an exchange which exists with other properties fails with a clear error
+/// instead of being retried like a connection error, while declaring it again with the
+/// same properties is fine.
+#[tokio::test]
+async fn test_amqp_exchange_mismatch() {
+    let broker_url =
+        std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672//".into());
+    async fn build(broker_url: &str, kind: ExchangeKind) -> Result<Box<dyn Broker>, BrokerError> {
+        Box::new(AMQPBrokerBuilder::new(broker_url))
+            .declare_exchange("mismatched", kind, false)
+            .bind_queue("mismatched_queue", "mismatched", "mismatched.#")
+            .build(5)
+            .await
+    }
+    build(&broker_url, ExchangeKind::Topic).await.unwrap();
+    build(&broker_url, ExchangeKind::Topic).await.unwrap();
+    match build(&broker_url, ExchangeKind::Direct).await {
+        Err(BrokerError::ExchangeMismatch(exchange, _)) => assert_eq!(exchange, "mismatched"),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+
+    let result = Box::new(AMQPBrokerBuilder::new(&broker_url))
+        .bind_queue("mismatched_queue", "undeclared", "")
+        .build(5)
+        .await;
+    assert!(
+        matches!(result, Err(BrokerError::UnknownExchange(exchange)) if exchange == "undeclared")
+    );
+}
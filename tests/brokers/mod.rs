@@ -1,2 +1,8 @@
 mod amqp;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "pubsub")]
+mod pubsub;
 mod redis;
+#[cfg(feature = "servicebus")]
+mod servicebus;
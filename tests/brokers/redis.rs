@@ -1,11 +1,15 @@
 #![allow(non_upper_case_globals)]
 use anyhow::Result;
 use async_trait::async_trait;
+use celery::broker::{BrokerBuilder, QueueOptions, RedisBrokerBuilder, RedisTransport};
 use celery::error::TaskError;
-use celery::task::{Request, Signature, Task, TaskOptions};
+use celery::protocol::Message;
+use celery::task::{Request, Signature, Task, TaskContext, TaskOptions};
+use futures::StreamExt;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::sync::Mutex;
 use tokio::time::{self, Duration};
 
@@ -54,11 +58,11 @@ impl Task for add {
         Ok(params.x + params.y)
     }
 
-    async fn on_success(&self, returned: &Self::Returns) {
+    async fn on_success(&self, ctx: &TaskContext<'_, Self>, returned: &Self::Returns) {
         SUCCESSES
             .lock()
             .unwrap()
-            .insert(self.request().id.clone(), Ok(*returned));
+            .insert(ctx.task_id.into(), Ok(*returned));
     }
 }
 
@@ -119,3 +123,279 @@ async fn test_redis_broker() -> Result<()> {
     assert_eq!(successes[&task_id_2].as_ref().unwrap(), &4);
     Ok(())
 }
+
+#[tokio::test]
+async fn test_redis_restores_unacked_messages() {
+    let broker_url =
+        std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://127.0.0.1:6379/".into());
+    let broker = Box::new(RedisBrokerBuilder::new(&broker_url))
+        .visibility_timeout(1)
+        .declare_queue("visibility")
+        .build(5)
+        .await
+        .unwrap();
+
+    let message = Message::try_from(add::new(1, 2)).unwrap();
+    broker.send(&message, "visibility").await.unwrap();
+
+    // Receive the message without acknowledging it, like a worker dying mid-task would.
+    let (_, mut deliveries) = broker
+        .consume("visibility", Box::new(|_| {}))
+        .await
+        .unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    assert_eq!(
+        message.task_id(),
+        delivery.try_deserialize_message().unwrap().task_id()
+    );
+
+    // Once the visibility timeout is over, the message is delivered again.
+    let redelivery = time::timeout(Duration::from_secs(10), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    assert_eq!(
+        message.task_id(),
+        redelivery.try_deserialize_message().unwrap().task_id()
+    );
+    broker.ack(redelivery.as_ref()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_redis_streams_claims_unacked_messages() {
+    let broker_url =
+        std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://127.0.0.1:6379/".into());
+    let broker = Box::new(RedisBrokerBuilder::new(&broker_url).transport(RedisTransport::Stream))
+        .visibility_timeout(1)
+        .declare_queue("streams")
+        .build(5)
+        .await
+        .unwrap();
+
+    let message = Message::try_from(add::new(1, 2)).unwrap();
+    broker.send(&message, "streams").await.unwrap();
+
+    // Receive the message without acknowledging it, like a worker dying mid-task would.
+    let (_, mut deliveries) = broker.consume("streams", Box::new(|_| {})).await.unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    assert_eq!(
+        message.task_id(),
+        delivery.try_deserialize_message().unwrap().task_id()
+    );
+
+    // Once the visibility timeout is over, the pending entry is claimed again.
+    let redelivery = time::timeout(Duration::from_secs(10), deliveries.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .ok()
+        .unwrap();
+    assert_eq!(
+        message.task_id(),
+        redelivery.try_deserialize_message().unwrap().task_id()
+    );
+    broker.ack(redelivery.as_ref()).await.unwrap();
+}
+
+/// The retries of a task keep their ETA, with both transports.
+#[tokio::test]
+async fn test_redis_retry_keeps_eta() {
+    let broker_url =
+        std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://127.0.0.1:6379/".into());
+    for (transport, queue) in [
+        (RedisTransport::List, "retry_eta"),
+        (RedisTransport::Stream, "retry_eta_streams"),
+    ] {
+        let broker = Box::new(RedisBrokerBuilder::new(&broker_url).transport(transport))
+            .declare_queue(queue)
+            .build(5)
+            .await
+            .unwrap();
+
+        let message = Message::try_from(add::new(1, 2)).unwrap();
+        broker.send(&message, queue).await.unwrap();
+        let (_, mut deliveries) = broker.consume(queue, Box::new(|_| {})).await.unwrap();
+        let delivery = time::timeout(Duration::from_secs(5), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .ok()
+            .unwrap();
+
+        let eta = chrono::Utc::now() + chrono::Duration::seconds(30);
+        broker.retry(delivery.as_ref(), Some(eta)).await.unwrap();
+        broker.ack(delivery.as_ref()).await.unwrap();
+
+        let retried = time::timeout(Duration::from_secs(5), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .ok()
+            .unwrap();
+        let retried_message = retried.try_deserialize_message().unwrap();
+        assert_eq!(message.task_id(), retried_message.task_id());
+        assert_eq!(Some(1), retried_message.headers.retries);
+        assert_eq!(
+            Some(eta.timestamp_millis()),
+            retried_message
+                .headers
+                .eta
+                .map(|eta| eta.timestamp_millis())
+        );
+        broker.ack(retried.as_ref()).await.unwrap();
+    }
+}
+
+/// A backlog in one queue only takes the prefetch slots of its own prefetch count, so the
+/// other queues consumed by the worker are still serviced.
+#[tokio::test]
+async fn test_redis_queue_prefetch_count() {
+    let broker_url =
+        std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://127.0.0.1:6379/".into());
+    let broker = Box::new(RedisBrokerBuilder::new(&broker_url))
+        .prefetch_count(10)
+        .declare_queue_with_options(
+            "fair_backlog",
+            QueueOptions {
+                prefetch_count: Some(5),
+                ..Default::default()
+            },
+        )
+        .declare_queue("fair_other")
+        .build(5)
+        .await
+        .unwrap();
+    for i in 0..1000 {
+        let message = Message::try_from(add::new(i, 1)).unwrap();
+        broker.send(&message, "fair_backlog").await.unwrap();
+    }
+    let message = Message::try_from(add::new(1, 2)).unwrap();
+    broker.send(&message, "fair_other").await.unwrap();
+
+    // Consume both queues at once like a worker does, without acknowledging anything.
+    let mut deliveries = tokio_stream::StreamMap::new();
+    for queue in &["fair_backlog", "fair_other"] {
+        let (_, consumer) = broker.consume(queue, Box::new(|_| {})).await.unwrap();
+        deliveries.insert(*queue, consumer);
+    }
+    let mut received: HashMap<&str, usize> = HashMap::new();
+    while let Ok(Some((queue, delivery))) =
+        time::timeout(Duration::from_secs(2), deliveries.next()).await
+    {
+        assert!(delivery.is_ok());
+        *received.entry(queue).or_default() += 1;
+    }
+    assert!(received.get("fair_backlog").copied().unwrap_or_default() <= 5);
+    assert!(received.get("fair_other").copied().unwrap_or_default() >= 1);
+}
+
+/// Brokers with different key prefixes don't see each other's messages, even when their
+/// queues have the same name.
+#[tokio::test]
+async fn test_redis_key_prefix() {
+    let broker_url =
+        std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://127.0.0.1:6379/".into());
+    let mut brokers = vec![];
+    for prefix in &["app1:", "app2:"] {
+        let broker = Box::new(RedisBrokerBuilder::new(&broker_url).key_prefix(prefix))
+            .declare_queue("prefixed")
+            .build(5)
+            .await
+            .unwrap();
+        brokers.push(broker);
+    }
+
+    let messages = [
+        Message::try_from(add::new(1, 2)).unwrap(),
+        Message::try_from(add::new(3, 4)).unwrap(),
+    ];
+    for (broker, message) in brokers.iter().zip(messages.iter()) {
+        broker.send(message, "prefixed").await.unwrap();
+    }
+
+    // Each broker only receives the message it sent.
+    for (broker, message) in brokers.iter().zip(messages.iter()) {
+        let (_, mut deliveries) = broker.consume("prefixed", Box::new(|_| {})).await.unwrap();
+        let delivery = time::timeout(Duration::from_secs(5), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .ok()
+            .unwrap();
+        assert_eq!(
+            message.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+        broker.ack(delivery.as_ref()).await.unwrap();
+        assert!(time::timeout(Duration::from_secs(2), deliveries.next())
+            .await
+            .is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_redis_send_batch() {
+    let broker_url =
+        std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://127.0.0.1:6379/".into());
+    let broker = Box::new(RedisBrokerBuilder::new(&broker_url))
+        .declare_queue("batch")
+        .build(5)
+        .await
+        .unwrap();
+
+    let messages = [
+        Message::try_from(add::new(1, 2)).unwrap(),
+        Message::try_from(add::new(3, 4)).unwrap(),
+    ];
+    let results = broker
+        .send_batch(&[(&messages[0], "batch"), (&messages[1], "batch")])
+        .await;
+    assert!(results.iter().all(Result::is_ok));
+
+    let (_, mut deliveries) = broker.consume("batch", Box::new(|_| {})).await.unwrap();
+    for message in &messages {
+        let delivery = time::timeout(Duration::from_secs(5), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .ok()
+            .unwrap();
+        assert_eq!(
+            message.task_id(),
+            delivery.try_deserialize_message().unwrap().task_id()
+        );
+        broker.ack(delivery.as_ref()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_redis_queue_len() {
+    let broker_url =
+        std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://127.0.0.1:6379/".into());
+    let queue = format!("queue_len_{}", uuid::Uuid::new_v4());
+    let broker = Box::new(RedisBrokerBuilder::new(&broker_url))
+        .declare_queue(&queue)
+        .build(5)
+        .await
+        .unwrap();
+    assert_eq!(Some(0), broker.queue_len(&queue).await.unwrap());
+
+    // Messages of every priority are counted.
+    let mut message = Message::try_from(add::new(1, 2)).unwrap();
+    broker.send(&message, &queue).await.unwrap();
+    message.properties.priority = Some(9);
+    broker.send(&message, &queue).await.unwrap();
+    assert_eq!(Some(2), broker.queue_len(&queue).await.unwrap());
+}
@@ -1,5 +1,9 @@
 use celery::error::TaskError;
-use celery::task::{Task, TaskResult};
+use celery::protocol::{Compression, DeliveryMode, Message};
+use celery::task::{RateLimit, Request, Task, TaskContext, TaskOptions, TaskResult};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
 
 #[celery::task(name = "add")]
 fn add(x: i32, y: i32) -> TaskResult<i32> {
@@ -29,11 +33,23 @@ fn test_auto_name() {
 #[celery::task(
     time_limit = 2,
     hard_time_limit = 3,
+    soft_time_limit = 1,
     max_retries = 3,
     min_retry_delay = 0,
     max_retry_delay = 60,
+    retry_backoff = false,
+    retry_backoff_max = 30,
+    retry_jitter = true,
     retry_for_unexpected = false,
-    acks_late = true
+    acks_late = true,
+    reject_on_worker_lost = true,
+    priority = 7,
+    delivery_mode = Transient,
+    compression = Zlib,
+    rate_limit = "100/m",
+    dedup = true,
+    dedup_ttl = 600,
+    ignore_result = true
 )]
 fn task_with_options() -> TaskResult<String> {
     Ok("it worked!".into())
@@ -43,14 +59,44 @@ fn task_with_options() -> TaskResult<String> {
 fn test_task_options() {
     assert_eq!(task_with_options::DEFAULTS.time_limit, Some(2));
     assert_eq!(task_with_options::DEFAULTS.hard_time_limit, Some(3));
+    assert_eq!(task_with_options::DEFAULTS.soft_time_limit, Some(1));
     assert_eq!(task_with_options::DEFAULTS.max_retries, Some(3));
     assert_eq!(task_with_options::DEFAULTS.min_retry_delay, Some(0));
     assert_eq!(task_with_options::DEFAULTS.max_retry_delay, Some(60));
+    assert_eq!(task_with_options::DEFAULTS.retry_backoff, Some(false));
+    assert_eq!(
+        task_with_options::DEFAULTS.retry_backoff_max,
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(task_with_options::DEFAULTS.retry_jitter, Some(true));
     assert_eq!(
         task_with_options::DEFAULTS.retry_for_unexpected,
         Some(false)
     );
     assert_eq!(task_with_options::DEFAULTS.acks_late, Some(true));
+    assert_eq!(
+        task_with_options::DEFAULTS.reject_on_worker_lost,
+        Some(true)
+    );
+    assert_eq!(task_with_options::DEFAULTS.priority, Some(7));
+    assert_eq!(
+        task_with_options::DEFAULTS.delivery_mode,
+        Some(DeliveryMode::Transient)
+    );
+    assert_eq!(
+        task_with_options::DEFAULTS.compression,
+        Some(Compression::Zlib)
+    );
+    assert_eq!(
+        task_with_options::DEFAULTS.rate_limit,
+        Some(RateLimit::per_minute(100))
+    );
+    assert_eq!(task_with_options::DEFAULTS.dedup, Some(true));
+    assert_eq!(
+        task_with_options::DEFAULTS.dedup_ttl,
+        Some(Duration::from_secs(600))
+    );
+    assert_eq!(task_with_options::DEFAULTS.ignore_result, Some(true));
 }
 
 #[celery::task(bind = true)]
@@ -81,23 +127,153 @@ fn task_with_strings(s1: String, s2: String) -> TaskResult<String> {
     Ok(format!("{}, {}", s1, s2))
 }
 
-async fn task_on_failure<T: Task>(task: &T, _err: &TaskError) {
-    println!("Ahhhhh task {}[{}] failed!", task.name(), task.request().id);
+async fn task_on_failure<T: Task>(task: &T, ctx: &TaskContext<'_, T>, _err: &TaskError) {
+    println!("Ahhhhh task {}[{}] failed!", task.name(), ctx.task_id);
+}
+
+/// The retry announced by the last call of `task_on_retry`.
+static NEXT_RETRY: AtomicU32 = AtomicU32::new(0);
+
+async fn task_on_retry<T: Task>(task: &T, ctx: &TaskContext<'_, T>, _err: &TaskError) {
+    println!(
+        "Task {}[{}] failed, retrying ({})",
+        task.name(),
+        ctx.task_id,
+        ctx.retries + 1
+    );
+    NEXT_RETRY.store(ctx.retries + 1, Ordering::Relaxed);
 }
 
-async fn task_on_success<T: Task>(task: &T, _ret: &T::Returns) {
+async fn task_on_success<T: Task>(task: &T, ctx: &TaskContext<'_, T>, _ret: &T::Returns) {
     println!(
-        "Woooooo task {}[{}] succeeded!",
+        "Woooooo task {}[{}] succeeded in {:?}!",
         task.name(),
-        task.request().id
+        ctx.task_id,
+        ctx.elapsed
     );
 }
 
-#[celery::task(on_failure = task_on_failure, on_success = task_on_success)]
+#[celery::task(
+    on_failure = task_on_failure,
+    on_retry = task_on_retry,
+    on_success = task_on_success
+)]
 fn task_with_callbacks() {
     println!("Yeup yeup yeup");
 }
 
+#[tokio::test]
+async fn test_on_retry() {
+    let task = task_with_callbacks::from_request(
+        Request::try_from(Message::try_from(task_with_callbacks::new()).unwrap()).unwrap(),
+        TaskOptions::default(),
+    );
+    let ctx = TaskContext {
+        task_id: &task.request().id,
+        params: &task.request().params,
+        retries: 2,
+        is_retry: true,
+        eta: None,
+        queue: None,
+        elapsed: Duration::from_secs(1),
+    };
+    task.on_retry(&ctx, &TaskError::ExpectedError("timeout".into()))
+        .await;
+    assert_eq!(3, NEXT_RETRY.load(Ordering::Relaxed));
+}
+
+/// Whether `task_on_soft_timeout` was called.
+static SOFT_TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+async fn task_on_soft_timeout<T: Task>(task: &T) {
+    println!("Hurry up task {}[{}]!", task.name(), task.request().id);
+    SOFT_TIMED_OUT.store(true, Ordering::Relaxed);
+}
+
+#[celery::task(
+    bind = true,
+    soft_time_limit = 1,
+    time_limit = 2,
+    on_soft_timeout = task_on_soft_timeout
+)]
+async fn task_with_soft_time_limit(t: &Self) -> TaskResult<bool> {
+    Ok(t.request().is_soft_time_limit_exceeded())
+}
+
+#[tokio::test]
+async fn test_on_soft_timeout() {
+    assert_eq!(task_with_soft_time_limit::DEFAULTS.soft_time_limit, Some(1));
+    let task = task_with_soft_time_limit::from_request(
+        Request::try_from(Message::try_from(task_with_soft_time_limit::new()).unwrap()).unwrap(),
+        TaskOptions::default(),
+    );
+    task.on_soft_timeout().await;
+    assert!(SOFT_TIMED_OUT.load(Ordering::Relaxed));
+}
+
+fn retry_expected_only(err: &TaskError) -> bool {
+    matches!(err, TaskError::ExpectedError(_))
+}
+
+#[celery::task(should_retry = retry_expected_only)]
+fn task_with_should_retry() -> TaskResult<()> {
+    Err(TaskError::UnexpectedError("invalid input".into()))
+}
+
+#[test]
+fn test_should_retry() {
+    let task = task_with_should_retry::from_request(
+        Request::try_from(Message::try_from(task_with_should_retry::new()).unwrap()).unwrap(),
+        TaskOptions::default(),
+    );
+    assert!(task.should_retry(&TaskError::ExpectedError("timeout".into())));
+    assert!(!task.should_retry(&TaskError::UnexpectedError("invalid input".into())));
+}
+
+#[celery::task(blocking = true)]
+fn blocking_sum(numbers: Vec<u64>) -> TaskResult<u64> {
+    Ok(numbers.iter().sum())
+}
+
+#[celery::task(bind = true, blocking = true)]
+fn bound_blocking_task(t: &Self, default_time_limit: u32) -> TaskResult<u32> {
+    Ok(t.time_limit().unwrap_or(default_time_limit))
+}
+
+#[tokio::test]
+async fn test_blocking() {
+    assert_eq!(
+        [true, true, false],
+        [
+            blocking_sum::BLOCKING,
+            bound_blocking_task::BLOCKING,
+            add::BLOCKING
+        ]
+    );
+
+    let task = blocking_sum::from_request(
+        Request::try_from(Message::try_from(blocking_sum::new(vec![1, 2, 3])).unwrap()).unwrap(),
+        TaskOptions::default(),
+    );
+    let params = task.request().params.clone();
+    assert_eq!(6, task.run(params).await.unwrap());
+}
+
+#[celery::task(queue = "emails")]
+fn queued_task() {}
+
+#[celery::task(exchange = "events", routing_key = "events.audit")]
+fn exchange_task() {}
+
+#[test]
+fn test_routing() {
+    assert_eq!(queued_task::QUEUE, Some("emails"));
+    assert_eq!(queued_task::EXCHANGE, None);
+    assert_eq!(exchange_task::QUEUE, None);
+    assert_eq!(exchange_task::EXCHANGE, Some(("events", "events.audit")));
+    assert_eq!(add::QUEUE, None);
+}
+
 #[celery::task]
 fn inferred_return_type() {
     println!("Yeeeup");
@@ -109,3 +285,19 @@ use celery::protocol::MessageContentType::MsgPack;
 fn custom_content_type() {
     println!()
 }
+
+#[test]
+fn test_content_type() {
+    assert!(matches!(
+        custom_content_type::DEFAULTS.content_type,
+        Some(MsgPack)
+    ));
+    // Tasks without a content type use the one of the app.
+    assert!(add::DEFAULTS.content_type.is_none());
+}
+
+#[test]
+fn test_attr_errors() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/task_codegen/ui/*.rs");
+}
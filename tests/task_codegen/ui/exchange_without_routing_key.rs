@@ -0,0 +1,4 @@
+#[celery::task(exchange = "events")]
+fn misconfigured() {}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[celery::task(max_retries = "5")]
+fn misconfigured() {}
+
+fn main() {}
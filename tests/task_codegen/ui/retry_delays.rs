@@ -0,0 +1,4 @@
+#[celery::task(min_retry_delay = 600, max_retry_delay = 300)]
+fn misconfigured() {}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[celery::task(max_retries = 5, max_retry = 300)]
+fn misconfigured() {}
+
+fn main() {}